@@ -0,0 +1,1029 @@
+use crate::models::{Message, Role};
+use crate::redact;
+use crate::tools::ToolCall;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::process::{Child, Command};
+use tokio::sync::{mpsc, oneshot};
+
+/// Default upper bound on reason/execute/analyze iterations for one goal, see `:set maxsteps`.
+pub const MAX_AUTONOMOUS_STEPS: usize = 15;
+
+// Command output is truncated to this many characters before being shown to the model
+const MAX_OUTPUT_CHARS: usize = 4000;
+
+// Bytes of a command's output kept from its start and from its end, what it prints in
+// between is dropped while it runs
+const OUTPUT_HEAD_BYTES: usize = 512 * 1024;
+const OUTPUT_TAIL_BYTES: usize = 512 * 1024;
+
+/// The model's decision for the next step, requested as structured output.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ReasoningResponse {
+    pub thought: String,
+    #[serde(default)]
+    pub command: Option<String>,
+    /// Structured tool to call instead of a command
+    #[serde(default)]
+    pub tool: Option<ToolCall>,
+    #[serde(default)]
+    pub done: bool,
+    #[serde(default)]
+    pub summary: Option<String>,
+}
+
+impl ReasoningResponse {
+    /// JSON schema passed as the request `format`, so Ollama constrains the reply to it.
+    pub fn schema(tool_names: &[String]) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "thought": { "type": "string" },
+                "command": { "type": ["string", "null"] },
+                "tool": {
+                    "type": ["object", "null"],
+                    "properties": {
+                        "name": { "type": "string", "enum": tool_names },
+                        "arguments": { "type": "object" }
+                    },
+                    "required": ["name", "arguments"]
+                },
+                "done": { "type": "boolean" },
+                "summary": { "type": ["string", "null"] }
+            },
+            "required": ["thought", "done"]
+        })
+    }
+}
+
+/// The model's assessment of a command's output.
+#[derive(Deserialize, Debug, Clone)]
+pub struct AnalysisResponse {
+    pub analysis: String,
+    pub goal_achieved: bool,
+}
+
+impl AnalysisResponse {
+    pub fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "analysis": { "type": "string" },
+                "goal_achieved": { "type": "boolean" }
+            },
+            "required": ["analysis", "goal_achieved"]
+        })
+    }
+}
+
+/// The plan proposed before any command runs.
+#[derive(Deserialize, Debug, Clone)]
+pub struct PlanResponse {
+    pub steps: Vec<String>,
+}
+
+impl PlanResponse {
+    pub fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "steps": { "type": "array", "items": { "type": "string" } }
+            },
+            "required": ["steps"]
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AgentState {
+    Starting,
+    Planning,
+    AwaitingPlanApproval,
+    Reasoning,
+    Executing,
+    Analyzing,
+    Paused, // Waiting for the user to resume or allow the pending command
+    Completed,
+    Failed(String),
+    Stopped,
+}
+
+impl AgentState {
+    pub fn is_finished(&self) -> bool {
+        matches!(self, AgentState::Completed | AgentState::Failed(_) | AgentState::Stopped)
+    }
+
+    /// Inverse of `label` for runs loaded from the database. Requests in flight
+    /// when the app exited are lost, so unfinished runs come back `Paused`.
+    pub fn from_label(label: &str) -> Self {
+        match label {
+            "completed" => AgentState::Completed,
+            "stopped" => AgentState::Stopped,
+            _ => match label.strip_prefix("failed: ") {
+                Some(reason) => AgentState::Failed(reason.to_string()),
+                None => AgentState::Paused,
+            },
+        }
+    }
+
+    pub fn label(&self) -> String {
+        match self {
+            AgentState::Starting => "starting".to_string(),
+            AgentState::Planning => "planning".to_string(),
+            AgentState::AwaitingPlanApproval => "waiting for plan approval".to_string(),
+            AgentState::Reasoning => "reasoning".to_string(),
+            AgentState::Executing => "executing".to_string(),
+            AgentState::Analyzing => "analyzing".to_string(),
+            AgentState::Paused => "paused".to_string(),
+            AgentState::Completed => "completed".to_string(),
+            AgentState::Failed(reason) => format!("failed: {}", reason),
+            AgentState::Stopped => "stopped".to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AgentStep {
+    pub thought: String,
+    pub command: Option<String>,
+    pub tool: Option<ToolCall>,
+    pub output: Option<Result<String, String>>,
+    /// Exit code of the command, `None` if it has not run or was killed by a signal
+    pub exit_code: Option<i32>,
+    pub analysis: Option<String>,
+}
+
+/// How a command came to be executed, recorded in the audit log.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Approval {
+    Automatic, // Covered by the approval policy or the allowlist
+    User,      // Confirmed with `n`
+    Override,  // Run despite the command policy with `!`
+}
+
+impl Approval {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Approval::Automatic => "automatic",
+            Approval::User => "user",
+            Approval::Override => "policy override",
+        }
+    }
+}
+
+/// One executed command in the append-only audit log.
+#[derive(Clone, Debug)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub executed_at: chrono::DateTime<chrono::Utc>,
+    pub session_id: i64,
+    pub command: String,
+    pub cwd: String,
+    pub risk: String,
+    pub approval: String,
+    pub dry_run: bool,
+    pub exit_code: Option<i32>,
+    pub output: String,
+}
+
+/// Result of running one command through the shell.
+#[derive(Clone, Debug)]
+pub struct CommandOutput {
+    pub exit_code: Option<i32>,
+    pub result: Result<String, String>,
+}
+
+/// One autonomous run working towards `goal` inside a chat session.
+#[derive(Clone, Debug)]
+pub struct AutonomousRun {
+    /// Database row id, 0 until the run has been saved
+    pub id: i64,
+    pub session_id: i64,
+    pub goal: String,
+    /// Plan approved (possibly edited) by the user before execution started
+    pub plan: Vec<String>,
+    pub steps: Vec<AgentStep>,
+    pub state: AgentState,
+    pub max_steps: usize,
+    /// Pause at the next checkpoint instead of continuing
+    pub pause_requested: bool,
+    /// Wait for confirmation before every command
+    pub single_step: bool,
+    /// Command proposed by the model but not yet run, set while paused before execution
+    pub pending_command: Option<String>,
+    /// Tool call waiting in the same way as `pending_command`
+    pub pending_tool: Option<ToolCall>,
+    /// Why the command policy blocked the pending command or tool, cleared by an override
+    pub policy_violation: Option<String>,
+}
+
+impl AutonomousRun {
+    pub fn new(session_id: i64, goal: String) -> Self {
+        Self {
+            id: 0,
+            session_id,
+            goal,
+            plan: Vec::new(),
+            steps: Vec::new(),
+            state: AgentState::Starting,
+            max_steps: MAX_AUTONOMOUS_STEPS,
+            pause_requested: false,
+            single_step: false,
+            pending_command: None,
+            pending_tool: None,
+            policy_violation: None,
+        }
+    }
+
+    /// Steps taken so far, as context for the next reasoning or analysis call.
+    fn history(&self) -> String {
+        let mut history = String::new();
+        for (i, step) in self.steps.iter().enumerate() {
+            history.push_str(&format!("Step {}:\nThought: {}\n", i + 1, step.thought));
+            if let Some(command) = &step.command {
+                history.push_str(&format!("Command: {}\n", command));
+            }
+            if let Some(tool) = &step.tool {
+                history.push_str(&format!("Tool: {} {}\n", tool.name, truncate(&tool.arguments.to_string())));
+            }
+            match &step.output {
+                Some(Ok(output)) => history.push_str(&format!("Output:\n{}\n", truncate(output))),
+                Some(Err(error)) => history.push_str(&format!("Failed:\n{}\n", truncate(error))),
+                None => {}
+            }
+            if let Some(analysis) = &step.analysis {
+                history.push_str(&format!("Analysis: {}\n", analysis));
+            }
+            history.push('\n');
+        }
+        history
+    }
+
+    fn system_context(cwd: &Path) -> String {
+        format!(
+            "You are an autonomous agent working on a {} machine in the directory {}.",
+            std::env::consts::OS,
+            cwd.display(),
+        )
+    }
+
+    // Project instructions and the `:context` snapshot, when there are any, follow the instructions
+    fn with_project(mut system: String, project: Option<&str>) -> String {
+        if let Some(project) = project {
+            system.push_str("\n\n");
+            system.push_str(project);
+        }
+        system
+    }
+
+    pub fn plan_messages(&self, cwd: &Path, project: Option<&str>) -> Vec<Message> {
+        let system = format!(
+            "{} Before running anything, break the user's goal into a short list of concrete steps, \
+             each achievable with one or a few shell commands. Reply with JSON: \"steps\" is the list of steps.",
+            Self::system_context(cwd)
+        );
+        vec![
+            Message::new(Role::System, Self::with_project(system, project)),
+            Message::new(Role::User, format!("Goal: {}", self.goal)),
+        ]
+    }
+
+    fn plan_text(&self) -> String {
+        if self.plan.is_empty() {
+            return String::new();
+        }
+        let steps: Vec<String> = self
+            .plan
+            .iter()
+            .enumerate()
+            .map(|(i, step)| format!("{}. {}", i + 1, step))
+            .collect();
+        format!("Approved plan:\n{}\n\n", steps.join("\n"))
+    }
+
+    /// `tools` describes the tools on offer, from `tools::describe`.
+    pub fn reasoning_messages(&self, cwd: &Path, tools: &str, project: Option<&str>) -> Vec<Message> {
+        let system = format!(
+            "{} Reach the user's goal by running one shell command per step, following the approved plan. \
+             Reply with JSON: \"thought\" explains your reasoning, \"command\" is the next shell command \
+             (null when finished), \"done\" is true once the goal is reached and \"summary\" describes the result. \
+             Instead of a command you can call one of these tools by setting \"tool\" to {{\"name\", \"arguments\"}}: {}. \
+             Use the file tools rather than `cat`, `sed` or `echo >` to read and change files. \
+             Prefer read-only commands and never run anything destructive without being asked to. \
+             A `cd` carries over to later steps.",
+            Self::system_context(cwd),
+            tools
+        );
+        let history = if self.steps.is_empty() {
+            "No steps taken yet.".to_string()
+        } else {
+            self.history()
+        };
+        vec![
+            Message::new(Role::System, Self::with_project(system, project)),
+            Message::new(
+                Role::User,
+                format!(
+                    "Goal: {}\n\n{}Steps so far:\n{}\nWhat is the next step?",
+                    self.goal,
+                    self.plan_text(),
+                    history
+                ),
+            ),
+        ]
+    }
+
+    pub fn analysis_messages(&self) -> Vec<Message> {
+        let system = "You review the result of a shell command run by an autonomous agent. \
+                      Reply with JSON: \"analysis\" briefly explains what the output means for the goal, \
+                      \"goal_achieved\" is true only when the goal has been fully reached."
+            .to_string();
+        vec![
+            Message::new(Role::System, system),
+            Message::new(
+                Role::User,
+                format!("Goal: {}\n\n{}Analyze the latest step.", self.goal, self.history()),
+            ),
+        ]
+    }
+}
+
+/// How much damage a command could do, from read-only to destructive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RiskLevel {
+    Safe,     // Only reads
+    Low,      // Changes files locally
+    Moderate, // Talks to the network or installs project dependencies
+    High,     // Needs privileges or changes the system
+    Critical, // Deletes data irrecoverably
+}
+
+impl RiskLevel {
+    pub fn label(&self) -> &'static str {
+        match self {
+            RiskLevel::Safe => "safe",
+            RiskLevel::Low => "low",
+            RiskLevel::Moderate => "moderate",
+            RiskLevel::High => "high",
+            RiskLevel::Critical => "critical",
+        }
+    }
+}
+
+/// Which commands run without asking first, based on their risk level.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ApprovalPolicy {
+    Manual,       // Confirm every command
+    AutoSafe,     // Run read-only commands automatically
+    AutoModerate, // Run everything below high risk automatically
+}
+
+impl ApprovalPolicy {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "manual" => Some(ApprovalPolicy::Manual),
+            "auto-safe" => Some(ApprovalPolicy::AutoSafe),
+            "auto-moderate" => Some(ApprovalPolicy::AutoModerate),
+            _ => None,
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            ApprovalPolicy::Manual => "manual",
+            ApprovalPolicy::AutoSafe => "auto-safe",
+            ApprovalPolicy::AutoModerate => "auto-moderate",
+        }
+    }
+
+    /// High and critical commands always need confirmation.
+    pub fn auto_approves(&self, risk: RiskLevel) -> bool {
+        match self {
+            ApprovalPolicy::Manual => false,
+            ApprovalPolicy::AutoSafe => risk == RiskLevel::Safe,
+            ApprovalPolicy::AutoModerate => risk <= RiskLevel::Moderate,
+        }
+    }
+}
+
+const READ_ONLY_PROGRAMS: &[&str] = &[
+    "ls", "cat", "head", "tail", "less", "wc", "grep", "rg", "pwd", "echo", "which", "whoami", "date",
+    "uname", "stat", "file", "du", "df", "tree", "printenv", "id", "hostname", "sort", "uniq", "cut",
+    "diff", "true",
+];
+const READ_ONLY_GIT: &[&str] = &["status", "log", "diff", "show", "blame", "remote"];
+const NETWORK_PROGRAMS: &[&str] = &["curl", "wget", "ssh", "scp", "rsync", "nc", "ping", "ftp", "telnet", "dig", "nslookup"];
+const NETWORK_GIT: &[&str] = &["push", "pull", "fetch", "clone"];
+const PRIVILEGED_PROGRAMS: &[&str] = &[
+    "sudo", "su", "doas", "chown", "chmod", "chgrp", "systemctl", "service", "mount", "umount", "kill",
+    "killall", "pkill", "useradd", "userdel", "passwd", "crontab", "iptables", "apt", "apt-get", "yum", "dnf",
+    "pacman", "brew", "snap",
+];
+const DESTRUCTIVE_PROGRAMS: &[&str] = &["mkfs", "dd", "shred", "wipefs", "fdisk", "parted", "format"];
+// Programs that run the command in their arguments, with their options that take a value
+const WRAPPER_PROGRAMS: &[(&str, &[&str])] = &[
+    ("env", &["-u", "--unset", "-C", "--chdir"]),
+    (
+        "xargs",
+        &["-a", "--arg-file", "-d", "--delimiter", "-E", "-I", "-L", "--max-lines", "-n", "--max-args", "-P", "--max-procs", "-s", "--max-chars"],
+    ),
+    ("nohup", &[]),
+    ("nice", &["-n", "--adjustment"]),
+    ("timeout", &["-s", "--signal", "-k", "--kill-after"]),
+    ("time", &["-f", "--format", "-o", "--output"]),
+    ("command", &[]),
+    ("exec", &["-a"]),
+];
+// Options of sudo and doas that take a value, ahead of the command they run
+const SUDO_VALUE_OPTIONS: &[&str] = &["-u", "--user", "-g", "--group", "-C", "--close-from", "-D", "--chdir", "-h", "--host", "-p", "--prompt", "-r", "--role", "-t", "--type", "-U", "--other-user", "-T", "--command-timeout"];
+// Options of git that take a value, ahead of the subcommand
+const GIT_VALUE_OPTIONS: &[&str] = &["-C", "-c", "--git-dir", "--work-tree", "--namespace", "--exec-path", "--config-env"];
+const SHELLS: &[&str] = &["sh", "bash", "zsh", "dash", "ksh", "fish"];
+// Interpreters that run code given with `-c` or `-e`
+const INTERPRETERS: &[&str] = &["python", "python2", "python3", "perl", "ruby", "node", "php", "lua", "osascript"];
+// Where the command or process substitutions of a command start
+const SUBSTITUTIONS: &[&str] = &["$(", "`", "<(", ">("];
+
+/// Rule-based risk score of a shell command: the highest risk of any command in
+/// its pipeline or chain. Unknown commands count as local changes.
+pub fn assess_risk_level(command: &str) -> RiskLevel {
+    if command.contains(":(){") {
+        return RiskLevel::Critical; // Fork bomb
+    }
+    // `2>&1` and `&>` redirect rather than chain commands
+    let chain = command.replace(">&", "> ").replace("&>", " >");
+    let mut risk = chain
+        .split(['|', ';', '&', '\n'])
+        .map(segment_risk)
+        .max()
+        .unwrap_or(RiskLevel::Safe)
+        .max(redirect_risk(command));
+    // What a substitution runs is hidden from the words of its segment
+    if SUBSTITUTIONS.iter().any(|start| command.contains(start)) {
+        risk = substitutions(command).iter().map(|inner| assess_risk_level(inner)).fold(risk.max(RiskLevel::High), RiskLevel::max);
+    }
+    risk
+}
+
+// Output redirections: to a disk device they are critical, to /dev/null harmless, and
+// anything else overwrites a file. Each `>` is checked on its own
+fn redirect_risk(command: &str) -> RiskLevel {
+    let mut risk = RiskLevel::Safe;
+    let mut rest = command;
+    while let Some(index) = rest.find('>') {
+        let after = rest[index..].trim_start_matches('>');
+        rest = after;
+        // `>(...)` is a process substitution, `2>&1` duplicates a descriptor
+        if after.starts_with('(') || after.starts_with('&') {
+            continue;
+        }
+        let target = after.trim_start();
+        let target = &target[..target.find(|c: char| c.is_whitespace() || ";|&<>)".contains(c)).unwrap_or(target.len())];
+        let target = target.trim_matches(|c| c == '"' || c == '\'');
+        risk = risk.max(match target {
+            "/dev/null" => RiskLevel::Safe,
+            _ if target.starts_with("/dev/sd") || target.starts_with("/dev/nvme") => RiskLevel::Critical,
+            _ => RiskLevel::Low,
+        });
+    }
+    risk
+}
+
+// The commands inside the `$(...)`, `<(...)`, `>(...)` and backtick substitutions of `command`
+fn substitutions(command: &str) -> Vec<&str> {
+    let mut found = Vec::new();
+    let bytes = command.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'`' {
+            let end = command[i + 1..].find('`').map_or(command.len(), |end| i + 1 + end);
+            found.push(&command[i + 1..end]);
+            i = end + 1;
+            continue;
+        }
+        if matches!(bytes[i], b'$' | b'<' | b'>') && bytes.get(i + 1) == Some(&b'(') {
+            let start = i + 2;
+            let mut depth = 1;
+            let mut end = start;
+            while end < bytes.len() {
+                match bytes[end] {
+                    b'(' => depth += 1,
+                    b')' if depth == 1 => break,
+                    b')' => depth -= 1,
+                    _ => {}
+                }
+                end += 1;
+            }
+            found.push(&command[start..end]);
+            i = end + 1;
+            continue;
+        }
+        i += 1;
+    }
+    found
+}
+
+// The words of the command that `args` of a wrapper program run, past the wrapper's own
+// options; `value_options` are those that take the next word as their value
+fn skip_options<'a>(args: &'a [&'a str], value_options: &[&str]) -> &'a [&'a str] {
+    let mut start = 0;
+    while let Some(arg) = args.get(start) {
+        if *arg == "--" {
+            start += 1;
+            break;
+        }
+        if value_options.contains(arg) {
+            start += 2;
+        } else if arg.starts_with('-') {
+            start += 1;
+        } else {
+            break;
+        }
+    }
+    args.get(start..).unwrap_or_default()
+}
+
+// Code given to a shell with `-c` (or `-lc` and the like), quotes around it removed
+fn inline_code(args: &[&str]) -> Option<String> {
+    let index = args.iter().position(|arg| arg.starts_with('-') && !arg.starts_with("--") && arg.contains('c'))?;
+    let code = args[index + 1..].join(" ");
+    Some(code.trim_matches(|c| c == '"' || c == '\'').to_string())
+}
+
+fn segment_risk(segment: &str) -> RiskLevel {
+    // Skip `VAR=value` prefixes to find the program
+    let words: Vec<&str> = segment.split_whitespace().skip_while(|w| w.contains('=') && !w.starts_with('-')).collect();
+    let Some(first) = words.first() else {
+        return RiskLevel::Safe;
+    };
+    let program = first.rsplit('/').next().unwrap_or(first);
+    let args = &words[1..];
+    let has_flag = |flags: &[&str]| args.iter().any(|a| flags.contains(a));
+    let short_flag = |c: char| args.iter().any(|a| a.starts_with('-') && !a.starts_with("--") && a.contains(c));
+
+    if let Some((_, value_options)) = WRAPPER_PROGRAMS.iter().find(|(name, _)| *name == program) {
+        if program == "command" && has_flag(&["-v", "-V"]) {
+            return RiskLevel::Safe; // Only looks the name up
+        }
+        // As risky as what it runs; alone, env prints the environment and xargs runs echo
+        let mut wrapped = skip_options(args, value_options);
+        if program == "timeout" {
+            wrapped = wrapped.get(1..).unwrap_or_default(); // The duration
+        }
+        return segment_risk(&wrapped.join(" "));
+    }
+    if matches!(program, "sudo" | "doas") {
+        // Privileged, and possibly worse depending on what runs
+        return segment_risk(&skip_options(args, SUDO_VALUE_OPTIONS).join(" ")).max(RiskLevel::High);
+    }
+    // Code run by a shell or interpreter is only rated as far as it can be read here
+    if INTERPRETERS.contains(&program) && has_flag(&["-c", "-e", "-E"]) {
+        return RiskLevel::High;
+    }
+    let code = match program {
+        "eval" => Some(args.join(" ")),
+        _ if SHELLS.contains(&program) => inline_code(args),
+        _ => None,
+    };
+    if let Some(code) = code {
+        return assess_risk_level(&code).max(RiskLevel::High);
+    }
+    if DESTRUCTIVE_PROGRAMS.iter().any(|p| program == *p || program.starts_with("mkfs."))
+        || (program == "rm" && (short_flag('r') || short_flag('R') || has_flag(&["--recursive"])))
+        || (program == "find" && has_flag(&["-delete"]))
+    {
+        return RiskLevel::Critical;
+    }
+    if program == "git" {
+        let subcommand = skip_options(args, GIT_VALUE_OPTIONS).first().copied().unwrap_or_default();
+        return match subcommand {
+            "reset" if has_flag(&["--hard"]) => RiskLevel::Critical,
+            "clean" => RiskLevel::Critical,
+            "push" if has_flag(&["--force", "-f"]) => RiskLevel::Critical,
+            s if NETWORK_GIT.contains(&s) => RiskLevel::Moderate,
+            s if READ_ONLY_GIT.contains(&s) || s.is_empty() => RiskLevel::Safe,
+            "branch" | "tag" if args.len() == 1 => RiskLevel::Safe,
+            _ => RiskLevel::Low,
+        };
+    }
+    if PRIVILEGED_PROGRAMS.contains(&program) {
+        return RiskLevel::High;
+    }
+    if NETWORK_PROGRAMS.contains(&program)
+        || (matches!(program, "pip" | "pip3" | "npm" | "yarn" | "cargo" | "go" | "gem") && args.first() == Some(&"install"))
+        || (program == "docker" && matches!(args.first().copied(), Some("pull" | "push" | "run")))
+    {
+        return RiskLevel::Moderate;
+    }
+    if program == "find"
+        && let Some(index) = args.iter().position(|arg| matches!(*arg, "-exec" | "-execdir" | "-ok" | "-okdir"))
+    {
+        // As risky as the command run for the files found, up to its `;` or `+`;
+        // after splitting at `;` that is the `\` or quote in front of it
+        let payload: Vec<&str> = args[index + 1..].iter().copied().take_while(|arg| !matches!(*arg, "\\" | "'" | "\"" | "+")).collect();
+        return segment_risk(&payload.join(" ")).max(RiskLevel::Low);
+    }
+    if READ_ONLY_PROGRAMS.contains(&program) || program == "find" {
+        return RiskLevel::Safe;
+    }
+    RiskLevel::Low
+}
+
+// Variable names that suggest a secret, matched case-insensitively
+const SECRET_NAME_PARTS: &[&str] = &["TOKEN", "SECRET", "PASSWORD", "PASSWD", "KEY", "AUTH", "CREDENTIAL"];
+
+pub fn is_secret_name(name: &str) -> bool {
+    let name = name.to_uppercase();
+    SECRET_NAME_PARTS.iter().any(|part| name.contains(part))
+}
+
+/// Value of an environment variable as it may be displayed.
+pub fn masked_value(name: &str, value: &str) -> String {
+    if is_secret_name(name) {
+        "*".repeat(value.chars().count().clamp(4, 12))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Replace the values of secret variables in `text`, and whatever else the redaction
+/// `rules` match, so command output can be shown and stored without leaking them.
+pub fn redact_secrets(text: &str, env: &HashMap<String, String>, rules: &[redact::Rule]) -> String {
+    let mut text = text.to_string();
+    for (name, value) in env {
+        if is_secret_name(name) && !value.is_empty() {
+            text = text.replace(value, &format!("<{}>", name));
+        }
+    }
+    redact::apply(&text, rules)
+}
+
+/// Directory a successful `command` leaves the shell in, when it changes directory
+/// with `cd`, so the next command can start there.
+pub fn directory_after(command: &str, cwd: &Path) -> Option<PathBuf> {
+    let mut dir: Option<PathBuf> = None;
+    for segment in command.split("&&").flat_map(|s| s.split(';')) {
+        let mut words = segment.split_whitespace();
+        if words.next() != Some("cd") {
+            continue;
+        }
+        let target = words.next().unwrap_or("~").trim_matches(|c| c == '"' || c == '\'');
+        let base = dir.clone().unwrap_or_else(|| cwd.to_path_buf());
+        let next = if target == "~" {
+            PathBuf::from(std::env::var_os("HOME")?)
+        } else {
+            base.join(crate::commands::expand_tilde(target))
+        };
+        dir = Some(next.canonicalize().ok()?);
+    }
+    dir
+}
+
+// Code block languages whose lines `x` runs; in the session ones only `$ ` lines are commands
+const SHELL_LANGUAGES: &[&str] = &["sh", "bash", "zsh", "shell", "console", "shell-session"];
+
+/// Commands of the fenced shell blocks in a reply, one per line. Comments and `$ `
+/// prompts are dropped, and a line ending in `\` is joined with the next.
+pub fn shell_block_commands(text: &str) -> Vec<String> {
+    let mut commands = Vec::new();
+    let mut block: Option<&str> = None; // Language of the code block the line is in
+    let mut command = String::new();
+    for line in text.lines() {
+        if let Some(fence) = line.trim_start().strip_prefix("```") {
+            block = match block {
+                Some(_) => None,
+                None => Some(fence.split_whitespace().next().unwrap_or("")),
+            };
+            command.clear();
+            continue;
+        }
+        let Some(language) = block.filter(|language| SHELL_LANGUAGES.contains(language)) else {
+            continue;
+        };
+        let line = line.trim();
+        let line = match line.strip_prefix("$ ") {
+            Some(line) => line,
+            // Output printed by the command of a session
+            None if command.is_empty() && matches!(language, "console" | "shell-session") => continue,
+            None => line,
+        };
+        if command.is_empty() && (line.is_empty() || line.starts_with('#')) {
+            continue;
+        }
+        match line.strip_suffix('\\') {
+            Some(part) => {
+                command.push_str(part.trim_end());
+                command.push(' ');
+            }
+            None => {
+                command.push_str(line);
+                commands.push(std::mem::take(&mut command));
+            }
+        }
+    }
+    commands
+}
+
+pub fn truncate(text: &str) -> String {
+    if text.chars().count() <= MAX_OUTPUT_CHARS {
+        return text.to_string();
+    }
+    let truncated: String = text.chars().take(MAX_OUTPUT_CHARS).collect();
+    format!("{}\n[output truncated]", truncated)
+}
+
+/// Run `command` through the platform shell with `input` on its stdin (`:!`), returning
+/// its stdout. A non-zero exit status is reported as an error carrying stderr.
+pub async fn pipe_through(
+    command: &str,
+    input: String,
+    cwd: &Path,
+    env: &HashMap<String, String>,
+    timeout: Option<Duration>,
+) -> Result<String, String> {
+    let mut shell = if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    let mut child = shell
+        .arg(command)
+        .current_dir(cwd)
+        .envs(env)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| format!("Failed to start command: {}", e))?;
+    // Written from a task of its own, a command printing as it reads would otherwise block on a full pipe
+    if let Some(mut stdin) = child.stdin.take() {
+        tokio::spawn(async move {
+            tokio::io::AsyncWriteExt::write_all(&mut stdin, input.as_bytes()).await.ok();
+        });
+    }
+    let output = match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, child.wait_with_output())
+            .await
+            .map_err(|_| format!("Timed out after {}s", timeout.as_secs()))?,
+        None => child.wait_with_output().await,
+    }
+    .map_err(|e| e.to_string())?;
+    if !output.status.success() {
+        return Err(format!("{}\n{}", output.status, String::from_utf8_lossy(&output.stderr).trim_end()));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Run `command` through the platform shell, returning combined stdout and stderr.
+/// A non-zero exit status is reported as an error carrying the output. With
+/// `dry_run` nothing is executed and a simulated success is returned instead.
+/// The command is killed once `timeout` elapses or when `cancel` fires. Output is
+/// also sent to `live_output` chunk by chunk while the command runs.
+pub async fn execute_command(
+    command: &str,
+    cwd: &Path,
+    env: &HashMap<String, String>,
+    dry_run: bool,
+    timeout: Option<Duration>,
+    mut cancel: oneshot::Receiver<()>,
+    live_output: Option<mpsc::UnboundedSender<Vec<u8>>>,
+) -> CommandOutput {
+    if dry_run {
+        return CommandOutput {
+            exit_code: Some(0),
+            result: Ok(format!("[dry run] `{}` was not executed", command)),
+        };
+    }
+    let mut shell = if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    // Run in a new process group so a kill also reaches whatever the shell started
+    #[cfg(unix)]
+    shell.process_group(0);
+    let mut child = match shell
+        .arg(command)
+        .current_dir(cwd)
+        .envs(env)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) => {
+            return CommandOutput {
+                exit_code: None,
+                result: Err(format!("Failed to start command: {}", e)),
+            };
+        }
+    };
+
+    // stdout and stderr are interleaved in the order they arrive, like in a terminal
+    let output = Arc::new(Mutex::new(CapturedOutput::default()));
+    let readers = [
+        tokio::spawn(read_into(child.stdout.take(), output.clone(), live_output.clone())),
+        tokio::spawn(read_into(child.stderr.take(), output.clone(), live_output)),
+    ];
+
+    let deadline = async {
+        match timeout {
+            Some(timeout) => tokio::time::sleep(timeout).await,
+            None => std::future::pending().await,
+        }
+    };
+    let status = tokio::select! {
+        status = child.wait() => status.map_err(|e| e.to_string()),
+        _ = deadline => Err(format!("Timed out after {}s", timeout.unwrap_or_default().as_secs())),
+        Ok(()) = &mut cancel => Err("Killed by user".to_string()),
+    };
+    if status.is_err() {
+        kill_process_group(&mut child).await;
+    }
+    // Processes started in the background may keep the pipes open, don't wait for them forever
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(1);
+    for reader in readers {
+        tokio::time::timeout_at(deadline, reader).await.ok();
+    }
+    let text = output.lock().unwrap_or_else(|e| e.into_inner()).text();
+
+    match status {
+        Ok(status) => CommandOutput {
+            exit_code: status.code(),
+            result: if status.success() {
+                Ok(text)
+            } else {
+                Err(format!("{}\n{}", status, text))
+            },
+        },
+        Err(reason) => CommandOutput {
+            exit_code: None,
+            result: Err(format!("{}\n{}", reason, text)),
+        },
+    }
+}
+
+async fn kill_process_group(child: &mut Child) {
+    #[cfg(unix)]
+    if let Some(pid) = child.id() {
+        // SAFETY: only sends a signal, a stale process group id fails harmlessly
+        unsafe {
+            libc::kill(-(pid as i32), libc::SIGKILL);
+        }
+    }
+    child.kill().await.ok();
+}
+
+// Output of a running command, bounded however much it prints
+#[derive(Default)]
+struct CapturedOutput {
+    head: Vec<u8>,
+    tail: VecDeque<u8>,
+    dropped: usize,
+}
+
+impl CapturedOutput {
+    fn push(&mut self, bytes: &[u8]) {
+        let room = OUTPUT_HEAD_BYTES - self.head.len();
+        let (head, rest) = bytes.split_at(room.min(bytes.len()));
+        self.head.extend_from_slice(head);
+        self.tail.extend(rest);
+        let excess = self.tail.len().saturating_sub(OUTPUT_TAIL_BYTES);
+        self.tail.drain(..excess);
+        self.dropped += excess;
+    }
+
+    fn text(&mut self) -> String {
+        let mut text = String::from_utf8_lossy(&self.head).into_owned();
+        if self.dropped > 0 {
+            text.push_str(&format!("\n[{} bytes of output omitted]\n", self.dropped));
+        }
+        text.push_str(&String::from_utf8_lossy(self.tail.make_contiguous()));
+        text
+    }
+}
+
+async fn read_into(
+    pipe: Option<impl AsyncRead + Unpin>,
+    output: Arc<Mutex<CapturedOutput>>,
+    live_output: Option<mpsc::UnboundedSender<Vec<u8>>>,
+) {
+    let Some(mut pipe) = pipe else {
+        return;
+    };
+    let mut buffer = [0u8; 4096];
+    while let Ok(n) = pipe.read(&mut buffer).await {
+        if n == 0 {
+            break;
+        }
+        output.lock().unwrap_or_else(|e| e.into_inner()).push(&buffer[..n]);
+        if let Some(live_output) = &live_output {
+            live_output.send(buffer[..n].to_vec()).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wrappers_are_rated_by_the_command_they_run() {
+        assert_eq!(assess_risk_level("env"), RiskLevel::Safe);
+        assert_eq!(assess_risk_level("env FOO=1 rm -rf build"), RiskLevel::Critical);
+        assert_eq!(assess_risk_level("find . -name '*.o' | xargs -n 1 rm -rf"), RiskLevel::Critical);
+        assert_eq!(assess_risk_level("nohup sudo reboot"), RiskLevel::High);
+        assert_eq!(assess_risk_level("nice -n 10 curl example.com"), RiskLevel::Moderate);
+        assert_eq!(assess_risk_level("timeout -s KILL 5 dd if=/dev/zero of=disk.img"), RiskLevel::Critical);
+        assert_eq!(assess_risk_level("time ls"), RiskLevel::Safe);
+        assert_eq!(assess_risk_level("command -v rm"), RiskLevel::Safe);
+        assert_eq!(assess_risk_level("exec -a name git clean -fd"), RiskLevel::Critical);
+    }
+
+    #[test]
+    fn substitutions_are_rated_by_what_they_run() {
+        assert_eq!(assess_risk_level("echo $(git clean -fdx)"), RiskLevel::Critical);
+        assert_eq!(assess_risk_level("ls `shred x`"), RiskLevel::Critical);
+        assert_eq!(assess_risk_level("cat <(ls)"), RiskLevel::High);
+        assert_eq!(assess_risk_level("tee >(wc -l)"), RiskLevel::High);
+        assert_eq!(assess_risk_level("echo $(date)"), RiskLevel::High);
+        assert_eq!(assess_risk_level("echo $HOME"), RiskLevel::Safe);
+    }
+
+    #[test]
+    fn each_redirect_is_checked() {
+        assert_eq!(assess_risk_level("ls >/dev/null; echo x > Cargo.toml"), RiskLevel::Low);
+        assert_eq!(assess_risk_level("ls > /dev/null 2>&1"), RiskLevel::Safe);
+        assert_eq!(assess_risk_level("cat image >/dev/null && cat image > /dev/sda"), RiskLevel::Critical);
+        assert_eq!(assess_risk_level("echo x >>notes.txt"), RiskLevel::Low);
+        assert_eq!(assess_risk_level("make &> /dev/null"), RiskLevel::Low);
+        assert_eq!(assess_risk_level("ls &> /dev/null"), RiskLevel::Safe);
+    }
+
+    #[test]
+    fn git_options_do_not_hide_the_subcommand() {
+        assert_eq!(assess_risk_level("git -C d clean -fdx"), RiskLevel::Critical);
+        assert_eq!(assess_risk_level("git -c k=v reset --hard"), RiskLevel::Critical);
+        assert_eq!(assess_risk_level("git --git-dir .git --no-pager log"), RiskLevel::Safe);
+        assert_eq!(assess_risk_level("git -C d push --force"), RiskLevel::Critical);
+    }
+
+    #[test]
+    fn shells_and_interpreters_are_rated_by_their_code() {
+        assert_eq!(assess_risk_level("sh -c 'rm -rf build'"), RiskLevel::Critical);
+        assert_eq!(assess_risk_level("bash -lc \"ls\""), RiskLevel::High);
+        assert_eq!(assess_risk_level("eval rm -rf x"), RiskLevel::Critical);
+        assert_eq!(assess_risk_level("eval ls"), RiskLevel::High);
+        assert_eq!(assess_risk_level("python3 -c 'import shutil'"), RiskLevel::High);
+        assert_eq!(assess_risk_level("perl -e 'unlink glob q(*)'"), RiskLevel::High);
+        assert_eq!(assess_risk_level("python3 script.py"), RiskLevel::Low);
+        assert_eq!(assess_risk_level("find . -exec rm -rf {} +"), RiskLevel::Critical);
+        assert_eq!(assess_risk_level("find . -name '*.rs' -exec wc -l {} \\;"), RiskLevel::Low);
+        assert_eq!(assess_risk_level("find . -delete"), RiskLevel::Critical);
+        assert_eq!(assess_risk_level("ls | xargs shred"), RiskLevel::Critical);
+    }
+
+    #[test]
+    fn sudo_options_are_skipped() {
+        assert_eq!(assess_risk_level("sudo -u root rm -rf x"), RiskLevel::Critical);
+        assert_eq!(assess_risk_level("sudo -E -u deploy ls"), RiskLevel::High);
+        assert_eq!(assess_risk_level("doas -u root shred disk"), RiskLevel::Critical);
+        assert_eq!(assess_risk_level("sudo -i"), RiskLevel::High);
+    }
+
+    #[test]
+    fn captured_output_keeps_its_start_and_end() {
+        let mut output = CapturedOutput::default();
+        output.push(b"start\n");
+        for _ in 0..1000 {
+            output.push(&[b'.'; 4096]);
+        }
+        output.push(b"\nend");
+        let text = output.text();
+        assert!(text.starts_with("start\n...."));
+        assert!(text.ends_with("....\nend"));
+        let dropped = 6 + 1000 * 4096 + 4 - OUTPUT_HEAD_BYTES - OUTPUT_TAIL_BYTES;
+        assert!(text.contains(&format!("\n[{} bytes of output omitted]\n", dropped)));
+
+        let mut short = CapturedOutput::default();
+        short.push(b"all of it");
+        assert_eq!(short.text(), "all of it");
+    }
+}
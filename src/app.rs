@@ -1,12 +1,15 @@
-use crate::{config, db, models};
+use crate::{agent, attach, bench, clipboard, commands, compare, config, context, crypto, db, fuzzy, gitmsg, howto, image, keymap, layout, memory, models, options, policy, project, prompts, rag, redact, sync, toast, tools, workflow};
 use anyhow::{anyhow, Result};
 use ratatui::widgets::ListState;
 use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
 use reqwest::Client;
 use rusqlite::Connection;
-use textwrap::wrap;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-#[derive(PartialEq, Eq)]
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub enum AppMode {
     Normal,         // Vim normal mode
     Insert,         // Vim insert mode for typing messages
@@ -15,23 +18,181 @@ pub enum AppMode {
     ModelSelection,
     SessionSelection,
     Agent,          // New agent mode
+    Autonomous,     // Autonomous agent working towards a goal (:auto)
+    PlanReview,     // Approve or edit the autonomous agent's plan before it runs
+    AgentRuns,      // History of autonomous runs (:runs)
+    Audit,          // Log of every command the agent executed (:audit)
+    ToolApproval,   // Diff of a file change the agent wants to make
     Help,           // Help popup mode
+    DeleteMessageConfirmation, // Confirm deleting the selected message
+    PinnedMessages, // Popup listing pinned messages across sessions
+    CommandHistory, // Searchable window of past : commands (q:)
+    Info,           // Read-only text popup (e.g. :set listing)
+    CommandPalette, // Fuzzy-searchable list of every command (Ctrl+P)
+    PromptVariables, // Values for the placeholders of a prompt snippet (:prompt use)
+    Workflows,      // Workflow list and the steps of the running one (:wf)
+    WorkflowVariables, // Values for the variables a workflow uses but does not define
+    WorkflowDraft,  // Workflow drafted by the model, saved on approval (:wf generate)
+    WorkflowEditor, // Form to create or change a workflow (:wf new, :wf edit)
+    Compare,        // Replies of the two :compare models side by side
+    Bench,          // Timings of the :bench prompts per model
+    CommitMessage,  // Commit message written for the staged changes (:gitmsg)
+    HowTo,          // Shell command suggested for a question (:howto)
 }
 
+/// How `:split` and `:vsplit` arrange the two chat panes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SplitDirection {
+    Horizontal, // One above the other (:split)
+    Vertical,   // Side by side (:vsplit)
+}
+
+// How long a status message stays, once it is the one in the status bar
+const STATUS_TTL: Duration = Duration::from_secs(4);
+// Messages waiting to be shown beyond this are dropped, oldest first
+const STATUS_QUEUE_LIMIT: usize = 5;
+// Pause after the start of a key sequence before its continuations are shown
+const WHICH_KEY_DELAY: Duration = Duration::from_millis(500);
+
+/// A message in the status bar.
+#[derive(Clone, Debug)]
+pub struct StatusMessage {
+    pub text: String,
+    pub error: bool,
+    shown: Instant, // When it reached the status bar
+}
+
+/// A second session shown next to the current one.
+#[derive(Debug)]
+pub struct SplitView {
+    pub session_id: i64,
+    pub direction: SplitDirection,
+    pub list_state: ListState, // Scroll position of the unfocused pane
+    pub current_first: bool,   // Whether the focused session is in the top/left pane
+}
+
+/// A place in the chat that marks and the jump list return to: a line of a message,
+/// so it survives rewrapping.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChatPosition {
+    pub session_id: i64,
+    pub message: usize,
+    pub offset: usize, // Line within the message
+}
+
+/// The last reply piped through a shell command with `:!cmd`, or `:.!cmd` to put the
+/// output into the input instead of a popup.
+#[derive(Clone, Debug)]
+pub struct PipeRequest {
+    pub command: String,
+    pub input: String,
+    pub into_input: bool,
+}
+
+/// Which messages of the current session the chat pane shows (:filter).
+#[derive(Clone, Debug)]
+pub enum ChatFilter {
+    Role(models::Role),
+    Pattern(regex::Regex),
+}
+
+impl ChatFilter {
+    /// `user`, `assistant`, `system` or `/regex/`.
+    pub fn parse(text: &str) -> Result<Self> {
+        match text {
+            "user" => Ok(ChatFilter::Role(models::Role::User)),
+            "assistant" | "ai" => Ok(ChatFilter::Role(models::Role::Assistant)),
+            "system" => Ok(ChatFilter::Role(models::Role::System)),
+            _ => {
+                let pattern = text
+                    .strip_prefix('/')
+                    .and_then(|rest| rest.strip_suffix('/'))
+                    .filter(|pattern| !pattern.is_empty())
+                    .ok_or_else(|| anyhow!("Filter by user, assistant, system or /regex/"))?;
+                Ok(ChatFilter::Pattern(regex::Regex::new(pattern)?))
+            }
+        }
+    }
+
+    pub fn matches(&self, message: &models::Message) -> bool {
+        match self {
+            ChatFilter::Role(role) => message.role == *role,
+            ChatFilter::Pattern(pattern) => pattern.is_match(&message.content),
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            ChatFilter::Role(models::Role::User) => "user".to_string(),
+            ChatFilter::Role(models::Role::Assistant) => "assistant".to_string(),
+            ChatFilter::Role(models::Role::System) => "system".to_string(),
+            ChatFilter::Pattern(pattern) => format!("/{}/", pattern.as_str()),
+        }
+    }
+}
+
+const COMMAND_HISTORY_LIMIT: usize = 500;
+const JUMP_LIST_LIMIT: usize = 100;
+const AUDIT_VIEW_LIMIT: usize = 500;
+const MAX_ALIAS_DEPTH: usize = 10;
+
 pub struct AppState {
     pub mode: AppMode,
     pub vim_command: String,        // Command being typed in command mode
+    pub completion_candidates: Vec<String>, // Tab completion candidates for the command line
+    pub completion_index: Option<usize>,    // Candidate currently applied to the command line
+    pub completion_start: usize,            // Byte offset in vim_command where the completed token starts
+    pub command_history: Vec<String>,       // Executed : commands, oldest first
+    pub history_index: Option<usize>,       // Entry recalled with Up/Down in command mode
+    pub history_draft: String,              // What was typed before browsing history
+    pub history_filter: String,             // Search text in the q: window
+    pub history_list_state: ListState,
+    pub palette_query: String,              // Search text in the command palette
+    pub palette_list_state: ListState,
     pub visual_start: Option<usize>, // Start line of visual selection
     pub visual_end: Option<usize>,   // End line of visual selection
-    pub status_message: Option<String>, // Temporary status message
+    pub status_messages: VecDeque<StatusMessage>, // The first is shown, the rest wait for it to expire
+    pub info_title: String,             // Title of the Info popup
+    pub info_lines: Vec<String>,        // Content of the Info popup
+    pub info_scroll: u16,
+    pub help_context: AppMode,          // Mode the help screen was opened from, shown first and returned to
+    pub help_filter: String,            // Search typed after `/` in the help screen
+    pub help_searching: bool,
+    pub help_scroll: u16,
+    pub options: options::Options,      // Runtime options changed with :set
+    pub count_prefix: Option<usize>, // Pending numeric count in normal mode (e.g. the 5 in 5j)
+    pub pending_keys: Vec<keymap::Chord>, // Start of a key sequence being typed (e.g. the first d in dd)
+    pub pending_key_time: Instant,        // When the last of the pending keys was pressed
+    pub keymap: keymap::Keymap,
+    pub registers: BTreeMap<char, String>, // Yanked text by register name, `"` is the unnamed one
+    pub pending_register: Option<char>,    // Register picked with `"x` for the next yank
+    pub awaiting_register: bool,           // `"` was typed, the register name comes next
+    pub pending_argument: Option<(keymap::Action, keymap::Chord)>, // Action waiting for a character, such as `m` for the mark name
+    pub marks: BTreeMap<char, ChatPosition>,
+    pub jumps: Vec<ChatPosition>, // Jump list, oldest first
+    pub jump_index: usize,        // Entry Ctrl+O/Ctrl+I moved to, the length when at the newest
+    pub chat_filter: Option<ChatFilter>, // Messages of the current session shown in the chat pane
+    pub pending_delete_message: Option<usize>, // Index of the message awaiting delete confirmation
+    pub pinned_messages: Vec<(usize, usize)>, // (session index, message index) shown in the pins popup
+    pub pinned_list_state: ListState,
     pub sessions: Vec<models::ChatSession>,
     pub current_session_index: usize,
     pub session_list_state: ListState,
+    pub alternate_session_id: Option<i64>, // Previously active session, for `Ctrl+^`
     pub input: String,
     pub current_model: String,
-    pub available_models: Vec<String>,
-    pub model_list_state: ListState,
-    pub is_loading: bool,
+    pub available_models: Vec<models::ModelDetails>,
+    pub model_list_state: ListState, // Indexes into filtered_models()
+    pub model_filter: String,        // Fuzzy filter typed in the model picker
+    pub model_sort: models::ModelSort,
+    pub loading_sessions: HashSet<i64>, // Sessions with a response streaming
+    pub unread_sessions: HashSet<i64>,  // Sessions that finished generating in the background
+    pub prompt_queue: VecDeque<(i64, String)>, // Prompts submitted while their session was busy
+    pub generation_started: HashMap<i64, Instant>, // When each streaming response was requested
+    pub pending_cache_keys: HashMap<i64, String>, // Cache key of each streaming reply that may be cached
+    pub generations: HashMap<i64, u64>, // Generation streaming into each session
+    next_generation: u64,
+    pub terminal_focused: bool,
     pub is_fetching_models: bool,
     pub scroll_offset: u16,
     #[allow(dead_code)]
@@ -47,20 +208,89 @@ pub struct AppState {
     // Agent mode fields
     pub agent_mode: bool,
     pub pending_commands: Vec<models::AgentCommand>,
+    pub autonomous: Option<agent::AutonomousRun>,
+    pub plan_list_state: ListState,
+    pub plan_edit: Option<String>, // Text of the plan step being edited
+    pub agent_runs: Vec<agent::AutonomousRun>, // Saved runs shown by :runs, newest first
+    pub runs_list_state: ListState,
+    pub agent_step_list_state: ListState, // Selected step in the autonomous progress panel
+    pub expanded_agent_steps: HashSet<usize>, // Steps whose details are shown in the panel
+    pub command_edit: Option<String>, // Text of the pending agent command being edited
+    pub pending_change: Option<tools::FileChange>, // File change of the pending tool call, shown for approval
+    pub diff_scroll: u16,
+    pub pending_commit: Option<tools::CommitPreview>, // Commit of the pending git_commit call, files selectable
+    pub commit_file_state: ListState,
+    pub command_cancel: Option<tokio::sync::oneshot::Sender<()>>, // Kills the running agent command
+    pub live_command_output: Vec<u8>, // Output of the running agent command so far
+    pub pending_audit: Option<agent::AuditEntry>, // Audit record of the running agent command
+    pub audit_entries: Vec<agent::AuditEntry>, // Shown by :audit, newest first
+    pub audit_list_state: ListState,
+    pub command_env: HashMap<String, String>, // Variables added to agent commands, from config and :env
+    pub redaction: Vec<redact::Rule>,         // Secret patterns removed from saved messages and command output
+    pub command_policy: policy::CompiledPolicy, // `command_policy` of the config, project overrides applied
     pub command_approval_index: Option<usize>,
-    #[allow(dead_code)]
-    pub agent_context: String,
+    pub agent_context: Option<String>, // Project snapshot from :context, added to agent system prompts
+    pub project_instructions: Option<(PathBuf, String)>, // AGENTS.md or similar found at startup
+    pub attachments: Vec<attach::Attachment>, // Files staged with :attach for the next message
+    pub project: Option<project::ProjectConfig>, // .ollama-tui.toml found at startup
+    pub pending_ingest: Option<(i64, Vec<(String, String)>)>, // Session and (name, text) of documents to embed
+    pub pending_pipe: Option<PipeRequest>, // Shell command of :! to run on the last reply
+    pub knowledge_cache: HashMap<i64, Arc<Vec<rag::Chunk>>>, // Chunks of knowledge sessions, loaded on first use
+    pub memories: Vec<memory::Memory>, // Long-term facts, oldest first
+    pub prompt_fill: Option<prompts::PromptFill>, // Snippet whose placeholders are being filled in
+    pub workflows: workflow::WorkflowManager,
+    pub workflow_list_state: ListState,
+    pub workflow_run: Option<workflow::WorkflowRun>, // Latest workflow started with :wf, kept once finished
+    pub workflow_fill: Option<(workflow::Workflow, prompts::PromptFill)>, // Workflow waiting for variable values
+    pub workflow_queue: VecDeque<(String, i64, Vec<String>)>, // Triggered workflows: name, session, chain so far
+    pub pending_workflow_request: Option<String>, // Description given to :wf generate, sent by the main loop
+    pub workflow_draft: Option<workflow::Workflow>, // Draft waiting for approval
+    pub draft_scroll: u16,
+    pub workflow_editor: Option<workflow::WorkflowEditor>,
+    pub workflow_editor_state: ListState,
+    pub pending_commit_diff: Option<String>, // Staged changes of :gitmsg, sent by the main loop
+    pub commit_message: Option<String>, // Message waiting to be copied, edited or committed
+    pub commit_message_editing: bool,
+    pub pending_howto: Option<String>, // Question of :howto, sent by the main loop
+    pub howto: Option<howto::Suggestion>, // Command waiting to be copied or run
+    pub compare_models: Option<[String; 2]>, // Models every prompt is sent to while A/B mode is on
+    pub comparison: Option<compare::Comparison>, // Latest comparison, kept once recorded in its session
+    pub compare_scroll: u16,
+    pub pending_bench: Option<bench::Bench>, // Benchmark set up by :bench, started by the main loop
+    pub bench: Option<bench::Bench>, // Running or latest benchmark
+    pub bench_scroll: u16,
+    pub split: Option<SplitView>,
+    pub images: image::Settings,
+    pub image_placements: Vec<image::Placement>, // Images visible in the last frame
+    pub drawn_images: Vec<image::Placement>,     // Images the terminal is currently showing
+    pub toasts: toast::Toasts,
+    pub layout: layout::LayoutCache, // Wrapped lines of the messages, kept between frames
 }
 
 impl AppState {
-    pub fn load(config: models::Config) -> Result<Self> {
+    pub fn load(mut config: models::Config) -> Result<Self> {
+        // A .ollama-tui.toml up from the launch directory overrides the global config
+        let project = project::discover(&std::env::current_dir()?)?;
+        if let Some(project) = &project {
+            project.apply_policy(&mut config.command_policy);
+        }
         let db_path = config::get_config_path()?
             .parent()
             .ok_or_else(|| anyhow!("Config path has no parent directory"))?
             .join(&config.db_filename);
         let conn = db::get_connection(&db_path)?;
+        crypto::unlock(&conn, &config.encryption)?;
+        Self::with_connection(config, conn, project)
+    }
+
+    /// The state for `config` on an open, unlocked database.
+    pub fn with_connection(config: models::Config, conn: Connection, project: Option<project::ProjectConfig>) -> Result<Self> {
         let mut sessions = db::load_sessions(&conn)?;
 
+        let command_history = db::load_command_history(&conn, COMMAND_HISTORY_LIMIT)?;
+        let options = options::Options::load(&conn)?;
+        let memories = db::load_memories(&conn)?;
+
         let last_model =
             db::load_config(&conn, "current_model")?.unwrap_or_else(|| "No model selected".to_string());
         let last_session_id: i64 = db::load_config(&conn, "current_session_id")?
@@ -84,38 +314,81 @@ impl AppState {
         let ollama_base_url = format!("{}:{}", config.ollama_host, config.ollama_port);
 
         let mut headers = HeaderMap::new();
-        if config.auth_enabled {
-            if let Some(auth_method) = &config.auth_method {
-                match auth_method {
-                    models::AuthMethod::Bearer { token } => {
-                        headers.insert(
-                            AUTHORIZATION,
-                            HeaderValue::from_str(&format!("Bearer {}", token))?,
-                        );
-                    }
-                    _ => {}
-                }
-            }
+        if config.auth_enabled
+            && let Some(models::AuthMethod::Bearer { token }) = &config.auth_method
+        {
+            headers.insert(
+                AUTHORIZATION,
+                HeaderValue::from_str(&format!("Bearer {}", token))?,
+            );
         }
 
         let client = reqwest::Client::builder()
             .default_headers(headers)
             .build()?;
 
-        Ok(Self {
+        let command_env = config.env.clone();
+        let redaction = redact::rules(&config.redaction).map_err(|e| anyhow!(e))?;
+        let command_policy = policy::CompiledPolicy::new(&config.command_policy).map_err(|e| anyhow!(e))?;
+        let images = image::Settings::new(&config.images);
+        let keymap = keymap::Keymap::new(&config.keys).map_err(|e| anyhow!(e))?;
+        let mut state = Self {
             mode: AppMode::Normal,
             vim_command: String::new(),
+            completion_candidates: Vec::new(),
+            completion_index: None,
+            completion_start: 0,
+            command_history,
+            history_index: None,
+            history_draft: String::new(),
+            history_filter: String::new(),
+            history_list_state: ListState::default(),
+            palette_query: String::new(),
+            palette_list_state: ListState::default(),
             visual_start: None,
             visual_end: None,
-            status_message: None, // Initialize status message
+            status_messages: VecDeque::new(),
+            info_title: String::new(),
+            info_lines: Vec::new(),
+            info_scroll: 0,
+            help_context: AppMode::Normal,
+            help_filter: String::new(),
+            help_searching: false,
+            help_scroll: 0,
+            options,
+            count_prefix: None,
+            pending_keys: Vec::new(),
+            pending_key_time: Instant::now(),
+            keymap,
+            registers: BTreeMap::new(),
+            pending_register: None,
+            awaiting_register: false,
+            pending_argument: None,
+            marks: BTreeMap::new(),
+            jumps: Vec::new(),
+            jump_index: 0,
+            chat_filter: None,
+            pending_delete_message: None,
+            pinned_messages: Vec::new(),
+            pinned_list_state: ListState::default(),
             sessions,
             current_session_index,
             session_list_state,
+            alternate_session_id: None,
             input: String::new(),
-            current_model: last_model,
+            current_model: project.as_ref().and_then(|project| project.model.clone()).unwrap_or(last_model),
             available_models: Vec::new(),
             model_list_state: ListState::default(),
-            is_loading: false,
+            model_filter: String::new(),
+            model_sort: models::ModelSort::default(),
+            loading_sessions: HashSet::new(),
+            unread_sessions: HashSet::new(),
+            prompt_queue: VecDeque::new(),
+            generation_started: HashMap::new(),
+            pending_cache_keys: HashMap::new(),
+            generations: HashMap::new(),
+            next_generation: 0,
+            terminal_focused: true,
             is_fetching_models: false,
             scroll_offset: 0,
             target_scroll_offset: 0,
@@ -130,20 +403,94 @@ impl AppState {
             // Initialize agent fields
             agent_mode: false,
             pending_commands: Vec::new(),
+            autonomous: None,
+            plan_list_state: ListState::default(),
+            plan_edit: None,
+            agent_runs: Vec::new(),
+            runs_list_state: ListState::default(),
+            agent_step_list_state: ListState::default(),
+            expanded_agent_steps: HashSet::new(),
+            command_edit: None,
+            pending_change: None,
+            diff_scroll: 0,
+            pending_commit: None,
+            commit_file_state: ListState::default(),
+            command_cancel: None,
+            live_command_output: Vec::new(),
+            pending_audit: None,
+            audit_entries: Vec::new(),
+            audit_list_state: ListState::default(),
+            command_env,
+            redaction,
+            command_policy,
             command_approval_index: None,
-            agent_context: String::new(),
-        })
+            agent_context: None,
+            project_instructions: std::env::current_dir()
+                .ok()
+                .and_then(|dir| context::find_instructions(&dir)),
+            attachments: Vec::new(),
+            project,
+            pending_ingest: None,
+            pending_pipe: None,
+            knowledge_cache: HashMap::new(),
+            memories,
+            prompt_fill: None,
+            workflows: workflow::WorkflowManager::load(),
+            workflow_list_state: ListState::default(),
+            workflow_run: None,
+            workflow_fill: None,
+            workflow_queue: VecDeque::new(),
+            pending_workflow_request: None,
+            workflow_draft: None,
+            draft_scroll: 0,
+            workflow_editor: None,
+            workflow_editor_state: ListState::default(),
+            pending_commit_diff: None,
+            commit_message: None,
+            commit_message_editing: false,
+            pending_howto: None,
+            howto: None,
+            compare_models: None,
+            comparison: None,
+            compare_scroll: 0,
+            pending_bench: None,
+            bench: None,
+            bench_scroll: 0,
+            split: None,
+            images,
+            image_placements: Vec::new(),
+            drawn_images: Vec::new(),
+            toasts: toast::Toasts::default(),
+            layout: layout::LayoutCache::default(),
+        };
+        if let Some(project) = state.project.clone() {
+            state.set_status_message(format!("Using project config {}", project.path.display()));
+            let keys = project.policy_keys();
+            if !keys.is_empty() {
+                let message = format!("Command policy changed by {}: {}", project.path.display(), keys.join(", "));
+                state.push_toast(toast::Severity::Warning, message);
+            }
+        }
+        state.sort_sessions();
+        let broken = state.workflows.errors().len();
+        if broken > 0 {
+            state.push_toast(toast::Severity::Warning, format!("{} workflow file(s) failed to load, :wf shows why", broken));
+        }
+        Ok(state)
     }
 
     pub fn new_session(&mut self) -> Result<()> {
         let mut new_session = models::ChatSession::new(&self.db_conn)?;
         db::save_session(&self.db_conn, &mut new_session)?;
         self.sessions.push(new_session);
+        self.record_jump();
+        self.remember_alternate_session(self.sessions.len() - 1);
         self.current_session_index = self.sessions.len() - 1;
         self.session_list_state
             .select(Some(self.current_session_index));
         self.scroll_offset = 0;
         self.mode = AppMode::Normal;
+        self.sort_sessions();
         db::save_config(
             &self.db_conn,
             "current_session_id",
@@ -152,19 +499,94 @@ impl AppState {
         Ok(())
     }
 
+    /// Reorder the sidebar according to the `sessionsort` option, keeping the
+    /// current session and any pinned-message references pointing at the same sessions.
+    pub fn sort_sessions(&mut self) {
+        let current_id = self.current_session_id();
+        let pinned_ids: Vec<(i64, usize)> = self
+            .pinned_messages
+            .iter()
+            .map(|&(session_index, message_index)| (self.sessions[session_index].id, message_index))
+            .collect();
+
+        match self.options.session_sort.as_str() {
+            "name" => self.sessions.sort_by(|a, b| a.name.cmp(&b.name)),
+            "created" => self.sessions.sort_by_key(|s| s.created_at),
+            // Most recently active first
+            _ => self.sessions.sort_by_key(|s| std::cmp::Reverse(s.last_activity)),
+        }
+
+        let index_of = |sessions: &[models::ChatSession], id: i64| sessions.iter().position(|s| s.id == id);
+        self.current_session_index = index_of(&self.sessions, current_id).unwrap_or(0);
+        if self.mode != AppMode::SessionSelection {
+            self.session_list_state.select(Some(self.current_session_index));
+        }
+        self.pinned_messages = pinned_ids
+            .into_iter()
+            .filter_map(|(id, message_index)| Some((index_of(&self.sessions, id)?, message_index)))
+            .collect();
+    }
+
     pub fn clear_current_session(&mut self) -> Result<()> {
         let session_id = self.current_session_id();
         db::clear_messages_for_session(&self.db_conn, session_id)?;
         let messages = self.current_messages_mut();
         messages.clear();
-        messages.push(models::Message {
-            role: models::Role::Assistant,
-            content: "History Cleared.".to_string(),
-        });
+        messages.push(models::Message::new(
+            models::Role::Assistant,
+            "History Cleared.".to_string(),
+        ));
         self.chat_list_state = ListState::default(); // Reset chat list state
         Ok(())
     }
 
+    /// `:sync [dir]`: merge the sessions with a directory of session files, by default
+    /// `sync_dir`, then reload them if the sync changed any.
+    pub fn sync_sessions(&mut self, dir: Option<&str>) -> Result<()> {
+        let dir = match dir.filter(|dir| !dir.is_empty()) {
+            Some(dir) => self.resolve_path(dir),
+            None => sync::default_dir(&self.config).ok_or_else(|| anyhow!("Usage: :sync <dir>, or set sync_dir in config.toml"))?,
+        };
+        if !self.loading_sessions.is_empty() {
+            return Err(anyhow!("Wait for the responses to finish before syncing"));
+        }
+        let report = sync::sync(&self.db_conn, &dir)?;
+        if report.changed_sessions() {
+            self.reload_sessions()?;
+        }
+        self.set_status_message(report.summary(&dir));
+        Ok(())
+    }
+
+    // Sessions as they are in the database, after it was changed behind the app's back
+    fn reload_sessions(&mut self) -> Result<()> {
+        let current_id = self.current_session_id();
+        self.sessions = db::load_sessions(&self.db_conn)?;
+        if self.sessions.is_empty() {
+            let mut new_session = models::ChatSession::new(&self.db_conn)?;
+            db::save_session(&self.db_conn, &mut new_session)?;
+            self.sessions.push(new_session);
+        }
+        self.pinned_messages.clear();
+        self.knowledge_cache.clear();
+        let sessions: HashSet<i64> = self.sessions.iter().map(|session| session.id).collect();
+        self.prompt_queue.retain(|(id, _)| sessions.contains(id));
+        if self.split.as_ref().is_some_and(|split| !sessions.contains(&split.session_id)) {
+            self.split = None;
+        }
+        match self.session_index_by_id(current_id) {
+            Some(index) => self.current_session_index = index,
+            None => {
+                self.current_session_index = 0;
+                self.scroll_offset = 0;
+            }
+        }
+        // The current session may have new messages, or be a different one
+        self.chat_list_state = ListState::default();
+        self.sort_sessions();
+        Ok(())
+    }
+
     pub fn delete_current_session(&mut self) -> Result<()> {
         if self.sessions.len() <= 1 {
             // Don't delete the last session, just clear it instead
@@ -175,6 +597,7 @@ impl AppState {
         
         // Delete from database
         db::delete_session(&self.db_conn, session_id)?;
+        self.prompt_queue.retain(|(id, _)| *id != session_id);
         
         // Remove from sessions list
         self.sessions.remove(self.current_session_index);
@@ -216,7 +639,12 @@ impl AppState {
 
     pub fn switch_to_selected_session(&mut self) -> Result<()> {
         if let Some(selected) = self.session_list_state.selected() {
+            if selected != self.current_session_index {
+                self.record_jump();
+            }
+            self.remember_alternate_session(selected);
             self.current_session_index = selected;
+            self.unread_sessions.remove(&self.sessions[selected].id);
             self.chat_list_state = ListState::default(); // Reset chat list state
             self.mode = AppMode::Normal;
             db::save_config(
@@ -228,6 +656,242 @@ impl AppState {
         Ok(())
     }
 
+    /// Show `target` (a session name or number, the alternate session when empty) in a
+    /// second pane next to the current session, which keeps the focus.
+    pub fn open_split(&mut self, direction: SplitDirection, target: &str) -> Result<()> {
+        let index = if target.is_empty() {
+            self.alternate_session_id.and_then(|id| self.session_index_by_id(id))
+        } else {
+            self.sessions
+                .iter()
+                .position(|s| s.name == target)
+                .or_else(|| target.parse::<usize>().ok().and_then(|n| n.checked_sub(1)))
+        };
+        let Some(session) = index.and_then(|index| self.sessions.get(index)) else {
+            return Err(anyhow!(if target.is_empty() { "No alternate session to split with".to_string() } else { format!("Session '{}' not found", target) }));
+        };
+        if session.id == self.current_session_id() {
+            return Err(anyhow!("'{}' is already the current session", session.name));
+        }
+        self.split = Some(SplitView {
+            session_id: session.id,
+            direction,
+            list_state: ListState::default(),
+            current_first: true,
+        });
+        Ok(())
+    }
+
+    /// The split view, if its other session still exists.
+    pub fn split_view(&self) -> Option<&SplitView> {
+        self.split
+            .as_ref()
+            .filter(|split| split.session_id != self.current_session_id() && self.session_index_by_id(split.session_id).is_some())
+    }
+
+    /// Move the focus to the other pane of the split view (`Ctrl+W w`), making its session the current one.
+    pub fn focus_other_pane(&mut self) -> Result<()> {
+        if self.split_view().is_none() {
+            self.split = None;
+            return Err(anyhow!("No split view, open one with :vsplit or :split"));
+        }
+        let Some(split) = self.split.as_mut() else {
+            return Ok(());
+        };
+        let Some(index) = self.sessions.iter().position(|s| s.id == split.session_id) else {
+            return Ok(());
+        };
+        split.session_id = self.sessions[self.current_session_index].id;
+        split.current_first = !split.current_first;
+        std::mem::swap(&mut split.list_state, &mut self.chat_list_state);
+        self.record_jump();
+        self.remember_alternate_session(index);
+        self.current_session_index = index;
+        self.unread_sessions.remove(&self.sessions[index].id);
+        db::save_config(&self.db_conn, "current_session_id", &self.sessions[index].id.to_string())?;
+        Ok(())
+    }
+
+    /// Record the session being left so `Ctrl+^` / `:b#` can return to it.
+    fn remember_alternate_session(&mut self, new_index: usize) {
+        if new_index != self.current_session_index
+            && let Some(session) = self.sessions.get(self.current_session_index)
+        {
+            self.alternate_session_id = Some(session.id);
+        }
+    }
+
+    pub fn switch_to_alternate_session(&mut self) -> Result<()> {
+        let index = self
+            .alternate_session_id
+            .and_then(|id| self.sessions.iter().position(|s| s.id == id));
+        match index {
+            Some(index) => {
+                self.session_list_state.select(Some(index));
+                self.switch_to_selected_session()
+            }
+            None => {
+                self.set_status_message("No alternate session".to_string());
+                Ok(())
+            }
+        }
+    }
+
+    /// Whether a response is currently streaming into the current session.
+    pub fn is_loading(&self) -> bool {
+        self.loading_sessions.contains(&self.current_session_id())
+    }
+
+    /// Id of a new generation streaming into `session_id`. It replaces any generation
+    /// before it, whose chunks are dropped from then on.
+    pub fn start_generation(&mut self, session_id: i64) -> u64 {
+        self.next_generation += 1;
+        self.generations.insert(session_id, self.next_generation);
+        self.next_generation
+    }
+
+    /// The reply `generation` streams into, unless the generation was replaced or the
+    /// reply deleted meanwhile.
+    pub fn generation_reply(&mut self, session_id: i64, generation: u64) -> Option<&mut models::Message> {
+        if self.generations.get(&session_id) != Some(&generation) {
+            return None;
+        }
+        let index = self.session_index_by_id(session_id)?;
+        self.sessions[index].messages.iter_mut().rev().find(|message| message.generation == Some(generation))
+    }
+
+    pub fn queued_prompt_count(&self, session_id: i64) -> usize {
+        self.prompt_queue.iter().filter(|(id, _)| *id == session_id).count()
+    }
+
+    /// Hold a prompt until the current session's response has finished.
+    pub fn queue_prompt(&mut self, content: String) {
+        let session_id = self.current_session_id();
+        self.prompt_queue.push_back((session_id, content));
+        self.set_status_message(format!(
+            "Prompt queued ({} waiting)",
+            self.queued_prompt_count(session_id)
+        ));
+    }
+
+    /// Stage the files matching each whitespace-separated pattern, relative to the session's
+    /// working directory. Restaging a file replaces its earlier copy.
+    pub fn attach_files(&mut self, patterns: &str) -> Result<()> {
+        let cwd = self.working_dir(self.current_session_id());
+        let mut staged = 0;
+        for pattern in patterns.split_whitespace() {
+            let used = self.attachment_chars();
+            for attachment in attach::load(pattern, &cwd, used).map_err(|e| anyhow!(e))? {
+                self.attachments.retain(|existing| existing.name != attachment.name);
+                self.attachments.push(attachment);
+                staged += 1;
+            }
+        }
+        self.set_status_message(format!(
+            "Attached {} file(s), {} staged for the next message (~{} tokens)",
+            staged,
+            self.attachments.len(),
+            attach::estimated_tokens(self.attachment_chars())
+        ));
+        Ok(())
+    }
+
+    pub fn attachment_chars(&self) -> usize {
+        self.attachments.iter().map(|attachment| attachment.content.len()).sum()
+    }
+
+    /// Put the staged attachments in front of a message about to be sent, unstaging them.
+    pub fn with_attachments(&mut self, message: String) -> String {
+        if self.attachments.is_empty() {
+            return message;
+        }
+        let attachments = std::mem::take(&mut self.attachments);
+        attach::prepend(&attachments, &message)
+    }
+
+    /// Read the documents matching each pattern for the main loop to chunk and embed
+    /// into the current session, turning it into a knowledge session.
+    pub fn ingest_files(&mut self, patterns: &str) -> Result<()> {
+        let cwd = self.working_dir(self.current_session_id());
+        let mut documents = Vec::new();
+        for pattern in patterns.split_whitespace() {
+            for (name, path) in attach::expand(pattern, &cwd).map_err(|e| anyhow!(e))? {
+                let bytes = std::fs::read(&path).map_err(|e| anyhow!("Cannot read {}: {}", name, e))?;
+                // Skip binaries matched by a glob rather than failing the whole ingest
+                if !bytes.contains(&0) {
+                    documents.push((name, String::from_utf8_lossy(&bytes).into_owned()));
+                }
+            }
+        }
+        if documents.is_empty() {
+            return Err(anyhow!("No text files to ingest"));
+        }
+        self.pending_ingest = Some((self.current_session_id(), documents));
+        Ok(())
+    }
+
+    /// Chunks ingested into a session, read from the database the first time.
+    pub fn knowledge_chunks(&mut self, session_id: i64) -> Result<Arc<Vec<rag::Chunk>>> {
+        if let Some(chunks) = self.knowledge_cache.get(&session_id) {
+            return Ok(chunks.clone());
+        }
+        let chunks = Arc::new(db::load_knowledge_chunks(&self.db_conn, session_id)?);
+        self.knowledge_cache.insert(session_id, chunks.clone());
+        Ok(chunks)
+    }
+
+    /// Store embedded chunks from `:ingest` once the main loop has them.
+    pub fn finish_ingest(&mut self, session_id: i64, chunks: Vec<rag::Chunk>) -> Result<()> {
+        db::save_knowledge_chunks(&self.db_conn, session_id, &chunks)?;
+        self.knowledge_cache.remove(&session_id);
+        if let Some(index) = self.session_index_by_id(session_id) {
+            self.sessions[index].knowledge = true;
+        }
+        self.set_status_message(format!("Ingested {} chunks, prompts now include the most relevant ones", chunks.len()));
+        Ok(())
+    }
+
+    /// Once both models of the running comparison are done, record the prompt and
+    /// their replies in its session. Returns the session id if it was recorded.
+    pub fn finish_comparison(&mut self) -> Option<i64> {
+        let comparison = self.comparison.as_ref().filter(|comparison| comparison.is_done())?;
+        let session_id = comparison.session_id;
+        if !self.loading_sessions.remove(&session_id) {
+            return None;
+        }
+        self.generation_started.remove(&session_id);
+        let replies = comparison.messages();
+        let index = self.session_index_by_id(session_id)?;
+        let session = &mut self.sessions[index];
+        // The prompt was added when it was sent, the replies follow it
+        if let Some(prompt) = session.messages.last_mut() {
+            redact::message(prompt, &self.redaction);
+            prompt.id = db::save_message(&self.db_conn, session_id, prompt).ok();
+        }
+        for mut reply in replies {
+            redact::message(&mut reply, &self.redaction);
+            reply.id = db::save_message(&self.db_conn, session_id, &reply).ok();
+            session.messages.push(reply);
+        }
+        session.last_activity = chrono::Utc::now();
+        if index == self.current_session_index {
+            self.trigger_auto_scroll();
+        } else {
+            self.unread_sessions.insert(session_id);
+        }
+        self.sort_sessions();
+        Some(session_id)
+    }
+
+    pub fn take_queued_prompt(&mut self, session_id: i64) -> Option<String> {
+        let position = self.prompt_queue.iter().position(|(id, _)| *id == session_id)?;
+        self.prompt_queue.remove(position).map(|(_, content)| content)
+    }
+
+    pub fn session_index_by_id(&self, session_id: i64) -> Option<usize> {
+        self.sessions.iter().position(|s| s.id == session_id)
+    }
+
     pub fn current_messages_mut(&mut self) -> &mut Vec<models::Message> {
         &mut self.sessions[self.current_session_index].messages
     }
@@ -240,28 +904,91 @@ impl AppState {
         self.sessions[self.current_session_index].id
     }
 
+    /// Indices into `available_models` matching the picker filter, best match first.
+    pub fn filtered_models(&self) -> Vec<usize> {
+        fuzzy::filter(&self.model_filter, self.available_models.iter().map(|m| m.name.as_str()))
+    }
+
+    pub fn model_names(&self) -> Vec<String> {
+        self.available_models.iter().map(|m| m.name.clone()).collect()
+    }
+
+    /// Replace the model list after a fetch, falling back to the first model if
+    /// none has been chosen yet.
+    pub fn set_available_models(&mut self, models: Vec<models::ModelDetails>) {
+        self.available_models = models;
+        if !self.available_models.is_empty() && self.current_model == "No model selected" {
+            self.current_model = self.available_models[0].name.clone();
+        }
+        self.sort_models();
+    }
+
+    pub fn cycle_model_sort(&mut self) {
+        self.model_sort = self.model_sort.next();
+        self.sort_models();
+    }
+
+    /// Order the model list by the current sort column, keeping the highlighted
+    /// model (or the current model) selected.
+    fn sort_models(&mut self) {
+        let selected_name = self
+            .model_list_state
+            .selected()
+            .and_then(|i| self.filtered_models().get(i).copied())
+            .map(|i| self.available_models[i].name.clone())
+            .unwrap_or_else(|| self.current_model.clone());
+
+        match self.model_sort {
+            models::ModelSort::Name => self.available_models.sort_by(|a, b| a.name.cmp(&b.name)),
+            // Largest and most recent first
+            models::ModelSort::Size => self.available_models.sort_by_key(|m| std::cmp::Reverse(m.size)),
+            models::ModelSort::Modified => self.available_models.sort_by_key(|m| std::cmp::Reverse(m.modified_at)),
+        }
+
+        let filtered = self.filtered_models();
+        let position = filtered
+            .iter()
+            .position(|&i| self.available_models[i].name == selected_name)
+            .or(if filtered.is_empty() { None } else { Some(0) });
+        self.model_list_state.select(position);
+    }
+
+    pub fn set_model_filter(&mut self, filter: String) {
+        self.model_filter = filter;
+        let has_matches = !self.filtered_models().is_empty();
+        self.model_list_state.select(if has_matches { Some(0) } else { None });
+    }
+
     pub fn next_model(&mut self) {
+        let len = self.filtered_models().len();
+        if len == 0 {
+            return;
+        }
         let i = match self.model_list_state.selected() {
-            Some(i) => if i >= self.available_models.len() - 1 { 0 } else { i + 1 },
+            Some(i) => if i >= len - 1 { 0 } else { i + 1 },
             None => 0,
         };
         self.model_list_state.select(Some(i));
     }
 
     pub fn previous_model(&mut self) {
+        let len = self.filtered_models().len();
+        if len == 0 {
+            return;
+        }
         let i = match self.model_list_state.selected() {
-            Some(i) => if i == 0 { self.available_models.len() - 1 } else { i - 1 },
+            Some(i) => if i == 0 { len - 1 } else { i - 1 },
             None => 0,
         };
         self.model_list_state.select(Some(i));
     }
 
     pub fn confirm_model_selection(&mut self) -> Result<()> {
-        if let Some(selected) = self.model_list_state.selected() {
-            if let Some(model_name) = self.available_models.get(selected) {
-                self.current_model = model_name.clone();
-                db::save_config(&self.db_conn, "current_model", &self.current_model)?;
-            }
+        if let Some(selected) = self.model_list_state.selected()
+            && let Some(&index) = self.filtered_models().get(selected)
+            && let Some(model) = self.available_models.get(index)
+        {
+            self.select_model(model.name.clone())?;
         }
         self.mode = AppMode::Normal;
         Ok(())
@@ -294,15 +1021,25 @@ impl AppState {
         }
     }
 
+    /// Width chat messages are wrapped at; with `:set nowrap` lines only break
+    /// at newlines and are clipped by the chat pane.
+    pub fn wrap_width(&self, chat_width: u16) -> usize {
+        if self.options.wrap {
+            (chat_width as usize).saturating_sub(6)
+        } else {
+            usize::MAX
+        }
+    }
+
     pub fn calculate_total_message_lines(&self, chat_width: u16) -> usize {
         let mut total_lines = 0;
-        for message in self.current_messages() {
+        for message in self.current_messages().iter().filter(|message| self.shows_message(message)) {
             // Use the same wrap width calculation as in render_messages
-            let wrap_width = (chat_width as usize).saturating_sub(6);
-            let wrapped_content = wrap(&message.content, wrap_width);
+            let wrap_width = self.wrap_width(chat_width);
+            let layout = self.layout.get(message, wrap_width, self.images);
             
             // Each message gets at least 1 line (for the first line with prefix)
-            total_lines += std::cmp::max(1, wrapped_content.len());
+            total_lines += std::cmp::max(1, layout.lines.len());
             
             // Add empty line after each message (if content is not empty)
             if !message.content.is_empty() {
@@ -312,137 +1049,2501 @@ impl AppState {
         total_lines
     }
 
-    pub fn auto_scroll_to_bottom(&mut self, _chat_height: u16, chat_width: u16) {
-        if !self.auto_scroll {
-            return;
-        }
-        let total_lines = self.calculate_total_message_lines(chat_width);
-        
-        // Auto-scroll to show the last lines
-        if total_lines > 0 {
-            let last_index = total_lines.saturating_sub(1);
-            self.chat_list_state.select(Some(last_index));
-        }
+    /// Whether `message` of the current session passes the `:filter`.
+    pub fn shows_message(&self, message: &models::Message) -> bool {
+        self.chat_filter.as_ref().is_none_or(|filter| filter.matches(message))
     }
 
-    pub fn execute_vim_command(&mut self, command: &str) -> Result<()> {
-        match command {
-            "q" => {
-                // This will be handled in the main loop to exit
-                return Ok(());
-            }
-            "w" => {
-                // Save current session
-                if let Some(session) = self.sessions.get_mut(self.current_session_index) {
-                    db::save_session(&self.db_conn, session)?;
-                }
-            }
-            "wq" => {
-                // Save and quit
-                if let Some(session) = self.sessions.get_mut(self.current_session_index) {
-                    db::save_session(&self.db_conn, session)?;
-                }
-                // Quit signal will be handled in main loop
-            }
-            "n" => {
-                self.new_session()?;
-            }
-            "c" => {
-                self.clear_current_session()?;
-            }
-            "m" => {
-                self.mode = AppMode::ModelSelection;
-                self.is_fetching_models = true;
-                // The models will be fetched in the main loop
-            }
-            "s" => {
-                self.mode = AppMode::SessionSelection;
-                // Ensure the session list state is properly selected
-                self.session_list_state.select(Some(self.current_session_index));
-            }
-            "a" => {
-                self.mode = AppMode::Agent;
-                self.agent_mode = true;
-            }
-            "h" | "?" => {
-                self.mode = AppMode::Help;
-            }
-            cmd if cmd.starts_with("d") => {
-                // Delete session command
-                if cmd == "d" {
-                    self.delete_current_session()?;
-                } else if let Some(session_num) = cmd.strip_prefix("d") {
-                    if let Ok(index) = session_num.parse::<usize>() {
-                        if index > 0 && index <= self.sessions.len() {
-                            self.current_session_index = index - 1;
-                            self.delete_current_session()?;
-                        }
-                    }
-                }
+    /// Show only the messages matching `filter`, or all of them again, keeping the
+    /// selected message in view when it is still shown.
+    pub fn set_chat_filter(&mut self, filter: Option<ChatFilter>) -> Result<()> {
+        let position = self.chat_position();
+        self.chat_filter = filter;
+        self.go_to_position(position)?;
+        match &self.chat_filter {
+            Some(filter) => {
+                let shown = self.current_messages().iter().filter(|message| filter.matches(message)).count();
+                self.set_status_message(format!("{} of {} messages match {}", shown, self.current_messages().len(), filter.describe()));
             }
-            cmd if cmd.starts_with("b") => {
-                // Switch to buffer/session command
-                if let Some(session_num) = cmd.strip_prefix("b") {
-                    if let Ok(index) = session_num.parse::<usize>() {
-                        if index > 0 && index <= self.sessions.len() {
-                            self.current_session_index = index - 1;
-                            self.session_list_state.select(Some(self.current_session_index));
-                            self.chat_list_state = ListState::default();
-                            db::save_config(
-                                &self.db_conn,
-                                "current_session_id",
-                                &self.sessions[self.current_session_index].id.to_string(),
-                            )?;
-                        }
-                    }
-                }
+            None => self.set_status_message("Filter cleared".to_string()),
+        }
+        Ok(())
+    }
+
+    /// First line index of every message in the chat list, matching the layout
+    /// produced by `render_messages_as_list`. Messages hidden by the `:filter` take
+    /// no lines, they start where the next shown message does.
+    pub fn message_start_lines(&self, chat_width: u16) -> Vec<usize> {
+        let wrap_width = self.wrap_width(chat_width);
+        let mut starts = Vec::new();
+        let mut line_index = 0;
+        for message in self.current_messages() {
+            starts.push(line_index);
+            if !self.shows_message(message) {
+                continue;
             }
-            _ => {
-                // Unknown command, ignore
+            line_index += std::cmp::max(1, self.layout.get(message, wrap_width, self.images).lines.len());
+            if !message.content.is_empty() {
+                line_index += 1;
             }
         }
-        Ok(())
+        starts
     }
 
-    #[allow(dead_code)]
-    pub fn should_fetch_models(&self) -> bool {
-        self.mode == AppMode::ModelSelection && self.is_fetching_models && !self.available_models.is_empty() == false
+    /// Index of the message containing the currently selected chat line.
+    pub fn selected_message_index(&self) -> Option<usize> {
+        let selected = self.chat_list_state.selected()?;
+        let chat_width = (self.terminal_width * 3) / 4;
+        self.message_start_lines(chat_width)
+            .iter()
+            .rposition(|&start| start <= selected)
     }
 
-    pub fn start_visual_selection(&mut self) {
-        if let Some(selected) = self.chat_list_state.selected() {
-            self.visual_start = Some(selected);
-            self.visual_end = Some(selected);
-            self.mode = AppMode::Visual;
+    pub fn request_delete_selected_message(&mut self) {
+        let Some(index) = self.selected_message_index() else {
+            self.set_status_message("No message selected".to_string());
+            return;
+        };
+        if self.is_loading() && index + 1 == self.current_messages().len() {
+            self.set_error_message("Cannot delete a message while it is streaming".to_string());
+            return;
         }
+        self.pending_delete_message = Some(index);
+        self.mode = AppMode::DeleteMessageConfirmation;
     }
 
-    pub fn update_visual_selection(&mut self, line: usize) {
-        if self.visual_start.is_some() {
-            self.visual_end = Some(line);
+    pub fn delete_message(&mut self, index: usize) -> Result<()> {
+        if index >= self.current_messages().len() {
+            return Ok(());
+        }
+        if let Some(id) = self.current_messages()[index].id {
+            db::delete_message(&self.db_conn, id)?;
+        }
+        self.current_messages_mut().remove(index);
+        // The completion contexts of later replies still hold the deleted message
+        for message in &mut self.current_messages_mut()[index..] {
+            message.context = None;
         }
+
+        // Keep the selection inside the (now shorter) chat list
+        let chat_width = (self.terminal_width * 3) / 4;
+        let total_lines = self.calculate_total_message_lines(chat_width);
+        if total_lines == 0 {
+            self.chat_list_state.select(None);
+        } else if let Some(selected) = self.chat_list_state.selected() {
+            self.chat_list_state.select(Some(selected.min(total_lines - 1)));
+        }
+        Ok(())
     }
 
-    pub fn get_selected_text(&self) -> String {
-        if let (Some(start), Some(end)) = (self.visual_start, self.visual_end) {
-            let start_line = std::cmp::min(start, end);
-            let end_line = std::cmp::max(start, end);
+    /// Move the chat selection to the first line of the given message.
+    pub fn select_message(&mut self, index: usize) {
+        let chat_width = (self.terminal_width * 3) / 4;
+        if let Some(&start) = self.message_start_lines(chat_width).get(index) {
+            self.auto_scroll = false;
+            self.chat_list_state.select(Some(start));
+        }
+    }
+
+    pub fn toggle_pin_selected_message(&mut self) -> Result<()> {
+        let Some(index) = self.selected_message_index() else {
+            self.set_status_message("No message selected".to_string());
+            return Ok(());
+        };
+        let message = &self.current_messages()[index];
+        let Some(id) = message.id else {
+            self.set_status_message("Only saved messages can be pinned".to_string());
+            return Ok(());
+        };
+        let pinned = !message.pinned;
+        db::set_message_pinned(&self.db_conn, id, pinned)?;
+        self.current_messages_mut()[index].pinned = pinned;
+        self.set_status_message(if pinned { "Message pinned" } else { "Message unpinned" }.to_string());
+        Ok(())
+    }
+
+    /// Expand or fold the `<think>` block of the selected message (`za`).
+    pub fn toggle_selected_thinking(&mut self) {
+        let Some(index) = self.selected_message_index() else {
+            self.set_status_message("No message selected".to_string());
+            return;
+        };
+        let message = &mut self.current_messages_mut()[index];
+        if !message.has_thinking() {
+            self.set_status_message("The message has no thinking to fold".to_string());
+            return;
+        }
+        message.thinking_expanded = !message.thinking_expanded;
+    }
+
+    /// Expand (`zR`) or fold (`zM`) every `<think>` block in the current session.
+    pub fn set_all_thinking_expanded(&mut self, expanded: bool) {
+        for message in self.current_messages_mut() {
+            message.thinking_expanded = expanded;
+        }
+    }
+
+    pub fn open_pinned_messages(&mut self) {
+        self.pinned_messages = self
+            .sessions
+            .iter()
+            .enumerate()
+            .flat_map(|(session_index, session)| {
+                session
+                    .messages
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, message)| message.pinned)
+                    .map(move |(message_index, _)| (session_index, message_index))
+            })
+            .collect();
+        self.pinned_list_state
+            .select(if self.pinned_messages.is_empty() { None } else { Some(0) });
+        self.mode = AppMode::PinnedMessages;
+    }
+
+    pub fn next_pinned_message(&mut self) {
+        if self.pinned_messages.is_empty() {
+            return;
+        }
+        let i = match self.pinned_list_state.selected() {
+            Some(i) => if i >= self.pinned_messages.len() - 1 { 0 } else { i + 1 },
+            None => 0,
+        };
+        self.pinned_list_state.select(Some(i));
+    }
+
+    pub fn previous_pinned_message(&mut self) {
+        if self.pinned_messages.is_empty() {
+            return;
+        }
+        let i = match self.pinned_list_state.selected() {
+            Some(i) => if i == 0 { self.pinned_messages.len() - 1 } else { i - 1 },
+            None => 0,
+        };
+        self.pinned_list_state.select(Some(i));
+    }
+
+    /// Switch to the session holding the selected pin and scroll to the message.
+    pub fn jump_to_selected_pin(&mut self) -> Result<()> {
+        let Some(&(session_index, message_index)) = self
+            .pinned_list_state
+            .selected()
+            .and_then(|i| self.pinned_messages.get(i))
+        else {
+            return Ok(());
+        };
+        self.session_list_state.select(Some(session_index));
+        self.switch_to_selected_session()?;
+        self.select_message(message_index);
+        Ok(())
+    }
+
+    /// Unpin the message selected in the pins popup and drop it from the list.
+    pub fn unpin_selected_pin(&mut self) -> Result<()> {
+        let Some(selected) = self.pinned_list_state.selected() else {
+            return Ok(());
+        };
+        let Some(&(session_index, message_index)) = self.pinned_messages.get(selected) else {
+            return Ok(());
+        };
+        let message = &mut self.sessions[session_index].messages[message_index];
+        if let Some(id) = message.id {
+            db::set_message_pinned(&self.db_conn, id, false)?;
+        }
+        message.pinned = false;
+        self.pinned_messages.remove(selected);
+        if self.pinned_messages.is_empty() {
+            self.pinned_list_state.select(None);
+        } else {
+            self.pinned_list_state
+                .select(Some(selected.min(self.pinned_messages.len() - 1)));
+        }
+        Ok(())
+    }
+
+    /// Prefill the input with a `> ` quoted copy of the selected message.
+    pub fn quote_selected_message(&mut self) {
+        let Some(index) = self.selected_message_index() else {
+            self.set_status_message("No message selected".to_string());
+            return;
+        };
+        let message = &self.current_messages()[index];
+        let content = if self.options.think_copy { message.content.as_str() } else { message.answer() };
+        let quoted: Vec<String> = content
+            .lines()
+            .map(|line| format!("> {}", line))
+            .collect();
+        self.input = format!("{}\n\n", quoted.join("\n"));
+        self.mode = AppMode::Insert;
+    }
+
+    pub fn push_count_digit(&mut self, digit: u32) {
+        let count = self.count_prefix.unwrap_or(0);
+        self.count_prefix = Some(count.saturating_mul(10).saturating_add(digit as usize));
+    }
+
+    /// Consume the pending count prefix, defaulting to 1 like vim does.
+    pub fn take_count(&mut self) -> usize {
+        self.count_prefix.take().unwrap_or(1).max(1)
+    }
+
+    pub fn scroll_chat_down(&mut self, lines: usize) {
+        let chat_width = (self.terminal_width * 3) / 4;
+        let total_lines = self.calculate_total_message_lines(chat_width);
+        if total_lines == 0 {
+            return;
+        }
+        let new_index = match self.chat_list_state.selected() {
+            Some(i) => std::cmp::min(i.saturating_add(lines), total_lines - 1),
+            None => 0,
+        };
+        self.chat_list_state.select(Some(new_index));
+    }
+
+    pub fn scroll_chat_up(&mut self, lines: usize) {
+        let new_index = match self.chat_list_state.selected() {
+            Some(i) => i.saturating_sub(lines),
+            None => {
+                let chat_width = (self.terminal_width * 3) / 4;
+                let total_lines = self.calculate_total_message_lines(chat_width);
+                if total_lines == 0 {
+                    return;
+                }
+                total_lines - 1
+            }
+        };
+        self.chat_list_state.select(Some(new_index));
+    }
+
+    /// Move the selection to the start of the `count`-th following message (`}`).
+    pub fn jump_to_next_message(&mut self, count: usize) {
+        let chat_width = (self.terminal_width * 3) / 4;
+        let starts = self.message_start_lines(chat_width);
+        let mut current = self.chat_list_state.selected().unwrap_or(0);
+        for _ in 0..count {
+            match starts.iter().find(|&&start| start > current) {
+                Some(&start) => current = start,
+                None => {
+                    // No further message: land on the last line like vim's `}`
+                    current = self.calculate_total_message_lines(chat_width).saturating_sub(1);
+                    break;
+                }
+            }
+        }
+        self.chat_list_state.select(Some(current));
+    }
+
+    /// Where the selection is in the current session.
+    pub fn chat_position(&self) -> ChatPosition {
+        let selected = self.chat_list_state.selected().unwrap_or(0);
+        let starts = self.message_start_lines((self.terminal_width * 3) / 4);
+        let message = starts.iter().rposition(|&start| start <= selected).unwrap_or(0);
+        ChatPosition {
+            session_id: self.current_session_id(),
+            message,
+            offset: selected - starts.get(message).copied().unwrap_or(0),
+        }
+    }
+
+    /// Select `position`, switching to its session first. Positions past the end of
+    /// a session that has lost messages land on its last line.
+    pub fn go_to_position(&mut self, position: ChatPosition) -> Result<()> {
+        if position.session_id != self.current_session_id() {
+            let index = self
+                .session_index_by_id(position.session_id)
+                .ok_or_else(|| anyhow!("The session of that position was deleted"))?;
+            self.remember_alternate_session(index);
+            self.current_session_index = index;
+            self.session_list_state.select(Some(index));
+            self.unread_sessions.remove(&position.session_id);
+            db::save_config(&self.db_conn, "current_session_id", &position.session_id.to_string())?;
+        }
+        let chat_width = (self.terminal_width * 3) / 4;
+        let starts = self.message_start_lines(chat_width);
+        let total_lines = self.calculate_total_message_lines(chat_width);
+        let line = match starts.get(position.message) {
+            Some(start) => start + position.offset,
+            None => total_lines,
+        };
+        self.auto_scroll = false;
+        self.chat_list_state.select(Some(line.min(total_lines.saturating_sub(1))));
+        Ok(())
+    }
+
+    /// Remember the current position in the jump list before moving far away from it.
+    /// Jumping from an older entry drops the newer ones, like a browser history.
+    pub fn record_jump(&mut self) {
+        let position = self.chat_position();
+        self.jumps.truncate(self.jump_index);
+        if self.jumps.last() != Some(&position) {
+            self.jumps.push(position);
+        }
+        if self.jumps.len() > JUMP_LIST_LIMIT {
+            self.jumps.remove(0);
+        }
+        self.jump_index = self.jumps.len();
+    }
+
+    /// Go `count` entries back (`Ctrl+O`) or forward (`Ctrl+I`) in the jump list.
+    pub fn walk_jump_list(&mut self, count: usize, back: bool) -> Result<()> {
+        if back && self.jump_index == self.jumps.len() {
+            // Leaving the newest position, keep it so Ctrl+I can come back
+            let position = self.chat_position();
+            if self.jumps.last() != Some(&position) {
+                self.jumps.push(position);
+            }
+            self.jump_index = self.jumps.len() - 1;
+        }
+        let target = if back { self.jump_index.checked_sub(count) } else { Some(self.jump_index + count).filter(|&i| i < self.jumps.len()) };
+        let Some(target) = target else {
+            self.set_status_message(if back { "At the oldest jump" } else { "At the newest jump" }.to_string());
+            return Ok(());
+        };
+        self.jump_index = target;
+        self.go_to_position(self.jumps[target])
+    }
+
+    pub fn set_mark(&mut self, name: char) {
+        let position = self.chat_position();
+        self.marks.insert(name, position);
+        self.set_status_message(format!("Mark {} set", name));
+    }
+
+    /// Jump to the mark `name`, to its line or, with `message_start`, the start of its message (`'a`).
+    pub fn jump_to_mark(&mut self, name: char, message_start: bool) -> Result<()> {
+        let mut position = *self.marks.get(&name).ok_or_else(|| anyhow!("Mark {} is not set", name))?;
+        if message_start {
+            position.offset = 0;
+        }
+        self.record_jump();
+        self.go_to_position(position)
+    }
+
+    /// Lines of the `:marks` popup.
+    pub fn mark_lines(&self) -> Vec<String> {
+        if self.marks.is_empty() {
+            return vec!["No marks yet, set one with ma".to_string()];
+        }
+        self.marks
+            .iter()
+            .map(|(name, position)| {
+                let session = self
+                    .session_index_by_id(position.session_id)
+                    .map(|index| self.sessions[index].name.as_str())
+                    .unwrap_or("(deleted session)");
+                format!("{}  {}, message {}, line {}", name, session, position.message + 1, position.offset + 1)
+            })
+            .collect()
+    }
+
+    /// Move the selection to the start of the `count`-th preceding message (`{`).
+    pub fn jump_to_previous_message(&mut self, count: usize) {
+        let chat_width = (self.terminal_width * 3) / 4;
+        let starts = self.message_start_lines(chat_width);
+        let mut current = self.chat_list_state.selected().unwrap_or(0);
+        for _ in 0..count {
+            match starts.iter().rev().find(|&&start| start < current) {
+                Some(&start) => current = start,
+                None => {
+                    current = 0;
+                    break;
+                }
+            }
+        }
+        self.chat_list_state.select(Some(current));
+    }
+
+    pub fn auto_scroll_to_bottom(&mut self, _chat_height: u16, chat_width: u16) {
+        if !self.auto_scroll {
+            return;
+        }
+        let total_lines = self.calculate_total_message_lines(chat_width);
+        
+        // Auto-scroll to show the last lines
+        if total_lines > 0 {
+            let last_index = total_lines.saturating_sub(1);
+            self.chat_list_state.select(Some(last_index));
+        }
+    }
+
+    /// Tab completion in command mode: the first Tab computes candidates for the
+    /// token under the cursor, further presses cycle through them.
+    pub fn complete_command(&mut self, forward: bool) {
+        if self.completion_candidates.is_empty() {
+            let session_names: Vec<String> = self.sessions.iter().map(|s| s.name.clone()).collect();
+            let mut alias_names: Vec<String> = self.config.aliases.keys().cloned().collect();
+            alias_names.sort();
+            let template_names: Vec<String> = self
+                .templates()
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(name, _)| name)
+                .collect();
+            let (start, candidates) = commands::complete(
+                &self.vim_command,
+                &self.working_dir(self.current_session_id()),
+                &self.model_names(),
+                &session_names,
+                &template_names,
+                &alias_names,
+            );
+            if candidates.is_empty() {
+                return;
+            }
+            self.completion_start = start;
+            self.completion_candidates = candidates;
+            self.completion_index = None;
+        }
+
+        let len = self.completion_candidates.len();
+        let index = match (self.completion_index, forward) {
+            (None, true) => 0,
+            (None, false) => len - 1,
+            (Some(i), true) => (i + 1) % len,
+            (Some(i), false) => (i + len - 1) % len,
+        };
+        self.completion_index = Some(index);
+        self.vim_command.truncate(self.completion_start);
+        self.vim_command.push_str(&self.completion_candidates[index]);
+
+        // A single match is final; drop the popup so the next Tab completes afresh
+        if len == 1 {
+            self.clear_completion();
+        }
+    }
+
+    pub fn clear_completion(&mut self) {
+        self.completion_candidates.clear();
+        self.completion_index = None;
+        self.completion_start = 0;
+    }
+
+    pub fn record_command_history(&mut self, command: &str) {
+        // History is stored in the database, keep secrets set with :env out of it
+        let sets_secret = command
+            .strip_prefix("env ")
+            .and_then(|assignment| assignment.split_once('='))
+            .is_some_and(|(name, _)| agent::is_secret_name(name.trim()));
+        if sets_secret
+            || command.trim().is_empty()
+            || self.command_history.last().map(String::as_str) == Some(command)
+        {
+            return;
+        }
+        self.command_history.push(command.to_string());
+        if self.command_history.len() > COMMAND_HISTORY_LIMIT {
+            self.command_history.remove(0);
+        }
+        db::save_command_history(&self.db_conn, command).ok();
+    }
+
+    /// Recall older (`older == true`) or newer history entries matching the
+    /// text typed before browsing started, like vim's command-line history.
+    pub fn browse_command_history(&mut self, older: bool) {
+        if self.history_index.is_none() {
+            self.history_draft = self.vim_command.clone();
+        }
+        let matches = |entry: &String| entry.starts_with(&self.history_draft);
+        let next = match (self.history_index, older) {
+            (None, true) => self.command_history.iter().rposition(matches),
+            (None, false) => None,
+            (Some(i), true) => self.command_history[..i].iter().rposition(matches).or(Some(i)),
+            (Some(i), false) => self.command_history[i + 1..]
+                .iter()
+                .position(matches)
+                .map(|offset| i + 1 + offset),
+        };
+        match next {
+            Some(i) => {
+                self.history_index = Some(i);
+                self.vim_command = self.command_history[i].clone();
+            }
+            None if !older => {
+                // Walked past the newest entry: restore the draft
+                self.history_index = None;
+                self.vim_command = self.history_draft.clone();
+            }
+            None => {}
+        }
+    }
+
+    pub fn reset_history_browsing(&mut self) {
+        self.history_index = None;
+        self.history_draft.clear();
+    }
+
+    pub fn open_command_history(&mut self) {
+        self.history_filter.clear();
+        self.history_list_state
+            .select(if self.command_history.is_empty() { None } else { Some(0) });
+        self.mode = AppMode::CommandHistory;
+    }
+
+    /// History entries matching the q: window filter, newest first.
+    pub fn filtered_command_history(&self) -> Vec<&String> {
+        let filter = self.history_filter.to_lowercase();
+        self.command_history
+            .iter()
+            .rev()
+            .filter(|entry| entry.to_lowercase().contains(&filter))
+            .collect()
+    }
+
+    pub fn move_history_selection(&mut self, down: bool) {
+        let len = self.filtered_command_history().len();
+        if len == 0 {
+            self.history_list_state.select(None);
+            return;
+        }
+        let i = match self.history_list_state.selected() {
+            Some(i) if down => if i >= len - 1 { 0 } else { i + 1 },
+            Some(i) => if i == 0 { len - 1 } else { i - 1 },
+            None => 0,
+        };
+        self.history_list_state.select(Some(i));
+    }
+
+    pub fn selected_history_command(&self) -> Option<String> {
+        let index = self.history_list_state.selected()?;
+        self.filtered_command_history().get(index).map(|s| s.to_string())
+    }
+
+    pub fn open_command_palette(&mut self) {
+        self.palette_query.clear();
+        self.palette_list_state.select(Some(0));
+        self.mode = AppMode::CommandPalette;
+    }
+
+    /// Palette entries as (command, description, requires input), best fuzzy match first.
+    pub fn filtered_palette_entries(&self) -> Vec<(String, String, bool)> {
+        let mut entries: Vec<(String, String, bool)> = commands::COMMANDS
+            .iter()
+            .map(|c| (c.name.to_string(), c.description.to_string(), c.args.requires_input()))
+            .collect();
+        let mut aliases: Vec<(&String, &Vec<String>)> = self.config.aliases.iter().collect();
+        aliases.sort();
+        entries.extend(aliases.into_iter().map(|(name, steps)| {
+            (name.clone(), format!("Alias: {}", steps.join(" | ")), false)
+        }));
+
+        let haystacks: Vec<String> = entries
+            .iter()
+            .map(|(name, description, _)| format!("{} {}", name, description))
+            .collect();
+        fuzzy::filter(&self.palette_query, haystacks.iter().map(String::as_str))
+            .into_iter()
+            .map(|i| entries[i].clone())
+            .collect()
+    }
+
+    pub fn move_palette_selection(&mut self, down: bool) {
+        let len = self.filtered_palette_entries().len();
+        if len == 0 {
+            self.palette_list_state.select(None);
+            return;
+        }
+        let i = match self.palette_list_state.selected() {
+            Some(i) if down => if i >= len - 1 { 0 } else { i + 1 },
+            Some(i) => if i == 0 { len - 1 } else { i - 1 },
+            None => 0,
+        };
+        self.palette_list_state.select(Some(i));
+    }
+
+    pub fn show_info(&mut self, title: &str, lines: Vec<String>) {
+        self.info_title = title.to_string();
+        self.info_lines = lines;
+        self.info_scroll = 0;
+        self.mode = AppMode::Info;
+    }
+
+    /// Open the help screen for the current mode; from the command line or palette that is normal mode.
+    pub fn open_help(&mut self) {
+        self.help_context = match self.mode {
+            AppMode::Command | AppMode::CommandPalette | AppMode::Help => AppMode::Normal,
+            mode => mode,
+        };
+        self.help_filter.clear();
+        self.help_searching = false;
+        self.help_scroll = 0;
+        self.mode = AppMode::Help;
+    }
+
+    pub fn get_option(&self, name: &str) -> Option<String> {
+        if let Some(value) = self.sessions[self.current_session_index].options.get(name) {
+            return Some(value.clone());
+        }
+        match name {
+            "model" => Some(self.current_model.clone()),
+            name if options::SESSION_OPTIONS.contains(&name) => Some(String::new()),
+            _ => self.options.get(name),
+        }
+    }
+
+    /// The model replies in `session_id` come from: the one of its template, or the current one.
+    pub fn session_model(&self, session_id: i64) -> String {
+        self.session_index_by_id(session_id)
+            .and_then(|index| self.sessions[index].options.get("model"))
+            .cloned()
+            .unwrap_or_else(|| self.current_model.clone())
+    }
+
+    // Picking a model also applies to a session whose template chose another one
+    fn select_model(&mut self, model: String) -> Result<()> {
+        self.current_model = model;
+        db::save_config(&self.db_conn, "current_model", &self.current_model)?;
+        let session = &mut self.sessions[self.current_session_index];
+        if session.options.remove("model").is_some() {
+            db::set_session_options(&self.db_conn, session.id, &session.options)?;
+        }
+        Ok(())
+    }
+
+    pub fn set_option(&mut self, name: &str, value: &str) -> Result<()> {
+        match name {
+            "model" => {
+                if value.is_empty() {
+                    return Err(anyhow!("Model name cannot be empty"));
+                }
+                self.select_model(value.to_string())?;
+            }
+            name if options::SESSION_OPTIONS.contains(&name)
+                || self.sessions[self.current_session_index].options.contains_key(name) =>
+            {
+                // Checked, and kept the way :set shows it
+                let mut parsed = options::Options::default();
+                parsed.set(name, value)?;
+                let value = parsed.get(name).unwrap_or_default();
+                let session = &mut self.sessions[self.current_session_index];
+                if value.is_empty() {
+                    session.options.remove(name);
+                } else {
+                    session.options.insert(name.to_string(), value);
+                }
+                db::set_session_options(&self.db_conn, session.id, &session.options)?;
+            }
+            _ => {
+                self.options.set(name, value)?;
+                self.options.save(&self.db_conn, name)?;
+                if name == "sessionsort" {
+                    self.sort_sessions();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Request options for `model` in the session `session_id`: its own options, then those
+    /// set with `:set`, then the model's config defaults.
+    pub fn request_options(&self, session_id: i64, model: &str) -> models::ModelOptions {
+        let no_options = HashMap::new();
+        let session = self.session_index_by_id(session_id).map_or(&no_options, |index| &self.sessions[index].options);
+        self.options.model_options(model, &self.config.model_options, session)
+    }
+
+    /// Templates from the config file, overridden by ones saved with `:template save`.
+    pub fn templates(&self) -> Result<Vec<(String, models::SessionTemplate)>> {
+        let mut templates: Vec<(String, models::SessionTemplate)> = self
+            .config
+            .templates
+            .iter()
+            .map(|(name, template)| (name.clone(), template.clone()))
+            .collect();
+        for (name, template) in db::load_templates(&self.db_conn)? {
+            templates.retain(|(existing, _)| *existing != name);
+            templates.push((name, template));
+        }
+        templates.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(templates)
+    }
+
+    /// Prompt snippets from the config file and the database, the latter winning on name clashes.
+    pub fn prompts(&self) -> Result<Vec<(String, String)>> {
+        let mut prompts: Vec<(String, String)> = self
+            .config
+            .prompts
+            .iter()
+            .map(|(name, text)| (name.clone(), text.clone()))
+            .collect();
+        for (name, text) in db::load_prompts(&self.db_conn)? {
+            prompts.retain(|(existing, _)| *existing != name);
+            prompts.push((name, text));
+        }
+        prompts.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(prompts)
+    }
+
+    fn list_prompts(&mut self) -> Result<()> {
+        let prompts = self.prompts()?;
+        let lines = if prompts.is_empty() {
+            vec!["No prompts. Save one with :prompt save <name> <text>, {{name}} marks a placeholder".to_string()]
+        } else {
+            prompts
+                .iter()
+                .map(|(name, text)| format!("{:<16} {}", name, text.lines().next().unwrap_or("")))
+                .collect()
+        };
+        self.show_info("Prompts (:prompt use <name>)", lines);
+        Ok(())
+    }
+
+    /// Save `text`, or the input box when it is empty, as a named prompt snippet.
+    fn save_prompt(&mut self, args: &str) -> Result<()> {
+        let (name, text) = args.trim().split_once(' ').unwrap_or((args.trim(), ""));
+        if name.is_empty() {
+            return Err(anyhow!("Usage: :prompt save <name> [text]"));
+        }
+        let text = if text.trim().is_empty() { self.input.trim() } else { text.trim() };
+        if text.is_empty() {
+            return Err(anyhow!("Nothing to save, give the text or type it in the input box first"));
+        }
+        db::save_prompt(&self.db_conn, name, text)?;
+        let count = prompts::placeholders(text).len();
+        self.set_status_message(format!("Saved prompt '{}' with {} placeholder(s)", name, count));
+        Ok(())
+    }
+
+    /// Put a prompt snippet in the input box, asking for its placeholders first.
+    fn use_prompt(&mut self, name: &str) -> Result<()> {
+        let text = self
+            .prompts()?
+            .into_iter()
+            .find(|(existing, _)| existing == name)
+            .map(|(_, text)| text)
+            .ok_or_else(|| anyhow!("No prompt named '{}'", name))?;
+        let fill = prompts::PromptFill::new(name, &text);
+        if fill.current().is_some() {
+            self.prompt_fill = Some(fill);
+            self.mode = AppMode::PromptVariables;
+        } else {
+            self.finish_prompt_fill(fill);
+        }
+        Ok(())
+    }
+
+    /// Accept the value typed for the current placeholder, inserting the prompt after the last.
+    pub fn next_prompt_variable(&mut self) {
+        let Some(mut fill) = self.prompt_fill.take() else {
+            return;
+        };
+        fill.next();
+        if fill.current().is_some() {
+            self.prompt_fill = Some(fill);
+        } else {
+            self.finish_prompt_fill(fill);
+        }
+    }
+
+    fn finish_prompt_fill(&mut self, fill: prompts::PromptFill) {
+        self.input = fill.fill();
+        self.mode = AppMode::Insert;
+        self.set_status_message(format!("Prompt '{}' ready, Enter sends it", fill.name));
+    }
+
+    fn list_templates(&mut self) -> Result<()> {
+        let templates = self.templates()?;
+        let lines = if templates.is_empty() {
+            vec!["No templates. Save the current setup with :template save <name>".to_string()]
+        } else {
+            templates
+                .iter()
+                .map(|(name, template)| {
+                    let prompt = template.system_prompt.as_deref().unwrap_or("").lines().next().unwrap_or("");
+                    format!(
+                        "{:<16} {:<20} {}",
+                        name,
+                        template.model.as_deref().unwrap_or("-"),
+                        prompt
+                    )
+                })
+                .collect()
+        };
+        self.show_info("Templates (:new <name> to start a session)", lines);
+        Ok(())
+    }
+
+    /// Capture the current model, options and system prompt as a named template.
+    pub fn save_template(&mut self, name: &str) -> Result<()> {
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(anyhow!("Template names must be a single word"));
+        }
+        let options = options::REQUEST_OPTIONS
+            .iter()
+            .filter_map(|name| Some((name.to_string(), self.get_option(name)?)))
+            .filter(|(_, value)| !value.is_empty())
+            .collect();
+        let template = models::SessionTemplate {
+            model: Some(self.session_model(self.current_session_id())).filter(|m| m != "No model selected"),
+            system_prompt: self.sessions[self.current_session_index].system_prompt.clone(),
+            options,
+        };
+        db::save_template(&self.db_conn, name, &template)
+    }
+
+    pub fn new_session_from_template(&mut self, name: &str) -> Result<()> {
+        let template = self
+            .templates()?
+            .into_iter()
+            .find(|(existing, _)| existing == name)
+            .map(|(_, template)| template)
+            .ok_or_else(|| anyhow!("No template named '{}'", name))?;
+
+        // The template sets up its own session, the global model and options stay as they are
+        let mut session_options = HashMap::new();
+        let mut ignored = Vec::new();
+        for (option, value) in &template.options {
+            if !options::REQUEST_OPTIONS.contains(&option.as_str()) {
+                ignored.push(option.as_str());
+                continue;
+            }
+            options::Options::default()
+                .set(option, value)
+                .map_err(|e| anyhow!("Template '{}': {}", name, e))?;
+            session_options.insert(option.clone(), value.clone());
+        }
+        if let Some(model) = &template.model {
+            session_options.insert("model".to_string(), model.clone());
+        }
+
+        self.new_session()?;
+        let session = &mut self.sessions[self.current_session_index];
+        session.options = session_options;
+        db::set_session_options(&self.db_conn, session.id, &session.options)?;
+        self.set_system_prompt(template.system_prompt.clone())?;
+        if ignored.is_empty() {
+            self.set_status_message(format!("New session from template '{}'", name));
+        } else {
+            ignored.sort();
+            self.set_status_message(format!(
+                "New session from template '{}'; {} only apply globally and were left out",
+                name,
+                ignored.join(", ")
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn set_system_prompt(&mut self, system_prompt: Option<String>) -> Result<()> {
+        let session = &mut self.sessions[self.current_session_index];
+        session.system_prompt = system_prompt.filter(|p| !p.is_empty());
+        db::set_session_system_prompt(&self.db_conn, session.id, session.system_prompt.as_deref())
+    }
+
+    /// Begin working towards `goal`; the main loop sends the first reasoning request.
+    pub fn start_autonomous_run(&mut self, goal: String) -> Result<()> {
+        if goal.is_empty() {
+            return Err(anyhow!("Usage: :auto <goal>"));
+        }
+        if self.autonomous.as_ref().is_some_and(|run| !run.state.is_finished()) {
+            return Err(anyhow!("An autonomous run is already in progress"));
+        }
+        let session_id = self.current_session_id();
+        self.push_session_message(session_id, format!("Autonomous goal: {}", goal))?;
+        let mut run = agent::AutonomousRun::new(session_id, goal);
+        run.max_steps = self.options.max_steps;
+        self.autonomous = Some(run);
+        self.reset_agent_panel();
+        self.persist_autonomous_run();
+        self.mode = AppMode::Autonomous;
+        Ok(())
+    }
+
+    fn reset_agent_panel(&mut self) {
+        self.agent_step_list_state.select(None);
+        self.expanded_agent_steps.clear();
+        self.command_edit = None;
+        self.clear_tool_preview();
+    }
+
+    pub fn move_agent_step_selection(&mut self, down: bool) {
+        let len = self.autonomous.as_ref().map(|run| run.steps.len()).unwrap_or(0);
+        if len == 0 {
+            return;
+        }
+        let i = match self.agent_step_list_state.selected() {
+            Some(i) if down => (i + 1).min(len - 1),
+            Some(i) => i.saturating_sub(1),
+            None => len - 1, // Start from the latest step
+        };
+        self.agent_step_list_state.select(Some(i));
+    }
+
+    /// Expand or collapse the details of the selected step in the progress panel.
+    pub fn toggle_agent_step(&mut self) {
+        if let Some(i) = self.agent_step_list_state.selected()
+            && !self.expanded_agent_steps.remove(&i)
+        {
+            self.expanded_agent_steps.insert(i);
+        }
+    }
+
+    /// Queue a command the user picked as the next step of the paused run,
+    /// replacing the command the agent proposed, optionally editing it first.
+    pub fn queue_agent_command(&mut self, command: String, edit: bool) -> Result<()> {
+        match self.autonomous.as_ref() {
+            None => return Err(anyhow!("No autonomous run to queue the command in, start one with :auto <goal>")),
+            Some(run) if run.state != agent::AgentState::Paused => {
+                return Err(anyhow!("Pause the autonomous run before queueing a command"));
+            }
+            Some(_) => {}
+        }
+        let violation = match self.check_command_policy(&command) {
+            policy::Verdict::Denied(reason) => Some(reason),
+            _ => None,
+        };
+        let Some(run) = self.autonomous.as_mut() else {
+            return Ok(());
+        };
+        let replaced_tool = run.pending_tool.take().is_some();
+        if (run.pending_command.take().is_some() || replaced_tool)
+            && let Some(step) = run.steps.last_mut()
+        {
+            step.output = Some(Err("Replaced by a command queued by the user".to_string()));
+        }
+        run.steps.push(agent::AgentStep {
+            thought: "Queued by the user".to_string(),
+            command: Some(command.clone()),
+            tool: None,
+            output: None,
+            exit_code: None,
+            analysis: None,
+        });
+        run.pending_command = Some(command.clone());
+        run.policy_violation = violation;
+        self.agent_step_list_state.select(Some(run.steps.len() - 1));
+        self.clear_tool_preview();
+        self.command_edit = edit.then_some(command);
+        self.mode = AppMode::Autonomous;
+        self.set_status_message(if edit {
+            "Edit the command, Enter to queue it".to_string()
+        } else {
+            "Command queued, press n to run it".to_string()
+        });
+        Ok(())
+    }
+
+    /// Re-queue the command of the step selected in the progress panel.
+    pub fn requeue_selected_step(&mut self, edit: bool) -> Result<()> {
+        let command = self
+            .autonomous
+            .as_ref()
+            .zip(self.agent_step_list_state.selected())
+            .and_then(|(run, i)| run.steps.get(i))
+            .and_then(|step| step.command.clone())
+            .ok_or_else(|| anyhow!("Select a step that ran a command"))?;
+        self.queue_agent_command(command, edit)
+    }
+
+    /// Re-queue the command of the entry selected in the audit log.
+    pub fn requeue_selected_audit_entry(&mut self, edit: bool) -> Result<()> {
+        let Some(entry) = self.audit_list_state.selected().and_then(|i| self.audit_entries.get(i)) else {
+            return Ok(());
+        };
+        self.queue_agent_command(entry.command.clone(), edit)
+    }
+
+    /// Open the command waiting for approval in the inline editor.
+    pub fn start_command_edit(&mut self) -> Result<()> {
+        let command = self
+            .autonomous
+            .as_ref()
+            .filter(|run| run.state == agent::AgentState::Paused)
+            .and_then(|run| run.pending_command.clone())
+            .ok_or_else(|| anyhow!("No command is waiting for approval"))?;
+        self.command_edit = Some(command);
+        Ok(())
+    }
+
+    pub fn finish_command_edit(&mut self, save: bool) {
+        let Some(buffer) = self.command_edit.take() else {
+            return;
+        };
+        let command = buffer.trim().to_string();
+        if !save || command.is_empty() {
+            return;
+        }
+        let violation = match self.check_command_policy(&command) {
+            policy::Verdict::Denied(reason) => Some(reason),
+            _ => None,
+        };
+        let Some(run) = self.autonomous.as_mut() else {
+            return;
+        };
+        if run.pending_command.is_none() {
+            return;
+        }
+        if let Some(step) = run.steps.last_mut() {
+            step.command = Some(command.clone());
+        }
+        run.pending_command = Some(command);
+        run.policy_violation = violation;
+    }
+
+    /// Kill the agent command that is currently running, if any.
+    pub fn cancel_agent_command(&mut self) -> bool {
+        match self.command_cancel.take() {
+            Some(cancel) => cancel.send(()).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Check a command the agent wants to run against the configured policy.
+    pub fn check_command_policy(&self, command: &str) -> policy::Verdict {
+        let session_id = self.autonomous.as_ref().map(|run| run.session_id).unwrap_or(self.current_session_id());
+        policy::evaluate(&self.command_policy, command, &self.working_dir(session_id))
+    }
+
+    pub fn check_tool_policy(&self, call: &tools::ToolCall) -> policy::Verdict {
+        let session_id = self.autonomous.as_ref().map(|run| run.session_id).unwrap_or(self.current_session_id());
+        policy::evaluate_tool(&self.command_policy, call, &self.working_dir(session_id))
+    }
+
+    /// Work out what a tool call would change, for the approval popup.
+    pub fn prepare_tool_preview(&mut self, call: &tools::ToolCall) -> std::result::Result<(), String> {
+        let session_id = self.autonomous.as_ref().map(|run| run.session_id).unwrap_or(self.current_session_id());
+        let cwd = self.working_dir(session_id);
+        self.clear_tool_preview();
+        self.pending_change = tools::prepare_change(call, &cwd)?;
+        self.pending_commit = tools::prepare_commit(call, &cwd)?;
+        self.commit_file_state.select(self.pending_commit.as_ref().map(|_| 0));
+        Ok(())
+    }
+
+    pub fn clear_tool_preview(&mut self) {
+        self.pending_change = None;
+        self.pending_commit = None;
+        self.diff_scroll = 0;
+    }
+
+    pub fn move_commit_file_selection(&mut self, down: bool) {
+        let len = self.pending_commit.as_ref().map(|commit| commit.files.len()).unwrap_or(0);
+        if len == 0 {
+            return;
+        }
+        let i = match self.commit_file_state.selected() {
+            Some(i) if down => (i + 1) % len,
+            Some(i) => (i + len - 1) % len,
+            None => 0,
+        };
+        self.commit_file_state.select(Some(i));
+    }
+
+    /// Include or exclude the selected file, or with `all` every file at once.
+    pub fn toggle_commit_file(&mut self, all: bool) {
+        let Some(commit) = self.pending_commit.as_mut() else {
+            return;
+        };
+        if all {
+            let select = !commit.files.iter().all(|file| file.selected);
+            commit.files.iter_mut().for_each(|file| file.selected = select);
+        } else if let Some(file) = self.commit_file_state.selected().and_then(|i| commit.files.get_mut(i)) {
+            file.selected = !file.selected;
+        }
+    }
+
+    /// Whether the pending tool call may run: a commit needs at least one file.
+    pub fn apply_commit_selection(&mut self) -> bool {
+        if self.pending_commit.as_ref().is_some_and(|commit| !commit.files.iter().any(|file| file.selected)) {
+            self.set_status_message("Select at least one file to commit".to_string());
+            return false;
+        }
+        true
+    }
+
+    /// Directory agent commands of a session run in.
+    pub fn working_dir(&self, session_id: i64) -> PathBuf {
+        self.session_index_by_id(session_id)
+            .and_then(|index| self.sessions[index].working_dir.as_ref())
+            .map(PathBuf::from)
+            .or_else(|| self.project.as_ref().and_then(|project| project.working_dir()))
+            .unwrap_or_else(|| std::env::current_dir().unwrap_or_default())
+    }
+
+    /// `path` as typed in a command, relative to the session's working directory.
+    pub fn resolve_path(&self, path: &str) -> PathBuf {
+        self.working_dir(self.current_session_id()).join(commands::expand_tilde(path))
+    }
+
+    /// The last reply of the current session, without its thinking unless `thinkcopy` is set.
+    pub fn last_answer(&self) -> Result<String> {
+        let message = self
+            .current_messages()
+            .iter()
+            .rev()
+            .find(|message| message.role == models::Role::Assistant && !message.content.is_empty())
+            .ok_or_else(|| anyhow!("No assistant message in this session"))?;
+        Ok(if self.options.think_copy { message.content.clone() } else { message.answer().trim().to_string() })
+    }
+
+    /// Write the last reply to `path`, or add it to the end with `append` (`:w >>`, `:append`).
+    /// Appended replies are separated by an empty line.
+    pub fn write_last_answer(&mut self, path: &str, append: bool) -> Result<()> {
+        if path.is_empty() {
+            return Err(anyhow!("Usage: :w <file>, :w >> <file> or :append <file>"));
+        }
+        let mut text = self.last_answer()?;
+        text.push('\n');
+        let target = self.resolve_path(path);
+        if append {
+            let existing = std::fs::metadata(&target).map(|meta| meta.len()).unwrap_or(0);
+            let mut file = std::fs::OpenOptions::new().create(true).append(true).open(&target)?;
+            if existing > 0 {
+                text.insert(0, '\n');
+            }
+            std::io::Write::write_all(&mut file, text.as_bytes())?;
+        } else {
+            std::fs::write(&target, &text)?;
+        }
+        let lines = text.trim().lines().count();
+        let verb = if append { "Appended" } else { "Wrote" };
+        self.set_status_message(format!("{} {} line(s) to {}", verb, lines, target.display()));
+        Ok(())
+    }
+
+    /// Change a session's working directory, relative to its current one.
+    pub fn change_directory(&mut self, session_id: i64, path: &str) -> Result<PathBuf> {
+        let target = if path.is_empty() || path == "~" {
+            std::env::var_os("HOME").map(PathBuf::from).ok_or_else(|| anyhow!("HOME is not set"))?
+        } else {
+            self.working_dir(session_id).join(commands::expand_tilde(path))
+        };
+        let dir = target
+            .canonicalize()
+            .map_err(|e| anyhow!("Cannot change to '{}': {}", path, e))?;
+        if !dir.is_dir() {
+            return Err(anyhow!("Not a directory: {}", dir.display()));
+        }
+        if let Some(index) = self.session_index_by_id(session_id) {
+            let dir_str = dir.to_string_lossy().to_string();
+            db::set_session_working_dir(&self.db_conn, session_id, Some(&dir_str))?;
+            self.sessions[index].working_dir = Some(dir_str);
+        }
+        Ok(dir)
+    }
+
+    /// Save the current run so it survives restarts and shows up in `:runs`.
+    pub fn persist_autonomous_run(&mut self) {
+        if let Some(run) = self.autonomous.as_mut()
+            && let Err(e) = db::save_agent_run(&self.db_conn, run)
+        {
+            self.set_error_message(format!("Failed to save autonomous run: {}", e));
+        }
+    }
+
+    pub fn open_workflows(&mut self) {
+        if self.workflow_list_state.selected().is_none() {
+            self.workflow_list_state.select(Some(0));
+        }
+        self.mode = AppMode::Workflows;
+    }
+
+    pub fn move_workflow_selection(&mut self, down: bool) {
+        let len = self.workflows.workflows().len();
+        if len == 0 {
+            return;
+        }
+        let i = match self.workflow_list_state.selected() {
+            Some(i) if down => (i + 1) % len,
+            Some(i) => (i + len - 1) % len,
+            None => 0,
+        };
+        self.workflow_list_state.select(Some(i));
+    }
+
+    pub fn selected_workflow(&self) -> Option<&workflow::Workflow> {
+        self.workflow_list_state.selected().and_then(|i| self.workflows.workflows().get(i))
+    }
+
+    /// Start the selected workflow, first asking for the variables it uses but does not
+    /// define. Returns whether it was started.
+    pub fn start_selected_workflow(&mut self) -> Result<bool> {
+        if let Some(run) = self.workflow_run.as_ref().filter(|run| !run.finished) {
+            return Err(anyhow!("Workflow '{}' is still running, x stops it", run.workflow.name));
+        }
+        let Some(mut workflow) = self.selected_workflow().cloned() else {
+            return Err(anyhow!("No workflow selected"));
+        };
+        let missing = self.resolve_variables(&mut workflow);
+        if !missing.is_empty() {
+            self.workflow_fill = Some((workflow.clone(), prompts::PromptFill::for_names(&workflow.name, missing)));
+            self.mode = AppMode::WorkflowVariables;
+            return Ok(false);
+        }
+        self.start_workflow(workflow, self.current_session_id(), Vec::new());
+        Ok(true)
+    }
+
+    /// Run `commands` as a one-off workflow of the current session, so each one is
+    /// approved as the command policy requires, and show its progress.
+    fn start_one_off_workflow(&mut self, name: &str, description: &str, commands: Vec<String>) -> Result<()> {
+        if let Some(run) = self.workflow_run.as_ref().filter(|run| !run.finished) {
+            return Err(anyhow!("Workflow '{}' is still running, x stops it", run.workflow.name));
+        }
+        let workflow = workflow::from_commands(name, description, commands);
+        self.start_workflow(workflow, self.current_session_id(), Vec::new());
+        self.mode = AppMode::Workflows;
+        Ok(())
+    }
+
+    /// Run the commands of the shell blocks in the selected message. Returns whether they were started.
+    pub fn run_selected_code_block(&mut self) -> Result<bool> {
+        let Some(index) = self.selected_message_index() else {
+            self.set_status_message("No message selected".to_string());
+            return Ok(false);
+        };
+        let commands = agent::shell_block_commands(self.current_messages()[index].answer());
+        if commands.is_empty() {
+            return Err(anyhow!("The selected message has no shell code block"));
+        }
+        self.start_one_off_workflow("code-block", &format!("Shell commands of message {}", index + 1), commands)?;
+        Ok(true)
+    }
+
+    /// Show the command suggested by :howto.
+    pub fn review_howto(&mut self, suggestion: howto::Suggestion) {
+        self.howto = Some(suggestion);
+        self.mode = AppMode::HowTo;
+    }
+
+    /// Run the command suggested by :howto through the workflow approval.
+    pub fn run_howto(&mut self) -> Result<()> {
+        let Some(suggestion) = self.howto.as_ref() else {
+            return Ok(());
+        };
+        let command = suggestion.command.trim().to_string();
+        self.start_one_off_workflow("howto", &suggestion.explanation.clone(), vec![command])?;
+        self.howto = None;
+        Ok(())
+    }
+
+    /// Show the message written by :gitmsg for the staged changes.
+    pub fn review_commit_message(&mut self, message: String) {
+        self.commit_message = Some(message);
+        self.commit_message_editing = false;
+        self.mode = AppMode::CommitMessage;
+    }
+
+    /// Commit the staged changes with the reviewed message, through the workflow approval.
+    pub fn commit_with_message(&mut self) -> Result<()> {
+        let message = self.commit_message.as_deref().map(str::trim).unwrap_or_default().to_string();
+        if message.is_empty() {
+            return Err(anyhow!("The commit message is empty"));
+        }
+        self.start_one_off_workflow("git-commit", "Commit the staged changes", vec![gitmsg::commit_command(&message)])?;
+        self.commit_message = None;
+        Ok(())
+    }
+
+    /// Fill in the variables that have a source, returning the names still without a value.
+    fn resolve_variables(&self, workflow: &mut workflow::Workflow) -> Vec<String> {
+        let mut missing = Vec::new();
+        for name in workflow::referenced_variables(workflow) {
+            if workflow.variables.contains_key(&name) {
+                continue;
+            }
+            match self.workflow_variable_source(&name) {
+                Some(value) => {
+                    workflow.variables.insert(name, value);
+                }
+                None => missing.push(name),
+            }
+        }
+        missing
+    }
+
+    /// Queue workflows started by a trigger in `session_id`, skipping those already in `chain`.
+    pub fn queue_workflows(&mut self, names: Vec<String>, session_id: i64, chain: Vec<String>) {
+        for name in names {
+            if !chain.contains(&name) && !self.workflow_queue.iter().any(|(queued, _, _)| *queued == name) {
+                self.workflow_queue.push_back((name, session_id, chain.clone()));
+            }
+        }
+    }
+
+    /// Start the next queued workflow if none is running; returns whether one was started.
+    pub fn start_queued_workflow(&mut self) -> bool {
+        if self.workflow_run.as_ref().is_some_and(|run| !run.finished) {
+            return false;
+        }
+        while let Some((name, session_id, chain)) = self.workflow_queue.pop_front() {
+            let Some(mut workflow) = self.workflows.workflows().iter().find(|w| w.name == name).cloned() else {
+                continue;
+            };
+            let missing = self.resolve_variables(&mut workflow);
+            if !missing.is_empty() {
+                self.set_status_message(format!(
+                    "Workflow '{}' was triggered but needs {}, start it with :wf {}",
+                    name,
+                    missing.join(", "),
+                    name
+                ));
+                continue;
+            }
+            let cause = chain.last().map(|last| format!("after '{}'", last)).unwrap_or_else(|| "by an agent command".to_string());
+            self.push_session_message(session_id, format!("Workflow '{}' triggered {}.", name, cause)).ok();
+            self.start_workflow(workflow, session_id, chain);
+            return true;
+        }
+        false
+    }
+
+    // `${env:NAME}` comes from the agent environment, `${response}` from the last reply.
+    // Both are quoted, model output or an environment variable must not inject commands
+    fn workflow_variable_source(&self, name: &str) -> Option<String> {
+        let value = if let Some(var) = name.strip_prefix("env:") {
+            self.command_env.get(var).cloned().or_else(|| std::env::var(var).ok())
+        } else if name == "response" {
+            self.current_messages()
+                .iter()
+                .rev()
+                .find(|message| message.role == models::Role::Assistant && !message.content.trim().is_empty())
+                .map(|message| workflow::response_value(&message.content))
+        } else {
+            None
+        };
+        value.map(|value| workflow::shell_quote(&value))
+    }
+
+    /// Accept the value typed for the current variable; returns whether the workflow
+    /// was started after the last one.
+    pub fn next_workflow_variable(&mut self) -> bool {
+        let Some((mut workflow, mut fill)) = self.workflow_fill.take() else {
+            return false;
+        };
+        fill.next();
+        if fill.current().is_some() {
+            self.workflow_fill = Some((workflow, fill));
+            return false;
+        }
+        workflow.variables.extend(fill.placeholders.into_iter().zip(fill.values));
+        self.mode = AppMode::Workflows;
+        self.start_workflow(workflow, self.current_session_id(), Vec::new());
+        true
+    }
+
+    /// Queue the workflow's steps as agent commands of the session.
+    fn start_workflow(&mut self, workflow: workflow::Workflow, session_id: i64, chain: Vec<String>) {
+        self.set_pending_commands(workflow::step_commands(&workflow));
+        // A condition needs the same approval as the command it guards
+        for (i, step) in workflow.steps.iter().enumerate() {
+            if let Some(condition) = &step.condition {
+                let condition = workflow::expand_variables(condition, &workflow.variables);
+                let risk = self.pending_commands[i].risk;
+                if !self.runs_without_asking(&condition, risk) {
+                    self.pending_commands[i].approved = false;
+                }
+            }
+        }
+        self.command_approval_index = self.pending_commands.iter().position(|c| !c.approved);
+        self.workflow_run = Some(workflow::WorkflowRun {
+            workflow,
+            session_id,
+            running: None,
+            attempt: 0,
+            output: Vec::new(),
+            skipped: Vec::new(),
+            chain,
+            finished: false,
+            audit: None,
+            cancel: None,
+        });
+    }
+
+    /// Show a workflow drafted by the model for approval.
+    pub fn review_workflow_draft(&mut self, workflow: workflow::Workflow) {
+        self.workflow_draft = Some(workflow);
+        self.draft_scroll = 0;
+        self.mode = AppMode::WorkflowDraft;
+    }
+
+    /// Save the approved draft to the workflow directory and select it in the list.
+    pub fn save_workflow_draft(&mut self) -> Result<()> {
+        let Some(workflow) = self.workflow_draft.take() else {
+            return Ok(());
+        };
+        let mut workflow = workflow;
+        let path = workflow::save(&mut workflow)?;
+        let name = workflow.name.clone();
+        self.workflows.add(workflow);
+        let index = self.workflows.workflows().iter().position(|workflow| workflow.name == name);
+        self.workflow_list_state.select(index);
+        self.mode = AppMode::Workflows;
+        self.set_status_message(format!("Saved workflow '{}' to {}", name, path.display()));
+        Ok(())
+    }
+
+    /// Open the editor on a copy of `workflow`; `original_name` is `None` for a new one.
+    pub fn open_workflow_editor(&mut self, workflow: workflow::Workflow, original_name: Option<String>) {
+        self.workflow_editor = Some(workflow::WorkflowEditor::new(workflow, original_name));
+        self.workflow_editor_state.select(Some(0));
+        self.mode = AppMode::WorkflowEditor;
+    }
+
+    pub fn edit_selected_workflow(&mut self) {
+        if let Some(workflow) = self.selected_workflow().cloned() {
+            let name = workflow.name.clone();
+            self.open_workflow_editor(workflow, Some(name));
+        }
+    }
+
+    pub fn new_workflow(&mut self, name: &str) {
+        let workflow = workflow::Workflow {
+            name: name.to_string(),
+            path: None,
+            description: String::new(),
+            variables: HashMap::new(),
+            steps: Vec::new(),
+            triggers: Vec::new(),
+        };
+        self.open_workflow_editor(workflow, None);
+    }
+
+    /// Validate the edited workflow and write it to its file.
+    pub fn save_workflow_editor(&mut self) -> Result<()> {
+        let Some(editor) = self.workflow_editor.as_mut() else {
+            return Ok(());
+        };
+        editor.commit().map_err(|e| anyhow!(e))?;
+        let mut workflow = editor.workflow.clone();
+        let original_name = editor.original_name.clone();
+        workflow.name = workflow.name.trim().to_string();
+        if workflow.name.is_empty() {
+            return Err(anyhow!("The workflow needs a name"));
+        }
+        workflow.validate().map_err(|e| anyhow!("Workflow {}", e))?;
+        // Built-in workflows may be replaced, other files not
+        if original_name.as_deref() != Some(workflow.name.as_str())
+            && self.workflows.workflows().iter().any(|w| w.name == workflow.name && w.path.is_some())
+        {
+            return Err(anyhow!("A workflow named '{}' already exists", workflow.name));
+        }
+        let path = workflow::save(&mut workflow)?;
+        // A renamed workflow no longer answers to its old name, unless that is a built-in one
+        if let Some(original) = original_name.filter(|original| *original != workflow.name)
+            && self.workflows.workflows().iter().any(|w| w.name == original && w.path.as_ref() == Some(&path))
+        {
+            self.workflows.remove(&original);
+        }
+        let name = workflow.name.clone();
+        self.workflows.add(workflow);
+        let index = self.workflows.workflows().iter().position(|workflow| workflow.name == name);
+        self.workflow_list_state.select(index);
+        self.workflow_editor = None;
+        self.mode = AppMode::Workflows;
+        self.set_status_message(format!("Saved workflow '{}' to {}", name, path.display()));
+        Ok(())
+    }
+
+    /// Pre-approve what the allowlist and approval policy cover, ask about the rest.
+    pub fn set_pending_commands(&mut self, mut commands: Vec<models::AgentCommand>) {
+        for command in &mut commands {
+            command.approved = self.runs_without_asking(&command.command, command.risk);
+        }
+        self.command_approval_index = commands.iter().position(|c| !c.approved);
+        self.pending_commands = commands;
+    }
+
+    fn runs_without_asking(&self, command: &str, risk: agent::RiskLevel) -> bool {
+        match self.check_command_policy(command) {
+            policy::Verdict::Allowed => true,
+            policy::Verdict::Unlisted => self.options.agent_approval.auto_approves(risk),
+            policy::Verdict::Denied(_) => false,
+        }
+    }
+
+    /// Progress of one step of the workflow run, shown next to it in the popup.
+    pub fn workflow_step_status(&self, index: usize) -> workflow::StepStatus {
+        let Some(run) = &self.workflow_run else {
+            return workflow::StepStatus::Pending;
+        };
+        match self.pending_commands.get(index) {
+            _ if run.running == Some(index) => workflow::StepStatus::Running,
+            _ if run.skipped.contains(&index) => workflow::StepStatus::Skipped,
+            Some(command) if command.executed && command.error.is_some() => workflow::StepStatus::Failed,
+            Some(command) if command.executed => workflow::StepStatus::Succeeded,
+            _ if run.finished => workflow::StepStatus::Skipped,
+            _ if self.command_approval_index == Some(index) => workflow::StepStatus::AwaitingApproval,
+            _ => workflow::StepStatus::Pending,
+        }
+    }
+
+    /// Allow the step waiting for approval; returns how it was approved, for the audit log.
+    pub fn approve_workflow_step(&mut self) -> Option<agent::Approval> {
+        self.workflow_run.as_ref().filter(|run| !run.finished)?;
+        let index = self.command_approval_index.take()?;
+        let command = self.pending_commands.get_mut(index)?;
+        command.approved = true;
+        let mut commands = vec![command.command.clone()];
+        if let Some(run) = &self.workflow_run
+            && let Some(condition) = &run.workflow.steps[index].condition
+        {
+            commands.push(workflow::expand_variables(condition, &run.workflow.variables));
+        }
+        let denied = commands
+            .iter()
+            .any(|command| matches!(self.check_command_policy(command), policy::Verdict::Denied(_)));
+        Some(if denied { agent::Approval::Override } else { agent::Approval::User })
+    }
+
+    /// End the workflow run, killing the step that is executing.
+    pub fn stop_workflow(&mut self, reason: &str) {
+        let Some(run) = self.workflow_run.as_mut().filter(|run| !run.finished) else {
+            self.set_status_message("No workflow is running".to_string());
+            return;
+        };
+        run.finished = true;
+        if let Some(cancel) = run.cancel.take() {
+            cancel.send(()).ok();
+        }
+        // Stopping by hand also cancels the rest of a chain
+        self.workflow_queue.clear();
+        let session_id = run.session_id;
+        let message = format!("Workflow '{}' {}.", run.workflow.name, reason);
+        self.command_approval_index = None;
+        self.push_session_message(session_id, message.clone()).ok();
+        self.set_status_message(message);
+    }
+
+    pub fn open_agent_runs(&mut self) -> Result<()> {
+        self.agent_runs = db::load_agent_runs(&self.db_conn)?;
+        if self.agent_runs.is_empty() {
+            return Err(anyhow!("No autonomous runs yet, start one with :auto <goal>"));
+        }
+        self.runs_list_state.select(Some(0));
+        self.mode = AppMode::AgentRuns;
+        Ok(())
+    }
+
+    pub fn move_runs_selection(&mut self, down: bool) {
+        let len = self.agent_runs.len();
+        if len == 0 {
+            return;
+        }
+        let i = match self.runs_list_state.selected() {
+            Some(i) if down => (i + 1) % len,
+            Some(i) => (i + len - 1) % len,
+            None => 0,
+        };
+        self.runs_list_state.select(Some(i));
+    }
+
+    /// Show the goal, plan and every step of the selected run.
+    pub fn show_selected_run(&mut self) {
+        let Some(run) = self.runs_list_state.selected().and_then(|i| self.agent_runs.get(i)) else {
+            return;
+        };
+        let mut lines = vec![format!("Goal: {}", run.goal), format!("State: {}", run.state.label())];
+        if !run.plan.is_empty() {
+            lines.push(String::new());
+            lines.push("Plan:".to_string());
+            lines.extend(run.plan.iter().enumerate().map(|(i, step)| format!("  {}. {}", i + 1, step)));
+        }
+        for (i, step) in run.steps.iter().enumerate() {
+            lines.push(String::new());
+            lines.push(match step.exit_code {
+                Some(code) => format!("Step {}: {} [exit {}]", i + 1, step.thought, code),
+                None => format!("Step {}: {}", i + 1, step.thought),
+            });
+            if let Some(command) = &step.command {
+                lines.push(format!("  $ {}", command));
+            }
+            if let Some(tool) = &step.tool {
+                lines.push(format!("  Tool: {}", tool.summary()));
+            }
+            match &step.output {
+                Some(Ok(output)) => lines.extend(output.lines().map(|l| format!("  {}", l))),
+                Some(Err(error)) => {
+                    lines.push("  Command failed:".to_string());
+                    lines.extend(error.lines().map(|l| format!("  {}", l)));
+                }
+                None => {}
+            }
+            if let Some(analysis) = &step.analysis {
+                lines.push(format!("  Analysis: {}", analysis));
+            }
+        }
+        if let Some(command) = &run.pending_command {
+            lines.push(String::new());
+            lines.push(format!("Pending command: {}", command));
+        }
+        if let Some(tool) = &run.pending_tool {
+            lines.push(String::new());
+            lines.push(format!("Pending tool: {}", tool.summary()));
+        }
+        let title = format!("Autonomous run #{}", run.id);
+        self.show_info(&title, lines);
+    }
+
+    pub fn open_audit_log(&mut self) -> Result<()> {
+        self.audit_entries = db::load_audit_log(&self.db_conn, AUDIT_VIEW_LIMIT)?;
+        if self.audit_entries.is_empty() {
+            return Err(anyhow!("No agent commands have been executed yet"));
+        }
+        self.audit_list_state.select(Some(0));
+        self.mode = AppMode::Audit;
+        Ok(())
+    }
+
+    pub fn move_audit_selection(&mut self, down: bool) {
+        let len = self.audit_entries.len();
+        if len == 0 {
+            return;
+        }
+        let i = match self.audit_list_state.selected() {
+            Some(i) if down => (i + 1) % len,
+            Some(i) => (i + len - 1) % len,
+            None => 0,
+        };
+        self.audit_list_state.select(Some(i));
+    }
+
+    pub fn show_selected_audit_entry(&mut self) {
+        let Some(entry) = self.audit_list_state.selected().and_then(|i| self.audit_entries.get(i)) else {
+            return;
+        };
+        let mut lines = vec![
+            format!("Command:   {}", entry.command),
+            format!("Time:      {}", entry.executed_at.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S")),
+            format!("Directory: {}", entry.cwd),
+            format!("Risk:      {}", entry.risk),
+            format!("Approval:  {}{}", entry.approval, if entry.dry_run { " (dry run)" } else { "" }),
+            format!(
+                "Exit code: {}",
+                entry.exit_code.map(|code| code.to_string()).unwrap_or_else(|| "none".to_string())
+            ),
+            String::new(),
+        ];
+        lines.extend(entry.output.lines().map(String::from));
+        let title = format!("Audit entry #{}", entry.id);
+        self.show_info(&title, lines);
+    }
+
+    /// Continue an unfinished run from the history, paused so the user decides when it proceeds.
+    pub fn resume_selected_run(&mut self) -> Result<()> {
+        if self.autonomous.as_ref().is_some_and(|run| !run.state.is_finished()) {
+            return Err(anyhow!("An autonomous run is already in progress"));
+        }
+        let Some(run) = self.runs_list_state.selected().and_then(|i| self.agent_runs.get(i)) else {
+            return Ok(());
+        };
+        if run.state.is_finished() {
+            return Err(anyhow!("Run #{} is {}", run.id, run.state.label()));
+        }
+        let Some(session_index) = self.session_index_by_id(run.session_id) else {
+            return Err(anyhow!("The session of run #{} no longer exists", run.id));
+        };
+        let mut run = run.clone();
+        run.state = agent::AgentState::Paused;
+        run.max_steps = self.options.max_steps;
+        // The violation is not persisted, so check the pending command again
+        if let Some(command) = &run.pending_command
+            && let policy::Verdict::Denied(reason) = self.check_command_policy(command)
+        {
+            run.policy_violation = Some(reason);
+        }
+        if let Some(call) = &run.pending_tool
+            && let policy::Verdict::Denied(reason) = self.check_tool_policy(call)
+        {
+            run.policy_violation = Some(reason);
+        }
+        self.autonomous = Some(run);
+        if let Some(call) = self.autonomous.as_ref().and_then(|run| run.pending_tool.clone()) {
+            self.prepare_tool_preview(&call).ok();
+        }
+        self.reset_agent_panel();
+        self.session_list_state.select(Some(session_index));
+        self.switch_to_selected_session()?;
+        self.mode = AppMode::Autonomous;
+        self.set_status_message("Run resumed paused, press n to continue".to_string());
+        Ok(())
+    }
+
+    /// Show the model's proposed plan for review.
+    pub fn review_plan(&mut self, steps: Vec<String>) {
+        let Some(run) = self.autonomous.as_mut() else {
+            return;
+        };
+        run.plan = steps;
+        run.state = agent::AgentState::AwaitingPlanApproval;
+        self.plan_list_state.select(Some(0));
+        self.plan_edit = None;
+        if self.mode == AppMode::Autonomous {
+            self.mode = AppMode::PlanReview;
+        } else {
+            self.set_status_message("Autonomous plan ready, :auto to review it".to_string());
+        }
+    }
+
+    pub fn approve_plan(&mut self) {
+        self.plan_edit = None;
+        self.persist_autonomous_run();
+        self.mode = AppMode::Autonomous;
+    }
+
+    pub fn move_plan_selection(&mut self, forward: bool) {
+        let len = self.autonomous.as_ref().map(|run| run.plan.len()).unwrap_or(0);
+        if len == 0 {
+            return;
+        }
+        let i = match self.plan_list_state.selected() {
+            Some(i) if forward => (i + 1) % len,
+            Some(i) => (i + len - 1) % len,
+            None => 0,
+        };
+        self.plan_list_state.select(Some(i));
+    }
+
+    /// Start editing the selected step, or a new step inserted below it.
+    pub fn start_plan_step_edit(&mut self, insert: bool) {
+        let Some(run) = self.autonomous.as_mut() else {
+            return;
+        };
+        let selected = self.plan_list_state.selected();
+        if insert || run.plan.is_empty() {
+            let index = selected.map(|i| i + 1).unwrap_or(0).min(run.plan.len());
+            run.plan.insert(index, String::new());
+            self.plan_list_state.select(Some(index));
+            self.plan_edit = Some(String::new());
+        } else if let Some(step) = selected.and_then(|i| run.plan.get(i)) {
+            self.plan_edit = Some(step.clone());
+        }
+    }
+
+    pub fn finish_plan_step_edit(&mut self, save: bool) {
+        let Some(buffer) = self.plan_edit.take() else {
+            return;
+        };
+        let Some(run) = self.autonomous.as_mut() else {
+            return;
+        };
+        if let Some(index) = self.plan_list_state.selected()
+            && index < run.plan.len()
+        {
+            if save && !buffer.trim().is_empty() {
+                run.plan[index] = buffer.trim().to_string();
+            } else if run.plan[index].is_empty() {
+                // Cancelled or emptied a freshly inserted step
+                run.plan.remove(index);
+                self.plan_list_state.select(if run.plan.is_empty() { None } else { Some(index.saturating_sub(1)) });
+            }
+        }
+    }
+
+    pub fn delete_plan_step(&mut self) {
+        let Some(run) = self.autonomous.as_mut() else {
+            return;
+        };
+        if let Some(index) = self.plan_list_state.selected()
+            && index < run.plan.len()
+        {
+            run.plan.remove(index);
+            let len = run.plan.len();
+            self.plan_list_state.select(if len == 0 { None } else { Some(index.min(len - 1)) });
+        }
+    }
+
+    pub fn stop_autonomous_run(&mut self) {
+        if let Some(run) = self.autonomous.as_mut()
+            && !run.state.is_finished()
+        {
+            run.state = agent::AgentState::Stopped;
+            let session_id = run.session_id;
+            self.cancel_agent_command();
+            self.push_session_message(session_id, "Autonomous run stopped.".to_string()).ok();
+            self.persist_autonomous_run();
+        }
+    }
+
+    /// Append an assistant message to a session and persist it right away.
+    pub fn push_session_message(&mut self, session_id: i64, content: String) -> Result<()> {
+        let Some(index) = self.session_index_by_id(session_id) else {
+            return Ok(());
+        };
+        let mut message = models::Message::new(models::Role::Assistant, redact::apply(&content, &self.redaction));
+        message.id = Some(db::save_message(&self.db_conn, session_id, &message)?);
+        let session = &mut self.sessions[index];
+        session.messages.push(message);
+        session.last_activity = chrono::Utc::now();
+        if index == self.current_session_index {
+            self.auto_scroll = self.options.autoscroll;
+            self.trigger_auto_scroll();
+        }
+        Ok(())
+    }
+
+    /// Memories related to the session's first prompt, so each session keeps the same
+    /// recalled facts throughout, when `:set memory` is on.
+    fn recalled_memories(&self, session: &models::ChatSession) -> Option<String> {
+        if !self.options.memory {
+            return None;
+        }
+        let first_prompt = session.messages.iter().find(|m| m.role == models::Role::User)?;
+        let recalled = memory::relevant(&self.memories, &first_prompt.content, memory::MAX_INJECTED);
+        if recalled.is_empty() {
+            return None;
+        }
+        let facts: Vec<String> = recalled.iter().map(|memory| format!("- {}", memory.fact)).collect();
+        Some(format!("What you remember about the user from earlier conversations:\n{}", facts.join("\n")))
+    }
+
+    /// Store facts extracted from a finished exchange, skipping ones already known.
+    pub fn remember(&mut self, facts: Vec<String>, session_id: Option<i64>) -> Result<usize> {
+        let mut added = 0;
+        for fact in facts.iter().map(|fact| fact.trim()).filter(|fact| !fact.is_empty()) {
+            if let Some(id) = db::save_memory(&self.db_conn, fact, session_id)? {
+                self.memories.push(memory::Memory { id, fact: fact.to_string() });
+                added += 1;
+            }
+        }
+        Ok(added)
+    }
+
+    /// Project instructions file (unless `:set noinstructions`) and `:context` snapshot,
+    /// added to the system prompt of the agent.
+    pub fn agent_system_context(&self) -> Option<String> {
+        let instructions = self
+            .active_instructions()
+            .map(|(path, text)| format!("Project instructions from {}:\n{}", path.display(), text));
+        let parts: Vec<String> = instructions.into_iter().chain(self.agent_context.clone()).collect();
+        (!parts.is_empty()).then(|| parts.join("\n\n"))
+    }
+
+    pub fn active_instructions(&self) -> Option<&(PathBuf, String)> {
+        self.project_instructions.as_ref().filter(|_| self.options.instructions)
+    }
+
+    /// Messages to send to the model: the session's system prompt (or else the project's),
+    /// recalled memories, the project instructions and snapshot in agent mode, then the conversation.
+    pub fn request_messages(&self, session_index: usize) -> Vec<models::Message> {
+        let session = &self.sessions[session_index];
+        let mut messages = Vec::with_capacity(session.messages.len() + 1);
+        let project_prompt = self.project.as_ref().and_then(|project| project.system_prompt.as_ref());
+        if let Some(prompt) = session.system_prompt.as_ref().or(project_prompt) {
+            messages.push(models::Message::new(models::Role::System, prompt.clone()));
+        }
+        if let Some(recalled) = self.recalled_memories(session) {
+            messages.push(models::Message::new(models::Role::System, recalled));
+        }
+        if self.agent_mode
+            && let Some(context) = self.agent_system_context()
+        {
+            messages.push(models::Message::new(models::Role::System, context));
+        }
+        messages.extend(session.messages.iter().map(|message| {
+            let mut message = message.clone();
+            if !self.options.think_context {
+                message.content = message.answer().to_string();
+            }
+            message
+        }));
+        messages
+    }
+
+    /// vim-style `:set` arguments: `name`, `noname`, `name!`, `name?`, `name=value`.
+    pub fn set_command(&mut self, args: &str) -> Result<()> {
+        let args = args.trim();
+        if args.is_empty() || args == "all" {
+            let lines = options::OPTIONS
+                .iter()
+                .map(|info| {
+                    format!(
+                        "{:<14} = {:<20} {}",
+                        info.name,
+                        self.get_option(info.name).unwrap_or_default(),
+                        info.description
+                    )
+                })
+                .collect();
+            self.show_info("Options (:set name=value, :set noname, :set name!)", lines);
+            return Ok(());
+        }
+
+        let mut messages = Vec::new();
+        for arg in args.split_whitespace() {
+            if let Some((name, value)) = arg.split_once('=') {
+                let value = value.trim_matches('"');
+                options::find_option(name).ok_or_else(|| anyhow!("Unknown option: {}", name))?;
+                self.set_option(name, value)?;
+                messages.push(format!("{}={}", name, value));
+            } else if let Some(name) = arg.strip_suffix('?') {
+                let value = self.get_option(name).ok_or_else(|| anyhow!("Unknown option: {}", name))?;
+                messages.push(format!("{}={}", name, value));
+            } else if let Some(name) = arg.strip_suffix('!') {
+                let info = options::find_option(name).ok_or_else(|| anyhow!("Unknown option: {}", name))?;
+                if info.kind != options::OptionKind::Bool {
+                    return Err(anyhow!("Option {} is not a toggle", name));
+                }
+                let current = options::parse_bool(&self.get_option(name).unwrap_or_default())?;
+                self.set_option(name, &(!current).to_string())?;
+                messages.push(format!("{}={}", name, !current));
+            } else if let Some(info) = options::find_option(arg) {
+                if info.kind == options::OptionKind::Bool {
+                    self.set_option(arg, "true")?;
+                    messages.push(format!("{}=true", arg));
+                } else {
+                    messages.push(format!("{}={}", arg, self.get_option(arg).unwrap_or_default()));
+                }
+            } else if let Some(name) = arg.strip_prefix("no")
+                && options::find_option(name).is_some_and(|info| info.kind == options::OptionKind::Bool)
+            {
+                self.set_option(name, "false")?;
+                messages.push(format!("{}=false", name));
+            } else {
+                return Err(anyhow!("Unknown option: {}", arg));
+            }
+        }
+        self.set_status_message(messages.join("  "));
+        Ok(())
+    }
+
+    pub fn execute_vim_command(&mut self, command: &str) -> Result<()> {
+        self.execute_command_line(command, 0)
+    }
+
+    fn execute_command_line(&mut self, command: &str, alias_depth: usize) -> Result<()> {
+        match command {
+            "q" => {
+                // This will be handled in the main loop to exit
+                return Ok(());
+            }
+            "w" => {
+                // Save current session
+                if let Some(session) = self.sessions.get_mut(self.current_session_index) {
+                    db::save_session(&self.db_conn, session)?;
+                }
+            }
+            cmd if cmd.starts_with("w ") => {
+                let target = cmd["w ".len()..].trim();
+                match target.strip_prefix(">>") {
+                    Some(path) => self.write_last_answer(path.trim(), true)?,
+                    None => self.write_last_answer(target, false)?,
+                }
+            }
+            cmd if cmd.starts_with('!') || cmd.starts_with(".!") => {
+                let into_input = cmd.starts_with('.');
+                let command = cmd.strip_prefix('.').unwrap_or(cmd)[1..].trim();
+                if command.is_empty() {
+                    return Err(anyhow!("Usage: :!<command> or :.!<command>"));
+                }
+                self.pending_pipe = Some(PipeRequest { command: command.to_string(), input: self.last_answer()?, into_input });
+                self.set_status_message(format!("Running {}", command));
+            }
+            cmd if cmd.starts_with("append ") => {
+                self.write_last_answer(cmd["append ".len()..].trim(), true)?;
+            }
+            "wq" => {
+                // Save and quit
+                if let Some(session) = self.sessions.get_mut(self.current_session_index) {
+                    db::save_session(&self.db_conn, session)?;
+                }
+                // Quit signal will be handled in main loop
+            }
+            "n" | "new" => {
+                self.new_session()?;
+            }
+            cmd if cmd.starts_with("n ") || cmd.starts_with("new ") => {
+                let (_, name) = cmd.split_once(' ').unwrap_or_default();
+                self.new_session_from_template(name.trim())?;
+            }
+            "system" => {
+                let prompt = self.sessions[self.current_session_index]
+                    .system_prompt
+                    .clone()
+                    .unwrap_or_else(|| "(no system prompt, set one with :system <prompt>)".to_string());
+                self.show_info("System prompt", prompt.lines().map(String::from).collect());
+            }
+            "system!" => {
+                self.set_system_prompt(None)?;
+                self.set_status_message("System prompt cleared".to_string());
+            }
+            cmd if cmd.starts_with("system ") => {
+                self.set_system_prompt(Some(cmd["system ".len()..].trim().to_string()))?;
+                self.set_status_message("System prompt set".to_string());
+            }
+            cmd if cmd.starts_with("r ") || cmd.starts_with("read ") => {
+                let (_, path) = cmd.split_once(' ').unwrap_or_default();
+                let cwd = self.working_dir(self.current_session_id());
+                let text = attach::read_for_input(path.trim(), &cwd).map_err(|e| anyhow!(e))?;
+                if !self.input.is_empty() && !self.input.ends_with('\n') {
+                    self.input.push('\n');
+                }
+                self.input.push_str(&text);
+                self.input.push('\n');
+                self.mode = AppMode::Insert;
+            }
+            "attach" => {
+                let lines = if self.attachments.is_empty() {
+                    vec!["(nothing attached, stage files with :attach <path or glob>)".to_string()]
+                } else {
+                    self.attachments
+                        .iter()
+                        .map(|a| format!("{:<40} ~{} tokens", a.name, attach::estimated_tokens(a.content.len())))
+                        .collect()
+                };
+                self.show_info("Attachments for the next message", lines);
+            }
+            "attach!" => {
+                self.attachments.clear();
+                self.set_status_message("Attachments removed".to_string());
+            }
+            cmd if cmd.starts_with("attach ") => {
+                self.attach_files(&cmd["attach ".len()..])?;
+            }
+            "ingest" => {
+                let session_id = self.current_session_id();
+                let chunks = self.knowledge_chunks(session_id)?;
+                let mut sources: Vec<(String, usize)> = Vec::new();
+                for chunk in chunks.iter() {
+                    match sources.iter_mut().find(|(source, _)| *source == chunk.source) {
+                        Some((_, count)) => *count += 1,
+                        None => sources.push((chunk.source.clone(), 1)),
+                    }
+                }
+                let lines = if sources.is_empty() {
+                    vec!["(not a knowledge session, add documents with :ingest <path or glob>)".to_string()]
+                } else {
+                    sources.iter().map(|(source, count)| format!("{:<40} {} chunks", source, count)).collect()
+                };
+                self.show_info("Ingested documents", lines);
+            }
+            "ingest!" => {
+                let session_id = self.current_session_id();
+                db::delete_knowledge_chunks(&self.db_conn, session_id)?;
+                self.knowledge_cache.remove(&session_id);
+                self.sessions[self.current_session_index].knowledge = false;
+                self.set_status_message("Ingested documents removed from this session".to_string());
+            }
+            cmd if cmd.starts_with("ingest ") => {
+                self.ingest_files(&cmd["ingest ".len()..])?;
+            }
+            "memory" => {
+                let lines = if self.memories.is_empty() {
+                    vec!["(nothing remembered yet, :set memory to start)".to_string()]
+                } else {
+                    self.memories
+                        .iter()
+                        .enumerate()
+                        .map(|(i, memory)| format!("{:>3}. {}", i + 1, memory.fact))
+                        .collect()
+                };
+                self.show_info("Memories (:forget <N> removes one)", lines);
+            }
+            "memory!" => {
+                db::clear_memories(&self.db_conn)?;
+                self.memories.clear();
+                self.set_status_message("All memories forgotten".to_string());
+            }
+            cmd if cmd.starts_with("memory ") => {
+                let fact = cmd["memory ".len()..].trim().to_string();
+                if self.remember(vec![fact], None)? == 0 {
+                    self.set_status_message("Already remembered".to_string());
+                } else {
+                    self.set_status_message("Remembered".to_string());
+                }
+            }
+            cmd if cmd.starts_with("forget ") => {
+                let number: usize = cmd["forget ".len()..]
+                    .trim()
+                    .parse()
+                    .map_err(|_| anyhow!("Usage: :forget <N>, numbered as in :memory"))?;
+                let index = number
+                    .checked_sub(1)
+                    .filter(|&i| i < self.memories.len())
+                    .ok_or_else(|| anyhow!("No memory {}", number))?;
+                let removed = self.memories.remove(index);
+                db::delete_memory(&self.db_conn, removed.id)?;
+                self.set_status_message(format!("Forgot: {}", removed.fact));
+            }
+            "context" => {
+                let dir = self.working_dir(self.current_session_id());
+                let snapshot = context::snapshot(&dir, context::CONTEXT_BUDGET);
+                self.set_status_message(format!(
+                    "Project context of {} loaded ({} characters)",
+                    dir.display(),
+                    snapshot.len()
+                ));
+                self.agent_context = Some(snapshot);
+            }
+            "context?" => {
+                let snapshot = self
+                    .agent_system_context()
+                    .unwrap_or_else(|| "(no project context, build one with :context)".to_string());
+                self.show_info("Project context", snapshot.lines().map(String::from).collect());
+            }
+            "context!" => {
+                self.agent_context = None;
+                self.set_status_message("Project context cleared".to_string());
+            }
+            "prompt" | "prompts" | "prompt list" => {
+                self.list_prompts()?;
+            }
+            cmd if cmd.starts_with("prompt save ") => {
+                self.save_prompt(&cmd["prompt save ".len()..])?;
+            }
+            cmd if cmd.starts_with("prompt use ") => {
+                self.use_prompt(cmd["prompt use ".len()..].trim())?;
+            }
+            cmd if cmd.starts_with("prompt delete ") => {
+                let name = cmd["prompt delete ".len()..].trim();
+                if db::delete_prompt(&self.db_conn, name)? {
+                    self.set_status_message(format!("Deleted prompt '{}'", name));
+                } else if self.config.prompts.contains_key(name) {
+                    self.set_status_message(format!("Prompt '{}' is defined in the config file", name));
+                } else {
+                    return Err(anyhow!("No prompt named '{}'", name));
+                }
+            }
+            "template" | "templates" => {
+                self.list_templates()?;
+            }
+            cmd if cmd.starts_with("template save ") => {
+                let name = cmd["template save ".len()..].trim();
+                self.save_template(name)?;
+                self.set_status_message(format!("Saved template '{}'", name));
+            }
+            cmd if cmd.starts_with("template delete ") => {
+                let name = cmd["template delete ".len()..].trim();
+                if db::delete_template(&self.db_conn, name)? {
+                    self.set_status_message(format!("Deleted template '{}'", name));
+                } else if self.config.templates.contains_key(name) {
+                    self.set_status_message(format!("Template '{}' is defined in the config file", name));
+                } else {
+                    self.set_status_message(format!("No template named '{}'", name));
+                }
+            }
+            "c" => {
+                self.clear_current_session()?;
+            }
+            "m" | "model" => {
+                self.mode = AppMode::ModelSelection;
+                self.model_filter.clear();
+                self.is_fetching_models = true;
+                // The models will be fetched in the main loop
+            }
+            cmd if cmd.starts_with("model ") => {
+                let model_name = cmd["model ".len()..].trim();
+                if !model_name.is_empty() {
+                    self.select_model(model_name.to_string())?;
+                    self.set_status_message(format!("Model set to {}", self.current_model));
+                }
+            }
+            "s" | "session" => {
+                self.mode = AppMode::SessionSelection;
+                // Ensure the session list state is properly selected
+                self.session_list_state.select(Some(self.current_session_index));
+            }
+            cmd if cmd.starts_with("session ") => {
+                // Accept either a session name or its 1-based number
+                let target = cmd["session ".len()..].trim();
+                let index = self
+                    .sessions
+                    .iter()
+                    .position(|s| s.name == target)
+                    .or_else(|| target.parse::<usize>().ok().and_then(|n| n.checked_sub(1)));
+                match index {
+                    Some(index) if index < self.sessions.len() => {
+                        self.session_list_state.select(Some(index));
+                        self.switch_to_selected_session()?;
+                    }
+                    _ => self.set_status_message(format!("No session named '{}'", target)),
+                }
+            }
+            "a" => {
+                self.mode = AppMode::Agent;
+                self.agent_mode = true;
+            }
+            "auto" => {
+                // Reopen the view of a running goal
+                let state = self.autonomous.as_ref().map(|run| run.state.clone());
+                if state == Some(agent::AgentState::AwaitingPlanApproval) {
+                    self.mode = AppMode::PlanReview;
+                } else if state.is_some_and(|state| !state.is_finished()) {
+                    self.mode = AppMode::Autonomous;
+                } else {
+                    self.set_status_message("Usage: :auto <goal>".to_string());
+                }
+            }
+            cmd if cmd.starts_with("auto ") => {
+                self.start_autonomous_run(cmd["auto ".len()..].trim().to_string())?;
+            }
+            "runs" => {
+                self.open_agent_runs()?;
+            }
+            "wf" => {
+                self.open_workflows();
+            }
+            cmd if cmd.starts_with("howto ") => {
+                let question = cmd["howto ".len()..].trim();
+                if question.is_empty() {
+                    return Err(anyhow!("Usage: :howto <question>"));
+                }
+                self.pending_howto = Some(question.to_string());
+                self.set_status_message("Working out the command...".to_string());
+            }
+            "cache" => {
+                let count = db::count_cached_responses(&self.db_conn)?;
+                let state = if self.options.cache { "on" } else { "off (:set cache)" };
+                self.set_status_message(format!("{} cached replies, caching is {}", count, state));
+            }
+            "cache clear" => {
+                let count = db::clear_cached_responses(&self.db_conn)?;
+                self.set_status_message(format!("Removed {} cached replies", count));
+            }
+            "sync" => self.sync_sessions(None)?,
+            cmd if cmd.starts_with("sync ") => self.sync_sessions(Some(cmd["sync ".len()..].trim()))?,
+            "gitmsg" => {
+                let diff = gitmsg::staged_diff(&self.working_dir(self.current_session_id())).map_err(|e| anyhow!(e))?;
+                self.pending_commit_diff = Some(diff);
+                self.set_status_message("Writing commit message...".to_string());
+            }
+            cmd if cmd.starts_with("wf generate ") => {
+                let description = cmd["wf generate ".len()..].trim();
+                if description.is_empty() {
+                    return Err(anyhow!("Usage: :wf generate <description>"));
+                }
+                self.pending_workflow_request = Some(description.to_string());
+                self.set_status_message("Drafting workflow...".to_string());
+            }
+            cmd if cmd.starts_with("wf new ") => {
+                self.new_workflow(cmd["wf new ".len()..].trim());
+            }
+            cmd if cmd.starts_with("wf edit ") => {
+                let name = cmd["wf edit ".len()..].trim();
+                let Some(workflow) = self.workflows.workflows().iter().find(|workflow| workflow.name == name).cloned() else {
+                    return Err(anyhow!("No workflow named '{}'", name));
+                };
+                self.open_workflow_editor(workflow, Some(name.to_string()));
+            }
+            cmd if cmd.starts_with("wf ") => {
+                let name = cmd["wf ".len()..].trim();
+                let Some(index) = self.workflows.workflows().iter().position(|workflow| workflow.name == name) else {
+                    return Err(anyhow!("No workflow named '{}'", name));
+                };
+                self.workflow_list_state.select(Some(index));
+                self.open_workflows();
+            }
+            "compare" => {
+                if self.comparison.is_none() {
+                    return Err(anyhow!("Usage: :compare <model-a> <model-b>, or :compare off"));
+                }
+                self.compare_scroll = 0;
+                self.mode = AppMode::Compare;
+            }
+            "compare off" => {
+                self.compare_models = None;
+                self.set_status_message("A/B mode off".to_string());
+            }
+            cmd if cmd.starts_with("compare ") => {
+                let names: Vec<&str> = cmd["compare ".len()..].split_whitespace().collect();
+                let [first, second] = names[..] else {
+                    return Err(anyhow!("Usage: :compare <model-a> <model-b>"));
+                };
+                let known = self.model_names();
+                if let Some(unknown) = [first, second].into_iter().find(|name| !known.is_empty() && !known.iter().any(|known| known == name)) {
+                    return Err(anyhow!("Unknown model '{}'", unknown));
+                }
+                self.compare_models = Some([first.to_string(), second.to_string()]);
+                self.set_status_message(format!("A/B mode: prompts go to {} and {} (:compare off to stop)", first, second));
+            }
+            cmd if cmd == "vsplit" || cmd == "vs" || cmd.starts_with("vsplit ") || cmd.starts_with("vs ") => {
+                let target = cmd.split_once(' ').map_or("", |(_, target)| target.trim());
+                self.open_split(SplitDirection::Vertical, target)?;
+            }
+            cmd if cmd == "split" || cmd == "sp" || cmd.starts_with("split ") || cmd.starts_with("sp ") => {
+                let target = cmd.split_once(' ').map_or("", |(_, target)| target.trim());
+                self.open_split(SplitDirection::Horizontal, target)?;
+            }
+            "only" | "on" => {
+                self.split = None;
+            }
+            "bench" => {
+                if self.bench.is_none() {
+                    return Err(anyhow!("Usage: :bench <model> [model...]"));
+                }
+                self.bench_scroll = 0;
+                self.mode = AppMode::Bench;
+            }
+            cmd if cmd.starts_with("bench ") => {
+                if self.bench.as_ref().is_some_and(|bench| bench.is_running()) {
+                    return Err(anyhow!("A benchmark is already running, x in :bench stops it"));
+                }
+                let names: Vec<String> = cmd["bench ".len()..].split_whitespace().map(str::to_string).collect();
+                let known = self.model_names();
+                if let Some(unknown) = names.iter().find(|name| !known.is_empty() && !known.contains(name)) {
+                    return Err(anyhow!("Unknown model '{}'", unknown));
+                }
+                let prompts = if self.config.bench_prompts.is_empty() {
+                    bench::DEFAULT_PROMPTS.iter().map(|prompt| prompt.to_string()).collect()
+                } else {
+                    self.config.bench_prompts.clone()
+                };
+                self.pending_bench = Some(bench::Bench::new(names, prompts));
+                self.bench_scroll = 0;
+                self.mode = AppMode::Bench;
+            }
+            "audit" => {
+                self.open_audit_log()?;
+            }
+            "filter" | "nofilter" => self.set_chat_filter(None)?,
+            cmd if cmd.starts_with("filter ") => {
+                let filter = ChatFilter::parse(cmd["filter ".len()..].trim())?;
+                self.set_chat_filter(Some(filter))?;
+            }
+            "marks" => {
+                let lines = self.mark_lines();
+                self.show_info("Marks", lines);
+            }
+            "registers" | "reg" => {
+                let lines = self.register_lines();
+                self.show_info("Registers", lines);
+            }
+            "messages" | "mes" => {
+                self.toasts.dismiss();
+                let lines = self.toasts.history_lines();
+                self.show_info("Messages", lines);
+            }
+            "env" => {
+                let mut lines: Vec<String> = self
+                    .command_env
+                    .iter()
+                    .map(|(name, value)| format!("{}={}", name, agent::masked_value(name, value)))
+                    .collect();
+                lines.sort();
+                if lines.is_empty() {
+                    lines.push("No variables set, add one with :env KEY=VALUE".to_string());
+                }
+                self.show_info("Agent environment (:env KEY=VALUE, :env KEY= to remove)", lines);
+            }
+            cmd if cmd.starts_with("env ") => {
+                let Some((name, value)) = cmd["env ".len()..].trim().split_once('=') else {
+                    return Err(anyhow!("Usage: :env KEY=VALUE"));
+                };
+                let name = name.trim();
+                if name.is_empty() {
+                    return Err(anyhow!("Usage: :env KEY=VALUE"));
+                }
+                if value.is_empty() {
+                    self.command_env.remove(name);
+                    self.set_status_message(format!("{} removed", name));
+                } else {
+                    self.command_env.insert(name.to_string(), value.to_string());
+                    self.set_status_message(format!("{}={}", name, agent::masked_value(name, value)));
+                }
+            }
+            "cd" | "pwd" => {
+                let dir = self.working_dir(self.current_session_id());
+                self.set_status_message(dir.display().to_string());
+            }
+            cmd if cmd.starts_with("cd ") => {
+                let dir = self.change_directory(self.current_session_id(), cmd["cd ".len()..].trim())?;
+                self.set_status_message(dir.display().to_string());
+            }
+            "h" | "?" => self.open_help(),
+            "pin" => {
+                self.toggle_pin_selected_message()?;
+            }
+            "set" => {
+                self.set_command("")?;
+            }
+            cmd if cmd.starts_with("set ") => {
+                if let Err(e) = self.set_command(&cmd["set ".len()..]) {
+                    self.set_error_message(e.to_string());
+                }
+            }
+            "pins" => {
+                self.open_pinned_messages();
+            }
+            cmd if cmd.starts_with("d") && cmd[1..].chars().all(|c| c.is_ascii_digit()) => {
+                // Delete session command
+                if cmd == "d" {
+                    self.delete_current_session()?;
+                } else if let Some(session_num) = cmd.strip_prefix("d")
+                    && let Ok(index) = session_num.parse::<usize>()
+                    && index > 0
+                    && index <= self.sessions.len()
+                {
+                    self.current_session_index = index - 1;
+                    self.delete_current_session()?;
+                }
+            }
+            cmd if cmd.starts_with("b") && cmd[1..].chars().all(|c| c.is_ascii_digit()) => {
+                // Switch to buffer/session command
+                if let Some(session_num) = cmd.strip_prefix("b")
+                    && let Ok(index) = session_num.parse::<usize>()
+                    && index > 0
+                    && index <= self.sessions.len()
+                {
+                    self.session_list_state.select(Some(index - 1));
+                    self.switch_to_selected_session()?;
+                }
+            }
+            "b#" => {
+                self.switch_to_alternate_session()?;
+            }
+            _ => {
+                let (name, args) = command.split_once(' ').unwrap_or((command, ""));
+                match self.config.aliases.get(name).cloned() {
+                    Some(_) if alias_depth >= MAX_ALIAS_DEPTH => {
+                        return Err(anyhow!("Alias recursion too deep in :{}", name));
+                    }
+                    Some(steps) => {
+                        for step in commands::expand_alias(&steps, args) {
+                            let step = step.trim().trim_start_matches(':').to_string();
+                            self.execute_command_line(&step, alias_depth + 1)?;
+                        }
+                    }
+                    None if command.is_empty() => {}
+                    None => self.set_status_message(format!("Not a command: {}", command)),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[allow(dead_code)]
+    pub fn should_fetch_models(&self) -> bool {
+        self.mode == AppMode::ModelSelection && self.is_fetching_models && self.available_models.is_empty()
+    }
+
+    pub fn start_visual_selection(&mut self) {
+        if let Some(selected) = self.chat_list_state.selected() {
+            self.visual_start = Some(selected);
+            self.visual_end = Some(selected);
+            self.mode = AppMode::Visual;
+        }
+    }
+
+    pub fn update_visual_selection(&mut self, line: usize) {
+        if self.visual_start.is_some() {
+            self.visual_end = Some(line);
+        }
+    }
+
+    pub fn get_selected_text(&self) -> String {
+        if let (Some(start), Some(end)) = (self.visual_start, self.visual_end) {
+            let start_line = std::cmp::min(start, end);
+            let end_line = std::cmp::max(start, end);
             
             let chat_width = (self.terminal_width * 3) / 4;
             let mut selected_text = String::new();
             let mut line_index = 0;
             
-            for message in self.current_messages() {
+            for message in self.current_messages().iter().filter(|message| self.shows_message(message)) {
                 let prefix = match message.role {
                     models::Role::User => "You: ",
                     models::Role::Assistant => "AI: ",
+                    models::Role::System => "System: ",
                 };
                 
-                let wrapped_content = wrap(&message.content, (chat_width as usize).saturating_sub(6));
-                
-                for (i, line_content) in wrapped_content.iter().enumerate() {
-                    if line_index >= start_line && line_index <= end_line {
-                        if i == 0 {
+                // The folded or expanded <think> block takes up the first lines
+                let layout = self.layout.get(message, self.wrap_width(chat_width), self.images);
+                let first_line = if self.options.think_copy { 0 } else { layout.thinking_lines };
+
+                for (i, (line_content, _)) in layout.lines.iter().enumerate() {
+                    let is_thinking = i < layout.thinking_lines;
+                    if line_index >= start_line && line_index <= end_line && (self.options.think_copy || !is_thinking) {
+                        if i == first_line {
                             selected_text.push_str(&format!("{}{}", prefix, line_content));
                         } else {
                             selected_text.push_str(&format!("     {}", line_content));
@@ -467,110 +3568,79 @@ impl AppState {
         }
     }
 
-    pub fn copy_selection_to_clipboard(&self) -> Result<()> {
-        let selected_text = self.get_selected_text();
-        if selected_text.is_empty() {
+    /// Yank `text` into the register picked with `"x`, or into the unnamed register and
+    /// the system clipboard (also `"+` and `"*`). An uppercase register name appends to the lowercase register.
+    pub fn yank(&mut self, text: String) -> Result<()> {
+        if text.is_empty() {
             return Ok(());
         }
-
-        // Try to copy to clipboard using external commands
-        #[cfg(target_os = "linux")]
-        {
-            use std::process::{Command, Stdio};
-            use std::io::Write;
-            
-            // Try xclip first
-            let xclip_result = Command::new("xclip")
-                .arg("-selection")
-                .arg("clipboard")
-                .stdin(Stdio::piped())
-                .spawn();
-            
-            if let Ok(mut child) = xclip_result {
-                if let Some(stdin) = child.stdin.as_mut() {
-                    let _ = stdin.write_all(selected_text.as_bytes());
-                    let _ = stdin.flush();
-                }
-                drop(child.stdin.take()); // Close stdin
-                let _ = child.wait();
-                return Ok(());
-            }
-            
-            // Fallback to xsel
-            let xsel_result = Command::new("xsel")
-                .arg("--clipboard")
-                .arg("--input")
-                .stdin(Stdio::piped())
-                .spawn();
-            
-            if let Ok(mut child) = xsel_result {
-                if let Some(stdin) = child.stdin.as_mut() {
-                    let _ = stdin.write_all(selected_text.as_bytes());
-                    let _ = stdin.flush();
+        let lines = text.lines().count().max(1);
+        self.registers.insert('"', text.clone());
+        match self.pending_register.take() {
+            Some(name) if name.is_ascii_uppercase() => {
+                let register = self.registers.entry(name.to_ascii_lowercase()).or_default();
+                if !register.is_empty() {
+                    register.push('\n');
                 }
-                drop(child.stdin.take()); // Close stdin
-                let _ = child.wait();
-                return Ok(());
+                register.push_str(&text);
+                self.set_status_message(format!("Appended {} line(s) to register \"{}", lines, name.to_ascii_lowercase()));
             }
-            
-            // Last resort: try wl-copy for Wayland
-            let wl_copy_result = Command::new("wl-copy")
-                .stdin(Stdio::piped())
-                .spawn();
-            
-            if let Ok(mut child) = wl_copy_result {
-                if let Some(stdin) = child.stdin.as_mut() {
-                    let _ = stdin.write_all(selected_text.as_bytes());
-                    let _ = stdin.flush();
-                }
-                drop(child.stdin.take()); // Close stdin
-                let _ = child.wait();
-                return Ok(());
+            Some(name) if !matches!(name, '"' | '+' | '*') => {
+                self.registers.insert(name, text);
+                self.set_status_message(format!("Yanked {} line(s) into register \"{}", lines, name));
             }
-            
-            return Err(anyhow!("No clipboard utility found (tried xclip, xsel, wl-copy)"));
-        }
-        
-        #[cfg(target_os = "macos")]
-        {
-            use std::process::{Command, Stdio};
-            use std::io::Write;
-            
-            let mut child = Command::new("pbcopy")
-                .stdin(Stdio::piped())
-                .spawn()?;
-            
-            if let Some(stdin) = child.stdin.as_mut() {
-                stdin.write_all(selected_text.as_bytes())?;
-                stdin.flush()?;
+            _ => {
+                clipboard::copy(&text)?;
+                self.set_status_message("Copied to clipboard".to_string());
             }
-            drop(child.stdin.take()); // Close stdin
-            child.wait()?;
-            return Ok(());
         }
-        
-        #[cfg(target_os = "windows")]
-        {
-            use std::process::{Command, Stdio};
-            use std::io::Write;
-            
-            let mut child = Command::new("clip")
-                .stdin(Stdio::piped())
-                .spawn()?;
-            
-            if let Some(stdin) = child.stdin.as_mut() {
-                stdin.write_all(selected_text.as_bytes())?;
-                stdin.flush()?;
-            }
-            drop(child.stdin.take()); // Close stdin
-            child.wait()?;
+        Ok(())
+    }
+
+    /// Yank the selected message, without its thinking unless `thinkcopy` is set.
+    pub fn yank_selected_message(&mut self) -> Result<()> {
+        let Some(index) = self.selected_message_index() else {
+            self.set_status_message("No message selected".to_string());
             return Ok(());
+        };
+        let message = &self.current_messages()[index];
+        let content = if self.options.think_copy { message.content.clone() } else { message.answer().to_string() };
+        self.yank(content)
+    }
+
+    /// Append the register `name`, or the clipboard, to the input. Without a clipboard
+    /// tool the unnamed register is pasted instead, it holds the last yank.
+    pub fn paste(&mut self, name: Option<char>) -> Result<()> {
+        let text = match name.filter(|name| *name != '+' && *name != '*') {
+            Some(name) => self
+                .registers
+                .get(&name.to_ascii_lowercase())
+                .cloned()
+                .ok_or_else(|| anyhow!("Register \"{} is empty", name.to_ascii_lowercase()))?,
+            None => match clipboard::paste() {
+                Ok(text) => text,
+                Err(e) => self.registers.get(&'"').cloned().ok_or(e)?,
+            },
+        };
+        self.input.push_str(&text);
+        Ok(())
+    }
+
+    /// Lines of the `:registers` popup, the first line of each register.
+    pub fn register_lines(&self) -> Vec<String> {
+        if self.registers.is_empty() {
+            return vec!["No registers yet, yank into one with \"ay".to_string()];
         }
-        
-        #[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
-        {
-            return Err(anyhow!("Clipboard not supported on this platform"));
-        }
+        self.registers
+            .iter()
+            .map(|(name, text)| {
+                let first = text.lines().next().unwrap_or("");
+                match text.lines().count() {
+                    0 | 1 => format!("\"{}  {}", name, first),
+                    lines => format!("\"{}  {} (+{} lines)", name, first, lines - 1),
+                }
+            })
+            .collect()
     }
 
     pub fn clear_visual_selection(&mut self) {
@@ -580,10 +3650,168 @@ impl AppState {
     }
 
     pub fn set_status_message(&mut self, message: String) {
-        self.status_message = Some(message);
+        self.queue_status_message(message, false);
+    }
+
+    pub fn set_error_message(&mut self, message: String) {
+        self.queue_status_message(message, true);
+    }
+
+    fn queue_status_message(&mut self, text: String, error: bool) {
+        // The same message again only keeps it up longer
+        if let Some(last) = self.status_messages.back_mut()
+            && last.text == text
+        {
+            last.shown = Instant::now();
+            return;
+        }
+        self.status_messages.push_back(StatusMessage { text, error, shown: Instant::now() });
+        if self.status_messages.len() > STATUS_QUEUE_LIMIT + 1 {
+            self.status_messages.remove(1);
+        }
+    }
+
+    pub fn status_message(&self) -> Option<&StatusMessage> {
+        self.status_messages.front()
+    }
+
+    /// Move on to the next status message once the shown one has been up for `STATUS_TTL`; called on every tick.
+    /// Move on to the next status message once the current one was shown long enough,
+    /// returns whether it did.
+    pub fn expire_status_message(&mut self) -> bool {
+        if self.status_messages.front().is_none_or(|message| message.shown.elapsed() < STATUS_TTL) {
+            return false;
+        }
+        self.status_messages.pop_front();
+        if let Some(next) = self.status_messages.front_mut() {
+            next.shown = Instant::now();
+        }
+        true
     }
 
     pub fn clear_status_message(&mut self) {
-        self.status_message = None;
+        self.status_messages.clear();
+    }
+
+    /// Apply a config file edited while running. The server, authentication and
+    /// database are only set up at startup and keep their values; returns whether
+    /// one of them changed, so a restart is needed for it.
+    pub fn reload_config(&mut self, mut config: models::Config) -> Result<bool> {
+        let keymap = keymap::Keymap::new(&config.keys).map_err(|e| anyhow!(e))?;
+        let redaction = redact::rules(&config.redaction).map_err(|e| anyhow!(e))?;
+        if let Some(project) = &self.project {
+            project.apply_policy(&mut config.command_policy);
+        }
+        let command_policy = policy::CompiledPolicy::new(&config.command_policy).map_err(|e| anyhow!(e))?;
+        let old = &self.config;
+        let restart = config.ollama_host != old.ollama_host
+            || config.ollama_port != old.ollama_port
+            || config.db_filename != old.db_filename
+            || config.auth_enabled != old.auth_enabled
+            || config.auth_method != old.auth_method
+            || config.server != old.server
+            || config.encryption != old.encryption;
+        config.ollama_host = old.ollama_host.clone();
+        config.ollama_port = old.ollama_port;
+        config.db_filename = old.db_filename.clone();
+        config.auth_enabled = old.auth_enabled;
+        config.auth_method = old.auth_method.clone();
+        config.server = old.server.clone();
+        config.encryption = old.encryption.clone();
+
+        // Variables set with :env stay unless the config file sets them
+        for name in old.env.keys().filter(|name| !config.env.contains_key(*name)) {
+            self.command_env.remove(name);
+        }
+        self.command_env.extend(config.env.clone());
+        self.images = image::Settings::new(&config.images);
+        self.layout.clear();
+        self.keymap = keymap;
+        self.redaction = redaction;
+        self.command_policy = command_policy;
+        self.pending_keys.clear();
+        self.config = config;
+        Ok(restart)
+    }
+
+    /// The keymap mode of the current mode, for those whose keys can be remapped.
+    pub fn key_mode(&self) -> Option<keymap::Mode> {
+        match self.mode {
+            AppMode::Normal => Some(keymap::Mode::Normal),
+            AppMode::Insert => Some(keymap::Mode::Insert),
+            AppMode::Visual => Some(keymap::Mode::Visual),
+            _ => None,
+        }
+    }
+
+    /// Add a key press to the sequence being typed.
+    pub fn push_pending_key(&mut self, chord: keymap::Chord) {
+        self.pending_keys.push(chord);
+        self.pending_key_time = Instant::now();
+    }
+
+    /// Whether to show the which-key popup: a sequence has been left unfinished for `WHICH_KEY_DELAY`.
+    pub fn which_key_due(&self) -> bool {
+        !self.pending_keys.is_empty() && self.pending_key_time.elapsed() >= WHICH_KEY_DELAY
+    }
+
+    pub fn push_toast(&mut self, severity: toast::Severity, message: String) {
+        self.toasts.push(severity, message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn workflow_responses_are_quoted() {
+        let conn = db::get_connection(Path::new(":memory:")).unwrap();
+        let mut app = AppState::with_connection(models::Config::default(), conn, None).unwrap();
+        let reply = "Run this:\n```sh\nls; rm -rf ~\n```".to_string();
+        app.current_messages_mut().push(models::Message::new(models::Role::Assistant, reply));
+        let mut workflow = workflow::from_commands("note", "", vec!["echo ${response}".to_string()]);
+        assert!(app.resolve_variables(&mut workflow).is_empty());
+        let commands = workflow::step_commands(&workflow);
+        assert_eq!(commands[0].command, "echo 'ls; rm -rf ~'");
+    }
+
+    #[test]
+    fn templates_only_change_their_session() {
+        let conn = db::get_connection(Path::new(":memory:")).unwrap();
+        let mut config = models::Config::default();
+        config.templates.insert(
+            "reviewer".to_string(),
+            models::SessionTemplate {
+                model: Some("qwen2.5-coder".to_string()),
+                system_prompt: Some("Review".to_string()),
+                options: HashMap::from([
+                    ("temperature".to_string(), "0.2".to_string()),
+                    ("wrap".to_string(), "false".to_string()),
+                ]),
+            },
+        );
+        let mut app = AppState::with_connection(config, conn, None).unwrap();
+        app.set_option("model", "llama3").unwrap();
+        let first = app.current_session_id();
+
+        app.new_session_from_template("reviewer").unwrap();
+        let session = app.current_session_id();
+        assert_eq!(app.session_model(session), "qwen2.5-coder");
+        assert_eq!(app.request_options(session, "qwen2.5-coder").temperature, Some(0.2));
+        assert_eq!(app.current_model, "llama3");
+        assert_eq!(app.options.temperature, None);
+        assert!(app.options.wrap);
+        assert_eq!(db::load_config(&app.db_conn, "current_model").unwrap().as_deref(), Some("llama3"));
+        assert_eq!(app.session_model(first), "llama3");
+        assert_eq!(app.request_options(first, "llama3").temperature, None);
+
+        // Kept across restarts, and :set changes the session's own value
+        let saved = db::load_sessions(&app.db_conn).unwrap();
+        assert_eq!(saved.iter().find(|s| s.id == session).unwrap().options.get("model").map(String::as_str), Some("qwen2.5-coder"));
+        app.set_option("temperature", "0.7").unwrap();
+        assert_eq!(app.get_option("temperature").as_deref(), Some("0.7"));
+        assert_eq!(app.options.temperature, None);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,168 @@
+use crate::commands;
+use regex::Regex;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Characters all attachments of one message may add up to, about 8k tokens
+pub const ATTACHMENT_BUDGET: usize = 32_000;
+
+// A glob is not expanded past this many files or directory levels
+const MAX_MATCHES: usize = 200;
+const MAX_DEPTH: usize = 12;
+
+/// A file staged with `:attach`, sent in front of the next message.
+#[derive(Clone, Debug)]
+pub struct Attachment {
+    /// Path as typed by the user, or relative to the glob it matched
+    pub name: String,
+    pub content: String,
+}
+
+/// Rough token count, for showing the size of the staged attachments.
+pub fn estimated_tokens(chars: usize) -> usize {
+    chars.div_ceil(4)
+}
+
+/// Files matching `pattern`, relative to `cwd`. Supports `*`, `?` and `**` for any
+/// number of directories; a pattern without them must name an existing file.
+pub fn expand(pattern: &str, cwd: &Path) -> Result<Vec<(String, PathBuf)>, String> {
+    let path = cwd.join(commands::expand_tilde(pattern));
+    if !pattern.contains(['*', '?']) {
+        if !path.is_file() {
+            return Err(format!("{} is not a file", pattern));
+        }
+        return Ok(vec![(pattern.to_string(), path)]);
+    }
+
+    // Walk from the last directory before the first wildcard
+    let (base, rest) = match pattern.find(['*', '?']).and_then(|i| pattern[..i].rfind('/')) {
+        Some(slash) => (&pattern[..=slash], &pattern[slash + 1..]),
+        None => ("", pattern),
+    };
+    let matcher = Regex::new(&glob_regex(rest)).map_err(|e| e.to_string())?;
+    let mut found = Vec::new();
+    walk(&cwd.join(commands::expand_tilde(base)), "", 0, &matcher, &mut found);
+    if found.is_empty() {
+        return Err(format!("No files match {}", pattern));
+    }
+    found.sort();
+    Ok(found
+        .into_iter()
+        .map(|(relative, path)| (format!("{}{}", base, relative), path))
+        .collect())
+}
+
+fn glob_regex(glob: &str) -> String {
+    let mut regex = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    regex.push_str("(?:.*/)?");
+                } else {
+                    regex.push_str(".*");
+                }
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+fn walk(dir: &Path, prefix: &str, depth: usize, matcher: &Regex, found: &mut Vec<(String, PathBuf)>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        if found.len() >= MAX_MATCHES {
+            return;
+        }
+        let name = entry.file_name().to_string_lossy().to_string();
+        // Hidden directories such as .git are never searched
+        if name.starts_with('.') {
+            continue;
+        }
+        let relative = format!("{}{}", prefix, name);
+        if entry.file_type().is_ok_and(|kind| kind.is_dir()) {
+            if depth < MAX_DEPTH {
+                walk(&entry.path(), &format!("{}/", relative), depth + 1, matcher, found);
+            }
+        } else if matcher.is_match(&relative) {
+            found.push((relative, entry.path()));
+        }
+    }
+}
+
+/// Read the files matching `pattern`, refusing binary files and anything that would
+/// take the staged attachments (`used` characters so far) over the budget.
+pub fn load(pattern: &str, cwd: &Path, used: usize) -> Result<Vec<Attachment>, String> {
+    let mut attachments = Vec::new();
+    let mut total = used;
+    for (name, path) in expand(pattern, cwd)? {
+        let bytes = fs::read(&path).map_err(|e| format!("Cannot read {}: {}", name, e))?;
+        if bytes.contains(&0) {
+            return Err(format!("{} looks like a binary file", name));
+        }
+        let content = String::from_utf8_lossy(&bytes).into_owned();
+        total += content.len();
+        if total > ATTACHMENT_BUDGET {
+            return Err(format!(
+                "Attaching {} would exceed the budget of {} characters (~{} tokens)",
+                name,
+                ATTACHMENT_BUDGET,
+                estimated_tokens(ATTACHMENT_BUDGET)
+            ));
+        }
+        attachments.push(Attachment { name, content });
+    }
+    Ok(attachments)
+}
+
+/// `message` preceded by the attachments, each fenced and labelled with its file name.
+pub fn prepend(attachments: &[Attachment], message: &str) -> String {
+    let mut out = String::new();
+    for attachment in attachments {
+        out.push_str(&format!("{}:\n{}\n\n", attachment.name, fence(&attachment.name, &attachment.content)));
+    }
+    out.push_str(message);
+    out
+}
+
+/// `content` in a code block tagged with the extension of `name`.
+fn fence(name: &str, content: &str) -> String {
+    // Use a fence longer than any backtick run in the file
+    let longest = content.split(|c| c != '`').map(str::len).max().unwrap_or(0);
+    let fence = "`".repeat(longest.max(2) + 1);
+    let language = Path::new(name)
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_string())
+        .unwrap_or_default();
+    format!("{}{}\n{}\n{}", fence, language, content.trim_end(), fence)
+}
+
+// Extensions of prose, read in as it is rather than as a code block
+const PROSE_EXTENSIONS: &[&str] = &["txt", "md", "markdown", "rst", "org", "adoc", "log"];
+
+/// The text `:r` puts into the input: the file at `path`, fenced unless it is prose.
+pub fn read_for_input(path: &str, cwd: &Path) -> Result<String, String> {
+    let file = cwd.join(commands::expand_tilde(path));
+    let bytes = fs::read(&file).map_err(|e| format!("Cannot read {}: {}", path, e))?;
+    if bytes.contains(&0) {
+        return Err(format!("{} looks like a binary file", path));
+    }
+    if bytes.len() > ATTACHMENT_BUDGET {
+        return Err(format!("{} is over {} characters, :attach it instead", path, ATTACHMENT_BUDGET));
+    }
+    let content = String::from_utf8_lossy(&bytes);
+    let extension = file.extension().map(|ext| ext.to_string_lossy().to_lowercase());
+    Ok(match extension {
+        Some(extension) if !PROSE_EXTENSIONS.contains(&extension.as_str()) => fence(path, &content),
+        _ => content.trim_end().to_string(),
+    })
+}
@@ -0,0 +1,148 @@
+use crate::app::AppState;
+use crate::models::{ChatSession, Message, Role};
+use crate::{cache, config, db, ollama, project, redact};
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+/// Prompts sent in order by `ollama-tui run <script>`, one conversation per model.
+#[derive(Deserialize, Debug)]
+pub struct Script {
+    /// Start of the session names, the file name when empty
+    #[serde(default)]
+    pub name: String,
+    /// The current model when empty
+    #[serde(default)]
+    pub models: Vec<String>,
+    #[serde(default)]
+    pub system_prompt: Option<String>,
+    pub prompts: Vec<String>,
+    /// Transcript written for each model, relative to the script; `{model}` and `{name}` are replaced
+    #[serde(default)]
+    pub output: Option<String>,
+}
+
+/// Read a script from TOML, or JSON for a `.json` file.
+pub fn load(path: &Path) -> Result<Script> {
+    let text = std::fs::read_to_string(path).map_err(|e| anyhow!("Cannot read {}: {}", path.display(), e))?;
+    let value = match path.extension().and_then(|e| e.to_str()) {
+        Some("json") => serde_json::from_str(&text)?,
+        Some("yaml" | "yml") => return Err(anyhow!("Scripts are written in TOML (or JSON), not YAML")),
+        _ => project::parse_toml(&text).map_err(|e| anyhow!("{}: {}", path.display(), e))?,
+    };
+    let mut script: Script = serde_json::from_value(value).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+    if script.prompts.is_empty() {
+        return Err(anyhow!("{} has no prompts", path.display()));
+    }
+    if script.name.trim().is_empty() {
+        script.name = path.file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default();
+    }
+    Ok(script)
+}
+
+// A model name such as `llama3:8b` or `library/qwen` as part of a file name
+fn file_part(model: &str) -> String {
+    model.chars().map(|c| if c.is_alphanumeric() || c == '.' || c == '-' { c } else { '-' }).collect()
+}
+
+fn transcript(script: &Script, model: &str, messages: &[Message]) -> String {
+    let mut out = format!("# {} ({})\n", script.name, model);
+    if let Some(system_prompt) = &script.system_prompt {
+        out.push_str(&format!("\n> {}\n", system_prompt.lines().collect::<Vec<_>>().join("\n> ")));
+    }
+    for (i, pair) in messages.chunks(2).enumerate() {
+        out.push_str(&format!("\n## Prompt {}\n\n{}\n", i + 1, pair[0].content));
+        if let Some(reply) = pair.get(1) {
+            out.push_str(&format!("\n### Reply\n\n{}\n", reply.content));
+        }
+    }
+    out
+}
+
+/// `ollama-tui run <script>`: send the prompts to every model of the script, without
+/// the TUI. Each model gets a session of its own, so the results can be read there.
+pub async fn run(path: &Path) -> Result<()> {
+    let script = load(path)?;
+    let app = AppState::load(config::load_or_create()?)?;
+    let models = if script.models.is_empty() {
+        if app.current_model == "No model selected" {
+            return Err(anyhow!("The script names no models and none is selected yet"));
+        }
+        vec![app.current_model.clone()]
+    } else {
+        script.models.clone()
+    };
+    let base_dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+    let mut failures = 0;
+    for model in &models {
+        let mut session = ChatSession::new(&app.db_conn)?;
+        session.name = format!("{} ({})", script.name, model);
+        session.messages.clear();
+        session.system_prompt = script.system_prompt.clone();
+        db::save_session(&app.db_conn, &mut session)?;
+        let options = app.options.model_options(model, &app.config.model_options, &session.options);
+        for (i, prompt) in script.prompts.iter().enumerate() {
+            let prompt = Message::new(Role::User, redact::apply(prompt, &app.redaction));
+            db::save_message(&app.db_conn, session.id, &prompt)?;
+            session.messages.push(prompt);
+            let mut messages = Vec::new();
+            if let Some(system_prompt) = &session.system_prompt {
+                messages.push(Message::new(Role::System, system_prompt.clone()));
+            }
+            messages.extend(session.messages.iter().cloned());
+            eprint!("{} {}/{} ", model, i + 1, script.prompts.len());
+            let cache_key = app.options.cache.then(|| cache::key(model, &messages, &options)).flatten();
+            if let Some(content) = cache_key.as_deref().map(|key| cache::lookup(&app.db_conn, key)).transpose()?.flatten() {
+                eprintln!("cached");
+                let reply = Message::new(Role::Assistant, content);
+                db::save_message(&app.db_conn, session.id, &reply)?;
+                session.messages.push(reply);
+                continue;
+            }
+            let started = Instant::now();
+            let result = ollama::complete_chat_text(
+                &app.http_client,
+                &app.ollama_base_url,
+                model,
+                &messages,
+                &options,
+                app.config.auth_enabled,
+                app.config.auth_method.as_ref(),
+            )
+            .await;
+            let reply = match result {
+                Ok(content) => {
+                    eprintln!("done in {:.1}s", started.elapsed().as_secs_f64());
+                    let content = redact::apply(&content, &app.redaction);
+                    if let Some(key) = &cache_key
+                        && !content.is_empty()
+                    {
+                        cache::store(&app.db_conn, key, &content)?;
+                    }
+                    Message::new(Role::Assistant, content)
+                }
+                Err(e) => {
+                    failures += 1;
+                    eprintln!("failed: {}", e);
+                    Message::new(Role::Assistant, redact::apply(&format!("Error: {}", e), &app.redaction))
+                }
+            };
+            db::save_message(&app.db_conn, session.id, &reply)?;
+            session.messages.push(reply);
+        }
+        if let Some(output) = &script.output {
+            let file: PathBuf = base_dir.join(output.replace("{model}", &file_part(model)).replace("{name}", &file_part(&script.name)));
+            if let Some(dir) = file.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            std::fs::write(&file, transcript(&script, model, &session.messages))?;
+            eprintln!("Wrote {}", file.display());
+        }
+        eprintln!("Saved as session '{}'", session.name);
+    }
+    if failures > 0 {
+        return Err(anyhow!("{} of {} prompts failed", failures, models.len() * script.prompts.len()));
+    }
+    Ok(())
+}
@@ -0,0 +1,100 @@
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+/// Prompts run by `:bench` when the config has no `bench_prompts`
+pub const DEFAULT_PROMPTS: &[&str] = &[
+    "Reply with the single word: ready",
+    "Explain in one paragraph how a hash map handles collisions.",
+    "Write a Python function that returns the n-th Fibonacci number, with a short docstring.",
+];
+
+/// How long one reply took, as measured while it streamed.
+#[derive(Clone, Debug)]
+pub struct Timing {
+    /// Time spent loading the model into memory, reported by Ollama
+    pub load: Duration,
+    /// From sending the request to the first content, excluding `load`
+    pub first_token: Duration,
+    pub total: Duration,
+    /// Generated tokens and the time Ollama spent generating them
+    pub tokens: u64,
+    pub eval: Duration,
+}
+
+impl Timing {
+    pub fn tokens_per_sec(&self) -> f64 {
+        if self.eval.is_zero() {
+            0.0
+        } else {
+            self.tokens as f64 / self.eval.as_secs_f64()
+        }
+    }
+}
+
+/// The result of running one prompt with one model.
+#[derive(Clone, Debug)]
+pub struct Sample {
+    pub model: String,
+    pub prompt: usize,
+    pub result: Result<Timing, String>,
+}
+
+/// Averages over the prompts a model completed.
+#[derive(Clone, Debug)]
+pub struct Summary {
+    pub load: Duration,
+    pub first_token: Duration,
+    pub total: Duration,
+    pub tokens_per_sec: f64,
+    pub failed: usize,
+}
+
+/// A `:bench` run: every prompt against every model, one request at a time so
+/// the models do not slow each other down.
+#[derive(Debug)]
+pub struct Bench {
+    pub models: Vec<String>,
+    pub prompts: Vec<String>,
+    pub samples: Vec<Sample>,
+    pub cancel: Option<oneshot::Sender<()>>,
+}
+
+impl Bench {
+    pub fn new(models: Vec<String>, prompts: Vec<String>) -> Self {
+        Self { models, prompts, samples: Vec::new(), cancel: None }
+    }
+
+    pub fn total_runs(&self) -> usize {
+        self.models.len() * self.prompts.len()
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.cancel.is_some() && self.samples.len() < self.total_runs()
+    }
+
+    pub fn summary(&self, model: &str) -> Option<Summary> {
+        let samples: Vec<&Sample> = self.samples.iter().filter(|sample| sample.model == model).collect();
+        let timings: Vec<&Timing> = samples.iter().filter_map(|sample| sample.result.as_ref().ok()).collect();
+        let failed = samples.len() - timings.len();
+        if timings.is_empty() {
+            return (failed > 0).then_some(Summary {
+                load: Duration::ZERO,
+                first_token: Duration::ZERO,
+                total: Duration::ZERO,
+                tokens_per_sec: 0.0,
+                failed,
+            });
+        }
+        let count = timings.len() as u32;
+        let tokens: u64 = timings.iter().map(|timing| timing.tokens).sum();
+        let eval: Duration = timings.iter().map(|timing| timing.eval).sum();
+        Some(Summary {
+            // Only the first request has to load the model, so the largest load time is the one that counts
+            load: timings.iter().map(|timing| timing.load).max().unwrap_or_default(),
+            first_token: timings.iter().map(|timing| timing.first_token).sum::<Duration>() / count,
+            total: timings.iter().map(|timing| timing.total).sum::<Duration>() / count,
+            tokens_per_sec: if eval.is_zero() { 0.0 } else { tokens as f64 / eval.as_secs_f64() },
+            failed,
+        })
+    }
+}
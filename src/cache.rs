@@ -0,0 +1,30 @@
+// Replies to deterministic requests, kept in the database so the same request made again
+// is answered at once. A request is deterministic when it samples greedily (temperature 0)
+// or fixes the seed; the key is a hash of everything sent to the model.
+
+use crate::models::{ChatRequest, Message, ModelOptions};
+use crate::{crypto, db};
+use anyhow::Result;
+use ring::digest::{digest, SHA256};
+use rusqlite::Connection;
+
+/// Cache key of a request, `None` when the reply could differ next time.
+pub fn key(model: &str, messages: &[Message], options: &ModelOptions) -> Option<String> {
+    if !options.is_deterministic() {
+        return None;
+    }
+    // Streamed or not, the reply is the same
+    let request = ChatRequest { model, messages, stream: false, options, format: options.format.as_ref() };
+    let json = serde_json::to_string(&request).ok()?;
+    Some(digest(&SHA256, json.as_bytes()).as_ref().iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+/// The reply cached under `key`, if any.
+pub fn lookup(conn: &Connection, key: &str) -> Result<Option<String>> {
+    db::load_cached_response(conn, key)?.map(|content| crypto::open(&content)).transpose()
+}
+
+/// Keep `content` as the reply to the request of `key`; stored encrypted like messages.
+pub fn store(conn: &Connection, key: &str, content: &str) -> Result<()> {
+    db::save_cached_response(conn, key, &crypto::seal(content)?)
+}
@@ -0,0 +1,278 @@
+// The command line: without arguments the TUI starts, everything else runs and exits.
+// Usage, completion scripts and the man page are all generated from the tables below.
+
+use std::path::PathBuf;
+
+const NAME: &str = "ollama-tui";
+const ABOUT: &str = "Vim-style terminal chat client for Ollama";
+const SHELLS: &[&str] = &["bash", "zsh", "fish"];
+
+/// What an argument takes, for the completion scripts.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Value {
+    None,
+    Text,  // Free-form, nothing to complete
+    Shell, // One of SHELLS
+    File,
+}
+
+struct Arg {
+    long: &'static str,
+    short: Option<char>,
+    value: Value,
+    placeholder: &'static str,
+    description: &'static str,
+}
+
+const OPTIONS: &[Arg] = &[
+    Arg {
+        long: "ask",
+        short: None,
+        value: Value::Text,
+        placeholder: "QUESTION",
+        description: "Print the shell command answering QUESTION, then run or copy it",
+    },
+    Arg { long: "help", short: Some('h'), value: Value::None, placeholder: "", description: "Print this help" },
+    Arg { long: "version", short: Some('V'), value: Value::None, placeholder: "", description: "Print the version" },
+];
+
+const SUBCOMMANDS: &[Arg] = &[
+    Arg {
+        long: "completions",
+        short: None,
+        value: Value::Shell,
+        placeholder: "SHELL",
+        description: "Print the completion script for bash, zsh or fish",
+    },
+    Arg { long: "man", short: None, value: Value::None, placeholder: "", description: "Print the man page" },
+    Arg {
+        long: "run",
+        short: None,
+        value: Value::File,
+        placeholder: "SCRIPT",
+        description: "Send the prompts of a TOML or JSON script to its models, saving a session per model",
+    },
+    Arg {
+        long: "sync",
+        short: None,
+        value: Value::File,
+        placeholder: "DIR",
+        description: "Merge the sessions with a directory of session files, sync_dir when left out",
+    },
+];
+
+/// What the command line asked for.
+pub enum Command {
+    Tui,
+    Ask(String),
+    Run(PathBuf),
+    Sync(Option<PathBuf>),
+    Completions(String),
+    Man,
+    Help,
+    Version,
+}
+
+pub fn parse(args: &[String]) -> Result<Command, String> {
+    let Some(first) = args.first() else {
+        return Ok(Command::Tui);
+    };
+    let rest = &args[1..];
+    match first.as_str() {
+        "--ask" => match rest.join(" ").trim() {
+            "" => Err(format!("Usage: {} --ask <question>", NAME)),
+            question => Ok(Command::Ask(question.to_string())),
+        },
+        "-h" | "--help" => Ok(Command::Help),
+        "-V" | "--version" => Ok(Command::Version),
+        "completions" => match rest {
+            [shell] if SHELLS.contains(&shell.as_str()) => Ok(Command::Completions(shell.clone())),
+            _ => Err(format!("Usage: {} completions <{}>", NAME, SHELLS.join("|"))),
+        },
+        "man" if rest.is_empty() => Ok(Command::Man),
+        "run" => match rest {
+            [script] => Ok(Command::Run(PathBuf::from(script))),
+            _ => Err(format!("Usage: {} run <script.toml>", NAME)),
+        },
+        "sync" => match rest {
+            [] => Ok(Command::Sync(None)),
+            [dir] => Ok(Command::Sync(Some(PathBuf::from(dir)))),
+            _ => Err(format!("Usage: {} sync [dir]", NAME)),
+        },
+        other => Err(format!("Unknown argument '{}', see {} --help", other, NAME)),
+    }
+}
+
+fn flags(arg: &Arg) -> String {
+    match arg.short {
+        Some(short) => format!("-{}, --{}", short, arg.long),
+        None => format!("--{}", arg.long),
+    }
+}
+
+fn with_placeholder(name: String, arg: &Arg) -> String {
+    if arg.placeholder.is_empty() { name } else { format!("{} <{}>", name, arg.placeholder) }
+}
+
+/// `--help`: the subcommands and options with what they do.
+pub fn usage() -> String {
+    let mut out = format!("{}\n\nUsage: {} [OPTIONS]\n       {} <COMMAND>\n\nCommands:\n", ABOUT, NAME, NAME);
+    for arg in SUBCOMMANDS {
+        out.push_str(&format!("  {:<24}{}\n", with_placeholder(arg.long.to_string(), arg), arg.description));
+    }
+    out.push_str("\nOptions:\n");
+    for arg in OPTIONS {
+        out.push_str(&format!("  {:<24}{}\n", with_placeholder(flags(arg), arg), arg.description));
+    }
+    out
+}
+
+/// The completion script for `shell`, one of SHELLS.
+pub fn completions(shell: &str) -> String {
+    match shell {
+        "bash" => bash_completions(),
+        "zsh" => zsh_completions(),
+        _ => fish_completions(),
+    }
+}
+
+fn bash_completions() -> String {
+    let mut words: Vec<String> = SUBCOMMANDS.iter().map(|arg| arg.long.to_string()).collect();
+    for arg in OPTIONS {
+        words.push(format!("--{}", arg.long));
+        if let Some(short) = arg.short {
+            words.push(format!("-{}", short));
+        }
+    }
+    let mut cases = String::new();
+    for arg in SUBCOMMANDS.iter().chain(OPTIONS) {
+        let name = if SUBCOMMANDS.iter().any(|sub| sub.long == arg.long) { arg.long.to_string() } else { format!("--{}", arg.long) };
+        match arg.value {
+            Value::Shell => cases.push_str(&format!(
+                "        {})\n            COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))\n            return ;;\n",
+                name,
+                SHELLS.join(" ")
+            )),
+            Value::File => cases.push_str(&format!("        {})\n            COMPREPLY=($(compgen -f -- \"$cur\"))\n            return ;;\n", name)),
+            Value::Text => cases.push_str(&format!("        {})\n            return ;;\n", name)),
+            Value::None => {}
+        }
+    }
+    format!(
+        "_ollama_tui() {{\n    local cur=\"${{COMP_WORDS[COMP_CWORD]}}\" prev=\"${{COMP_WORDS[COMP_CWORD-1]}}\"\n    COMPREPLY=()\n    case \"$prev\" in\n{}    esac\n    if [[ $COMP_CWORD -eq 1 ]]; then\n        COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))\n    fi\n}}\ncomplete -F _ollama_tui {}\n",
+        cases,
+        words.join(" "),
+        NAME
+    )
+}
+
+// Brackets and quotes would end the description of a zsh completion early
+fn zsh_escape(text: &str) -> String {
+    text.replace('\'', "'\\''").replace('[', "\\[").replace(']', "\\]").replace(':', "\\:")
+}
+
+fn zsh_completions() -> String {
+    let mut out = format!("#compdef {}\n\n_ollama_tui() {{\n    local state\n    _arguments \\\n", NAME);
+    for arg in OPTIONS {
+        let names = match arg.short {
+            Some(short) => format!("{{-{},--{}}}", short, arg.long),
+            None => format!("--{}", arg.long),
+        };
+        let value = if arg.value == Value::None { String::new() } else { format!(":{}:", arg.placeholder.to_lowercase()) };
+        out.push_str(&format!("        '(- *)'{}'[{}]{}' \\\n", names, zsh_escape(arg.description), value));
+    }
+    out.push_str("        '1: :->command' \\\n        '*:: :->args'\n    case $state in\n        command)\n            _values 'command'");
+    for arg in SUBCOMMANDS {
+        out.push_str(&format!(" '{}[{}]'", arg.long, zsh_escape(arg.description)));
+    }
+    out.push_str(" ;;\n        args)\n            case $words[1] in\n");
+    for arg in SUBCOMMANDS.iter().filter(|arg| arg.value == Value::Shell) {
+        out.push_str(&format!("                {}) _values 'shell' {} ;;\n", arg.long, SHELLS.join(" ")));
+    }
+    for arg in SUBCOMMANDS.iter().filter(|arg| arg.value == Value::File) {
+        out.push_str(&format!("                {}) _files ;;\n", arg.long));
+    }
+    out.push_str("            esac ;;\n    esac\n}\n\n_ollama_tui \"$@\"\n");
+    out
+}
+
+fn fish_completions() -> String {
+    let mut out = format!("complete -c {} -f\n", NAME);
+    for arg in SUBCOMMANDS {
+        out.push_str(&format!(
+            "complete -c {} -n __fish_use_subcommand -a {} -d '{}'\n",
+            NAME,
+            arg.long,
+            arg.description.replace('\'', "\\'")
+        ));
+        if arg.value == Value::Shell {
+            out.push_str(&format!(
+                "complete -c {} -n '__fish_seen_subcommand_from {}' -a '{}'\n",
+                NAME,
+                arg.long,
+                SHELLS.join(" ")
+            ));
+        }
+        if arg.value == Value::File {
+            out.push_str(&format!("complete -c {} -n '__fish_seen_subcommand_from {}' -F\n", NAME, arg.long));
+        }
+    }
+    for arg in OPTIONS {
+        let short = arg.short.map(|short| format!(" -s {}", short)).unwrap_or_default();
+        let value = if arg.value == Value::None { "" } else { " -r" };
+        out.push_str(&format!(
+            "complete -c {} -n __fish_use_subcommand{} -l {}{} -d '{}'\n",
+            NAME,
+            short,
+            arg.long,
+            value,
+            arg.description.replace('\'', "\\'")
+        ));
+    }
+    out
+}
+
+// Dashes are hyphens in roff unless escaped, and a leading dot or quote starts a request
+fn roff(text: &str) -> String {
+    let text = text.replace('\\', "\\e").replace('-', "\\-");
+    if text.starts_with('.') || text.starts_with('\'') { format!("\\&{}", text) } else { text }
+}
+
+fn roff_item(name: String, arg: &Arg) -> String {
+    let placeholder = if arg.placeholder.is_empty() { String::new() } else { format!(" \\fI{}\\fR", arg.placeholder) };
+    format!(".TP\n\\fB{}\\fR{}\n{}\n", roff(&name), placeholder, roff(arg.description))
+}
+
+/// `man`: the man page in roff, for `ollama-tui man > ollama-tui.1`.
+pub fn man_page() -> String {
+    let mut out = format!(
+        ".TH {} 1 \"\" \"{} {}\" \"User Commands\"\n.SH NAME\n{} \\- {}\n.SH SYNOPSIS\n.B {}\n[\\fIOPTIONS\\fR]\n",
+        NAME.to_uppercase(),
+        NAME,
+        env!("CARGO_PKG_VERSION"),
+        roff(NAME),
+        ABOUT,
+        roff(NAME)
+    );
+    for arg in SUBCOMMANDS {
+        out.push_str(&format!(".br\n.B {}\n\\fB{}\\fR", roff(NAME), roff(arg.long)));
+        if !arg.placeholder.is_empty() {
+            out.push_str(&format!(" \\fI{}\\fR", arg.placeholder));
+        }
+        out.push('\n');
+    }
+    out.push_str(&format!(
+        ".SH DESCRIPTION\n{}\n.PP\n{}\n",
+        roff("Without arguments, the terminal interface starts. Chats, sessions, the agent and workflows are used from there; press ? inside it for every key and command."),
+        roff("config.toml and the database live in the platform's configuration directory (~/.config/ollama-tui on Linux), and a .ollama-tui.toml up from the launch directory overrides them per project.")
+    ));
+    out.push_str(".SH COMMANDS\n");
+    for arg in SUBCOMMANDS {
+        out.push_str(&roff_item(arg.long.to_string(), arg));
+    }
+    out.push_str(".SH OPTIONS\n");
+    for arg in OPTIONS {
+        out.push_str(&roff_item(flags(arg), arg));
+    }
+    out
+}
@@ -0,0 +1,133 @@
+use anyhow::{anyhow, Result};
+
+/// Put `text` on the system clipboard with whatever tool the platform has.
+pub fn copy(text: &str) -> Result<()> {
+    // Try to copy to clipboard using external commands
+    #[cfg(target_os = "linux")]
+    {
+        use std::process::{Command, Stdio};
+        use std::io::Write;
+        
+        // Try xclip first
+        let xclip_result = Command::new("xclip")
+            .arg("-selection")
+            .arg("clipboard")
+            .stdin(Stdio::piped())
+            .spawn();
+        
+        if let Ok(mut child) = xclip_result {
+            if let Some(stdin) = child.stdin.as_mut() {
+                let _ = stdin.write_all(text.as_bytes());
+                let _ = stdin.flush();
+            }
+            drop(child.stdin.take()); // Close stdin
+            let _ = child.wait();
+            return Ok(());
+        }
+        
+        // Fallback to xsel
+        let xsel_result = Command::new("xsel")
+            .arg("--clipboard")
+            .arg("--input")
+            .stdin(Stdio::piped())
+            .spawn();
+        
+        if let Ok(mut child) = xsel_result {
+            if let Some(stdin) = child.stdin.as_mut() {
+                let _ = stdin.write_all(text.as_bytes());
+                let _ = stdin.flush();
+            }
+            drop(child.stdin.take()); // Close stdin
+            let _ = child.wait();
+            return Ok(());
+        }
+        
+        // Last resort: try wl-copy for Wayland
+        let wl_copy_result = Command::new("wl-copy")
+            .stdin(Stdio::piped())
+            .spawn();
+        
+        if let Ok(mut child) = wl_copy_result {
+            if let Some(stdin) = child.stdin.as_mut() {
+                let _ = stdin.write_all(text.as_bytes());
+                let _ = stdin.flush();
+            }
+            drop(child.stdin.take()); // Close stdin
+            let _ = child.wait();
+            return Ok(());
+        }
+        
+        Err(anyhow!("No clipboard utility found (tried xclip, xsel, wl-copy)"))
+    }
+    
+    #[cfg(target_os = "macos")]
+    {
+        use std::process::{Command, Stdio};
+        use std::io::Write;
+        
+        let mut child = Command::new("pbcopy")
+            .stdin(Stdio::piped())
+            .spawn()?;
+        
+        if let Some(stdin) = child.stdin.as_mut() {
+            stdin.write_all(text.as_bytes())?;
+            stdin.flush()?;
+        }
+        drop(child.stdin.take()); // Close stdin
+        child.wait()?;
+        return Ok(());
+    }
+    
+    #[cfg(target_os = "windows")]
+    {
+        use std::process::{Command, Stdio};
+        use std::io::Write;
+        
+        let mut child = Command::new("clip")
+            .stdin(Stdio::piped())
+            .spawn()?;
+        
+        if let Some(stdin) = child.stdin.as_mut() {
+            stdin.write_all(text.as_bytes())?;
+            stdin.flush()?;
+        }
+        drop(child.stdin.take()); // Close stdin
+        child.wait()?;
+        return Ok(());
+    }
+    
+    #[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+    {
+        return Err(anyhow!("Clipboard not supported on this platform"));
+    }
+}
+
+
+/// The text on the system clipboard, read with the first paste tool that works.
+pub fn paste() -> Result<String> {
+    use std::process::Command;
+
+    #[cfg(target_os = "linux")]
+    let tools: &[(&str, &[&str])] = &[
+        ("xclip", &["-selection", "clipboard", "-o"]),
+        ("xsel", &["--clipboard", "--output"]),
+        ("wl-paste", &["--no-newline"]),
+    ];
+    #[cfg(target_os = "macos")]
+    let tools: &[(&str, &[&str])] = &[("pbpaste", &[])];
+    #[cfg(target_os = "windows")]
+    let tools: &[(&str, &[&str])] = &[("powershell", &["-NoProfile", "-Command", "Get-Clipboard"])];
+    #[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+    let tools: &[(&str, &[&str])] = &[];
+
+    for (program, args) in tools {
+        if let Ok(output) = Command::new(program).args(*args).output()
+            && output.status.success()
+        {
+            let text = String::from_utf8_lossy(&output.stdout);
+            // PowerShell ends the text with a line break of its own
+            return Ok(if cfg!(target_os = "windows") { text.trim_end_matches("\r\n").to_string() } else { text.into_owned() });
+        }
+    }
+    Err(anyhow!("No clipboard utility found (tried {})", tools.iter().map(|(program, _)| *program).collect::<Vec<_>>().join(", ")))
+}
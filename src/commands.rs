@@ -0,0 +1,229 @@
+use crate::options;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// What kind of argument a command takes, used to pick completion candidates.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ArgKind {
+    None,
+    Model,
+    Session,
+    Template,
+    Option,
+    Number,
+    Path,
+    Text, // Free-form text such as a goal, nothing to complete
+}
+
+impl ArgKind {
+    /// Whether the command is useless without an argument, so pickers such as the
+    /// command palette should let the user type one instead of running it directly.
+    pub fn requires_input(self) -> bool {
+        matches!(self, ArgKind::Number | ArgKind::Path | ArgKind::Text)
+    }
+}
+
+pub struct CommandInfo {
+    pub name: &'static str,
+    pub args: ArgKind,
+    pub description: &'static str,
+}
+
+// Every `:` command understood by `AppState::execute_vim_command`
+pub const COMMANDS: &[CommandInfo] = &[
+    CommandInfo { name: "q", args: ArgKind::None, description: "Quit application" },
+    CommandInfo { name: "w", args: ArgKind::None, description: "Save current session, or write the last reply to a file (:w <file>)" },
+    CommandInfo { name: "append", args: ArgKind::Path, description: "Add the last reply to the end of a file (:w >> <file> too)" },
+    CommandInfo { name: "wq", args: ArgKind::None, description: "Save and quit" },
+    CommandInfo { name: "n", args: ArgKind::Template, description: "Create new session, optionally from a template" },
+    CommandInfo { name: "new", args: ArgKind::Template, description: "Create new session, optionally from a template" },
+    CommandInfo { name: "c", args: ArgKind::None, description: "Clear current session" },
+    CommandInfo { name: "m", args: ArgKind::None, description: "Select model" },
+    CommandInfo { name: "model", args: ArgKind::Model, description: "Select model, or switch to the named model" },
+    CommandInfo { name: "s", args: ArgKind::None, description: "Select session" },
+    CommandInfo { name: "session", args: ArgKind::Session, description: "Select session, or switch to the named session" },
+    CommandInfo { name: "vsplit", args: ArgKind::Session, description: "Show another session beside the current one (Ctrl+W w switches focus)" },
+    CommandInfo { name: "split", args: ArgKind::Session, description: "Show another session below the current one" },
+    CommandInfo { name: "only", args: ArgKind::None, description: "Close the split view" },
+    CommandInfo { name: "a", args: ArgKind::None, description: "Enter agent mode" },
+    CommandInfo { name: "auto", args: ArgKind::Text, description: "Run the autonomous agent towards a goal (:auto <goal>)" },
+    CommandInfo { name: "audit", args: ArgKind::None, description: "Show the log of commands run by the agent" },
+    CommandInfo { name: "messages", args: ArgKind::None, description: "Show the errors and notices of this run" },
+    CommandInfo { name: "filter", args: ArgKind::Text, description: "Show only user, assistant or system messages, or those matching /regex/ (:filter alone clears)" },
+    CommandInfo { name: "nofilter", args: ArgKind::None, description: "Show every message again" },
+    CommandInfo { name: "marks", args: ArgKind::None, description: "List the marks set with m{a-z}" },
+    CommandInfo { name: "registers", args: ArgKind::None, description: "Show the text yanked into each register (\"ay yanks into a)" },
+    CommandInfo { name: "env", args: ArgKind::Text, description: "List or set agent command environment variables (:env KEY=VALUE)" },
+    CommandInfo { name: "cd", args: ArgKind::Path, description: "Show or change the directory agent commands run in" },
+    CommandInfo { name: "r", args: ArgKind::Path, description: "Read a file into the input, in a code block unless it is prose (:read too)" },
+    CommandInfo { name: "!", args: ArgKind::Text, description: "Pipe the last reply through a shell command and show its output" },
+    CommandInfo { name: ".!", args: ArgKind::Text, description: "Pipe the last reply through a shell command into the input" },
+    CommandInfo { name: "attach", args: ArgKind::Path, description: "Stage files or globs for the next message (:attach lists, :attach! clears)" },
+    CommandInfo { name: "ingest", args: ArgKind::Path, description: "Embed documents into this session for retrieval (:ingest lists, :ingest! removes)" },
+    CommandInfo { name: "memory", args: ArgKind::Text, description: "List long-term memories, or remember a fact (:memory! forgets all)" },
+    CommandInfo { name: "forget", args: ArgKind::Number, description: "Forget memory N from the :memory list" },
+    CommandInfo { name: "context", args: ArgKind::None, description: "Load or refresh the project snapshot given to the agent (:context? shows, :context! clears)" },
+    CommandInfo { name: "runs", args: ArgKind::None, description: "Browse and resume past autonomous runs" },
+    CommandInfo { name: "howto", args: ArgKind::Text, description: "Ask for the one shell command doing something, to run, copy or insert" },
+    CommandInfo { name: "cache", args: ArgKind::None, description: "Count the replies cached for temperature 0 or seeded requests (:cache clear empties it)" },
+    CommandInfo { name: "sync", args: ArgKind::Path, description: "Merge the sessions with a directory of session files, sync_dir by default" },
+    CommandInfo { name: "gitmsg", args: ArgKind::None, description: "Write a conventional commit message for the staged changes, to copy, edit or commit" },
+    CommandInfo { name: "wf", args: ArgKind::None, description: "Run a workflow of shell commands step by step (:wf <name> selects one, :wf generate <text> drafts one)" },
+    CommandInfo { name: "compare", args: ArgKind::Model, description: "Send each prompt to two models side by side (:compare <a> <b>, :compare off)" },
+    CommandInfo { name: "bench", args: ArgKind::Model, description: "Time the benchmark prompts on each model (:bench <model> [model...])" },
+    CommandInfo { name: "pin", args: ArgKind::None, description: "Pin/unpin selected message" },
+    CommandInfo { name: "pins", args: ArgKind::None, description: "List pinned messages" },
+    CommandInfo { name: "system", args: ArgKind::None, description: "Show or set the session system prompt (:system! clears)" },
+    CommandInfo { name: "prompt", args: ArgKind::None, description: "List prompt snippets (:prompt save|use|delete <name>)" },
+    CommandInfo { name: "template", args: ArgKind::None, description: "List templates (:template save|delete <name>)" },
+    CommandInfo { name: "set", args: ArgKind::Option, description: "Show or change options (:set name=value)" },
+    CommandInfo { name: "h", args: ArgKind::None, description: "Show help" },
+    CommandInfo { name: "d", args: ArgKind::None, description: "Delete current session (:d<N> for session N)" },
+    CommandInfo { name: "b", args: ArgKind::Number, description: "Switch to session N (:b<N>)" },
+    CommandInfo { name: "b#", args: ArgKind::None, description: "Switch to the previously active session" },
+];
+
+pub fn find_command(name: &str) -> Option<&'static CommandInfo> {
+    COMMANDS.iter().find(|c| c.name == name)
+}
+
+/// Completion candidates for a partially typed command line.
+///
+/// Returns the byte offset where the completed token starts together with the
+/// candidates that can replace everything from that offset onwards. Relative paths
+/// are completed in `cwd`, the directory commands resolve them against.
+pub fn complete(
+    line: &str,
+    cwd: &Path,
+    models: &[String],
+    sessions: &[String],
+    templates: &[String],
+    aliases: &[String],
+) -> (usize, Vec<String>) {
+    let Some((name, arg)) = line.split_once(' ') else {
+        // Commands in table order, then aliases; an alias shadowing a command is listed once
+        let mut seen = HashSet::new();
+        let candidates: Vec<String> = COMMANDS
+            .iter()
+            .map(|c| c.name.to_string())
+            .chain(aliases.iter().cloned())
+            .filter(|name| name.starts_with(line) && seen.insert(name.clone()))
+            .collect();
+        return (0, candidates);
+    };
+
+    let arg_start = name.len() + 1;
+    let arg_kind = find_command(name).map(|c| c.args).unwrap_or(ArgKind::Path);
+    match arg_kind {
+        ArgKind::None | ArgKind::Number | ArgKind::Text => (arg_start, Vec::new()),
+        ArgKind::Model => {
+            // `:compare` takes two models, complete the last one
+            let word_start = line.rfind(' ').map(|i| i + 1).unwrap_or(arg_start);
+            (word_start, filter_prefix(models, &line[word_start..]))
+        }
+        ArgKind::Session => (arg_start, filter_prefix(sessions, arg)),
+        ArgKind::Template => (arg_start, filter_prefix(templates, arg)),
+        ArgKind::Option => {
+            // `:set` takes several options, complete the last one
+            let word_start = line.rfind(' ').map(|i| i + 1).unwrap_or(arg_start);
+            let names: Vec<String> = options::OPTIONS.iter().map(|o| o.name.to_string()).collect();
+            (word_start, filter_prefix(&names, &line[word_start..]))
+        }
+        ArgKind::Path => {
+            // Paths are completed one whitespace-separated word at a time
+            let word_start = line.rfind(' ').map(|i| i + 1).unwrap_or(arg_start);
+            (word_start, complete_path(&line[word_start..], cwd))
+        }
+    }
+}
+
+/// Substitute `$1`..`$9` with positional arguments and `$*` with all of them.
+pub fn expand_alias(steps: &[String], args: &str) -> Vec<String> {
+    let positional: Vec<&str> = args.split_whitespace().collect();
+    steps
+        .iter()
+        .map(|step| {
+            let mut expanded = step.replace("$*", args.trim());
+            for i in (1..=9).rev() {
+                let value = positional.get(i - 1).copied().unwrap_or("");
+                expanded = expanded.replace(&format!("${}", i), value);
+            }
+            expanded
+        })
+        .collect()
+}
+
+fn filter_prefix(options: &[String], prefix: &str) -> Vec<String> {
+    let prefix = prefix.to_lowercase();
+    options
+        .iter()
+        .filter(|o| o.to_lowercase().starts_with(&prefix))
+        .cloned()
+        .collect()
+}
+
+pub fn expand_tilde(path: &str) -> PathBuf {
+    if let Some(rest) = path.strip_prefix("~/")
+        && let Some(home) = std::env::var_os("HOME")
+    {
+        return PathBuf::from(home).join(rest);
+    }
+    PathBuf::from(path)
+}
+
+fn complete_path(partial: &str, cwd: &Path) -> Vec<String> {
+    let (dir_part, file_part) = match partial.rfind('/') {
+        Some(i) => (&partial[..=i], &partial[i + 1..]),
+        None => ("", partial),
+    };
+    let dir = cwd.join(expand_tilde(dir_part));
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+
+    let mut candidates: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            // Hide dotfiles unless the user started typing one
+            if !name.starts_with(file_part) || (name.starts_with('.') && !file_part.starts_with('.')) {
+                return None;
+            }
+            let suffix = if entry.path().is_dir() { "/" } else { "" };
+            Some(format!("{}{}{}", dir_part, name, suffix))
+        })
+        .collect();
+    candidates.sort();
+    candidates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_names_are_completed_once_in_order() {
+        let aliases = ["summary", "audit", "sync"].map(String::from);
+        let (start, candidates) = complete("s", Path::new("."), &[], &[], &[], &aliases);
+        assert_eq!(start, 0);
+        assert_eq!(candidates, ["s", "session", "split", "sync", "system", "set", "summary"]);
+    }
+
+    #[test]
+    fn paths_are_completed_in_the_given_directory() {
+        let dir = std::env::temp_dir().join(format!("ollama-tui-complete-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("notes")).unwrap();
+        fs::write(dir.join("notes.md"), "").unwrap();
+        fs::write(dir.join("notes").join("todo.md"), "").unwrap();
+        fs::write(dir.join(".hidden"), "").unwrap();
+
+        let (start, candidates) = complete("r no", &dir, &[], &[], &[], &[]);
+        assert_eq!(start, 2);
+        assert_eq!(candidates, ["notes.md", "notes/"]);
+        assert_eq!(complete("r notes/", &dir, &[], &[], &[], &[]).1, ["notes/todo.md"]);
+        assert_eq!(complete("r .h", &dir, &[], &[], &[], &[]).1, [".hidden"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
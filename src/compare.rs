@@ -0,0 +1,49 @@
+use crate::models::{Message, Role};
+use std::time::{Duration, Instant};
+
+/// One side of an A/B comparison.
+#[derive(Clone, Debug)]
+pub struct Reply {
+    pub model: String,
+    pub content: String,
+    /// How long the model took to finish, `None` while it is streaming
+    pub elapsed: Option<Duration>,
+}
+
+/// The same prompt sent to two models at once, shown side by side.
+#[derive(Clone, Debug)]
+pub struct Comparison {
+    pub session_id: i64,
+    pub prompt: String,
+    pub replies: [Reply; 2],
+    pub started: Instant,
+}
+
+impl Comparison {
+    pub fn new(session_id: i64, prompt: String, models: [String; 2]) -> Self {
+        Self {
+            session_id,
+            prompt,
+            replies: models.map(|model| Reply { model, content: String::new(), elapsed: None }),
+            started: Instant::now(),
+        }
+    }
+
+    pub fn finish(&mut self, side: usize) {
+        if let Some(reply) = self.replies.get_mut(side) {
+            reply.elapsed.get_or_insert(self.started.elapsed());
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.replies.iter().all(|reply| reply.elapsed.is_some())
+    }
+
+    /// Assistant messages recording both replies in the session, each headed by its model.
+    pub fn messages(&self) -> Vec<Message> {
+        self.replies
+            .iter()
+            .map(|reply| Message::new(Role::Assistant, format!("**{}**\n\n{}", reply.model, reply.content)))
+            .collect()
+    }
+}
@@ -1,8 +1,12 @@
-use crate::models::Config;
+use crate::models::{AuthMethod, Config};
+use crate::project::{parse_toml, toml_key, toml_string};
 use anyhow::{anyhow, Result};
 use directories::ProjectDirs;
+use serde_json::Value;
+use std::collections::HashMap;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
 impl Default for Config {
     fn default() -> Self {
@@ -13,28 +17,267 @@ impl Default for Config {
             auth_enabled: false,
             auth_method: None,
             theme: crate::models::Theme::default(),
+            aliases: HashMap::new(),
+            templates: HashMap::new(),
+            prompts: HashMap::new(),
+            notifications: crate::models::NotificationConfig::default(),
+            model_options: HashMap::new(),
+            command_policy: crate::models::CommandPolicy::default(),
+            env: HashMap::new(),
+            search: None,
+            plugins: Vec::new(),
+            images: crate::models::ImageConfig::default(),
+            server: crate::models::ServerConfig::default(),
+            encryption: crate::models::EncryptionConfig::default(),
+            redaction: crate::models::RedactionConfig::default(),
+            tick: crate::models::TickConfig::default(),
+            bench_prompts: Vec::new(),
+            sync_dir: String::new(),
+            keys: HashMap::new(),
         }
     }
 }
 
-pub fn get_config_path() -> Result<PathBuf> {
+// Comments written above each top-level setting of a new config.toml, in file order
+const COMMENTS: &[(&str, &str)] = &[
+    ("ollama_host", "Address of the Ollama server, OLLAMA_TUI_HOST overrides it"),
+    ("ollama_port", "OLLAMA_TUI_PORT overrides it"),
+    ("db_filename", "Chat history database, next to this file; OLLAMA_TUI_DB overrides it"),
+    ("auth_enabled", "Send credentials from a [bearer] (token) or [basic] (username, password) table;\nOLLAMA_TUI_TOKEN sets a bearer token"),
+    ("bearer", ""),
+    ("basic", ""),
+    ("bench_prompts", "Prompts run against each model by :bench, built-in ones when empty"),
+    ("sync_dir", "Directory :sync and `ollama-tui sync` keep one JSON file per session in, e.g. in git or Syncthing"),
+    ("theme", "Colors: names such as \"blue\" or \"dark_gray\", or \"#rrggbb\""),
+    ("aliases", "User-defined : commands, e.g. cold = [\"set temperature=0\", \"model $1\"]"),
+    ("templates", "Session presets for :new <template>, e.g. [templates.review] with model and system_prompt"),
+    ("prompts", "Prompt snippets for :prompt use <name>, {{name}} placeholders are asked for"),
+    ("notifications", "Bell and desktop notification when a response is ready"),
+    ("model_options", "Per-model :set defaults, e.g. [model_options.\"qwen2.5-coder\"] with temperature = \"0.2\""),
+    ("command_policy", "Rules checked before the agent runs a command: regular expressions or glob: wildcards"),
+    ("env", "Environment variables added to every agent command"),
+    ("search", "Provider of the agent's web_search tool: searxng, brave or duckduckgo"),
+    ("images", "Images in messages: protocol is auto, kitty, iterm2 or none, sizes in terminal cells"),
+    ("server", "HTTP API on 127.0.0.1 for editors and scripts, read at startup; it needs a token"),
+    ("encryption", "Encrypt conversation content in the database, read at startup, when the passphrase is asked for\n(OLLAMA_TUI_PASSPHRASE sets it); iterations are the PBKDF2 rounds used when encrypting"),
+    ("redaction", "Secrets replaced by [REDACTED:<name>] before messages are saved and command output is logged\nor sent to the model; patterns are regular expressions by name, \"\" turns off a built-in one"),
+    ("tick", "Milliseconds between checks for toasts expiring and the like: active_ms while a reply streams\nor something is about to change, idle_ms otherwise"),
+    ("keys", "Keys per mode and action, replacing the defaults, e.g. [keys.insert] send = \"ctrl+s\";\nthe README lists the actions"),
+    ("plugins", "External executables offered to the agent as tools, one [[plugins]] table each"),
+];
+
+// Environment variables overriding config settings, checked on every load
+const ENV_HOST: &str = "OLLAMA_TUI_HOST";
+const ENV_PORT: &str = "OLLAMA_TUI_PORT";
+const ENV_DB: &str = "OLLAMA_TUI_DB";
+const ENV_TOKEN: &str = "OLLAMA_TUI_TOKEN";
+
+fn config_dir() -> Result<PathBuf> {
     let proj_dirs = ProjectDirs::from("com", "rust-tui", "ollama-tui")
         .ok_or_else(|| anyhow!("Could not find a valid config directory."))?;
     let config_dir = proj_dirs.config_dir();
     fs::create_dir_all(config_dir)?;
-    Ok(config_dir.join("config.json"))
+    Ok(config_dir.to_path_buf())
 }
 
+pub fn get_config_path() -> Result<PathBuf> {
+    Ok(config_dir()?.join("config.toml"))
+}
+
+/// Turn a `config.json` from before the TOML format into `config.toml`, keeping the
+/// old file as `config.json.bak`. Returns the path of the new file when it migrated.
+pub fn migrate_json() -> Result<Option<PathBuf>> {
+    migrate_json_in(&config_dir()?)
+}
+
+fn migrate_json_in(dir: &Path) -> Result<Option<PathBuf>> {
+    let config_path = dir.join("config.toml");
+    let json_path = dir.join("config.json");
+    if config_path.exists() || !json_path.exists() {
+        return Ok(None);
+    }
+    let config: Config = serde_json::from_str(&fs::read_to_string(&json_path)?)
+        .map_err(|e| anyhow!("{}: {}", json_path.display(), e))?;
+    fs::write(&config_path, to_toml(&config)?)?;
+    fs::rename(&json_path, json_path.with_extension("json.bak"))?;
+    Ok(Some(config_path))
+}
+
+/// The config, migrated from `config.json` or created with the defaults when there is
+/// no `config.toml` yet. The TUI migrates first itself, to tell about it once it is up.
 pub fn load_or_create() -> Result<Config> {
-    let config_path = get_config_path()?;
-    if !config_path.exists() {
-        let config = Config::default();
-        fs::write(&config_path, serde_json::to_string_pretty(&config)?)?;
-        Ok(config)
-    } else {
-        let config_str = fs::read_to_string(config_path)?;
-        let config: Config = serde_json::from_str(&config_str)?;
-        Ok(config)
+    load_or_create_in(&config_dir()?)
+}
+
+fn load_or_create_in(dir: &Path) -> Result<Config> {
+    let config_path = dir.join("config.toml");
+    if let Some(path) = migrate_json_in(dir)? {
+        eprintln!("Settings of config.json moved to {}", path.display());
+    } else if !config_path.exists() {
+        fs::write(&config_path, to_toml(&Config::default())?)?;
     }
+    read(&config_path)
 }
 
+/// Read the config file and apply the environment overrides.
+pub fn read(path: &Path) -> Result<Config> {
+    let text = fs::read_to_string(path)?;
+    let value = parse_toml(&text).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+    let mut config: Config = serde_json::from_value(value).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+    apply_env(&mut config)?;
+    Ok(config)
+}
+
+fn apply_env(config: &mut Config) -> Result<()> {
+    if let Ok(host) = std::env::var(ENV_HOST) {
+        config.ollama_host = host;
+    }
+    if let Ok(port) = std::env::var(ENV_PORT) {
+        config.ollama_port = port.parse().map_err(|_| anyhow!("{} is not a port number: {}", ENV_PORT, port))?;
+    }
+    if let Ok(db_filename) = std::env::var(ENV_DB) {
+        config.db_filename = db_filename;
+    }
+    if let Ok(token) = std::env::var(ENV_TOKEN) {
+        config.auth_enabled = true;
+        config.auth_method = Some(AuthMethod::Bearer { token });
+    }
+    Ok(())
+}
+
+/// When the config file last changed, checked by the main loop to reload it.
+pub fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+/// The config as a commented `config.toml`.
+pub fn to_toml(config: &Config) -> Result<String> {
+    let Value::Object(root) = serde_json::to_value(config)? else {
+        return Err(anyhow!("Config is not a table"));
+    };
+    let comment = |key: &str| {
+        COMMENTS
+            .iter()
+            .find(|(name, _)| *name == key)
+            .map(|(_, text)| text.lines().map(|line| format!("# {}\n", line)).collect::<String>())
+            .unwrap_or_default()
+    };
+    // Keys in the order of COMMENTS, then the rest; plain values have to come before any table
+    let mut keys: Vec<&String> = root.keys().collect();
+    keys.sort_by_key(|key| COMMENTS.iter().position(|(name, _)| name == key).unwrap_or(COMMENTS.len()));
+    let (values, tables): (Vec<&String>, Vec<&String>) = keys.into_iter().partition(|key| !is_table(&root[*key]));
+
+    let mut out = String::from("# ollama-tui configuration, reloaded when saved\n");
+    for key in values {
+        if !root[key].is_null() {
+            out.push_str(&format!("\n{}{} = {}\n", comment(key), toml_key(key), inline_value(&root[key])));
+        }
+    }
+    for key in tables {
+        out.push('\n');
+        out.push_str(&comment(key));
+        write_table(&mut out, std::slice::from_ref(key), &root[key]);
+    }
+    // Optional sections left out of the file, so they are easy to find
+    for key in ["search"].into_iter().filter(|key| root.get(*key).is_none_or(Value::is_null)) {
+        out.push_str(&format!("\n{}# [{}]\n", comment(key), key));
+    }
+    Ok(out)
+}
+
+// Objects and arrays of objects are written as their own `[table]`s
+fn is_table(value: &Value) -> bool {
+    match value {
+        Value::Object(_) => true,
+        Value::Array(items) => !items.is_empty() && items.iter().all(Value::is_object),
+        _ => false,
+    }
+}
+
+fn write_table(out: &mut String, path: &[String], value: &Value) {
+    let header: Vec<String> = path.iter().map(|part| toml_key(part)).collect();
+    match value {
+        Value::Array(items) => {
+            for item in items {
+                out.push_str(&format!("[[{}]]\n", header.join(".")));
+                // Tables inside array entries stay inline, one level of headers is enough to read
+                if let Value::Object(map) = item {
+                    for (key, value) in map.iter().filter(|(_, value)| !value.is_null()) {
+                        out.push_str(&format!("{} = {}\n", toml_key(key), inline_value(value)));
+                    }
+                }
+            }
+        }
+        Value::Object(map) => {
+            let (values, tables): (Vec<_>, Vec<_>) = map.iter().filter(|(_, value)| !value.is_null()).partition(|(_, value)| !is_table(value));
+            // A table with only sub-tables needs no header of its own
+            if !values.is_empty() || tables.is_empty() {
+                out.push_str(&format!("[{}]\n", header.join(".")));
+            }
+            for (key, value) in values {
+                out.push_str(&format!("{} = {}\n", toml_key(key), inline_value(value)));
+            }
+            for (key, value) in tables {
+                let mut path = path.to_vec();
+                path.push(key.clone());
+                write_table(out, &path, value);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn inline_value(value: &Value) -> String {
+    match value {
+        // Text with line breaks, such as system prompts, is easier to edit as a multi-line string
+        Value::String(text) if text.contains('\n') && !text.contains("\"\"\"") && !text.ends_with('"') && !text.chars().any(|c| c.is_control() && c != '\n' && c != '\t') => {
+            format!("\"\"\"\n{}\"\"\"", text.replace('\\', "\\\\"))
+        }
+        // Literal strings keep regular expressions readable
+        Value::String(text) if text.contains('\\') && !text.contains('\'') && !text.chars().any(char::is_control) => {
+            format!("'{}'", text)
+        }
+        Value::String(text) => toml_string(text),
+        Value::Array(items) => {
+            let items: Vec<String> = items.iter().map(inline_value).collect();
+            format!("[{}]", items.join(", "))
+        }
+        Value::Object(map) => {
+            let entries: Vec<String> = map
+                .iter()
+                .filter(|(_, value)| !value.is_null())
+                .map(|(key, value)| format!("{} = {}", toml_key(key), inline_value(value)))
+                .collect();
+            if entries.is_empty() { "{}".to_string() } else { format!("{{ {} }}", entries.join(", ")) }
+        }
+        Value::Number(number) if number.is_f64() && !number.to_string().contains(['.', 'e']) => format!("{}.0", number),
+        Value::Null => "\"\"".to_string(),
+        value => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subcommands_migrate_config_json_before_creating_a_default() {
+        let dir = std::env::temp_dir().join(format!("ollama-tui-config-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("config.json"),
+            r#"{"ollama_host": "http://gpu-box", "ollama_port": 11434, "db_filename": "chats.sqlite",
+                "aliases": {"up": [":pull llama3"]}}"#,
+        )
+        .unwrap();
+
+        let config = load_or_create_in(&dir).unwrap();
+        assert_eq!(config.aliases["up"], vec![":pull llama3".to_string()]);
+        assert!(dir.join("config.toml").exists());
+        assert!(dir.join("config.json.bak").exists());
+        assert!(!dir.join("config.json").exists());
+        assert_eq!(load_or_create_in(&dir).unwrap().aliases["up"], vec![":pull llama3".to_string()]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,159 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Characters the whole snapshot may take up in the system prompt
+pub const CONTEXT_BUDGET: usize = 12_000;
+
+// Files whose opening lines say the most about a project
+const KEY_FILES: &[&str] = &[
+    "Cargo.toml",
+    "package.json",
+    "pyproject.toml",
+    "setup.py",
+    "go.mod",
+    "pom.xml",
+    "build.gradle",
+    "Gemfile",
+    "composer.json",
+    "CMakeLists.txt",
+    "Makefile",
+    "flake.nix",
+    "Dockerfile",
+];
+const README_NAMES: &[&str] = &["README.md", "README", "README.rst", "README.txt", "readme.md"];
+const HEADER_LINES: usize = 25;
+
+/// Project instruction files loaded for agent mode, in order of preference
+pub const INSTRUCTION_FILES: &[&str] = &["AGENTS.md", "AGENT.md", ".ollama-tui.md"];
+
+// Directories skipped when the project is not a git repository
+const SKIPPED_DIRS: &[&str] = &["target", "node_modules", "dist", "build", "vendor", "__pycache__"];
+const MAX_WALK_DEPTH: usize = 6;
+const MAX_WALK_FILES: usize = 5000;
+
+/// Compact description of the project in `root`: its file tree, the README and the
+/// first lines of manifests, cut down to roughly `budget` characters.
+pub fn snapshot(root: &Path, budget: usize) -> String {
+    let files = project_files(root);
+    let mut out = format!("Project snapshot of {} ({} files)\n\n", root.display(), files.len());
+
+    // The tree gets half the budget, the README and manifests share the rest
+    out.push_str("Files:\n");
+    out.push_str(&truncate(&tree(&files), budget / 2));
+
+    if let Some((name, text)) = README_NAMES
+        .iter()
+        .find_map(|name| fs::read_to_string(root.join(name)).ok().map(|text| (name, text)))
+    {
+        out.push_str(&format!("\n\n{}:\n", name));
+        out.push_str(&truncate(text.trim(), budget / 4));
+    }
+
+    for name in KEY_FILES {
+        let Ok(text) = fs::read_to_string(root.join(name)) else {
+            continue;
+        };
+        let header: Vec<&str> = text.lines().take(HEADER_LINES).collect();
+        let section = format!("\n\n{} (first {} lines):\n{}", name, header.len(), header.join("\n"));
+        if out.len() + section.len() > budget {
+            break;
+        }
+        out.push_str(&section);
+    }
+    truncate(&out, budget)
+}
+
+/// The first instruction file found in `dir` or its parents, up to the root of the git
+/// repository containing it.
+pub fn find_instructions(dir: &Path) -> Option<(PathBuf, String)> {
+    for dir in dir.ancestors() {
+        for name in INSTRUCTION_FILES {
+            let path = dir.join(name);
+            if let Ok(text) = fs::read_to_string(&path)
+                && !text.trim().is_empty()
+            {
+                return Some((path, text.trim().to_string()));
+            }
+        }
+        if dir.join(".git").exists() {
+            break;
+        }
+    }
+    None
+}
+
+/// Paths relative to `root`, honouring .gitignore when `root` is inside a git repository.
+fn project_files(root: &Path) -> Vec<String> {
+    let git = Command::new("git")
+        .current_dir(root)
+        .args(["ls-files", "--cached", "--others", "--exclude-standard"])
+        .output();
+    let mut files = match git {
+        Ok(output) if output.status.success() => String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(String::from)
+            .collect(),
+        _ => {
+            let mut files = Vec::new();
+            walk(root, "", 0, &mut files);
+            files
+        }
+    };
+    files.sort();
+    files
+}
+
+fn walk(dir: &Path, prefix: &str, depth: usize, files: &mut Vec<String>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        if files.len() >= MAX_WALK_FILES {
+            return;
+        }
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with('.') {
+            continue;
+        }
+        let path = format!("{}{}", prefix, name);
+        if entry.file_type().is_ok_and(|kind| kind.is_dir()) {
+            if depth < MAX_WALK_DEPTH && !SKIPPED_DIRS.contains(&name.as_str()) {
+                walk(&entry.path(), &format!("{}/", path), depth + 1, files);
+            }
+        } else {
+            files.push(path);
+        }
+    }
+}
+
+/// Indented tree of sorted relative paths, each directory listed once before its files.
+fn tree(files: &[String]) -> String {
+    let mut out = String::new();
+    let mut open: Vec<&str> = Vec::new();
+    for file in files {
+        let parts: Vec<&str> = file.split('/').collect();
+        let (name, dirs) = parts.split_last().unwrap_or((&"", &[]));
+        let common = open.iter().zip(dirs).take_while(|(a, b)| a == b).count();
+        open.truncate(common);
+        for dir in &dirs[common..] {
+            out.push_str(&format!("{}{}/\n", "  ".repeat(open.len()), dir));
+            open.push(dir);
+        }
+        out.push_str(&format!("{}{}\n", "  ".repeat(open.len()), name));
+    }
+    out.trim_end().to_string()
+}
+
+fn truncate(text: &str, limit: usize) -> String {
+    if text.len() <= limit {
+        return text.to_string();
+    }
+    let mut end = limit;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    // Cut at a line break so the snapshot does not end mid-line
+    let end = text[..end].rfind('\n').unwrap_or(end);
+    format!("{}\n... (truncated)", &text[..end])
+}
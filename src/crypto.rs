@@ -0,0 +1,247 @@
+// Encryption of message content at rest: AES-256-GCM with a key derived from a passphrase
+// by PBKDF2-HMAC-SHA256. The salt, the round count and a check value live in the config
+// table, so the key can be derived again and a wrong passphrase is noticed right away.
+
+use crate::db;
+use crate::models::EncryptionConfig;
+use anyhow::{anyhow, Result};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+use rusqlite::Connection;
+use std::io::Write;
+use std::num::NonZeroU32;
+use std::sync::OnceLock;
+
+// Encrypted content is this prefix, then the nonce and the ciphertext in hex
+const PREFIX: &str = "enc1:";
+const SALT_KEY: &str = "encryption_salt";
+const ITERATIONS_KEY: &str = "encryption_iterations";
+const CHECK_KEY: &str = "encryption_check";
+const CHECK_TEXT: &str = "ollama-tui";
+const ENV_PASSPHRASE: &str = "OLLAMA_TUI_PASSPHRASE";
+const ATTEMPTS: usize = 3;
+
+// Set once the passphrase was given; unset when the history is not encrypted
+static KEY: OnceLock<LessSafeKey> = OnceLock::new();
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok()).collect()
+}
+
+fn random<const N: usize>() -> Result<[u8; N]> {
+    let mut bytes = [0; N];
+    SystemRandom::new().fill(&mut bytes).map_err(|_| anyhow!("The system has no random numbers to offer"))?;
+    Ok(bytes)
+}
+
+fn derive(passphrase: &str, salt: &[u8], iterations: u32) -> Result<LessSafeKey> {
+    let iterations = NonZeroU32::new(iterations).ok_or_else(|| anyhow!("encryption.iterations has to be above 0"))?;
+    let mut key = [0; 32];
+    pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, iterations, salt, passphrase.as_bytes(), &mut key);
+    let key = UnboundKey::new(&AES_256_GCM, &key).map_err(|_| anyhow!("Cannot set up the encryption key"))?;
+    Ok(LessSafeKey::new(key))
+}
+
+fn seal_with(key: &LessSafeKey, text: &str) -> Result<String> {
+    let nonce = random::<NONCE_LEN>()?;
+    let mut data = text.as_bytes().to_vec();
+    key.seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut data)
+        .map_err(|_| anyhow!("Cannot encrypt the message"))?;
+    Ok(format!("{}{}{}", PREFIX, hex(&nonce), hex(&data)))
+}
+
+fn open_with(key: &LessSafeKey, text: &str) -> Result<String> {
+    let data = text
+        .strip_prefix(PREFIX)
+        .and_then(unhex)
+        .filter(|data| data.len() >= NONCE_LEN)
+        .ok_or_else(|| anyhow!("Malformed encrypted message"))?;
+    let (nonce, data) = data.split_at(NONCE_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| anyhow!("Malformed encrypted message"))?;
+    let mut data = data.to_vec();
+    let text = key
+        .open_in_place(nonce, Aad::empty(), &mut data)
+        .map_err(|_| anyhow!("Cannot decrypt a message, it was changed or encrypted with another key"))?;
+    Ok(String::from_utf8(text.to_vec())?)
+}
+
+/// `text` as stored: encrypted when the history is, as it is otherwise.
+pub fn seal(text: &str) -> Result<String> {
+    match KEY.get() {
+        Some(key) => seal_with(key, text),
+        None => Ok(text.to_string()),
+    }
+}
+
+/// Whether stored content is encrypted.
+pub fn enabled() -> bool {
+    KEY.get().is_some()
+}
+
+/// Stored content as written; without a key nothing is encrypted, so it is returned as is.
+pub fn open(text: &str) -> Result<String> {
+    match KEY.get() {
+        Some(key) if text.starts_with(PREFIX) => open_with(key, text),
+        _ => Ok(text.to_string()),
+    }
+}
+
+// Echo is off while the passphrase is typed, and back on when this is dropped
+#[cfg(unix)]
+struct HiddenInput(Option<libc::termios>);
+
+#[cfg(unix)]
+impl HiddenInput {
+    fn new() -> Self {
+        // SAFETY: termios is plain data, and the calls only read and set stdin's terminal flags
+        unsafe {
+            let mut term: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut term) != 0 {
+                return Self(None);
+            }
+            let saved = term;
+            term.c_lflag &= !libc::ECHO;
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &term);
+            Self(Some(saved))
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for HiddenInput {
+    fn drop(&mut self) {
+        if let Some(saved) = &self.0 {
+            // SAFETY: restores the flags read in `new`
+            unsafe {
+                libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, saved);
+            }
+        }
+    }
+}
+
+// Other platforms show the passphrase as it is typed
+#[cfg(not(unix))]
+struct HiddenInput;
+
+#[cfg(not(unix))]
+impl HiddenInput {
+    fn new() -> Self {
+        Self
+    }
+}
+
+fn read_passphrase(prompt: &str) -> Result<String> {
+    eprint!("{}", prompt);
+    std::io::stderr().flush()?;
+    let hidden = HiddenInput::new();
+    let mut line = String::new();
+    let read = std::io::stdin().read_line(&mut line)?;
+    drop(hidden);
+    eprintln!();
+    if read == 0 {
+        return Err(anyhow!("The chat history is encrypted and no passphrase was given, set {}", ENV_PASSPHRASE));
+    }
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+fn new_passphrase() -> Result<String> {
+    if let Ok(passphrase) = std::env::var(ENV_PASSPHRASE) {
+        return Ok(passphrase);
+    }
+    loop {
+        let passphrase = read_passphrase("New passphrase for the chat history: ")?;
+        if passphrase.is_empty() {
+            continue;
+        }
+        if read_passphrase("Repeat it: ")? == passphrase {
+            return Ok(passphrase);
+        }
+        eprintln!("The passphrases differ, try again");
+    }
+}
+
+fn existing_key(salt: &[u8], iterations: u32, check: &str) -> Result<LessSafeKey> {
+    let matches = |key: &LessSafeKey| open_with(key, check).is_ok_and(|text| text == CHECK_TEXT);
+    if let Ok(passphrase) = std::env::var(ENV_PASSPHRASE) {
+        let key = derive(&passphrase, salt, iterations)?;
+        return if matches(&key) { Ok(key) } else { Err(anyhow!("{} is not the passphrase of the chat history", ENV_PASSPHRASE)) };
+    }
+    for _ in 0..ATTEMPTS {
+        let key = derive(&read_passphrase("Passphrase for the chat history: ")?, salt, iterations)?;
+        if matches(&key) {
+            return Ok(key);
+        }
+        eprintln!("Wrong passphrase");
+    }
+    Err(anyhow!("Wrong passphrase for the chat history"))
+}
+
+// Rewritten rows leave their old content in free pages of the file until it is rebuilt
+fn vacuum(conn: &Connection) -> Result<()> {
+    conn.execute_batch("VACUUM")?;
+    Ok(())
+}
+
+/// Ask for the passphrase when the history is encrypted, or about to be because `config`
+/// turns encryption on; stored messages are encrypted or decrypted when the setting changed.
+/// Call before anything reads messages.
+pub fn unlock(conn: &Connection, config: &EncryptionConfig) -> Result<()> {
+    if KEY.get().is_some() {
+        return Ok(());
+    }
+    let Some(salt) = db::load_config(conn, SALT_KEY)? else {
+        if !config.enabled {
+            return Ok(());
+        }
+        let passphrase = new_passphrase()?;
+        let salt = random::<16>()?;
+        let key = derive(&passphrase, &salt, config.iterations)?;
+        let tx = conn.unchecked_transaction()?;
+        for (table, column) in db::SEALED_COLUMNS {
+            db::rewrite_column(&tx, table, column, |value| seal_with(&key, value))?;
+        }
+        // Cached replies are dropped rather than converted, they are only a shortcut
+        db::clear_cached_responses(&tx)?;
+        db::save_config(&tx, SALT_KEY, &hex(&salt))?;
+        db::save_config(&tx, ITERATIONS_KEY, &config.iterations.to_string())?;
+        db::save_config(&tx, CHECK_KEY, &seal_with(&key, CHECK_TEXT)?)?;
+        tx.commit()?;
+        vacuum(conn)?;
+        eprintln!("Chat history encrypted");
+        KEY.set(key).ok();
+        return Ok(());
+    };
+
+    let salt = unhex(&salt).ok_or_else(|| anyhow!("The stored encryption salt is damaged"))?;
+    let iterations = db::load_config(conn, ITERATIONS_KEY)?
+        .and_then(|iterations| iterations.parse().ok())
+        .ok_or_else(|| anyhow!("The stored encryption settings are damaged"))?;
+    let check = db::load_config(conn, CHECK_KEY)?.unwrap_or_default();
+    let key = existing_key(&salt, iterations, &check)?;
+    if config.enabled {
+        KEY.set(key).ok();
+        return Ok(());
+    }
+    let tx = conn.unchecked_transaction()?;
+    for (table, column) in db::SEALED_COLUMNS {
+        db::rewrite_column(&tx, table, column, |value| {
+            if value.starts_with(PREFIX) { open_with(&key, value) } else { Ok(value.to_string()) }
+        })?;
+    }
+    db::clear_cached_responses(&tx)?;
+    for name in [SALT_KEY, ITERATIONS_KEY, CHECK_KEY] {
+        db::delete_config(&tx, name)?;
+    }
+    tx.commit()?;
+    vacuum(conn)?;
+    eprintln!("Chat history decrypted");
+    Ok(())
+}
@@ -1,7 +1,12 @@
-use crate::models::{ChatSession, Message, Role};
+use crate::agent::{AgentState, AgentStep, AuditEntry, AutonomousRun};
+use crate::crypto;
+use crate::memory::Memory;
+use crate::models::{ChatSession, Message, Role, SessionTemplate};
+use crate::rag::{self, Chunk};
 use anyhow::Result;
 use chrono::{DateTime, Utc};
 use rusqlite::{params, Connection, Row};
+use std::collections::HashMap;
 use std::path::Path;
 
 pub fn get_connection(db_path: &Path) -> Result<Connection> {
@@ -10,6 +15,23 @@ pub fn get_connection(db_path: &Path) -> Result<Connection> {
     Ok(conn)
 }
 
+const AUDIT_LOG_NO_UPDATE: &str = "CREATE TRIGGER IF NOT EXISTS audit_log_no_update BEFORE UPDATE ON audit_log
+    BEGIN SELECT RAISE(ABORT, 'audit_log is append-only'); END;";
+
+/// Columns with conversation content, written through `crypto::seal` so they are
+/// encrypted along with the messages.
+pub const SEALED_COLUMNS: &[(&str, &str)] = &[
+    ("messages", "content"),
+    ("memories", "fact"),
+    ("knowledge_chunks", "content"),
+    ("agent_runs", "goal"),
+    ("agent_runs", "plan"),
+    ("agent_steps", "thought"),
+    ("agent_steps", "output"),
+    ("agent_steps", "analysis"),
+    ("audit_log", "output"),
+];
+
 fn setup_database(conn: &Connection) -> Result<()> {
     conn.execute_batch(
         "BEGIN;
@@ -29,8 +51,107 @@ fn setup_database(conn: &Connection) -> Result<()> {
             key TEXT PRIMARY KEY,
             value TEXT NOT NULL
         );
+        CREATE TABLE IF NOT EXISTS command_history (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            command TEXT NOT NULL,
+            executed_at TEXT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS agent_runs (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            session_id INTEGER NOT NULL,
+            goal TEXT NOT NULL,
+            plan TEXT NOT NULL,
+            state TEXT NOT NULL,
+            pending_command TEXT,
+            created_at TEXT NOT NULL,
+            updated_at TEXT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS agent_steps (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            run_id INTEGER NOT NULL,
+            step_index INTEGER NOT NULL,
+            thought TEXT NOT NULL,
+            command TEXT,
+            output TEXT,
+            success INTEGER,
+            analysis TEXT,
+            FOREIGN KEY (run_id) REFERENCES agent_runs (id)
+        );
+        CREATE TABLE IF NOT EXISTS audit_log (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            executed_at TEXT NOT NULL,
+            session_id INTEGER NOT NULL,
+            command TEXT NOT NULL,
+            cwd TEXT NOT NULL,
+            risk TEXT NOT NULL,
+            approval TEXT NOT NULL,
+            dry_run INTEGER NOT NULL,
+            exit_code INTEGER,
+            output TEXT NOT NULL
+        );
+        CREATE TRIGGER IF NOT EXISTS audit_log_no_delete BEFORE DELETE ON audit_log
+        BEGIN SELECT RAISE(ABORT, 'audit_log is append-only'); END;
+        CREATE TABLE IF NOT EXISTS templates (
+            name TEXT PRIMARY KEY,
+            data TEXT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS prompts (
+            name TEXT PRIMARY KEY,
+            text TEXT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS memories (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            fact TEXT NOT NULL UNIQUE,
+            session_id INTEGER,
+            created_at TEXT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS sync_state (
+            dir TEXT NOT NULL,
+            sync_id TEXT NOT NULL,
+            session_id INTEGER NOT NULL,
+            hash TEXT NOT NULL,
+            PRIMARY KEY (dir, sync_id)
+        );
+        CREATE TABLE IF NOT EXISTS knowledge_chunks (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            session_id INTEGER NOT NULL,
+            source TEXT NOT NULL,
+            content TEXT NOT NULL,
+            embedding BLOB NOT NULL,
+            FOREIGN KEY (session_id) REFERENCES sessions (id)
+        );
+        CREATE TABLE IF NOT EXISTS response_cache (
+            key TEXT PRIMARY KEY,
+            content TEXT NOT NULL,
+            created_at TEXT NOT NULL
+        );
         COMMIT;",
     )?;
+    conn.execute_batch(AUDIT_LOG_NO_UPDATE)?;
+    add_column_if_missing(conn, "messages", "pinned", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(conn, "sessions", "system_prompt", "TEXT")?;
+    add_column_if_missing(conn, "messages", "created_at", "TEXT")?;
+    add_column_if_missing(conn, "agent_steps", "exit_code", "INTEGER")?;
+    add_column_if_missing(conn, "agent_steps", "tool", "TEXT")?;
+    add_column_if_missing(conn, "agent_runs", "pending_tool", "TEXT")?;
+    add_column_if_missing(conn, "sessions", "working_dir", "TEXT")?;
+    add_column_if_missing(conn, "sessions", "options", "TEXT")?;
+    Ok(())
+}
+
+/// Add a column to an existing table, for databases created by older versions.
+fn add_column_if_missing(conn: &Connection, table: &str, column: &str, definition: &str) -> Result<()> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let exists = stmt
+        .query_map([], |row| row.get::<_, String>(1))?
+        .filter_map(|name| name.ok())
+        .any(|name| name == column);
+    if !exists {
+        conn.execute(
+            &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition),
+            [],
+        )?;
+    }
     Ok(())
 }
 
@@ -56,24 +177,75 @@ pub fn load_config(conn: &Connection, key: &str) -> Result<Option<String>> {
     Ok(value.ok())
 }
 
+pub fn delete_config(conn: &Connection, key: &str) -> Result<()> {
+    conn.execute("DELETE FROM config WHERE key = ?1", params![key])?;
+    Ok(())
+}
+
 pub fn save_session(conn: &Connection, session: &mut ChatSession) -> Result<()> {
     conn.execute(
-        "INSERT INTO sessions (name, created_at) VALUES (?1, ?2)",
-        params![session.name, session.created_at.to_rfc3339()],
+        "INSERT INTO sessions (name, created_at, system_prompt, working_dir) VALUES (?1, ?2, ?3, ?4)",
+        params![session.name, session.created_at.to_rfc3339(), session.system_prompt, session.working_dir],
     )?;
     session.id = conn.last_insert_rowid();
     Ok(())
 }
 
-pub fn save_message(conn: &Connection, session_id: i64, message: &Message) -> Result<()> {
+pub fn rename_session(conn: &Connection, session_id: i64, name: &str) -> Result<()> {
+    conn.execute("UPDATE sessions SET name = ?1 WHERE id = ?2", params![name, session_id])?;
+    Ok(())
+}
+
+pub fn set_session_system_prompt(conn: &Connection, session_id: i64, system_prompt: Option<&str>) -> Result<()> {
+    conn.execute(
+        "UPDATE sessions SET system_prompt = ?1 WHERE id = ?2",
+        params![system_prompt, session_id],
+    )?;
+    Ok(())
+}
+
+pub fn set_session_options(conn: &Connection, session_id: i64, options: &HashMap<String, String>) -> Result<()> {
+    let options = if options.is_empty() { None } else { Some(serde_json::to_string(options)?) };
+    conn.execute("UPDATE sessions SET options = ?1 WHERE id = ?2", params![options, session_id])?;
+    Ok(())
+}
+
+pub fn set_session_working_dir(conn: &Connection, session_id: i64, working_dir: Option<&str>) -> Result<()> {
+    conn.execute(
+        "UPDATE sessions SET working_dir = ?1 WHERE id = ?2",
+        params![working_dir, session_id],
+    )?;
+    Ok(())
+}
+
+pub fn save_message(conn: &Connection, session_id: i64, message: &Message) -> Result<i64> {
+    save_message_at(conn, session_id, message, &Utc::now().to_rfc3339())
+}
+
+/// Save a message written at `created_at` (RFC 3339), such as one synced from another machine.
+pub fn save_message_at(conn: &Connection, session_id: i64, message: &Message, created_at: &str) -> Result<i64> {
     let role_str = match message.role {
         Role::User => "user",
         Role::Assistant => "assistant",
+        Role::System => "system",
     };
     conn.execute(
-        "INSERT INTO messages (session_id, role, content) VALUES (?1, ?2, ?3)",
-        params![session_id, role_str, message.content],
+        "INSERT INTO messages (session_id, role, content, created_at) VALUES (?1, ?2, ?3, ?4)",
+        params![session_id, role_str, crypto::seal(&message.content)?, created_at],
     )?;
+    Ok(conn.last_insert_rowid())
+}
+
+pub fn set_message_pinned(conn: &Connection, message_id: i64, pinned: bool) -> Result<()> {
+    conn.execute(
+        "UPDATE messages SET pinned = ?1 WHERE id = ?2",
+        params![pinned, message_id],
+    )?;
+    Ok(())
+}
+
+pub fn delete_message(conn: &Connection, message_id: i64) -> Result<()> {
+    conn.execute("DELETE FROM messages WHERE id = ?1", params![message_id])?;
     Ok(())
 }
 
@@ -84,20 +256,40 @@ pub fn clear_messages_for_session(conn: &Connection, session_id: i64) -> Result<
 
 pub fn load_sessions(conn: &Connection) -> Result<Vec<ChatSession>> {
     let mut stmt =
-        conn.prepare("SELECT id, name, created_at FROM sessions ORDER BY created_at ASC")?;
+        conn.prepare(
+            "SELECT s.id, s.name, s.created_at, s.system_prompt,
+                (SELECT max(m.created_at) FROM messages m WHERE m.session_id = s.id), s.working_dir,
+                EXISTS (SELECT 1 FROM knowledge_chunks k WHERE k.session_id = s.id), s.options
+            FROM sessions s ORDER BY s.created_at ASC",
+        )?;
     let session_iter = stmt.query_map([], |row| {
         let id: i64 = row.get(0)?;
         let name: String = row.get(1)?;
         let created_at_str: String = row.get(2)?;
+        let system_prompt: Option<String> = row.get(3)?;
+        let last_message_at: Option<String> = row.get(4)?;
+        let working_dir: Option<String> = row.get(5)?;
+        let knowledge: bool = row.get(6)?;
+        let options: Option<String> = row.get(7)?;
         let created_at = DateTime::parse_from_rfc3339(&created_at_str)
             .map(|dt| dt.with_timezone(&Utc))
             .unwrap_or_else(|_| Utc::now());
+        // Messages saved before timestamps were recorded fall back to the session creation time
+        let last_activity = last_message_at
+            .and_then(|t| DateTime::parse_from_rfc3339(&t).ok())
+            .map(|dt| dt.with_timezone(&Utc))
+            .unwrap_or(created_at);
 
         Ok(ChatSession {
             id,
             name,
             messages: Vec::new(),
             created_at,
+            system_prompt,
+            last_activity,
+            working_dir,
+            knowledge,
+            options: options.and_then(|options| serde_json::from_str(&options).ok()).unwrap_or_default(),
         })
     })?;
 
@@ -110,18 +302,37 @@ pub fn load_sessions(conn: &Connection) -> Result<Vec<ChatSession>> {
     Ok(sessions)
 }
 
+// A column written through `crypto::seal`, as it was before
+fn opened(row: &Row, index: usize) -> rusqlite::Result<String> {
+    crypto::open(&row.get::<_, String>(index)?)
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(index, rusqlite::types::Type::Text, e.into()))
+}
+
+fn opened_optional(row: &Row, index: usize) -> rusqlite::Result<Option<String>> {
+    match row.get::<_, Option<String>>(index)? {
+        Some(_) => opened(row, index).map(Some),
+        None => Ok(None),
+    }
+}
+
 fn load_messages_for_session(conn: &Connection, session_id: i64) -> Result<Vec<Message>> {
     let mut stmt = conn
-        .prepare("SELECT role, content FROM messages WHERE session_id = ?1 ORDER BY id ASC")?;
+        .prepare("SELECT id, role, content, pinned FROM messages WHERE session_id = ?1 ORDER BY id ASC")?;
     let message_iter = stmt.query_map(params![session_id], |row: &Row| {
-        let role_str: String = row.get(0)?;
-        let content: String = row.get(1)?;
-        let role = if role_str == "user" {
-            Role::User
-        } else {
-            Role::Assistant
+        let id: i64 = row.get(0)?;
+        let role_str: String = row.get(1)?;
+        let content = opened(row, 2)?;
+        let pinned: bool = row.get(3)?;
+        let role = match role_str.as_str() {
+            "user" => Role::User,
+            "system" => Role::System,
+            _ => Role::Assistant,
         };
-        Ok(Message { role, content })
+        Ok(Message {
+            id: Some(id),
+            pinned,
+            ..Message::new(role, content)
+        })
     })?;
 
     let mut messages = Vec::new();
@@ -131,6 +342,42 @@ fn load_messages_for_session(conn: &Connection, session_id: i64) -> Result<Vec<M
     Ok(messages)
 }
 
+/// When each message of the session was written, in the order of `load_sessions`; `None`
+/// for messages saved before timestamps were recorded.
+pub fn load_message_times(conn: &Connection, session_id: i64) -> Result<Vec<Option<String>>> {
+    let mut stmt = conn.prepare("SELECT created_at FROM messages WHERE session_id = ?1 ORDER BY id ASC")?;
+    let times = stmt
+        .query_map(params![session_id], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(times)
+}
+
+/// Replace every value of one of `SEALED_COLUMNS` as stored with `convert(value)`, for
+/// encrypting or decrypting the history.
+pub fn rewrite_column(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    mut convert: impl FnMut(&str) -> Result<String>,
+) -> Result<()> {
+    let mut stmt = conn.prepare(&format!("SELECT id, {} FROM {} WHERE {} IS NOT NULL", column, table, column))?;
+    let values = stmt
+        .query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    // The audit log is append-only, except for this
+    if table == "audit_log" {
+        conn.execute("DROP TRIGGER IF EXISTS audit_log_no_update", [])?;
+    }
+    let mut update = conn.prepare(&format!("UPDATE {} SET {} = ?1 WHERE id = ?2", table, column))?;
+    for (id, value) in values {
+        update.execute(params![convert(&value)?, id])?;
+    }
+    if table == "audit_log" {
+        conn.execute_batch(AUDIT_LOG_NO_UPDATE)?;
+    }
+    Ok(())
+}
+
 pub fn delete_session(conn: &Connection, session_id: i64) -> Result<()> {
     // Delete all messages for this session first
     conn.execute(
@@ -138,6 +385,8 @@ pub fn delete_session(conn: &Connection, session_id: i64) -> Result<()> {
         params![session_id],
     )?;
 
+    delete_knowledge_chunks(conn, session_id)?;
+
     // Delete the session itself
     conn.execute(
         "DELETE FROM sessions WHERE id = ?1",
@@ -147,3 +396,314 @@ pub fn delete_session(conn: &Connection, session_id: i64) -> Result<()> {
     Ok(())
 }
 
+pub fn save_knowledge_chunks(conn: &Connection, session_id: i64, chunks: &[Chunk]) -> Result<()> {
+    let tx = conn.unchecked_transaction()?;
+    for chunk in chunks {
+        tx.execute(
+            "INSERT INTO knowledge_chunks (session_id, source, content, embedding) VALUES (?1, ?2, ?3, ?4)",
+            params![session_id, chunk.source, crypto::seal(&chunk.content)?, rag::encode_embedding(&chunk.embedding)],
+        )?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+pub fn load_knowledge_chunks(conn: &Connection, session_id: i64) -> Result<Vec<Chunk>> {
+    let mut stmt =
+        conn.prepare("SELECT source, content, embedding FROM knowledge_chunks WHERE session_id = ?1 ORDER BY id")?;
+    let chunks = stmt
+        .query_map(params![session_id], |row| {
+            let embedding: Vec<u8> = row.get(2)?;
+            Ok(Chunk {
+                source: row.get(0)?,
+                content: opened(row, 1)?,
+                embedding: rag::decode_embedding(&embedding),
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(chunks)
+}
+
+pub fn delete_knowledge_chunks(conn: &Connection, session_id: i64) -> Result<()> {
+    conn.execute("DELETE FROM knowledge_chunks WHERE session_id = ?1", params![session_id])?;
+    Ok(())
+}
+
+/// Remember a fact, returning its id, or `None` when it is already known.
+pub fn save_memory(conn: &Connection, fact: &str, session_id: Option<i64>) -> Result<Option<i64>> {
+    // An encrypted fact differs every time it is sealed, so UNIQUE alone does not catch repeats
+    if crypto::enabled() && load_memories(conn)?.iter().any(|memory| memory.fact == fact) {
+        return Ok(None);
+    }
+    let inserted = conn.execute(
+        "INSERT OR IGNORE INTO memories (fact, session_id, created_at) VALUES (?1, ?2, ?3)",
+        params![crypto::seal(fact)?, session_id, Utc::now().to_rfc3339()],
+    )?;
+    Ok((inserted > 0).then(|| conn.last_insert_rowid()))
+}
+
+pub fn load_memories(conn: &Connection) -> Result<Vec<Memory>> {
+    let mut stmt = conn.prepare("SELECT id, fact FROM memories ORDER BY id")?;
+    let memories = stmt
+        .query_map([], |row| Ok(Memory { id: row.get(0)?, fact: opened(row, 1)? }))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(memories)
+}
+
+pub fn delete_memory(conn: &Connection, id: i64) -> Result<()> {
+    conn.execute("DELETE FROM memories WHERE id = ?1", params![id])?;
+    Ok(())
+}
+
+pub fn clear_memories(conn: &Connection) -> Result<()> {
+    conn.execute("DELETE FROM memories", [])?;
+    Ok(())
+}
+
+/// Sessions synced with the directory `dir`: sync id, session id and the hash of the
+/// session as last synced.
+pub fn load_sync_state(conn: &Connection, dir: &str) -> Result<Vec<(String, i64, String)>> {
+    let mut stmt = conn.prepare("SELECT sync_id, session_id, hash FROM sync_state WHERE dir = ?1")?;
+    let rows = stmt
+        .query_map(params![dir], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(rows)
+}
+
+pub fn save_sync_state(conn: &Connection, dir: &str, sync_id: &str, session_id: i64, hash: &str) -> Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO sync_state (dir, sync_id, session_id, hash) VALUES (?1, ?2, ?3, ?4)",
+        params![dir, sync_id, session_id, hash],
+    )?;
+    Ok(())
+}
+
+pub fn delete_sync_state(conn: &Connection, dir: &str, sync_id: &str) -> Result<()> {
+    conn.execute("DELETE FROM sync_state WHERE dir = ?1 AND sync_id = ?2", params![dir, sync_id])?;
+    Ok(())
+}
+
+/// Reply cached under `key`, as stored.
+pub fn load_cached_response(conn: &Connection, key: &str) -> Result<Option<String>> {
+    let content = conn.query_row("SELECT content FROM response_cache WHERE key = ?1", params![key], |row| row.get(0));
+    Ok(content.ok())
+}
+
+pub fn save_cached_response(conn: &Connection, key: &str, content: &str) -> Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO response_cache (key, content, created_at) VALUES (?1, ?2, ?3)",
+        params![key, content, Utc::now().to_rfc3339()],
+    )?;
+    Ok(())
+}
+
+pub fn count_cached_responses(conn: &Connection) -> Result<usize> {
+    let count: i64 = conn.query_row("SELECT count(*) FROM response_cache", [], |row| row.get(0))?;
+    Ok(count as usize)
+}
+
+pub fn clear_cached_responses(conn: &Connection) -> Result<usize> {
+    Ok(conn.execute("DELETE FROM response_cache", [])?)
+}
+
+pub fn save_command_history(conn: &Connection, command: &str) -> Result<()> {
+    conn.execute(
+        "INSERT INTO command_history (command, executed_at) VALUES (?1, ?2)",
+        params![command, Utc::now().to_rfc3339()],
+    )?;
+    Ok(())
+}
+
+/// Most recent `limit` history entries, oldest first.
+pub fn load_command_history(conn: &Connection, limit: usize) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare(
+        "SELECT command FROM (SELECT id, command FROM command_history ORDER BY id DESC LIMIT ?1) ORDER BY id ASC",
+    )?;
+    let history = stmt
+        .query_map(params![limit as i64], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<String>>>()?;
+    Ok(history)
+}
+
+pub fn save_template(conn: &Connection, name: &str, template: &SessionTemplate) -> Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO templates (name, data) VALUES (?1, ?2)",
+        params![name, serde_json::to_string(template)?],
+    )?;
+    Ok(())
+}
+
+pub fn delete_template(conn: &Connection, name: &str) -> Result<bool> {
+    let deleted = conn.execute("DELETE FROM templates WHERE name = ?1", params![name])?;
+    Ok(deleted > 0)
+}
+
+pub fn load_templates(conn: &Connection) -> Result<Vec<(String, SessionTemplate)>> {
+    let mut stmt = conn.prepare("SELECT name, data FROM templates ORDER BY name ASC")?;
+    let rows = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?;
+    let mut templates = Vec::new();
+    for row in rows {
+        let (name, data) = row?;
+        // Skip rows that no longer match the template format
+        if let Ok(template) = serde_json::from_str(&data) {
+            templates.push((name, template));
+        }
+    }
+    Ok(templates)
+}
+
+pub fn save_prompt(conn: &Connection, name: &str, text: &str) -> Result<()> {
+    conn.execute("INSERT OR REPLACE INTO prompts (name, text) VALUES (?1, ?2)", params![name, text])?;
+    Ok(())
+}
+
+pub fn delete_prompt(conn: &Connection, name: &str) -> Result<bool> {
+    let deleted = conn.execute("DELETE FROM prompts WHERE name = ?1", params![name])?;
+    Ok(deleted > 0)
+}
+
+pub fn load_prompts(conn: &Connection) -> Result<Vec<(String, String)>> {
+    let mut stmt = conn.prepare("SELECT name, text FROM prompts ORDER BY name ASC")?;
+    let prompts = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(prompts)
+}
+
+/// Insert or update an autonomous run together with all of its steps.
+pub fn save_agent_run(conn: &Connection, run: &mut AutonomousRun) -> Result<()> {
+    let now = Utc::now().to_rfc3339();
+    let plan = crypto::seal(&serde_json::to_string(&run.plan)?)?;
+    let pending_tool = run.pending_tool.as_ref().map(serde_json::to_string).transpose()?;
+    if run.id == 0 {
+        conn.execute(
+            "INSERT INTO agent_runs (session_id, goal, plan, state, pending_command, pending_tool, created_at, updated_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?7)",
+            params![run.session_id, crypto::seal(&run.goal)?, plan, run.state.label(), run.pending_command, pending_tool, now],
+        )?;
+        run.id = conn.last_insert_rowid();
+    } else {
+        conn.execute(
+            "UPDATE agent_runs SET plan = ?1, state = ?2, pending_command = ?3, pending_tool = ?4, updated_at = ?5 WHERE id = ?6",
+            params![plan, run.state.label(), run.pending_command, pending_tool, now, run.id],
+        )?;
+    }
+
+    // Steps are few, rewriting them keeps this simpler than tracking changes
+    conn.execute("DELETE FROM agent_steps WHERE run_id = ?1", params![run.id])?;
+    for (index, step) in run.steps.iter().enumerate() {
+        let (output, success) = match &step.output {
+            Some(Ok(output)) => (Some(crypto::seal(output)?), Some(true)),
+            Some(Err(error)) => (Some(crypto::seal(error)?), Some(false)),
+            None => (None, None),
+        };
+        let analysis = step.analysis.as_deref().map(crypto::seal).transpose()?;
+        let tool = step.tool.as_ref().map(serde_json::to_string).transpose()?;
+        conn.execute(
+            "INSERT INTO agent_steps (run_id, step_index, thought, command, tool, output, success, exit_code, analysis)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![run.id, index as i64, crypto::seal(&step.thought)?, step.command, tool, output, success, step.exit_code, analysis],
+        )?;
+    }
+    Ok(())
+}
+
+/// All autonomous runs, newest first.
+pub fn load_agent_runs(conn: &Connection) -> Result<Vec<AutonomousRun>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, session_id, goal, plan, state, pending_command, pending_tool FROM agent_runs ORDER BY id DESC",
+    )?;
+    let run_iter = stmt.query_map([], |row| {
+        let plan = opened(row, 3)?;
+        let state: String = row.get(4)?;
+        let mut run = AutonomousRun::new(row.get(1)?, opened(row, 2)?);
+        run.id = row.get(0)?;
+        run.plan = serde_json::from_str(&plan).unwrap_or_default();
+        run.state = AgentState::from_label(&state);
+        run.pending_command = row.get(5)?;
+        let pending_tool: Option<String> = row.get(6)?;
+        run.pending_tool = pending_tool.and_then(|tool| serde_json::from_str(&tool).ok());
+        Ok(run)
+    })?;
+
+    let mut runs = Vec::new();
+    for run_result in run_iter {
+        let mut run = run_result?;
+        run.steps = load_agent_steps(conn, run.id)?;
+        runs.push(run);
+    }
+    Ok(runs)
+}
+
+fn load_agent_steps(conn: &Connection, run_id: i64) -> Result<Vec<AgentStep>> {
+    let mut stmt = conn.prepare(
+        "SELECT thought, command, tool, output, success, exit_code, analysis FROM agent_steps WHERE run_id = ?1 ORDER BY step_index ASC",
+    )?;
+    let steps = stmt
+        .query_map(params![run_id], |row| {
+            let tool: Option<String> = row.get(2)?;
+            let output = opened_optional(row, 3)?;
+            let success: Option<bool> = row.get(4)?;
+            Ok(AgentStep {
+                thought: opened(row, 0)?,
+                command: row.get(1)?,
+                tool: tool.and_then(|tool| serde_json::from_str(&tool).ok()),
+                output: match (output, success) {
+                    (Some(output), Some(false)) => Some(Err(output)),
+                    (Some(output), _) => Some(Ok(output)),
+                    (None, _) => None,
+                },
+                exit_code: row.get(5)?,
+                analysis: opened_optional(row, 6)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<AgentStep>>>()?;
+    Ok(steps)
+}
+
+pub fn append_audit_entry(conn: &Connection, entry: &AuditEntry) -> Result<()> {
+    conn.execute(
+        "INSERT INTO audit_log (executed_at, session_id, command, cwd, risk, approval, dry_run, exit_code, output)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        params![
+            entry.executed_at.to_rfc3339(),
+            entry.session_id,
+            entry.command,
+            entry.cwd,
+            entry.risk,
+            entry.approval,
+            entry.dry_run,
+            entry.exit_code,
+            crypto::seal(&entry.output)?
+        ],
+    )?;
+    Ok(())
+}
+
+/// The most recent audit log entries, newest first.
+pub fn load_audit_log(conn: &Connection, limit: usize) -> Result<Vec<AuditEntry>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, executed_at, session_id, command, cwd, risk, approval, dry_run, exit_code, output
+         FROM audit_log ORDER BY id DESC LIMIT ?1",
+    )?;
+    let entries = stmt
+        .query_map(params![limit as i64], |row| {
+            let executed_at: String = row.get(1)?;
+            Ok(AuditEntry {
+                id: row.get(0)?,
+                executed_at: DateTime::parse_from_rfc3339(&executed_at)
+                    .map(|dt| dt.with_timezone(&Utc))
+                    .unwrap_or_else(|_| Utc::now()),
+                session_id: row.get(2)?,
+                command: row.get(3)?,
+                cwd: row.get(4)?,
+                risk: row.get(5)?,
+                approval: row.get(6)?,
+                dry_run: row.get(7)?,
+                exit_code: row.get(8)?,
+                output: opened(row, 9)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<AuditEntry>>>()?;
+    Ok(entries)
+}
@@ -0,0 +1,282 @@
+/// Lines of unchanged context shown around each change
+const CONTEXT: usize = 3;
+
+// Past this many compared line pairs the diff falls back to replacing the whole middle
+const MAX_DIFF_CELLS: usize = 4_000_000;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Equal,
+    Remove,
+    Add,
+}
+
+/// Line edits turning `old` into `new`, from a longest common subsequence of the
+/// lines between the common prefix and suffix.
+fn line_ops<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<(Op, &'a str)> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let a = &old[prefix..old.len() - suffix];
+    let b = &new[prefix..new.len() - suffix];
+
+    let mut ops: Vec<(Op, &str)> = old[..prefix].iter().map(|line| (Op::Equal, *line)).collect();
+    if a.len() * b.len() > MAX_DIFF_CELLS {
+        ops.extend(a.iter().map(|line| (Op::Remove, *line)));
+        ops.extend(b.iter().map(|line| (Op::Add, *line)));
+    } else {
+        // lcs[i][j] is the length of the common subsequence of a[i..] and b[j..]
+        let width = b.len() + 1;
+        let mut lcs = vec![0u32; (a.len() + 1) * width];
+        for i in (0..a.len()).rev() {
+            for j in (0..b.len()).rev() {
+                lcs[i * width + j] = if a[i] == b[j] {
+                    lcs[(i + 1) * width + j + 1] + 1
+                } else {
+                    lcs[(i + 1) * width + j].max(lcs[i * width + j + 1])
+                };
+            }
+        }
+        let (mut i, mut j) = (0, 0);
+        while i < a.len() || j < b.len() {
+            if i < a.len() && j < b.len() && a[i] == b[j] {
+                ops.push((Op::Equal, a[i]));
+                i += 1;
+                j += 1;
+            } else if i < a.len() && (j == b.len() || lcs[(i + 1) * width + j] >= lcs[i * width + j + 1]) {
+                ops.push((Op::Remove, a[i]));
+                i += 1;
+            } else {
+                ops.push((Op::Add, b[j]));
+                j += 1;
+            }
+        }
+    }
+    ops.extend(old[old.len() - suffix..].iter().map(|line| (Op::Equal, *line)));
+    ops
+}
+
+/// Unified diff of two versions of `path`, empty when they are identical.
+pub fn unified(old: &str, new: &str, path: &str) -> String {
+    let old_lines: Vec<&str> = old.lines().collect();
+    let new_lines: Vec<&str> = new.lines().collect();
+    let ops = line_ops(&old_lines, &new_lines);
+
+    // Group changes with their context into hunks, merging hunks that touch
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    for (index, _) in ops.iter().enumerate().filter(|(_, (op, _))| *op != Op::Equal) {
+        let start = index.saturating_sub(CONTEXT);
+        let end = (index + 1 + CONTEXT).min(ops.len());
+        match hunks.last_mut() {
+            Some(last) if start <= last.1 => last.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+    if hunks.is_empty() {
+        return String::new();
+    }
+
+    let mut out = format!("--- a/{}\n+++ b/{}\n", path, path);
+    for (start, end) in hunks {
+        let old_before = ops[..start].iter().filter(|(op, _)| *op != Op::Add).count();
+        let new_before = ops[..start].iter().filter(|(op, _)| *op != Op::Remove).count();
+        let old_count = ops[start..end].iter().filter(|(op, _)| *op != Op::Add).count();
+        let new_count = ops[start..end].iter().filter(|(op, _)| *op != Op::Remove).count();
+        // Empty ranges point at the line before them
+        out.push_str(&format!(
+            "@@ -{},{} +{},{} @@\n",
+            old_before + usize::from(old_count > 0),
+            old_count,
+            new_before + usize::from(new_count > 0),
+            new_count
+        ));
+        for (op, line) in &ops[start..end] {
+            let marker = match op {
+                Op::Equal => ' ',
+                Op::Remove => '-',
+                Op::Add => '+',
+            };
+            out.push(marker);
+            out.push_str(line);
+            out.push('\n');
+        }
+    }
+    out
+}
+
+struct Hunk<'a> {
+    old_start: usize,
+    old: Vec<&'a str>,
+    /// Added lines, and context as its index in `old` so the file's own text is kept
+    new: Vec<(Option<usize>, &'a str)>,
+    /// Whether the old and the new side end without a newline, from "\ No newline at end of file"
+    old_no_newline: bool,
+    new_no_newline: bool,
+}
+
+fn parse_hunks(patch: &str) -> Result<Vec<Hunk<'_>>, String> {
+    let mut hunks: Vec<Hunk> = Vec::new();
+    let mut lines = patch.trim_end_matches(['\n', '\r']).lines().peekable();
+    let mut last_marker = ' ';
+    while let Some(line) = lines.next() {
+        // Headers of a second file would otherwise be read as removed and added lines
+        let next_file = line.starts_with("diff --git ")
+            || (line.starts_with("--- ") && lines.peek().is_some_and(|next| next.starts_with("+++ ")));
+        if next_file && !hunks.is_empty() {
+            return Err("the patch changes more than one file, send one patch per file".to_string());
+        }
+        if let Some(header) = line.strip_prefix("@@ ") {
+            let old_range = header
+                .split_whitespace()
+                .next()
+                .and_then(|range| range.strip_prefix('-'))
+                .ok_or_else(|| format!("malformed hunk header '{}'", line))?;
+            let (start, count) = old_range.split_once(',').unwrap_or((old_range, "1"));
+            let start: usize = start.parse().map_err(|_| format!("malformed hunk header '{}'", line))?;
+            let count: usize = count.parse().map_err(|_| format!("malformed hunk header '{}'", line))?;
+            // A count of 0 means the hunk inserts after line `start`
+            let old_start = if count == 0 { start } else { start.saturating_sub(1) };
+            hunks.push(Hunk { old_start, old: Vec::new(), new: Vec::new(), old_no_newline: false, new_no_newline: false });
+            continue;
+        }
+        let Some(hunk) = hunks.last_mut() else {
+            continue; // File headers and anything else before the first hunk
+        };
+        if let Some(text) = line.strip_prefix('-') {
+            hunk.old.push(text);
+            last_marker = '-';
+        } else if let Some(text) = line.strip_prefix('+') {
+            hunk.new.push((None, text));
+            last_marker = '+';
+        } else if line.starts_with('\\') {
+            // "\ No newline at end of file", about the line before it
+            hunk.old_no_newline |= last_marker != '+';
+            hunk.new_no_newline |= last_marker != '-';
+        } else {
+            // Context; models often strip the leading space of blank lines
+            let text = line.strip_prefix(' ').unwrap_or(line);
+            hunk.new.push((Some(hunk.old.len()), text));
+            hunk.old.push(text);
+            last_marker = ' ';
+        }
+    }
+    if hunks.is_empty() {
+        return Err("the patch contains no hunks".to_string());
+    }
+    Ok(hunks)
+}
+
+/// Apply a unified diff to `original`. Hunks are matched by content near the line
+/// numbers in their headers, so slightly stale line numbers still apply. The file
+/// keeps its line endings, and its final newline unless the patch changes that.
+pub fn apply(original: &str, patch: &str) -> Result<String, String> {
+    let lines: Vec<&str> = original.lines().collect();
+    let line_ending = if original.contains("\r\n") { "\r\n" } else { "\n" };
+    let mut final_newline = original.ends_with('\n') || original.is_empty();
+    let mut result: Vec<&str> = Vec::new();
+    let mut cursor = 0;
+    let mut offset: isize = 0;
+    for (number, hunk) in parse_hunks(patch)?.into_iter().enumerate() {
+        let expected = (hunk.old_start as isize + offset).clamp(cursor as isize, lines.len() as isize) as usize;
+        let position = if hunk.old.is_empty() {
+            Some(expected)
+        } else {
+            find_hunk(&lines, &hunk.old, cursor, expected)
+        };
+        let Some(position) = position else {
+            return Err(format!("hunk {} does not match the file", number + 1));
+        };
+        offset = position as isize - hunk.old_start as isize;
+        result.extend_from_slice(&lines[cursor..position]);
+        result.extend(hunk.new.iter().map(|&(context, text)| context.map_or(text, |index| lines[position + index])));
+        cursor = position + hunk.old.len();
+        if cursor == lines.len() && (hunk.old_no_newline || hunk.new_no_newline) {
+            final_newline = !hunk.new_no_newline;
+        }
+    }
+    result.extend_from_slice(&lines[cursor..]);
+
+    let mut patched = result.join(line_ending);
+    if !patched.is_empty() && final_newline {
+        patched.push_str(line_ending);
+    }
+    Ok(patched)
+}
+
+// Closest position at or after `from` where `old` matches, ignoring trailing whitespace
+fn find_hunk(lines: &[&str], old: &[&str], from: usize, expected: usize) -> Option<usize> {
+    let matches_at = |position: usize| {
+        position + old.len() <= lines.len()
+            && lines[position..position + old.len()]
+                .iter()
+                .zip(old)
+                .all(|(a, b)| a.trim_end() == b.trim_end())
+    };
+    (0..=lines.len()).find_map(|distance| {
+        [expected.checked_add(distance), expected.checked_sub(distance)]
+            .into_iter()
+            .flatten()
+            .find(|&position| position >= from && matches_at(position))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stale_line_numbers_and_trailing_whitespace_still_apply() {
+        let original = "intro\nmore\na\nb\nc\n";
+        let patch = "--- a/f\n+++ b/f\n@@ -1,3 +1,3 @@\n a  \n-b\n+B\n c\n";
+        assert_eq!(apply(original, patch).unwrap(), "intro\nmore\na\nB\nc\n");
+    }
+
+    #[test]
+    fn pure_insertions_go_after_the_header_line() {
+        let patch = "@@ -2,0 +3,2 @@\n+x\n+y\n";
+        assert_eq!(apply("a\nb\nc\n", patch).unwrap(), "a\nb\nx\ny\nc\n");
+        assert_eq!(apply("", "@@ -0,0 +1 @@\n+first\n").unwrap(), "first\n");
+    }
+
+    #[test]
+    fn mismatched_context_is_an_error() {
+        let patch = "@@ -1,2 +1,2 @@\n a\n-missing\n+b\n";
+        assert_eq!(apply("a\nb\n", patch).unwrap_err(), "hunk 1 does not match the file");
+        assert_eq!(apply("a\n", "no hunks here").unwrap_err(), "the patch contains no hunks");
+    }
+
+    #[test]
+    fn final_newline_follows_the_patch() {
+        // Kept as it was when the patch says nothing about it
+        assert_eq!(apply("a\nb", "@@ -1 +1 @@\n-a\n+A\n").unwrap(), "A\nb");
+        let drop = "@@ -1,2 +1,2 @@\n a\n-b\n+B\n\\ No newline at end of file\n";
+        assert_eq!(apply("a\nb\n", drop).unwrap(), "a\nB");
+        let add = "@@ -1,2 +1,2 @@\n a\n-b\n\\ No newline at end of file\n+B\n";
+        assert_eq!(apply("a\nb", add).unwrap(), "a\nB\n");
+    }
+
+    #[test]
+    fn crlf_line_endings_are_kept() {
+        let patch = "@@ -1,2 +1,2 @@\n a\n-b\n+B\n";
+        assert_eq!(apply("a\r\nb\r\n", patch).unwrap(), "a\r\nB\r\n");
+    }
+
+    #[test]
+    fn patches_of_several_files_are_rejected() {
+        let patch = "--- a/one\n+++ b/one\n@@ -1 +1 @@\n-a\n+b\n--- a/two\n+++ b/two\n@@ -1 +1 @@\n-c\n+d\n";
+        assert!(apply("a\n", patch).unwrap_err().contains("more than one file"));
+        let git = "diff --git a/one b/one\n@@ -1 +1 @@\n-a\n+b\ndiff --git a/two b/two\n@@ -1 +1 @@\n-c\n+d\n";
+        assert!(apply("a\n", git).unwrap_err().contains("more than one file"));
+    }
+
+    #[test]
+    fn unified_diffs_apply_back() {
+        let old = "one\ntwo\nthree\nfour\nfive\nsix\nseven\neight\nnine\n";
+        let new = "one\n2\nthree\nfour\nfive\nsix\nseven\neight\nnine\nten\n";
+        assert_eq!(apply(old, &unified(old, new, "f")).unwrap(), new);
+    }
+}
@@ -1,23 +1,816 @@
 use crate::{
-    app::{AppMode, AppState},
-    models, ollama,
+    agent,
+    app::{AppMode, AppState, PipeRequest, SplitDirection},
+    bench, cache, compare, gitmsg, howto,
+    keymap::{self, Action, Chord, Lookup},
+    memory, models, ollama, policy, rag, server, toast, tools, workflow,
 };
 use anyhow::Result;
-use crossterm::event::{KeyCode, KeyEvent};
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc;
 
 pub enum AppEvent {
     Terminal(KeyEvent),
-    OllamaChunk(Result<String, String>),
-    OllamaDone,
-    Models(Result<Vec<String>, String>),
+    Focus(bool), // Terminal gained (true) or lost (false) focus
+    Resize(u16, u16), // New terminal width and height, once it stopped changing
+    OllamaChunk(i64, u64, Result<String, String>), // Session id, generation id, content
+    OllamaDone(i64, u64),
+    GenerateContext(i64, u64, Vec<i64>), // Session id, generation id, context at the end of the reply
+    Models(Result<Vec<models::ModelDetails>, String>),
     #[allow(dead_code)]
     AgentCommands(Vec<models::AgentCommand>),
-    #[allow(dead_code)]
-    CommandExecuted(usize, Result<String, String>),
+    CommandExecuted(usize, Option<agent::CommandOutput>), // Workflow step and its output, `None` if its condition failed
+    WorkflowProgress(usize, workflow::StepEvent), // Attempt or output of the running workflow step
+    WorkflowDraft(Result<serde_json::Value, String>), // Workflow drafted for :wf generate
+    CompareChunk(usize, Result<String, String>), // Side of the :compare popup, content
+    CompareDone(usize),
+    BenchSample(bench::Sample), // One prompt of :bench run against one model
+    AgentPlan(Result<agent::PlanResponse, String>),
+    AgentReasoning(Result<agent::ReasoningResponse, String>),
+    CommandOutputChunk(Vec<u8>), // Output of the running agent command as it arrives
+    AgentCommandOutput(agent::CommandOutput),
+    AgentAnalysis(Result<agent::AnalysisResponse, String>),
+    Ingested(i64, Result<Vec<rag::Chunk>, String>), // Session id, embedded chunks
+    MemoryFacts(i64, Result<memory::FactsResponse, String>), // Session id, facts from its latest exchange
+    ConfigChanged(Result<Box<models::Config>, String>), // The config file was edited
+    PipeOutput(PipeRequest, Result<String, String>), // Output of the :! command
+    CommitMessage(Result<gitmsg::CommitMessage, String>), // Message written by :gitmsg
+    HowTo(Result<howto::Suggestion, String>), // Command suggested by :howto
+    ServerRequest(server::Request, tokio::sync::oneshot::Sender<server::Response>), // Call of the HTTP API
     Tick,
 }
 
+/// Append `content` as a user message to the given session and stream the
+/// reply into it. Chunks are tagged with the session id, so the user may switch
+/// sessions while the response is generated in the background.
+pub fn send_prompt(app: &mut AppState, session_id: i64, content: String, tx: mpsc::Sender<AppEvent>) {
+    if app.compare_models.is_some() {
+        send_comparison(app, session_id, content, tx);
+        return;
+    }
+    let Some(index) = app.session_index_by_id(session_id) else {
+        return;
+    };
+    // Chunks are only added to the reply of this generation
+    let generation = app.start_generation(session_id);
+    let messages = &mut app.sessions[index].messages;
+    // A completion continues from the context of the reply the prompt follows
+    let context = messages.last().and_then(|message| message.context.clone());
+    messages.push(models::Message::new(
+        models::Role::User,
+        content,
+    ));
+    let mut reply = models::Message::new(
+        models::Role::Assistant,
+        String::new(),
+    );
+    reply.generation = Some(generation);
+    messages.push(reply);
+
+    app.loading_sessions.insert(session_id);
+    app.generation_started.insert(session_id, Instant::now());
+    if index == app.current_session_index {
+        app.auto_scroll = app.options.autoscroll;
+        app.trigger_auto_scroll();
+    }
+
+    let client = app.http_client.clone();
+    let model = app.session_model(session_id);
+    let messages = app.request_messages(index);
+    let base_url = app.ollama_base_url.clone();
+    let options = app.request_options(session_id, &model);
+    let auth_config = app.config.auth_method.clone();
+    let auth_enabled = app.config.auth_enabled;
+    let knowledge = knowledge_for(app, index);
+    let embed_model = app.options.embed_model.clone();
+
+    // Knowledge sessions add the chunks found for the prompt, so their requests are not
+    // known here and are never cached; nor are completions, which depend on their context
+    let generate = app.options.api == "generate";
+    let cacheable = app.options.cache && knowledge.is_none() && !generate;
+    let cache_key = cacheable.then(|| cache::key(&model, &messages, &options)).flatten();
+    if let Some(key) = cache_key {
+        match cache::lookup(&app.db_conn, &key) {
+            Ok(Some(content)) => {
+                if let Some(reply) = app.sessions[index].messages.last_mut() {
+                    reply.cached = true;
+                }
+                tokio::spawn(async move {
+                    tx.send(AppEvent::OllamaChunk(session_id, generation, Ok(content))).await.ok();
+                    tx.send(AppEvent::OllamaDone(session_id, generation)).await.ok();
+                });
+                return;
+            }
+            Ok(None) => {
+                app.pending_cache_keys.insert(session_id, key);
+            }
+            Err(e) => app.push_toast(toast::Severity::Warning, format!("Cannot read the response cache: {}", e)),
+        }
+    }
+
+    tokio::spawn(async move {
+        let mut messages = messages;
+        if let Some(chunks) = knowledge
+            && let Err(e) =
+                add_knowledge(&client, &base_url, &embed_model, &chunks, &mut messages, auth_enabled, auth_config.as_ref()).await
+        {
+            tx.send(AppEvent::OllamaChunk(session_id, generation, Err(e))).await.ok();
+            tx.send(AppEvent::OllamaDone(session_id, generation)).await.ok();
+            return;
+        }
+        if generate {
+            ollama::stream_generate_request(
+                &client,
+                &base_url,
+                &model,
+                &messages,
+                context.as_deref(),
+                &options,
+                auth_enabled,
+                auth_config.as_ref(),
+                |content| AppEvent::OllamaChunk(session_id, generation, content),
+                |context| AppEvent::GenerateContext(session_id, generation, context),
+                || AppEvent::OllamaDone(session_id, generation),
+                tx,
+            )
+            .await;
+            return;
+        }
+        ollama::stream_chat_request(
+            &client,
+            &base_url,
+            &model,
+            &messages,
+            &options,
+            auth_enabled,
+            auth_config.as_ref(),
+            |content| AppEvent::OllamaChunk(session_id, generation, content),
+            || AppEvent::OllamaDone(session_id, generation),
+            tx,
+        )
+        .await;
+    });
+}
+
+/// Send `content` to both models of `:compare` at once, streaming the replies into
+/// the compare popup. They are added to the session once both are complete.
+fn send_comparison(app: &mut AppState, session_id: i64, content: String, tx: mpsc::Sender<AppEvent>) {
+    let (Some(index), Some(models)) = (app.session_index_by_id(session_id), app.compare_models.clone()) else {
+        return;
+    };
+    app.sessions[index].messages.push(models::Message::new(models::Role::User, content.clone()));
+    app.loading_sessions.insert(session_id);
+    app.generation_started.insert(session_id, Instant::now());
+    app.comparison = Some(compare::Comparison::new(session_id, content, models.clone()));
+    app.compare_scroll = 0;
+    if index == app.current_session_index {
+        app.mode = AppMode::Compare;
+    }
+
+    let client = app.http_client.clone();
+    let messages = app.request_messages(index);
+    let base_url = app.ollama_base_url.clone();
+    let options = models.clone().map(|model| app.request_options(session_id, &model));
+    let auth_config = app.config.auth_method.clone();
+    let auth_enabled = app.config.auth_enabled;
+    let knowledge = knowledge_for(app, index);
+    let embed_model = app.options.embed_model.clone();
+
+    tokio::spawn(async move {
+        let mut messages = messages;
+        if let Some(chunks) = knowledge
+            && let Err(e) =
+                add_knowledge(&client, &base_url, &embed_model, &chunks, &mut messages, auth_enabled, auth_config.as_ref()).await
+        {
+            for side in 0..2 {
+                tx.send(AppEvent::CompareChunk(side, Err(e.clone()))).await.ok();
+                tx.send(AppEvent::CompareDone(side)).await.ok();
+            }
+            return;
+        }
+        // Both models stream at the same time
+        let [first, second] = [0, 1].map(|side| {
+            ollama::stream_chat_request(
+                &client,
+                &base_url,
+                &models[side],
+                &messages,
+                &options[side],
+                auth_enabled,
+                auth_config.as_ref(),
+                move |content| AppEvent::CompareChunk(side, content),
+                move || AppEvent::CompareDone(side),
+                tx.clone(),
+            )
+        });
+        tokio::join!(first, second);
+    });
+}
+
+// Chunks ingested into a knowledge session, to be searched for the prompt being sent
+fn knowledge_for(app: &mut AppState, index: usize) -> Option<Arc<Vec<rag::Chunk>>> {
+    if app.sessions[index].knowledge {
+        app.knowledge_chunks(app.sessions[index].id).ok()
+    } else {
+        None
+    }
+}
+
+// Add the chunks closest to the last user message in front of it
+async fn add_knowledge(
+    client: &reqwest::Client,
+    base_url: &str,
+    embed_model: &str,
+    chunks: &[rag::Chunk],
+    messages: &mut [models::Message],
+    auth_enabled: bool,
+    auth_config: Option<&models::AuthMethod>,
+) -> Result<(), String> {
+    let query = messages
+        .iter()
+        .rev()
+        .find(|m| m.role == models::Role::User)
+        .map(|m| m.content.clone())
+        .unwrap_or_default();
+    let vectors = ollama::embed(client, base_url, embed_model, &[query], auth_enabled, auth_config).await?;
+    rag::augment(messages, &rag::top_k(chunks, &vectors[0], rag::TOP_K));
+    Ok(())
+}
+
+/// Run the prompts of `:bench` against each model in turn, one request at a time.
+pub fn start_bench(app: &mut AppState, tx: mpsc::Sender<AppEvent>) {
+    let Some(mut bench) = app.pending_bench.take() else {
+        return;
+    };
+    let (cancel_tx, mut cancel_rx) = tokio::sync::oneshot::channel();
+    bench.cancel = Some(cancel_tx);
+    let runs: Vec<(String, models::ModelOptions)> = bench
+        .models
+        .iter()
+        .map(|model| (model.clone(), app.request_options(app.current_session_id(), model)))
+        .collect();
+    let prompts = bench.prompts.clone();
+    app.bench = Some(bench);
+
+    let client = app.http_client.clone();
+    let base_url = app.ollama_base_url.clone();
+    let auth_config = app.config.auth_method.clone();
+    let auth_enabled = app.config.auth_enabled;
+    tokio::spawn(async move {
+        for (model, options) in &runs {
+            for (prompt, text) in prompts.iter().enumerate() {
+                let messages = [models::Message::new(models::Role::User, text.clone())];
+                let measured =
+                    ollama::measure_chat(&client, &base_url, model, &messages, options, auth_enabled, auth_config.as_ref());
+                let result = tokio::select! {
+                    result = measured => result,
+                    _ = &mut cancel_rx => return,
+                };
+                tx.send(AppEvent::BenchSample(bench::Sample { model: model.clone(), prompt, result })).await.ok();
+            }
+        }
+    });
+}
+
+/// Chunk the documents read by `:ingest` and embed them in the background.
+pub fn start_ingest(app: &mut AppState, tx: mpsc::Sender<AppEvent>) {
+    let Some((session_id, documents)) = app.pending_ingest.take() else {
+        return;
+    };
+    let pieces: Vec<(String, String)> = documents
+        .iter()
+        .flat_map(|(name, text)| rag::split(text).into_iter().map(move |chunk| (name.clone(), chunk)))
+        .collect();
+    app.set_status_message(format!(
+        "Embedding {} chunks from {} files with {}...",
+        pieces.len(),
+        documents.len(),
+        app.options.embed_model
+    ));
+
+    let client = app.http_client.clone();
+    let base_url = app.ollama_base_url.clone();
+    let model = app.options.embed_model.clone();
+    let auth_config = app.config.auth_method.clone();
+    let auth_enabled = app.config.auth_enabled;
+    tokio::spawn(async move {
+        let mut chunks = Vec::with_capacity(pieces.len());
+        for batch in pieces.chunks(rag::EMBED_BATCH) {
+            let inputs: Vec<String> = batch.iter().map(|(_, content)| content.clone()).collect();
+            match ollama::embed(&client, &base_url, &model, &inputs, auth_enabled, auth_config.as_ref()).await {
+                Ok(embeddings) => chunks.extend(batch.iter().zip(embeddings).map(|((source, content), embedding)| {
+                    rag::Chunk { source: source.clone(), content: content.clone(), embedding }
+                })),
+                Err(e) => {
+                    tx.send(AppEvent::Ingested(session_id, Err(e))).await.ok();
+                    return;
+                }
+            }
+        }
+        tx.send(AppEvent::Ingested(session_id, Ok(chunks))).await.ok();
+    });
+}
+
+/// Run the shell command of `:!` on the last reply in the session's directory.
+pub fn start_pipe(app: &mut AppState, tx: mpsc::Sender<AppEvent>) {
+    let Some(request) = app.pending_pipe.take() else {
+        return;
+    };
+    let cwd = app.working_dir(app.current_session_id());
+    let env = app.command_env.clone();
+    let timeout = Some(Duration::from_secs(app.options.command_timeout)).filter(|t| !t.is_zero());
+    tokio::spawn(async move {
+        let result = agent::pipe_through(&request.command, request.input.clone(), &cwd, &env, timeout).await;
+        tx.send(AppEvent::PipeOutput(request, result)).await.ok();
+    });
+}
+
+/// Ask the model which durable facts the latest exchange of a session revealed.
+pub fn request_memory_extraction(app: &AppState, session_id: i64, user: &str, assistant: &str, tx: mpsc::Sender<AppEvent>) {
+    let messages = memory::extraction_messages(&app.memories, user, assistant);
+    spawn_agent_completion(app, messages, memory::FactsResponse::schema(), tx, move |result| {
+        AppEvent::MemoryFacts(session_id, result)
+    });
+}
+
+/// Ask the model to draft the workflow described with `:wf generate`.
+pub fn request_workflow_draft(app: &mut AppState, tx: mpsc::Sender<AppEvent>) {
+    let Some(description) = app.pending_workflow_request.take() else {
+        return;
+    };
+    let messages = workflow::generation_messages(&description, &app.working_dir(app.current_session_id()));
+    spawn_agent_completion(app, messages, workflow::Workflow::schema(), tx, AppEvent::WorkflowDraft);
+}
+
+/// Ask the model for a commit message describing the changes staged for :gitmsg.
+pub fn request_commit_message(app: &mut AppState, tx: mpsc::Sender<AppEvent>) {
+    let Some(diff) = app.pending_commit_diff.take() else {
+        return;
+    };
+    let messages = gitmsg::generation_messages(&diff, &app.working_dir(app.current_session_id()));
+    spawn_agent_completion(app, messages, gitmsg::CommitMessage::schema(), tx, AppEvent::CommitMessage);
+}
+
+/// Ask the model for the shell command answering the question of :howto.
+pub fn request_howto(app: &mut AppState, tx: mpsc::Sender<AppEvent>) {
+    let Some(question) = app.pending_howto.take() else {
+        return;
+    };
+    let messages = howto::generation_messages(&question, &app.working_dir(app.current_session_id()));
+    spawn_agent_completion(app, messages, howto::Suggestion::schema(), tx, AppEvent::HowTo);
+}
+
+/// Ask the model for a plan to review before the autonomous run executes anything.
+pub fn request_agent_plan(app: &mut AppState, tx: mpsc::Sender<AppEvent>) {
+    let Some(run) = app.autonomous.as_mut() else {
+        return;
+    };
+    run.state = agent::AgentState::Planning;
+    let Some(run) = app.autonomous.as_ref() else {
+        return;
+    };
+    let messages = run.plan_messages(&app.working_dir(run.session_id), app.agent_system_context().as_deref());
+    spawn_agent_completion(app, messages, agent::PlanResponse::schema(), tx, AppEvent::AgentPlan);
+}
+
+/// Ask the model for the autonomous run's next step as structured output.
+pub fn request_agent_reasoning(app: &mut AppState, tx: mpsc::Sender<AppEvent>) {
+    let Some(run) = app.autonomous.as_mut() else {
+        return;
+    };
+    run.state = agent::AgentState::Reasoning;
+    let Some(run) = app.autonomous.as_ref() else {
+        return;
+    };
+    let messages = run.reasoning_messages(
+        &app.working_dir(run.session_id),
+        &tools::describe(&app.config),
+        app.agent_system_context().as_deref(),
+    );
+    let schema = agent::ReasoningResponse::schema(&tools::names(&app.config));
+    spawn_agent_completion(app, messages, schema, tx, AppEvent::AgentReasoning);
+}
+
+/// Ask the model to assess the output of the autonomous run's latest command.
+pub fn request_agent_analysis(app: &mut AppState, tx: mpsc::Sender<AppEvent>) {
+    let Some(run) = app.autonomous.as_mut() else {
+        return;
+    };
+    run.state = agent::AgentState::Analyzing;
+    let messages = run.analysis_messages();
+    spawn_agent_completion(app, messages, agent::AnalysisResponse::schema(), tx, AppEvent::AgentAnalysis);
+}
+
+fn spawn_agent_completion<T: serde::de::DeserializeOwned + Send + 'static>(
+    app: &AppState,
+    messages: Vec<models::Message>,
+    schema: serde_json::Value,
+    tx: mpsc::Sender<AppEvent>,
+    event: impl FnOnce(Result<T, String>) -> AppEvent + Send + 'static,
+) {
+    let client = app.http_client.clone();
+    let model = app.session_model(app.current_session_id());
+    let base_url = app.ollama_base_url.clone();
+    let mut options = app.request_options(app.current_session_id(), &model);
+    options.format = Some(schema);
+    let auth_config = app.config.auth_method.clone();
+    let auth_enabled = app.config.auth_enabled;
+
+    tokio::spawn(async move {
+        let result = ollama::complete_chat(
+            &client,
+            &base_url,
+            &model,
+            &messages,
+            &options,
+            auth_enabled,
+            auth_config.as_ref(),
+        )
+        .await;
+        tx.send(event(result)).await.ok();
+    });
+}
+
+/// Run the command the model proposed, unless the user asked to pause, to confirm
+/// every command, or the `approval` option does not cover its risk level, in which
+/// case the run waits in `Paused`. Commands the policy denies always wait for an
+/// explicit override; allowed ones never need confirmation.
+pub fn advance_to_command(app: &mut AppState, command: String, tx: mpsc::Sender<AppEvent>) {
+    let verdict = app.check_command_policy(&command);
+    let risk = agent::assess_risk_level(&command);
+    let approval = app.options.approval;
+    let Some(run) = app.autonomous.as_mut() else {
+        return;
+    };
+    if let policy::Verdict::Denied(reason) = verdict {
+        run.pause_requested = false;
+        run.pending_command = Some(command);
+        run.policy_violation = Some(reason.clone());
+        run.state = agent::AgentState::Paused;
+        app.set_status_message(format!("Command blocked ({}): ! to run anyway, x to reject", reason));
+        return;
+    }
+    let needs_confirmation = verdict != policy::Verdict::Allowed && (run.single_step || !approval.auto_approves(risk));
+    if run.pause_requested || needs_confirmation {
+        run.pause_requested = false;
+        run.pending_command = Some(command);
+        run.state = agent::AgentState::Paused;
+        return;
+    }
+    run_agent_command(app, command, agent::Approval::Automatic, tx);
+}
+
+/// Run a tool call or wait for approval. Changes to files always wait, with their diff shown.
+pub fn advance_to_tool(app: &mut AppState, call: tools::ToolCall, tx: mpsc::Sender<AppEvent>) {
+    let verdict = app.check_tool_policy(&call);
+    let approval = app.options.approval;
+    // A change that cannot be previewed is never run, the model gets the error instead
+    if let Err(e) = app.prepare_tool_preview(&call) {
+        if let Some(step) = app.autonomous.as_mut().and_then(|run| run.steps.last_mut()) {
+            step.output = Some(Err(e));
+        }
+        request_agent_analysis(app, tx);
+        return;
+    }
+    let has_preview = app.pending_change.is_some() || app.pending_commit.is_some();
+    let Some(run) = app.autonomous.as_mut() else {
+        return;
+    };
+    if let policy::Verdict::Denied(reason) = verdict {
+        run.pause_requested = false;
+        run.pending_tool = Some(call);
+        run.policy_violation = Some(reason.clone());
+        run.state = agent::AgentState::Paused;
+        app.set_status_message(format!("Tool call blocked ({}): ! to run anyway, x to reject", reason));
+        return;
+    }
+    let needs_confirmation = call.writes() || run.single_step || !approval.auto_approves(call.risk());
+    if run.pause_requested || needs_confirmation {
+        run.pause_requested = false;
+        run.pending_tool = Some(call);
+        run.state = agent::AgentState::Paused;
+        if has_preview && app.mode == AppMode::Autonomous {
+            app.mode = AppMode::ToolApproval;
+        }
+        return;
+    }
+    run_agent_tool(app, call, agent::Approval::Automatic, tx);
+}
+
+/// Ask for the next step once the current one is complete, unless a pause was requested.
+pub fn advance_to_reasoning(app: &mut AppState, tx: mpsc::Sender<AppEvent>) {
+    let Some(run) = app.autonomous.as_mut() else {
+        return;
+    };
+    if run.pause_requested {
+        run.pause_requested = false;
+        run.state = agent::AgentState::Paused;
+        return;
+    }
+    request_agent_reasoning(app, tx);
+}
+
+/// Continue a paused run from where it stopped.
+pub fn resume_agent(app: &mut AppState, tx: mpsc::Sender<AppEvent>) {
+    let Some(run) = app.autonomous.as_mut() else {
+        return;
+    };
+    if run.state != agent::AgentState::Paused || run.policy_violation.is_some() {
+        return;
+    }
+    if let Some(mut call) = run.pending_tool.take() {
+        if let Some(commit) = &app.pending_commit {
+            commit.apply_to(&mut call);
+        }
+        return run_agent_tool(app, call, agent::Approval::User, tx);
+    }
+    match run.pending_command.take() {
+        Some(command) => run_agent_command(app, command, agent::Approval::User, tx),
+        None => request_agent_reasoning(app, tx),
+    }
+}
+
+/// Run a command the policy blocked, after the user explicitly chose to.
+pub fn override_agent_policy(app: &mut AppState, tx: mpsc::Sender<AppEvent>) {
+    let Some(run) = app.autonomous.as_mut() else {
+        return;
+    };
+    if run.policy_violation.take().is_none() {
+        return;
+    }
+    if let Some(mut call) = run.pending_tool.take() {
+        if let Some(commit) = &app.pending_commit {
+            commit.apply_to(&mut call);
+        }
+        run_agent_tool(app, call, agent::Approval::Override, tx);
+    } else if let Some(command) = run.pending_command.take() {
+        run_agent_command(app, command, agent::Approval::Override, tx);
+    }
+}
+
+/// Refuse a blocked command; the model sees the refusal and picks another step.
+pub fn reject_agent_command(app: &mut AppState, tx: mpsc::Sender<AppEvent>) {
+    let Some(run) = app.autonomous.as_mut() else {
+        return;
+    };
+    let Some(reason) = run.policy_violation.take() else {
+        return;
+    };
+    run.pending_command = None;
+    run.pending_tool = None;
+    if let Some(step) = run.steps.last_mut() {
+        step.output = Some(Err(format!("Command rejected by the user, it {}", reason)));
+    }
+    app.clear_tool_preview();
+    request_agent_reasoning(app, tx);
+    app.persist_autonomous_run();
+}
+
+/// Refuse a file change from the approval popup; the model is told and carries on.
+pub fn reject_agent_tool(app: &mut AppState, tx: mpsc::Sender<AppEvent>) {
+    let Some(run) = app.autonomous.as_mut() else {
+        return;
+    };
+    if run.pending_tool.take().is_none() {
+        return;
+    }
+    run.policy_violation = None;
+    if let Some(step) = run.steps.last_mut() {
+        step.output = Some(Err("The user rejected this change".to_string()));
+    }
+    app.clear_tool_preview();
+    request_agent_reasoning(app, tx);
+    app.persist_autonomous_run();
+}
+
+// Ctrl+C in agent and autonomous mode
+fn interrupt_agent_command(app: &mut AppState) {
+    if !app.cancel_agent_command() {
+        app.set_status_message("No command is running".to_string());
+    }
+}
+
+pub fn run_agent_command(app: &mut AppState, command: String, approval: agent::Approval, tx: mpsc::Sender<AppEvent>) {
+    let Some(run) = app.autonomous.as_mut() else {
+        return;
+    };
+    run.state = agent::AgentState::Executing;
+    let session_id = run.session_id;
+    let cwd = app.working_dir(session_id);
+    let dry_run = app.options.dry_run;
+    // Completed with the exit code and output once the command finishes
+    app.pending_audit = Some(agent::AuditEntry {
+        id: 0,
+        executed_at: chrono::Utc::now(),
+        session_id,
+        command: command.clone(),
+        cwd: cwd.to_string_lossy().to_string(),
+        risk: agent::assess_risk_level(&command).label().to_string(),
+        approval: approval.label().to_string(),
+        dry_run,
+        exit_code: None,
+        output: String::new(),
+    });
+    let timeout = Some(Duration::from_secs(app.options.command_timeout)).filter(|t| !t.is_zero());
+    let env = app.command_env.clone();
+    let redaction = app.redaction.clone();
+    let (cancel_tx, cancel_rx) = tokio::sync::oneshot::channel();
+    app.command_cancel = Some(cancel_tx);
+    app.live_command_output.clear();
+    tokio::spawn(async move {
+        let (chunk_tx, mut chunk_rx) = mpsc::unbounded_channel();
+        let execution = agent::execute_command(&command, &cwd, &env, dry_run, timeout, cancel_rx, Some(chunk_tx));
+        tokio::pin!(execution);
+        // Forward output as it arrives; the final result follows the last chunk
+        let output = loop {
+            tokio::select! {
+                Some(chunk) = chunk_rx.recv() => {
+                    tx.send(AppEvent::CommandOutputChunk(chunk)).await.ok();
+                }
+                output = &mut execution => break output,
+            }
+        };
+        while let Ok(chunk) = chunk_rx.try_recv() {
+            tx.send(AppEvent::CommandOutputChunk(chunk)).await.ok();
+        }
+        // Secrets must not end up in the run history or in the model's context
+        let output = agent::CommandOutput {
+            exit_code: output.exit_code,
+            result: match output.result {
+                Ok(text) => Ok(agent::redact_secrets(&text, &env, &redaction)),
+                Err(text) => Err(agent::redact_secrets(&text, &env, &redaction)),
+            },
+        };
+        tx.send(AppEvent::AgentCommandOutput(output)).await.ok();
+    });
+}
+
+/// Run the next step of the workflow, or stop and ask when it is not approved yet.
+/// `approval` says how the step about to run was approved.
+pub fn advance_workflow(app: &mut AppState, approval: agent::Approval, tx: mpsc::Sender<AppEvent>) {
+    let Some(run) = app.workflow_run.as_ref().filter(|run| !run.finished && run.running.is_none()) else {
+        return;
+    };
+    let session_id = run.session_id;
+    let Some(index) = app.pending_commands.iter().position(|command| !command.executed) else {
+        let name = run.workflow.name.clone();
+        let mut chain = run.chain.clone();
+        chain.push(name.clone());
+        let message = format!("Workflow '{}' completed.", name);
+        if let Some(run) = app.workflow_run.as_mut() {
+            run.finished = true;
+        }
+        app.push_session_message(session_id, message.clone()).ok();
+        app.set_status_message(message);
+        let triggered = app.workflows.triggered_by_workflow(&name);
+        app.queue_workflows(triggered, session_id, chain);
+        start_queued_workflow(app, tx);
+        return;
+    };
+    let command = app.pending_commands[index].clone();
+    let step = run.workflow.steps[index].clone();
+    let condition = step
+        .condition
+        .as_ref()
+        .map(|condition| workflow::expand_variables(condition, &run.workflow.variables));
+    if !command.approved {
+        app.command_approval_index = Some(index);
+        let blocked = [Some(&command.command), condition.as_ref()]
+            .into_iter()
+            .flatten()
+            .find_map(|command| match app.check_command_policy(command) {
+                policy::Verdict::Denied(reason) => Some(format!(" (blocked: {})", reason)),
+                _ => None,
+            })
+            .unwrap_or_default();
+        app.set_status_message(format!(
+            "Run `{}`{} [{} risk]{}? y to approve, n to stop the workflow",
+            command.command,
+            condition.as_ref().map(|condition| format!(" if `{}`", condition)).unwrap_or_default(),
+            command.risk.label(),
+            blocked
+        ));
+        return;
+    }
+    app.command_approval_index = None;
+    let cwd = app.working_dir(session_id);
+    let dry_run = app.options.dry_run;
+    let timeout = Some(Duration::from_secs(app.options.command_timeout)).filter(|t| !t.is_zero());
+    let env = app.command_env.clone();
+    let redaction = app.redaction.clone();
+    let (cancel_tx, cancel_rx) = tokio::sync::oneshot::channel();
+    let Some(run) = app.workflow_run.as_mut() else {
+        return;
+    };
+    run.running = Some(index);
+    run.attempt = 1;
+    run.output.clear();
+    run.cancel = Some(cancel_tx);
+    run.audit = Some(agent::AuditEntry {
+        id: 0,
+        executed_at: chrono::Utc::now(),
+        session_id,
+        command: command.command.clone(),
+        cwd: cwd.to_string_lossy().to_string(),
+        risk: command.risk.label().to_string(),
+        approval: approval.label().to_string(),
+        dry_run,
+        exit_code: None,
+        output: String::new(),
+    });
+    tokio::spawn(async move {
+        let (progress_tx, mut progress_rx) = mpsc::unbounded_channel();
+        let execution = workflow::execute_step(
+            &step,
+            &command.command,
+            condition.as_deref(),
+            &cwd,
+            &env,
+            dry_run,
+            timeout,
+            cancel_rx,
+            progress_tx,
+        );
+        tokio::pin!(execution);
+        // Forward progress as it happens; the result follows the last event
+        let output = loop {
+            tokio::select! {
+                Some(event) = progress_rx.recv() => {
+                    tx.send(AppEvent::WorkflowProgress(index, event)).await.ok();
+                }
+                output = &mut execution => break output,
+            }
+        };
+        while let Ok(event) = progress_rx.try_recv() {
+            tx.send(AppEvent::WorkflowProgress(index, event)).await.ok();
+        }
+        let output = output.map(|output| agent::CommandOutput {
+            exit_code: output.exit_code,
+            result: match output.result {
+                Ok(text) => Ok(agent::redact_secrets(&text, &env, &redaction)),
+                Err(text) => Err(agent::redact_secrets(&text, &env, &redaction)),
+            },
+        });
+        tx.send(AppEvent::CommandExecuted(index, output)).await.ok();
+    });
+}
+
+/// Start the next workflow a trigger queued, once the running one has finished.
+pub fn start_queued_workflow(app: &mut AppState, tx: mpsc::Sender<AppEvent>) {
+    if app.start_queued_workflow() {
+        advance_workflow(app, agent::Approval::Automatic, tx);
+    }
+}
+
+/// Run a tool call; its result arrives as `AgentCommandOutput` like a command's would.
+pub fn run_agent_tool(app: &mut AppState, call: tools::ToolCall, approval: agent::Approval, tx: mpsc::Sender<AppEvent>) {
+    let Some(run) = app.autonomous.as_mut() else {
+        return;
+    };
+    run.state = agent::AgentState::Executing;
+    let session_id = run.session_id;
+    let cwd = app.working_dir(session_id);
+    let dry_run = app.options.dry_run;
+    app.pending_audit = Some(agent::AuditEntry {
+        id: 0,
+        executed_at: chrono::Utc::now(),
+        session_id,
+        command: call.summary(),
+        cwd: cwd.to_string_lossy().to_string(),
+        risk: call.risk().label().to_string(),
+        approval: approval.label().to_string(),
+        dry_run,
+        exit_code: None,
+        output: String::new(),
+    });
+    app.clear_tool_preview();
+    app.live_command_output.clear();
+    let env = app.command_env.clone();
+    let redaction = app.redaction.clone();
+    let context = tools::ToolContext {
+        cwd,
+        dry_run,
+        fetch_domains: app.config.command_policy.fetch_domains.clone(),
+        search: app.config.search.clone(),
+        plugins: app.config.plugins.clone(),
+        env: env.clone(),
+        timeout: Some(Duration::from_secs(app.options.command_timeout)).filter(|t| !t.is_zero()),
+    };
+    tokio::spawn(async move {
+        let output = tools::execute(&call, &context).await;
+        let output = agent::CommandOutput {
+            exit_code: output.exit_code,
+            result: match output.result {
+                Ok(text) => Ok(agent::redact_secrets(&text, &env, &redaction)),
+                Err(text) => Err(agent::redact_secrets(&text, &env, &redaction)),
+            },
+        };
+        tx.send(AppEvent::AgentCommandOutput(output)).await.ok();
+    });
+}
+
 pub async fn handle_key_event(key: KeyEvent, app: &mut AppState, tx: mpsc::Sender<AppEvent>) -> bool {
     match app.mode {
         AppMode::Normal => handle_normal_mode(key, app, tx).await,
@@ -27,91 +820,238 @@ pub async fn handle_key_event(key: KeyEvent, app: &mut AppState, tx: mpsc::Sende
         AppMode::ModelSelection => handle_model_selection_mode(key, app).await,
         AppMode::SessionSelection => handle_session_selection_mode(key, app).await,
         AppMode::Agent => handle_agent_mode(key, app, tx).await,
+        AppMode::Autonomous => handle_autonomous_mode(key, app, tx).await,
+        AppMode::PlanReview => handle_plan_review_mode(key, app, tx).await,
+        AppMode::AgentRuns => handle_agent_runs_mode(key, app).await,
+        AppMode::Audit => handle_audit_mode(key, app).await,
+        AppMode::ToolApproval => handle_tool_approval_mode(key, app, tx).await,
         AppMode::Help => handle_help_mode(key, app).await,
+        AppMode::DeleteMessageConfirmation => handle_delete_message_confirmation(key, app).await,
+        AppMode::PinnedMessages => handle_pinned_messages_mode(key, app).await,
+        AppMode::CommandHistory => handle_command_history_mode(key, app).await,
+        AppMode::Info => handle_info_mode(key, app).await,
+        AppMode::PromptVariables => handle_prompt_variables_mode(key, app).await,
+        AppMode::Workflows => handle_workflows_mode(key, app, tx).await,
+        AppMode::WorkflowVariables => handle_workflow_variables_mode(key, app, tx).await,
+        AppMode::WorkflowDraft => handle_workflow_draft_mode(key, app).await,
+        AppMode::CommitMessage => handle_commit_message_mode(key, app, tx).await,
+        AppMode::HowTo => handle_howto_mode(key, app, tx).await,
+        AppMode::WorkflowEditor => handle_workflow_editor_mode(key, app).await,
+        AppMode::Compare => handle_compare_mode(key, app).await,
+        AppMode::Bench => handle_bench_mode(key, app).await,
+        AppMode::CommandPalette => handle_command_palette_mode(key, app).await,
     }
 }
 
-async fn handle_normal_mode(key: KeyEvent, app: &mut AppState, _tx: mpsc::Sender<AppEvent>) -> bool {
-    // Clear any status message on any key press
-    app.clear_status_message();
-    
-    match key.code {
-        KeyCode::Char('q') => return true, // Quick quit
-        KeyCode::Char('i') => {
-            app.mode = AppMode::Insert;
+/// Take `"x`, which picks the register of the next yank, returning whether the key was part of it.
+fn register_prefix(key: &KeyEvent, app: &mut AppState) -> bool {
+    if app.awaiting_register {
+        app.awaiting_register = false;
+        match key.code {
+            KeyCode::Char(c) if c.is_ascii_alphanumeric() || matches!(c, '"' | '+' | '*') => app.pending_register = Some(c),
+            _ => app.pending_register = None, // Anything else cancels, as in vim
+        }
+        return true;
+    }
+    if app.pending_keys.is_empty() && app.count_prefix.is_none() && key.code == KeyCode::Char('"') {
+        app.awaiting_register = true;
+        return true;
+    }
+    false
+}
+
+async fn handle_normal_mode(key: KeyEvent, app: &mut AppState, tx: mpsc::Sender<AppEvent>) -> bool {
+    // The mark name after `m`, `` ` `` or `'`
+    if let Some((action, _)) = app.pending_argument.take() {
+        if let KeyCode::Char(name) = key.code
+            && name.is_ascii_alphabetic()
+        {
+            let result = match action {
+                Action::SetMark => {
+                    app.set_mark(name);
+                    Ok(())
+                }
+                _ => app.jump_to_mark(name, action == Action::JumpToMarkMessage),
+            };
+            if let Err(e) = result {
+                app.set_error_message(e.to_string());
+            }
         }
-        KeyCode::Char('o') => {
+        return false;
+    }
+    if register_prefix(&key, app) {
+        return false;
+    }
+
+    // Accumulate count prefixes (`5j`, `10k`); a leading 0 is not a count
+    if app.pending_keys.is_empty()
+        && let KeyCode::Char(c) = key.code
+        && let Some(digit) = c.to_digit(10)
+        && (digit != 0 || app.count_prefix.is_some())
+        && !key.modifiers.contains(KeyModifiers::CONTROL)
+    {
+        app.push_count_digit(digit);
+        return false;
+    }
+
+    // Sequences such as `dd` wait for their next key
+    app.push_pending_key(Chord::from_event(&key));
+    let action = match app.keymap.lookup(keymap::Mode::Normal, &app.pending_keys) {
+        Lookup::Pending => return false,
+        Lookup::Unbound => None,
+        Lookup::Action(action) => Some(action),
+    };
+    app.pending_keys.clear();
+    let count = app.take_count();
+    if let Some(action @ (Action::SetMark | Action::JumpToMark | Action::JumpToMarkMessage)) = action {
+        app.pending_argument = Some((action, Chord::from_event(&key)));
+        return false;
+    }
+    let quit = action.is_some_and(|action| normal_action(action, app, count, &tx));
+    app.pending_register = None;
+    quit
+}
+
+/// Run a normal mode action `count` times where that makes sense, returning true when the app should quit.
+fn normal_action(action: Action, app: &mut AppState, count: usize, tx: &mpsc::Sender<AppEvent>) -> bool {
+    match action {
+        Action::Quit => return true,
+        Action::ClearStatus => app.clear_status_message(),
+        Action::HalfPageDown => {
+            app.auto_scroll = false;
+            let half_page = (app.terminal_height.saturating_sub(6) / 2).max(1) as usize;
+            app.scroll_chat_down(half_page.saturating_mul(count));
+        }
+        Action::HalfPageUp => {
+            app.auto_scroll = false;
+            let half_page = (app.terminal_height.saturating_sub(6) / 2).max(1) as usize;
+            app.scroll_chat_up(half_page.saturating_mul(count));
+        }
+        Action::CommandPalette => app.open_command_palette(),
+        Action::CommandHistory => app.open_command_history(),
+        Action::AlternateSession => {
+            if let Err(e) = app.switch_to_alternate_session() {
+                app.set_error_message(format!("Error: {}", e));
+            }
+        }
+        Action::Insert => {
             app.mode = AppMode::Insert;
-            app.input.clear();
         }
-        KeyCode::Char('O') => {
+        Action::InsertNew => {
             app.mode = AppMode::Insert;
             app.input.clear();
         }
-        KeyCode::Char(':') => {
+        Action::CommandLine => {
             app.mode = AppMode::Command;
             app.vim_command.clear();
         }
-        KeyCode::Char('?') => {
-            app.mode = AppMode::Help;
+        Action::Help => app.open_help(),
+        Action::Visual => app.start_visual_selection(),
+        Action::Paste => {
+            let register = app.pending_register;
+            match app.paste(register) {
+                Ok(()) => app.mode = AppMode::Insert,
+                Err(e) => app.set_error_message(format!("Paste failed: {}", e)),
+            }
+        }
+        Action::Pin => {
+            if let Err(e) = app.toggle_pin_selected_message() {
+                app.set_error_message(format!("Pin failed: {}", e));
+            }
+        }
+        Action::DeleteMessage => app.request_delete_selected_message(),
+        Action::YankMessage => {
+            if let Err(e) = app.yank_selected_message() {
+                app.set_error_message(format!("Copy failed: {}", e));
+            }
+        }
+        Action::QuoteMessage => app.quote_selected_message(),
+        Action::RunCodeBlock => match app.run_selected_code_block() {
+            Ok(true) => advance_workflow(app, agent::Approval::Automatic, tx.clone()),
+            Ok(false) => {}
+            Err(e) => app.set_error_message(e.to_string()),
+        },
+        // Folds of <think> blocks, as in vim
+        Action::ToggleThinking => app.toggle_selected_thinking(),
+        Action::ExpandAllThinking => app.set_all_thinking_expanded(true),
+        Action::FoldAllThinking => app.set_all_thinking_expanded(false),
+        // Window commands after Ctrl+W, as in vim
+        Action::FocusOtherPane => {
+            if let Err(e) = app.focus_other_pane() {
+                app.set_error_message(e.to_string());
+            }
+        }
+        Action::CloseSplit => app.split = None,
+        Action::VerticalSplit | Action::HorizontalSplit => {
+            let direction = if action == Action::VerticalSplit { SplitDirection::Vertical } else { SplitDirection::Horizontal };
+            if let Err(e) = app.open_split(direction, "") {
+                app.set_error_message(e.to_string());
+            }
         }
-        KeyCode::Char('v') => {
-            app.start_visual_selection();
+        // Shortcuts for `:n`, `:s`, `:m` and `:a`, unbound by default
+        Action::NewSession | Action::Sessions | Action::Models | Action::Agent => {
+            let command = match action {
+                Action::NewSession => "n",
+                Action::Sessions => "s",
+                Action::Models => "m",
+                _ => "a",
+            };
+            if let Err(e) = app.execute_vim_command(command) {
+                app.set_error_message(format!("Error: {}", e));
+            }
         }
         // Navigation in normal mode
-        KeyCode::Char('j') | KeyCode::Down => {
+        Action::ScrollDown => {
             app.auto_scroll = false;
-            let selected = app.chat_list_state.selected();
-            let chat_width = (app.terminal_width * 3) / 4;
-            let total_lines = app.calculate_total_message_lines(chat_width);
-            
-            if let Some(i) = selected {
-                if i < total_lines.saturating_sub(1) {
-                    app.chat_list_state.select(Some(i + 1));
-                }
-            } else if total_lines > 0 {
-                app.chat_list_state.select(Some(0));
-            }
+            app.scroll_chat_down(count);
         }
-        KeyCode::Char('k') | KeyCode::Up => {
+        Action::ScrollUp => {
             app.auto_scroll = false;
-            let selected = app.chat_list_state.selected();
-            if let Some(i) = selected {
-                if i > 0 {
-                    app.chat_list_state.select(Some(i - 1));
-                }
-            } else {
-                let chat_width = (app.terminal_width * 3) / 4;
-                let total_lines = app.calculate_total_message_lines(chat_width);
-                if total_lines > 0 {
-                    app.chat_list_state.select(Some(total_lines - 1));
-                }
+            app.scroll_chat_up(count);
+        }
+        Action::JumpBack | Action::JumpForward => {
+            if let Err(e) = app.walk_jump_list(count, action == Action::JumpBack) {
+                app.set_error_message(e.to_string());
             }
         }
-        KeyCode::Char('g') => {
+        Action::NextMessage => {
+            // Jump to the start of the next message
+            app.record_jump();
+            app.auto_scroll = false;
+            app.jump_to_next_message(count);
+        }
+        Action::PreviousMessage => {
+            // Jump to the start of the previous message
+            app.record_jump();
+            app.auto_scroll = false;
+            app.jump_to_previous_message(count);
+        }
+        Action::Top => {
             // Go to top
+            app.record_jump();
             app.chat_list_state.select(Some(0));
         }
-        KeyCode::Char('G') => {
+        Action::Bottom => {
             // Go to bottom
+            app.record_jump();
             let chat_width = (app.terminal_width * 3) / 4;
             let total_lines = app.calculate_total_message_lines(chat_width);
             if total_lines > 0 {
                 app.chat_list_state.select(Some(total_lines - 1));
             }
         }
-        KeyCode::PageUp => {
+        Action::PageUp => {
             app.auto_scroll = false;
             let selected = app.chat_list_state.selected();
             let chat_height = app.terminal_height.saturating_sub(6);
             let page_size = chat_height as usize;
             
             if let Some(i) = selected {
-                let new_index = i.saturating_sub(page_size);
+                let new_index = i.saturating_sub(page_size.saturating_mul(count));
                 app.chat_list_state.select(Some(new_index));
             }
         }
-        KeyCode::PageDown => {
+        Action::PageDown => {
             app.auto_scroll = false;
             let selected = app.chat_list_state.selected();
             let chat_height = app.terminal_height.saturating_sub(6);
@@ -120,113 +1060,237 @@ async fn handle_normal_mode(key: KeyEvent, app: &mut AppState, _tx: mpsc::Sender
             let total_lines = app.calculate_total_message_lines(chat_width);
             
             if let Some(i) = selected {
-                let new_index = std::cmp::min(i + page_size, total_lines.saturating_sub(1));
+                let new_index = std::cmp::min(i + page_size.saturating_mul(count), total_lines.saturating_sub(1));
                 app.chat_list_state.select(Some(new_index));
             }
         }
-        _ => {}
+        // Actions of the other modes
+        Action::NormalMode | Action::Send | Action::Newline | Action::DeleteChar | Action::Yank | Action::PasteRegister => {}
+        // Taken by handle_normal_mode, they wait for the mark name
+        Action::SetMark | Action::JumpToMark | Action::JumpToMarkMessage => {}
     }
     false
 }
 
 async fn handle_insert_mode(key: KeyEvent, app: &mut AppState, tx: mpsc::Sender<AppEvent>) -> bool {
-    match key.code {
-        KeyCode::Esc => {
-            app.mode = AppMode::Normal;
+    // The register name after Ctrl+R
+    if app.awaiting_register {
+        app.awaiting_register = false;
+        if let KeyCode::Char(c) = key.code
+            && let Err(e) = app.paste(Some(c))
+        {
+            app.set_error_message(format!("Paste failed: {}", e));
         }
-        KeyCode::Char(c) => {
-            app.input.push(c);
+        return false;
+    }
+
+    let chord = Chord::from_event(&key);
+    app.push_pending_key(chord);
+    let action = match app.keymap.lookup(keymap::Mode::Insert, &app.pending_keys) {
+        Lookup::Pending => return false,
+        Lookup::Action(action) => Some(action),
+        Lookup::Unbound => {
+            // Keys held back for a longer binding are typed after all
+            let held: Vec<Chord> = app.pending_keys.drain(..app.pending_keys.len() - 1).collect();
+            app.input.extend(held.iter().filter_map(Chord::char));
+            match app.keymap.lookup(keymap::Mode::Insert, &app.pending_keys) {
+                Lookup::Action(action) => Some(action),
+                Lookup::Pending => return false,
+                Lookup::Unbound => None,
+            }
         }
-        KeyCode::Backspace => {
+    };
+    app.pending_keys.clear();
+
+    match action {
+        Some(Action::NormalMode) => {
+            app.mode = AppMode::Normal;
+        }
+        Some(Action::CommandPalette) => app.open_command_palette(),
+        Some(Action::DeleteChar) => {
             app.input.pop();
         }
-        KeyCode::Enter => {
-            if !app.input.is_empty() && !app.is_loading {
-                let user_input: String = app.input.drain(..).collect();
-                app.current_messages_mut().push(models::Message {
-                    role: models::Role::User,
-                    content: user_input,
-                });
-                app.current_messages_mut().push(models::Message {
-                    role: models::Role::Assistant,
-                    content: String::new(),
-                });
-
-                app.is_loading = true;
-                app.auto_scroll = true;
-                app.trigger_auto_scroll();
-
-                let client = app.http_client.clone();
-                let model = app.current_model.clone();
-                let messages = app.current_messages().clone();
-                let base_url = app.ollama_base_url.clone();
-                let auth_config = app.config.auth_method.clone();
-                let auth_enabled = app.config.auth_enabled;
-
-                tokio::spawn(async move {
-                    ollama::stream_chat_request(
-                        &client,
-                        &base_url,
-                        &model,
-                        &messages,
-                        auth_enabled,
-                        auth_config.as_ref(),
-                        tx,
-                    )
-                    .await;
-                });
+        Some(Action::Newline) => app.input.push('\n'),
+        Some(Action::Paste) => {
+            if let Err(e) = app.paste(None) {
+                app.set_error_message(format!("Paste failed: {}", e));
+            }
+        }
+        Some(Action::PasteRegister) => app.awaiting_register = true,
+        Some(Action::Send) if !app.input.is_empty() => {
+            let user_input: String = app.input.drain(..).collect();
+            let user_input = app.with_attachments(user_input);
+            if app.is_loading() {
+                app.queue_prompt(user_input);
+            } else {
+                send_prompt(app, app.current_session_id(), user_input, tx);
+            }
+        }
+        Some(_) => {}
+        None => {
+            if let Some(c) = chord.char() {
+                app.input.push(c);
             }
         }
-        _ => {}
     }
     false
 }
 
 async fn handle_command_mode(key: KeyEvent, app: &mut AppState) -> bool {
+    // Any key other than Tab accepts the current completion
+    if !matches!(key.code, KeyCode::Tab | KeyCode::BackTab) {
+        app.clear_completion();
+    }
+
     match key.code {
+        KeyCode::Tab => app.complete_command(true),
+        KeyCode::BackTab => app.complete_command(false),
         KeyCode::Esc => {
             app.mode = AppMode::Normal;
             app.vim_command.clear();
+            app.reset_history_browsing();
         }
         KeyCode::Char(c) => {
             app.vim_command.push(c);
+            app.reset_history_browsing();
         }
         KeyCode::Backspace => {
             app.vim_command.pop();
+            app.reset_history_browsing();
         }
+        KeyCode::Up => app.browse_command_history(true),
+        KeyCode::Down => app.browse_command_history(false),
         KeyCode::Enter => {
             let command = app.vim_command.clone();
             app.vim_command.clear();
-            
-            if command == "q" || command == "wq" {
-                return true; // Signal to quit
+            return run_command_line(app, &command);
+        }
+        _ => {}
+    }
+    false
+}
+
+/// Execute a `:` command line, returning true when the app should quit.
+fn run_command_line(app: &mut AppState, command: &str) -> bool {
+    app.reset_history_browsing();
+    app.record_command_history(command);
+
+    if command == "q" || command == "wq" {
+        return true; // Signal to quit
+    }
+
+    if let Err(e) = app.execute_vim_command(command) {
+        app.set_error_message(format!("Error: {}", e));
+    }
+
+    // Don't automatically return to Normal mode if we're entering a special mode
+    if app.mode == AppMode::SessionSelection || app.mode == AppMode::ModelSelection || app.mode == AppMode::Help || app.mode == AppMode::Agent || app.mode == AppMode::Autonomous || app.mode == AppMode::AgentRuns || app.mode == AppMode::Audit || app.mode == AppMode::PinnedMessages || app.mode == AppMode::CommandHistory || app.mode == AppMode::Info || app.mode == AppMode::CommandPalette || app.mode == AppMode::PromptVariables || app.mode == AppMode::Workflows || app.mode == AppMode::WorkflowEditor || app.mode == AppMode::Compare || app.mode == AppMode::Bench || app.mode == AppMode::Insert {
+        // Stay in the current mode
+    } else {
+        app.mode = AppMode::Normal;
+    }
+    false
+}
+
+async fn handle_command_palette_mode(key: KeyEvent, app: &mut AppState) -> bool {
+    match key.code {
+        KeyCode::Esc => {
+            app.mode = AppMode::Normal;
+        }
+        KeyCode::Up => app.move_palette_selection(false),
+        KeyCode::Down | KeyCode::Tab => app.move_palette_selection(true),
+        KeyCode::Char('p') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+            app.move_palette_selection(false);
+        }
+        KeyCode::Char('n') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+            app.move_palette_selection(true);
+        }
+        KeyCode::Enter => {
+            let entry = app
+                .palette_list_state
+                .selected()
+                .and_then(|i| app.filtered_palette_entries().get(i).cloned());
+            match entry {
+                // Commands that need an argument go to the command line for editing
+                Some((command, _, true)) => {
+                    app.vim_command = command;
+                    app.mode = AppMode::Command;
+                }
+                Some((command, _, false)) => return run_command_line(app, &command),
+                None => app.mode = AppMode::Normal,
             }
-            
-            app.execute_vim_command(&command).ok();
-            
-            // Don't automatically return to Normal mode if we're entering a special mode
-            if app.mode == AppMode::SessionSelection || app.mode == AppMode::ModelSelection || app.mode == AppMode::Help || app.mode == AppMode::Agent {
-                // Stay in the current mode
-            } else {
-                app.mode = AppMode::Normal;
+        }
+        KeyCode::Char(c) => {
+            app.palette_query.push(c);
+            app.palette_list_state.select(Some(0));
+        }
+        KeyCode::Backspace => {
+            app.palette_query.pop();
+            app.palette_list_state.select(Some(0));
+        }
+        _ => {}
+    }
+    false
+}
+
+async fn handle_command_history_mode(key: KeyEvent, app: &mut AppState) -> bool {
+    match key.code {
+        KeyCode::Esc => {
+            app.mode = AppMode::Normal;
+        }
+        KeyCode::Up => app.move_history_selection(false),
+        KeyCode::Down => app.move_history_selection(true),
+        KeyCode::Enter => {
+            if let Some(command) = app.selected_history_command() {
+                return run_command_line(app, &command);
+            }
+            app.mode = AppMode::Normal;
+        }
+        KeyCode::Tab => {
+            // Edit the selected entry on the command line before running it
+            if let Some(command) = app.selected_history_command() {
+                app.vim_command = command;
+                app.mode = AppMode::Command;
             }
         }
+        KeyCode::Char(c) => {
+            app.history_filter.push(c);
+            app.history_list_state.select(Some(0));
+        }
+        KeyCode::Backspace => {
+            app.history_filter.pop();
+            app.history_list_state.select(Some(0));
+        }
         _ => {}
     }
     false
 }
 
 async fn handle_model_selection_mode(key: KeyEvent, app: &mut AppState) -> bool {
+    // Printable keys go to the fuzzy filter, so navigation uses arrows and Ctrl+N/P
     match key.code {
-        KeyCode::Char('q') | KeyCode::Esc => {
+        KeyCode::Esc => {
             app.mode = AppMode::Normal;
         }
-        KeyCode::Up | KeyCode::Char('k') => app.previous_model(),
-        KeyCode::Down | KeyCode::Char('j') => app.next_model(),
+        KeyCode::Char('p') if key.modifiers.contains(KeyModifiers::CONTROL) => app.previous_model(),
+        KeyCode::Char('n') if key.modifiers.contains(KeyModifiers::CONTROL) => app.next_model(),
+        KeyCode::Char('s') if key.modifiers.contains(KeyModifiers::CONTROL) => app.cycle_model_sort(),
+        KeyCode::Up => app.previous_model(),
+        KeyCode::Down | KeyCode::Tab => app.next_model(),
         KeyCode::Enter => {
             app.confirm_model_selection().ok();
             app.mode = AppMode::Normal;
         }
+        KeyCode::Char(c) => {
+            let mut filter = app.model_filter.clone();
+            filter.push(c);
+            app.set_model_filter(filter);
+        }
+        KeyCode::Backspace => {
+            let mut filter = app.model_filter.clone();
+            filter.pop();
+            app.set_model_filter(filter);
+        }
         _ => {}
     }
     false
@@ -234,6 +1298,7 @@ async fn handle_model_selection_mode(key: KeyEvent, app: &mut AppState) -> bool
 
 async fn handle_session_selection_mode(key: KeyEvent, app: &mut AppState) -> bool {
     match key.code {
+        KeyCode::Char('?') => app.open_help(),
         KeyCode::Char('q') | KeyCode::Esc => {
             app.mode = AppMode::Normal;
         }
@@ -256,62 +1321,531 @@ async fn handle_agent_mode(key: KeyEvent, app: &mut AppState, tx: mpsc::Sender<A
         KeyCode::Char('q') | KeyCode::Esc => {
             app.mode = AppMode::Normal;
             app.agent_mode = false;
-            app.pending_commands.clear();
-            app.command_approval_index = None;
+            // A running workflow keeps its steps
+            if app.workflow_run.as_ref().is_none_or(|run| run.finished) {
+                app.pending_commands.clear();
+                app.command_approval_index = None;
+                app.workflow_run = None;
+            }
         }
-        KeyCode::Enter => {
-            if !app.input.trim().is_empty() && !app.is_loading {
-                let input_content = app.input.clone();
+        KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => interrupt_agent_command(app),
+        KeyCode::Enter if !app.input.trim().is_empty() => {
+            let input_content = app.input.clone();
+            let input_content = app.with_attachments(input_content);
+            
+            let _user_message = if app.agent_mode {
+                let _context = std::env::current_dir()
+                    .map(|p| p.to_string_lossy().to_string())
+                    .unwrap_or_else(|_| "Unknown directory".to_string());
                 
-                let _user_message = if app.agent_mode {
-                    let _context = std::env::current_dir()
-                        .map(|p| p.to_string_lossy().to_string())
-                        .unwrap_or_else(|_| "Unknown directory".to_string());
-                    
-                    format!("Agent mode: {}", input_content)
+                format!("Agent mode: {}", input_content)
+            } else {
+                input_content.clone()
+            };
+
+            app.input.clear();
+            if app.is_loading() {
+                app.queue_prompt(input_content);
+            } else {
+                send_prompt(app, app.current_session_id(), input_content, tx);
+            }
+        }
+        KeyCode::Char(c) => app.input.push(c),
+        KeyCode::Backspace => {
+            app.input.pop();
+        }
+        _ => {}
+    }
+    false
+}
+
+async fn handle_autonomous_mode(key: KeyEvent, app: &mut AppState, tx: mpsc::Sender<AppEvent>) -> bool {
+    // Editing the pending command inline
+    if let Some(buffer) = app.command_edit.as_mut() {
+        match key.code {
+            KeyCode::Enter => app.finish_command_edit(true),
+            KeyCode::Esc => app.finish_command_edit(false),
+            KeyCode::Backspace => {
+                buffer.pop();
+            }
+            KeyCode::Char(c) => buffer.push(c),
+            _ => {}
+        }
+        return false;
+    }
+
+    let paused = app
+        .autonomous
+        .as_ref()
+        .is_some_and(|run| run.state == agent::AgentState::Paused);
+    match key.code {
+        KeyCode::Char('?') => app.open_help(),
+        KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => interrupt_agent_command(app),
+        KeyCode::Esc | KeyCode::Char('q') => {
+            app.stop_autonomous_run();
+            app.mode = AppMode::Normal;
+        }
+        // Pause after the current step, or resume when already paused
+        KeyCode::Char('p') | KeyCode::Char(' ') => {
+            if paused {
+                resume_agent(app, tx);
+            } else if let Some(run) = app.autonomous.as_mut()
+                && !run.state.is_finished()
+            {
+                run.pause_requested = true;
+                app.set_status_message("Pausing after the current step".to_string());
+            }
+        }
+        // Allow the next command, in single-step mode or while paused
+        KeyCode::Char('n') | KeyCode::Enter if paused => resume_agent(app, tx),
+        // Run or refuse a command the policy blocked
+        KeyCode::Char('!') if paused => override_agent_policy(app, tx),
+        KeyCode::Char('x') if paused => {
+            if app.autonomous.as_ref().is_some_and(|run| run.policy_violation.is_some()) {
+                reject_agent_command(app, tx);
+            } else {
+                reject_agent_tool(app, tx);
+            }
+        }
+        // Show the diff of a pending file change again
+        KeyCode::Char('v') if app.pending_change.is_some() || app.pending_commit.is_some() => {
+            app.diff_scroll = 0;
+            app.mode = AppMode::ToolApproval;
+        }
+        KeyCode::Char('s') => {
+            if let Some(run) = app.autonomous.as_mut() {
+                run.single_step = !run.single_step;
+                let message = if run.single_step {
+                    "Single-step: confirm each command with n"
                 } else {
-                    input_content.clone()
+                    "Single-step off"
                 };
+                app.set_status_message(message.to_string());
+            }
+        }
+        KeyCode::Char('j') | KeyCode::Down => app.move_agent_step_selection(true),
+        KeyCode::Char('k') | KeyCode::Up => app.move_agent_step_selection(false),
+        KeyCode::Tab | KeyCode::Char('o') => app.toggle_agent_step(),
+        // Tweak the command waiting for approval
+        KeyCode::Char('e') => {
+            if let Err(e) = app.start_command_edit() {
+                app.set_error_message(e.to_string());
+            }
+        }
+        // Run the selected step's command again, as is or edited
+        KeyCode::Char('r') => requeue_step(app, false),
+        KeyCode::Char('R') => requeue_step(app, true),
+        _ => {}
+    }
+    false
+}
 
-                app.current_messages_mut().push(models::Message {
-                    role: models::Role::User,
-                    content: input_content,
-                });
-                app.input.clear();
+fn requeue_step(app: &mut AppState, edit: bool) {
+    if let Err(e) = app.requeue_selected_step(edit) {
+        app.set_error_message(e.to_string());
+    }
+}
 
-                app.current_messages_mut().push(models::Message {
-                    role: models::Role::Assistant,
-                    content: String::new(),
-                });
+async fn handle_agent_runs_mode(key: KeyEvent, app: &mut AppState) -> bool {
+    match key.code {
+        KeyCode::Char('?') => app.open_help(),
+        KeyCode::Esc | KeyCode::Char('q') => {
+            app.mode = AppMode::Normal;
+        }
+        KeyCode::Char('j') | KeyCode::Down => app.move_runs_selection(true),
+        KeyCode::Char('k') | KeyCode::Up => app.move_runs_selection(false),
+        KeyCode::Enter => app.show_selected_run(),
+        KeyCode::Char('r') => {
+            if let Err(e) = app.resume_selected_run() {
+                app.set_error_message(e.to_string());
+            }
+        }
+        _ => {}
+    }
+    false
+}
 
-                app.is_loading = true;
-                app.auto_scroll = true;
-                app.trigger_auto_scroll();
+async fn handle_workflows_mode(key: KeyEvent, app: &mut AppState, tx: mpsc::Sender<AppEvent>) -> bool {
+    match key.code {
+        KeyCode::Char('?') => app.open_help(),
+        // The workflow keeps running in the background
+        KeyCode::Esc | KeyCode::Char('q') => {
+            app.mode = AppMode::Normal;
+        }
+        KeyCode::Char('j') | KeyCode::Down => app.move_workflow_selection(true),
+        KeyCode::Char('k') | KeyCode::Up => app.move_workflow_selection(false),
+        KeyCode::Enter => match app.start_selected_workflow() {
+            Ok(true) => advance_workflow(app, agent::Approval::Automatic, tx),
+            Ok(false) => {}
+            Err(e) => app.set_error_message(e.to_string()),
+        },
+        KeyCode::Char('y') => match app.approve_workflow_step() {
+            Some(approval) => advance_workflow(app, approval, tx),
+            None => app.set_status_message("No workflow step is waiting for approval".to_string()),
+        },
+        KeyCode::Char('n') if app.command_approval_index.is_some() => app.stop_workflow("stopped, step rejected"),
+        KeyCode::Char('x') => app.stop_workflow("stopped"),
+        KeyCode::Char('e') => app.edit_selected_workflow(),
+        KeyCode::Char('c') => app.new_workflow(""),
+        _ => {}
+    }
+    false
+}
 
-                let client = app.http_client.clone();
-                let model = app.current_model.clone();
-                let messages = app.current_messages().clone();
-                let base_url = app.ollama_base_url.clone();
-                let auth_config = app.config.auth_method.clone();
-                let auth_enabled = app.config.auth_enabled;
+async fn handle_audit_mode(key: KeyEvent, app: &mut AppState) -> bool {
+    match key.code {
+        KeyCode::Char('?') => app.open_help(),
+        KeyCode::Esc | KeyCode::Char('q') => {
+            app.mode = AppMode::Normal;
+        }
+        KeyCode::Char('j') | KeyCode::Down => app.move_audit_selection(true),
+        KeyCode::Char('k') | KeyCode::Up => app.move_audit_selection(false),
+        KeyCode::Enter => app.show_selected_audit_entry(),
+        // Queue the selected command in the paused autonomous run
+        KeyCode::Char('r') | KeyCode::Char('e') => {
+            if let Err(e) = app.requeue_selected_audit_entry(key.code == KeyCode::Char('e')) {
+                app.set_error_message(e.to_string());
+            }
+        }
+        _ => {}
+    }
+    false
+}
 
-                tokio::spawn(async move {
-                    ollama::stream_chat_request(
-                        &client,
-                        &base_url,
-                        &model,
-                        &messages,
-                        auth_enabled,
-                        auth_config.as_ref(),
-                        tx,
-                    )
-                    .await;
-                });
+async fn handle_tool_approval_mode(key: KeyEvent, app: &mut AppState, tx: mpsc::Sender<AppEvent>) -> bool {
+    let committing = app.pending_commit.is_some();
+    match key.code {
+        KeyCode::Char('?') => app.open_help(),
+        KeyCode::Char('y') | KeyCode::Enter => {
+            if app.autonomous.as_ref().is_some_and(|run| run.policy_violation.is_some()) {
+                app.set_status_message("The change is blocked by the command policy, ! to apply it anyway".to_string());
+            } else if app.apply_commit_selection() {
+                app.mode = AppMode::Autonomous;
+                resume_agent(app, tx);
             }
         }
-        KeyCode::Char(c) => app.input.push(c),
+        KeyCode::Char('!') if app.apply_commit_selection() => {
+            app.mode = AppMode::Autonomous;
+            override_agent_policy(app, tx);
+        }
+        // Pick the files that go into the commit
+        KeyCode::Char('j') | KeyCode::Down if committing => app.move_commit_file_selection(true),
+        KeyCode::Char('k') | KeyCode::Up if committing => app.move_commit_file_selection(false),
+        KeyCode::Char(' ') if committing => app.toggle_commit_file(false),
+        KeyCode::Char('a') if committing => app.toggle_commit_file(true),
+        KeyCode::Char('n') | KeyCode::Char('x') => {
+            app.mode = AppMode::Autonomous;
+            reject_agent_tool(app, tx);
+        }
+        // Back to the panel, the change stays pending
+        KeyCode::Esc | KeyCode::Char('q') => app.mode = AppMode::Autonomous,
+        KeyCode::Char('j') | KeyCode::Down => app.diff_scroll = app.diff_scroll.saturating_add(1),
+        KeyCode::Char('k') | KeyCode::Up => app.diff_scroll = app.diff_scroll.saturating_sub(1),
+        KeyCode::PageDown | KeyCode::Char(' ') => app.diff_scroll = app.diff_scroll.saturating_add(10),
+        KeyCode::PageUp => app.diff_scroll = app.diff_scroll.saturating_sub(10),
+        _ => {}
+    }
+    false
+}
+
+async fn handle_plan_review_mode(key: KeyEvent, app: &mut AppState, tx: mpsc::Sender<AppEvent>) -> bool {
+    // Editing a single step inline
+    if let Some(buffer) = app.plan_edit.as_mut() {
+        match key.code {
+            KeyCode::Enter => app.finish_plan_step_edit(true),
+            KeyCode::Esc => app.finish_plan_step_edit(false),
+            KeyCode::Backspace => {
+                buffer.pop();
+            }
+            KeyCode::Char(c) => buffer.push(c),
+            _ => {}
+        }
+        return false;
+    }
+
+    match key.code {
+        KeyCode::Char('?') => app.open_help(),
+        KeyCode::Enter | KeyCode::Char('y') => {
+            app.approve_plan();
+            request_agent_reasoning(app, tx);
+        }
+        KeyCode::Esc | KeyCode::Char('n') | KeyCode::Char('q') => {
+            app.stop_autonomous_run();
+            app.mode = AppMode::Normal;
+        }
+        KeyCode::Down | KeyCode::Char('j') => app.move_plan_selection(true),
+        KeyCode::Up | KeyCode::Char('k') => app.move_plan_selection(false),
+        KeyCode::Char('e') => app.start_plan_step_edit(false),
+        KeyCode::Char('a') | KeyCode::Char('o') => app.start_plan_step_edit(true),
+        KeyCode::Char('d') => app.delete_plan_step(),
+        _ => {}
+    }
+    false
+}
+
+async fn handle_delete_message_confirmation(key: KeyEvent, app: &mut AppState) -> bool {
+    match key.code {
+        KeyCode::Char('y') | KeyCode::Enter => {
+            if let Some(index) = app.pending_delete_message.take() {
+                match app.delete_message(index) {
+                    Ok(_) => app.set_status_message("Message deleted".to_string()),
+                    Err(e) => app.set_error_message(format!("Delete failed: {}", e)),
+                }
+            }
+            app.mode = AppMode::Normal;
+        }
+        KeyCode::Char('n') | KeyCode::Char('q') | KeyCode::Esc => {
+            app.pending_delete_message = None;
+            app.mode = AppMode::Normal;
+        }
+        _ => {}
+    }
+    false
+}
+
+async fn handle_pinned_messages_mode(key: KeyEvent, app: &mut AppState) -> bool {
+    match key.code {
+        KeyCode::Char('?') => app.open_help(),
+        KeyCode::Char('q') | KeyCode::Esc => {
+            app.mode = AppMode::Normal;
+        }
+        KeyCode::Up | KeyCode::Char('k') => app.previous_pinned_message(),
+        KeyCode::Down | KeyCode::Char('j') => app.next_pinned_message(),
+        KeyCode::Enter => {
+            app.jump_to_selected_pin().ok();
+            app.mode = AppMode::Normal;
+        }
+        KeyCode::Char('p') | KeyCode::Char('d') => {
+            app.unpin_selected_pin().ok();
+        }
+        _ => {}
+    }
+    false
+}
+
+async fn handle_info_mode(key: KeyEvent, app: &mut AppState) -> bool {
+    match key.code {
+        KeyCode::Char('q') | KeyCode::Esc | KeyCode::Enter => {
+            app.mode = AppMode::Normal;
+        }
+        KeyCode::Char('j') | KeyCode::Down
+            if (app.info_scroll as usize) < app.info_lines.len().saturating_sub(1) =>
+        {
+            app.info_scroll += 1;
+        }
+        KeyCode::Char('k') | KeyCode::Up => {
+            app.info_scroll = app.info_scroll.saturating_sub(1);
+        }
+        _ => {}
+    }
+    false
+}
+
+async fn handle_prompt_variables_mode(key: KeyEvent, app: &mut AppState) -> bool {
+    match key.code {
+        KeyCode::Esc => {
+            app.prompt_fill = None;
+            app.mode = AppMode::Normal;
+        }
+        KeyCode::Enter => app.next_prompt_variable(),
         KeyCode::Backspace => {
-            app.input.pop();
+            if let Some(fill) = app.prompt_fill.as_mut() {
+                fill.input.pop();
+            }
+        }
+        KeyCode::Char(c) => {
+            if let Some(fill) = app.prompt_fill.as_mut() {
+                fill.input.push(c);
+            }
+        }
+        _ => {}
+    }
+    false
+}
+
+async fn handle_workflow_editor_mode(key: KeyEvent, app: &mut AppState) -> bool {
+    let Some(editor) = app.workflow_editor.as_mut() else {
+        app.mode = AppMode::Workflows;
+        return false;
+    };
+    if let Some(text) = editor.editing.as_mut() {
+        match key.code {
+            KeyCode::Esc => editor.editing = None,
+            KeyCode::Enter => {
+                if let Err(e) = editor.commit() {
+                    app.set_status_message(e);
+                }
+            }
+            KeyCode::Backspace => {
+                text.pop();
+            }
+            KeyCode::Char(c) => text.push(c),
+            _ => {}
+        }
+        return false;
+    }
+    match key.code {
+        KeyCode::Esc | KeyCode::Char('q') => {
+            app.workflow_editor = None;
+            app.mode = AppMode::Workflows;
+        }
+        KeyCode::Char('j') | KeyCode::Down => editor.move_selection(true),
+        KeyCode::Char('k') | KeyCode::Up => editor.move_selection(false),
+        KeyCode::Char('J') => editor.move_step(true),
+        KeyCode::Char('K') => editor.move_step(false),
+        KeyCode::Enter | KeyCode::Char('e') | KeyCode::Char(' ') => editor.edit(),
+        KeyCode::Char('a') => editor.add_step(),
+        KeyCode::Char('v') => editor.add_variable(),
+        KeyCode::Char('t') => editor.add_trigger(),
+        KeyCode::Char('d') => editor.delete(),
+        KeyCode::Char('s') => {
+            if let Err(e) = app.save_workflow_editor() {
+                app.set_error_message(format!("Cannot save workflow: {}", e));
+            }
+        }
+        _ => {}
+    }
+    false
+}
+
+async fn handle_workflow_draft_mode(key: KeyEvent, app: &mut AppState) -> bool {
+    match key.code {
+        KeyCode::Char('?') => app.open_help(),
+        KeyCode::Char('y') => {
+            if let Err(e) = app.save_workflow_draft() {
+                app.set_error_message(format!("Cannot save workflow: {}", e));
+            }
+        }
+        KeyCode::Char('n') | KeyCode::Esc => {
+            app.workflow_draft = None;
+            app.mode = AppMode::Normal;
+            app.set_status_message("Workflow draft discarded".to_string());
+        }
+        KeyCode::Char('e') => {
+            if let Some(draft) = app.workflow_draft.take() {
+                app.open_workflow_editor(draft, None);
+            }
+        }
+        KeyCode::Char('j') | KeyCode::Down => app.draft_scroll = app.draft_scroll.saturating_add(1),
+        KeyCode::Char('k') | KeyCode::Up => app.draft_scroll = app.draft_scroll.saturating_sub(1),
+        _ => {}
+    }
+    false
+}
+
+async fn handle_commit_message_mode(key: KeyEvent, app: &mut AppState, tx: mpsc::Sender<AppEvent>) -> bool {
+    // Editing the message in place, Enter starts a new line
+    if app.commit_message_editing {
+        let message = app.commit_message.get_or_insert_with(String::new);
+        match key.code {
+            KeyCode::Esc => app.commit_message_editing = false,
+            KeyCode::Enter => message.push('\n'),
+            KeyCode::Backspace => {
+                message.pop();
+            }
+            KeyCode::Char(c) => message.push(c),
+            _ => {}
+        }
+        return false;
+    }
+    match key.code {
+        KeyCode::Char('?') => app.open_help(),
+        KeyCode::Char('c') | KeyCode::Enter => match app.commit_with_message() {
+            Ok(()) => advance_workflow(app, agent::Approval::Automatic, tx),
+            Err(e) => app.set_error_message(e.to_string()),
+        },
+        KeyCode::Char('y') => {
+            let message = app.commit_message.clone().unwrap_or_default();
+            if let Err(e) = app.yank(message) {
+                app.set_error_message(format!("Copy failed: {}", e));
+            }
+        }
+        KeyCode::Char('e') => app.commit_message_editing = true,
+        KeyCode::Char('n') | KeyCode::Char('q') | KeyCode::Esc => {
+            app.commit_message = None;
+            app.mode = AppMode::Normal;
+            app.set_status_message("Commit message discarded".to_string());
+        }
+        _ => {}
+    }
+    false
+}
+
+async fn handle_howto_mode(key: KeyEvent, app: &mut AppState, tx: mpsc::Sender<AppEvent>) -> bool {
+    match key.code {
+        KeyCode::Char('?') => app.open_help(),
+        KeyCode::Char('r') | KeyCode::Enter => match app.run_howto() {
+            Ok(()) => advance_workflow(app, agent::Approval::Automatic, tx),
+            Err(e) => app.set_error_message(e.to_string()),
+        },
+        KeyCode::Char('y') => {
+            let command = app.howto.as_ref().map(|suggestion| suggestion.command.clone()).unwrap_or_default();
+            if let Err(e) = app.yank(command) {
+                app.set_error_message(format!("Copy failed: {}", e));
+            }
+        }
+        KeyCode::Char('i') => {
+            if let Some(suggestion) = app.howto.take() {
+                app.input.push_str(&suggestion.command);
+                app.mode = AppMode::Insert;
+            }
+        }
+        KeyCode::Char('n') | KeyCode::Char('q') | KeyCode::Esc => {
+            app.howto = None;
+            app.mode = AppMode::Normal;
+        }
+        _ => {}
+    }
+    false
+}
+
+// The replies keep streaming, and are still recorded, after the popup is closed
+async fn handle_compare_mode(key: KeyEvent, app: &mut AppState) -> bool {
+    match key.code {
+        KeyCode::Char('?') => app.open_help(),
+        KeyCode::Esc | KeyCode::Char('q') => app.mode = AppMode::Normal,
+        KeyCode::Char('i') => app.mode = AppMode::Insert,
+        KeyCode::Char('j') | KeyCode::Down => app.compare_scroll = app.compare_scroll.saturating_add(1),
+        KeyCode::Char('k') | KeyCode::Up => app.compare_scroll = app.compare_scroll.saturating_sub(1),
+        KeyCode::Char('g') => app.compare_scroll = 0,
+        _ => {}
+    }
+    false
+}
+
+async fn handle_bench_mode(key: KeyEvent, app: &mut AppState) -> bool {
+    match key.code {
+        KeyCode::Char('?') => app.open_help(),
+        KeyCode::Esc | KeyCode::Char('q') => app.mode = AppMode::Normal,
+        KeyCode::Char('x') => {
+            if let Some(cancel) = app.bench.as_mut().filter(|bench| bench.is_running()).and_then(|bench| bench.cancel.take()) {
+                cancel.send(()).ok();
+                app.set_status_message("Benchmark stopped".to_string());
+            }
+        }
+        KeyCode::Char('j') | KeyCode::Down => app.bench_scroll = app.bench_scroll.saturating_add(1),
+        KeyCode::Char('k') | KeyCode::Up => app.bench_scroll = app.bench_scroll.saturating_sub(1),
+        _ => {}
+    }
+    false
+}
+
+async fn handle_workflow_variables_mode(key: KeyEvent, app: &mut AppState, tx: mpsc::Sender<AppEvent>) -> bool {
+    match key.code {
+        KeyCode::Esc => {
+            app.workflow_fill = None;
+            app.mode = AppMode::Workflows;
+        }
+        KeyCode::Enter if app.next_workflow_variable() => advance_workflow(app, agent::Approval::Automatic, tx),
+        KeyCode::Backspace => {
+            if let Some((_, fill)) = app.workflow_fill.as_mut() {
+                fill.input.pop();
+            }
+        }
+        KeyCode::Char(c) => {
+            if let Some((_, fill)) = app.workflow_fill.as_mut() {
+                fill.input.push(c);
+            }
         }
         _ => {}
     }
@@ -319,61 +1853,105 @@ async fn handle_agent_mode(key: KeyEvent, app: &mut AppState, tx: mpsc::Sender<A
 }
 
 async fn handle_help_mode(key: KeyEvent, app: &mut AppState) -> bool {
+    // Typing the search after `/`
+    if app.help_searching {
+        match key.code {
+            KeyCode::Esc => {
+                app.help_searching = false;
+                app.help_filter.clear();
+            }
+            KeyCode::Enter => app.help_searching = false,
+            KeyCode::Backspace => {
+                app.help_filter.pop();
+            }
+            KeyCode::Char(c) => app.help_filter.push(c),
+            _ => {}
+        }
+        app.help_scroll = 0;
+        return false;
+    }
+
+    let half_page = (app.terminal_height.saturating_sub(6) / 2).max(1);
     match key.code {
+        // The first Esc drops the search, the next one closes the help
+        KeyCode::Esc if !app.help_filter.is_empty() => {
+            app.help_filter.clear();
+            app.help_scroll = 0;
+        }
         KeyCode::Char('q') | KeyCode::Esc | KeyCode::Char('?') => {
-            app.mode = AppMode::Normal;
+            app.mode = app.help_context;
+        }
+        KeyCode::Char('/') => {
+            app.help_searching = true;
+            app.help_filter.clear();
         }
+        KeyCode::Char('d') if key.modifiers.contains(KeyModifiers::CONTROL) => app.help_scroll = app.help_scroll.saturating_add(half_page),
+        KeyCode::Char('u') if key.modifiers.contains(KeyModifiers::CONTROL) => app.help_scroll = app.help_scroll.saturating_sub(half_page),
+        KeyCode::Char('j') | KeyCode::Down => app.help_scroll = app.help_scroll.saturating_add(1),
+        KeyCode::Char('k') | KeyCode::Up => app.help_scroll = app.help_scroll.saturating_sub(1),
+        KeyCode::PageDown => app.help_scroll = app.help_scroll.saturating_add(half_page * 2),
+        KeyCode::PageUp => app.help_scroll = app.help_scroll.saturating_sub(half_page * 2),
+        KeyCode::Char('g') => app.help_scroll = 0,
+        KeyCode::Char('G') => app.help_scroll = u16::MAX, // Clamped to the last page when drawn
         _ => {}
     }
     false
 }
 
 async fn handle_visual_mode(key: KeyEvent, app: &mut AppState) -> bool {
-    match key.code {
-        KeyCode::Esc | KeyCode::Char('q') => {
+    if register_prefix(&key, app) {
+        return false;
+    }
+    app.push_pending_key(Chord::from_event(&key));
+    let action = match app.keymap.lookup(keymap::Mode::Visual, &app.pending_keys) {
+        Lookup::Pending => return false,
+        Lookup::Unbound => None,
+        Lookup::Action(action) => Some(action),
+    };
+    app.pending_keys.clear();
+
+    match action {
+        Some(Action::NormalMode) => {
             app.clear_visual_selection();
         }
-        KeyCode::Char('y') => {
-            // Copy selection to clipboard
-            match app.copy_selection_to_clipboard() {
-                Ok(_) => {
-                    app.set_status_message("Copied to clipboard".to_string());
-                }
-                Err(e) => {
-                    app.set_status_message(format!("Copy failed: {}", e));
-                }
+        Some(Action::Help) => app.open_help(),
+        Some(Action::Yank) => {
+            // Copy selection to the register, or to the clipboard
+            let text = app.get_selected_text();
+            if let Err(e) = app.yank(text) {
+                app.set_error_message(format!("Copy failed: {}", e));
             }
             app.clear_visual_selection();
         }
-        KeyCode::Char('j') | KeyCode::Down => {
+        Some(Action::ScrollDown) => {
             let selected = app.chat_list_state.selected();
             let chat_width = (app.terminal_width * 3) / 4;
             let total_lines = app.calculate_total_message_lines(chat_width);
             
-            if let Some(i) = selected {
-                if i < total_lines.saturating_sub(1) {
-                    let new_pos = i + 1;
-                    app.chat_list_state.select(Some(new_pos));
-                    app.update_visual_selection(new_pos);
-                }
+            if let Some(i) = selected
+                && i < total_lines.saturating_sub(1)
+            {
+                let new_pos = i + 1;
+                app.chat_list_state.select(Some(new_pos));
+                app.update_visual_selection(new_pos);
             }
         }
-        KeyCode::Char('k') | KeyCode::Up => {
+        Some(Action::ScrollUp) => {
             let selected = app.chat_list_state.selected();
-            if let Some(i) = selected {
-                if i > 0 {
-                    let new_pos = i - 1;
-                    app.chat_list_state.select(Some(new_pos));
-                    app.update_visual_selection(new_pos);
-                }
+            if let Some(i) = selected
+                && i > 0
+            {
+                let new_pos = i - 1;
+                app.chat_list_state.select(Some(new_pos));
+                app.update_visual_selection(new_pos);
             }
         }
-        KeyCode::Char('g') => {
+        Some(Action::Top) => {
             // Go to top
             app.chat_list_state.select(Some(0));
             app.update_visual_selection(0);
         }
-        KeyCode::Char('G') => {
+        Some(Action::Bottom) => {
             // Go to bottom
             let chat_width = (app.terminal_width * 3) / 4;
             let total_lines = app.calculate_total_message_lines(chat_width);
@@ -383,7 +1961,7 @@ async fn handle_visual_mode(key: KeyEvent, app: &mut AppState) -> bool {
                 app.update_visual_selection(bottom);
             }
         }
-        KeyCode::PageUp => {
+        Some(Action::PageUp) => {
             let selected = app.chat_list_state.selected();
             let chat_height = app.terminal_height.saturating_sub(6);
             let page_size = chat_height as usize;
@@ -394,7 +1972,7 @@ async fn handle_visual_mode(key: KeyEvent, app: &mut AppState) -> bool {
                 app.update_visual_selection(new_index);
             }
         }
-        KeyCode::PageDown => {
+        Some(Action::PageDown) => {
             let selected = app.chat_list_state.selected();
             let chat_height = app.terminal_height.saturating_sub(6);
             let page_size = chat_height as usize;
@@ -409,6 +1987,7 @@ async fn handle_visual_mode(key: KeyEvent, app: &mut AppState) -> bool {
         }
         _ => {}
     }
+    app.pending_register = None;
     false
 }
 
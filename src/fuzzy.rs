@@ -0,0 +1,46 @@
+/// Case-insensitive subsequence match of `pattern` against `text`.
+///
+/// Returns `None` when not every pattern character appears in order, otherwise
+/// a score where higher is better: consecutive matches and matches at the start
+/// of words are rewarded, gaps are penalised.
+pub fn fuzzy_score(pattern: &str, text: &str) -> Option<i64> {
+    if pattern.is_empty() {
+        return Some(0);
+    }
+
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+    let mut score = 0;
+    let mut last_match: Option<usize> = None;
+    let mut position = 0;
+
+    for p in pattern.to_lowercase().chars() {
+        let found = text[position..].iter().position(|&c| c == p)? + position;
+        score += match last_match {
+            Some(last) if found == last + 1 => 10,
+            Some(last) => -((found - last) as i64).min(5),
+            None => -(found as i64).min(10),
+        };
+        let at_word_start = found == 0 || !text[found - 1].is_alphanumeric();
+        if at_word_start {
+            score += 8;
+        }
+        last_match = Some(found);
+        position = found + 1;
+    }
+    Some(score)
+}
+
+/// Indices of `items` matching `pattern`, best match first.
+pub fn filter<'a, I>(pattern: &str, items: I) -> Vec<usize>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let mut scored: Vec<(usize, i64)> = items
+        .into_iter()
+        .enumerate()
+        .filter_map(|(i, item)| fuzzy_score(pattern, item).map(|score| (i, score)))
+        .collect();
+    // Stable sort keeps the original order for equal scores
+    scored.sort_by_key(|&(_, score)| std::cmp::Reverse(score));
+    scored.into_iter().map(|(i, _)| i).collect()
+}
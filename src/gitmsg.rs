@@ -0,0 +1,91 @@
+use crate::models::{Message, Role};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::path::Path;
+use std::process::Command;
+
+// Staged patches beyond this many characters are cut, the file summary before them is kept
+const MAX_DIFF_CHARS: usize = 24_000;
+// Recent subjects shown to the model, so the message follows the repository's style
+const RECENT_COMMITS: &str = "-8";
+
+/// The commit message written for the staged changes, requested as structured output.
+#[derive(Deserialize, Debug, Clone)]
+pub struct CommitMessage {
+    pub subject: String,
+    #[serde(default)]
+    pub body: String,
+}
+
+impl CommitMessage {
+    pub fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "subject": { "type": "string" },
+                "body": { "type": "string" }
+            },
+            "required": ["subject"]
+        })
+    }
+
+    /// The subject, then the body after a blank line when there is one.
+    pub fn text(&self) -> String {
+        let subject = self.subject.trim();
+        match self.body.trim() {
+            "" => subject.to_string(),
+            body => format!("{}\n\n{}", subject, body),
+        }
+    }
+}
+
+fn git(cwd: &Path, args: &[&str]) -> Result<String, String> {
+    let output = Command::new("git")
+        .args(args)
+        .current_dir(cwd)
+        .output()
+        .map_err(|e| format!("Cannot run git: {}", e))?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// `git diff --staged` of the repository at `cwd`, after its `--stat` summary.
+pub fn staged_diff(cwd: &Path) -> Result<String, String> {
+    let stat = git(cwd, &["diff", "--staged", "--stat"])?;
+    if stat.trim().is_empty() {
+        return Err("Nothing is staged, git add the changes to describe first".to_string());
+    }
+    let patch = git(cwd, &["diff", "--staged"])?;
+    let mut diff = format!("{}\n{}", stat.trim_end(), patch);
+    if diff.chars().count() > MAX_DIFF_CHARS {
+        diff = diff.chars().take(MAX_DIFF_CHARS).collect();
+        diff.push_str("\n[diff truncated]");
+    }
+    Ok(diff)
+}
+
+/// Ask for a conventional-commit message describing the staged `diff` of the repository at `cwd`.
+pub fn generation_messages(diff: &str, cwd: &Path) -> Vec<Message> {
+    let system = "You write git commit messages in the Conventional Commits style. Reply with JSON: \
+                  \"subject\" is one line of at most 72 characters, `type(scope): summary` in the \
+                  imperative mood, where the type is one of feat, fix, docs, style, refactor, perf, test, \
+                  build, ci or chore and the scope is optional. \"body\" explains what changed and why in \
+                  a few short lines wrapped at 72 characters, or is empty when the subject says it all. \
+                  Describe only what the diff shows."
+        .to_string();
+    // A repository without commits has no history to follow
+    let recent = git(cwd, &["log", "--oneline", "--no-decorate", RECENT_COMMITS]).unwrap_or_default();
+    let mut prompt = String::new();
+    if !recent.trim().is_empty() {
+        prompt.push_str(&format!("Recent commits:\n{}\n\n", recent.trim_end()));
+    }
+    prompt.push_str(&format!("Staged changes:\n{}", diff));
+    vec![Message::new(Role::System, system), Message::new(Role::User, prompt)]
+}
+
+/// The shell command committing what is staged with `message`.
+pub fn commit_command(message: &str) -> String {
+    format!("git commit -m '{}'", message.replace('\'', "'\\''"))
+}
@@ -0,0 +1,223 @@
+use crate::{
+    app::{AppMode, AppState},
+    commands, keymap,
+};
+
+/// A titled group of the help screen: keys or commands and what they do.
+pub struct Section {
+    pub title: String,
+    pub entries: Vec<(String, String)>,
+}
+
+// Keys and what they do
+type Keys = &'static [(&'static str, &'static str)];
+
+// Keys of the modes that cannot be remapped, from their event handlers
+const MODE_KEYS: &[(AppMode, &str, Keys)] = &[
+    (AppMode::ToolApproval, "APPROVE CHANGE / COMMIT", &[
+        ("y/Enter", "Write the file, or commit the selected files"),
+        ("!", "Apply a change the command policy blocked"),
+        ("n/x", "Reject"),
+        ("j/k", "Scroll the diff, or select a file of the commit"),
+        ("Space", "Toggle the selected file of the commit, or page down the diff"),
+        ("a", "Toggle all files of the commit"),
+        ("PgUp/PgDn", "Page up/down the diff"),
+        ("ESC/q", "Back to the panel, the change stays pending"),
+    ]),
+    (AppMode::Autonomous, "AUTONOMOUS AGENT", &[
+        ("p/Space", "Pause after the current step, or resume"),
+        ("s", "Toggle single-step mode"),
+        ("n/Enter", "Run the next command (paused)"),
+        ("!/x", "Run/reject a command the policy blocked (paused)"),
+        ("e", "Edit the next command"),
+        ("j/k", "Select a step"),
+        ("Tab/o", "Expand the selected step"),
+        ("v", "View a pending file change"),
+        ("r/R", "Re-run the selected step's command, as is or edited"),
+        ("Ctrl+C", "Kill the running command"),
+        ("ESC/q", "Stop the run"),
+    ]),
+    (AppMode::PlanReview, "PLAN REVIEW", &[
+        ("Enter/y", "Approve the plan"),
+        ("e", "Edit the selected step"),
+        ("a/o", "Add a step"),
+        ("d", "Delete the selected step"),
+        ("j/k", "Select a step"),
+        ("ESC/n/q", "Reject the plan"),
+    ]),
+    (AppMode::Agent, "AGENT MODE", &[
+        ("Enter", "Send the message to the agent"),
+        ("Ctrl+C", "Kill the running command"),
+        ("ESC/q", "Leave agent mode"),
+    ]),
+    (AppMode::AgentRuns, "AGENT RUNS", &[
+        ("Enter", "Show the steps of the selected run"),
+        ("r", "Resume an unfinished run"),
+        ("j/k", "Select a run"),
+        ("ESC/q", "Close"),
+    ]),
+    (AppMode::Audit, "AUDIT LOG", &[
+        ("Enter", "Show the details of the selected command"),
+        ("r/e", "Queue/edit the command in the paused run"),
+        ("j/k", "Select a command"),
+        ("ESC/q", "Close"),
+    ]),
+    (AppMode::Workflows, "WORKFLOWS", &[
+        ("Enter", "Run the selected workflow"),
+        ("y/n", "Approve/reject the waiting step"),
+        ("x", "Stop the running workflow"),
+        ("e", "Edit the selected workflow"),
+        ("c", "Create a workflow"),
+        ("j/k", "Select a workflow"),
+        ("ESC/q", "Close, the workflow keeps running"),
+    ]),
+    (AppMode::WorkflowEditor, "WORKFLOW EDITOR", &[
+        ("j/k", "Select a field"),
+        ("Enter/e/Space", "Edit the selected field"),
+        ("a/v/t", "Add a step/variable/trigger"),
+        ("d", "Delete the selected item"),
+        ("J/K", "Move the selected step"),
+        ("s", "Save"),
+        ("ESC/q", "Close without saving"),
+    ]),
+    (AppMode::WorkflowDraft, "WORKFLOW DRAFT", &[
+        ("y", "Save to the workflow directory"),
+        ("e", "Edit before saving"),
+        ("j/k", "Scroll"),
+        ("n/ESC", "Discard"),
+    ]),
+    (AppMode::CommitMessage, "COMMIT MESSAGE", &[
+        ("c/Enter", "Commit the staged changes, approved like a workflow step"),
+        ("y", "Copy the message"),
+        ("e", "Edit the message, ESC when done"),
+        ("n/ESC/q", "Discard"),
+    ]),
+    (AppMode::HowTo, "HOW TO", &[
+        ("r/Enter", "Run the command, approved like a workflow step"),
+        ("y", "Copy the command"),
+        ("i", "Put the command into the input"),
+        ("n/ESC/q", "Close"),
+    ]),
+    (AppMode::WorkflowVariables, "WORKFLOW VARIABLES", &[
+        ("Enter", "Next variable"),
+        ("ESC", "Cancel"),
+    ]),
+    (AppMode::PromptVariables, "PROMPT VARIABLES", &[
+        ("Enter", "Next placeholder"),
+        ("ESC", "Cancel"),
+    ]),
+    (AppMode::ModelSelection, "MODEL SELECTION", &[
+        ("Type", "Filter the models"),
+        ("↑/↓, Ctrl+P/N", "Select a model"),
+        ("Ctrl+S", "Change the sort order"),
+        ("Enter", "Use the selected model"),
+        ("ESC", "Close"),
+    ]),
+    (AppMode::SessionSelection, "SESSION SELECTION", &[
+        ("j/k", "Select a session"),
+        ("Enter", "Switch to the selected session"),
+        ("d/Delete", "Delete the selected session"),
+        ("ESC/q", "Close"),
+    ]),
+    (AppMode::PinnedMessages, "PINNED MESSAGES", &[
+        ("Enter", "Jump to the selected pin"),
+        ("p/d", "Unpin"),
+        ("j/k", "Select a pin"),
+        ("ESC/q", "Close"),
+    ]),
+    (AppMode::Compare, "COMPARE", &[
+        ("i", "Write the next prompt"),
+        ("j/k", "Scroll"),
+        ("g", "Go to top"),
+        ("ESC/q", "Close, the replies keep streaming"),
+    ]),
+    (AppMode::Bench, "BENCHMARK", &[
+        ("x", "Stop the benchmark"),
+        ("j/k", "Scroll"),
+        ("ESC/q", "Close, the benchmark keeps running"),
+    ]),
+];
+
+// Keys of the help screen itself
+const HELP_KEYS: Keys = &[
+    ("/", "Search the help"),
+    ("j/k", "Scroll"),
+    ("Ctrl+D/Ctrl+U", "Half-page down/up"),
+    ("g/G", "Go to top/bottom"),
+    ("ESC/q/?", "Close"),
+];
+
+fn section(title: &str, keys: &[(&str, &str)]) -> Section {
+    Section {
+        title: title.to_string(),
+        entries: keys.iter().map(|(keys, description)| (keys.to_string(), description.to_string())).collect(),
+    }
+}
+
+fn keymap_section(app: &AppState, mode: keymap::Mode) -> Section {
+    let mut entries: Vec<(String, String)> = app
+        .keymap
+        .action_keys(mode)
+        .into_iter()
+        .map(|(keys, description)| (keys, description.to_string()))
+        .collect();
+    if mode == keymap::Mode::Normal {
+        entries.push(("{N} before a key".to_string(), "Repeat it N times (e.g. 5j)".to_string()));
+    }
+    Section { title: format!("{} MODE KEYS", mode.name().to_uppercase()), entries }
+}
+
+fn command_section(app: &AppState) -> Section {
+    let mut entries = vec![
+        ("Tab/Shift+Tab".to_string(), "Complete the command".to_string()),
+        ("↑/↓".to_string(), "Browse the command history".to_string()),
+    ];
+    entries.extend(commands::COMMANDS.iter().map(|command| (format!(":{}", command.name), command.description.to_string())));
+    let mut aliases: Vec<_> = app.config.aliases.iter().collect();
+    aliases.sort();
+    entries.extend(aliases.into_iter().map(|(name, steps)| (format!(":{}", name), format!("Alias for {}", steps.join(" | ")))));
+    Section { title: "COMMAND MODE COMMANDS".to_string(), entries }
+}
+
+/// The help screen for `context`, the mode it was opened from: the keys of that mode
+/// first, then the remappable modes as currently bound, the commands and the other modes.
+pub fn sections(app: &AppState, context: &AppMode) -> Vec<Section> {
+    let context_keys = match context {
+        AppMode::Normal => Some(keymap::Mode::Normal),
+        AppMode::Insert => Some(keymap::Mode::Insert),
+        AppMode::Visual => Some(keymap::Mode::Visual),
+        _ => None,
+    };
+    let mut out = Vec::new();
+    match context_keys {
+        Some(mode) => out.push(keymap_section(app, mode)),
+        None => out.extend(MODE_KEYS.iter().filter(|(mode, ..)| mode == context).map(|(_, title, keys)| section(title, keys))),
+    }
+    for mode in keymap::Mode::ALL.into_iter().filter(|mode| Some(*mode) != context_keys) {
+        out.push(keymap_section(app, mode));
+    }
+    out.push(command_section(app));
+    out.extend(MODE_KEYS.iter().filter(|(mode, ..)| mode != context).map(|(_, title, keys)| section(title, keys)));
+    out.push(section("HELP SCREEN", HELP_KEYS));
+    out
+}
+
+/// The sections matching `filter`, case-insensitively: a section whose title matches
+/// is kept whole, otherwise only its matching entries are.
+pub fn filter(sections: Vec<Section>, filter: &str) -> Vec<Section> {
+    let filter = filter.to_lowercase();
+    if filter.is_empty() {
+        return sections;
+    }
+    sections
+        .into_iter()
+        .filter_map(|mut section| {
+            if !section.title.to_lowercase().contains(&filter) {
+                section.entries.retain(|(keys, description)| {
+                    keys.to_lowercase().contains(&filter) || description.to_lowercase().contains(&filter)
+                });
+            }
+            (!section.entries.is_empty()).then_some(section)
+        })
+        .collect()
+}
@@ -0,0 +1,135 @@
+use crate::agent::{self, Approval, AuditEntry};
+use crate::app::AppState;
+use crate::models::{Message, Role};
+use crate::{clipboard, config, db, ollama, policy};
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+
+/// The one shell command answering a "how do I" question, requested as structured output.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Suggestion {
+    pub command: String,
+    #[serde(default)]
+    pub explanation: String,
+}
+
+impl Suggestion {
+    pub fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "command": { "type": "string" },
+                "explanation": { "type": "string" }
+            },
+            "required": ["command", "explanation"]
+        })
+    }
+}
+
+/// Ask for a single shell command doing what `question` describes, run in `cwd`.
+pub fn generation_messages(question: &str, cwd: &Path) -> Vec<Message> {
+    let shell = if cfg!(windows) { "cmd" } else { "sh" };
+    let system = format!(
+        "You turn requests into shell commands for a {} machine, run by {} in the directory {}. Reply \
+         with JSON: \"command\" is exactly one command line doing what was asked, using pipes or && \
+         rather than several lines, and \"explanation\" says in one or two short sentences what it \
+         does and what its flags mean. Prefer standard tools, and never use sudo unless asked.",
+        std::env::consts::OS,
+        shell,
+        cwd.display()
+    );
+    vec![Message::new(Role::System, system), Message::new(Role::User, question.to_string())]
+}
+
+/// `ollama-tui --ask <question>`: print the suggested command and its explanation, then
+/// copy or run it as the user chooses. It never runs without being asked to.
+pub async fn run_cli(question: &str) -> Result<()> {
+    let app = AppState::load(config::load_or_create()?)?;
+    if app.current_model == "No model selected" {
+        return Err(anyhow!("No model selected yet, pick one in the TUI first"));
+    }
+    let cwd = std::env::current_dir()?;
+    let mut options = app.request_options(app.current_session_id(), &app.current_model);
+    options.format = Some(Suggestion::schema());
+    let suggestion: Suggestion = ollama::complete_chat(
+        &app.http_client,
+        &app.ollama_base_url,
+        &app.current_model,
+        &generation_messages(question, &cwd),
+        &options,
+        app.config.auth_enabled,
+        app.config.auth_method.as_ref(),
+    )
+    .await
+    .map_err(|e| anyhow!(e))?;
+
+    let command = suggestion.command.trim().to_string();
+    let risk = agent::assess_risk_level(&command);
+    println!("$ {}", command);
+    if !suggestion.explanation.trim().is_empty() {
+        println!("\n{}", suggestion.explanation.trim());
+    }
+    println!("\n[{} risk]", risk.label());
+    // A command the policy denies runs only when asked for explicitly, like `!` in the TUI
+    let blocked = match policy::evaluate(&app.command_policy, &command, &cwd) {
+        policy::Verdict::Denied(reason) => {
+            println!("Blocked by the command policy: {}", reason);
+            true
+        }
+        _ => false,
+    };
+    loop {
+        print!("{}, [c]opy or [q]uit? ", if blocked { "[!] run anyway" } else { "[r]un" });
+        std::io::stdout().flush()?;
+        let mut answer = String::new();
+        if std::io::stdin().read_line(&mut answer)? == 0 {
+            return Ok(());
+        }
+        match answer.trim() {
+            "r" | "run" if !blocked => break,
+            "!" if blocked => break,
+            "c" | "copy" => {
+                clipboard::copy(&command)?;
+                println!("Copied to clipboard");
+                return Ok(());
+            }
+            "q" | "quit" | "" => return Ok(()),
+            _ => {}
+        }
+    }
+
+    let approval = if blocked { Approval::Override } else { Approval::User };
+    let timeout = Some(Duration::from_secs(app.options.command_timeout)).filter(|t| !t.is_zero());
+    // Never fired, Ctrl+C ends the whole process here
+    let (_cancel_tx, cancel_rx) = tokio::sync::oneshot::channel();
+    let output =
+        agent::execute_command(&command, &cwd, &app.command_env, app.options.dry_run, timeout, cancel_rx, None).await;
+    let text = match &output.result {
+        Ok(text) | Err(text) => agent::redact_secrets(text, &app.command_env, &app.redaction),
+    };
+    print!("{}", text);
+    if !text.ends_with('\n') {
+        println!();
+    }
+    let entry = AuditEntry {
+        id: 0,
+        executed_at: chrono::Utc::now(),
+        session_id: 0, // Not run in a session
+        command: command.clone(),
+        cwd: cwd.to_string_lossy().to_string(),
+        risk: risk.label().to_string(),
+        approval: approval.label().to_string(),
+        dry_run: app.options.dry_run,
+        exit_code: output.exit_code,
+        output: agent::truncate(&text),
+    };
+    db::append_audit_entry(&app.db_conn, &entry)?;
+    if output.result.is_err() {
+        return Err(anyhow!("`{}` failed", command));
+    }
+    Ok(())
+}
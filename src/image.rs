@@ -0,0 +1,154 @@
+use crate::models::ImageConfig;
+use regex::Regex;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+
+static IMAGE_REF: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^\s*!\[([^\]]*)\]\(([^)\s]+)\)\s*$").unwrap());
+
+// Kitty escape sequences are sent in pieces of at most this many base64 bytes
+const KITTY_CHUNK: usize = 4096;
+
+/// Terminal graphics protocol used to draw images in the chat pane.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    Kitty,
+    Iterm2,
+    None, // Only the placeholder line is shown
+}
+
+impl Protocol {
+    /// From `images.protocol`; `auto` recognizes kitty, and iTerm2 and WezTerm
+    /// (which speak the iTerm2 protocol) from the environment.
+    pub fn detect(config: &ImageConfig) -> Self {
+        match config.protocol.as_str() {
+            "kitty" => Protocol::Kitty,
+            "iterm2" => Protocol::Iterm2,
+            "auto" => {
+                let term = std::env::var("TERM").unwrap_or_default();
+                let program = std::env::var("TERM_PROGRAM").unwrap_or_default();
+                if term == "xterm-kitty" || std::env::var_os("KITTY_WINDOW_ID").is_some() {
+                    Protocol::Kitty
+                } else if matches!(program.as_str(), "iTerm.app" | "WezTerm") {
+                    Protocol::Iterm2
+                } else {
+                    Protocol::None
+                }
+            }
+            // Sixel needs the image decoded into pixels, which is not done here
+            _ => Protocol::None,
+        }
+    }
+
+    /// Whether the protocol can draw the file, by its extension.
+    pub fn supports(self, path: &Path) -> bool {
+        let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("").to_lowercase();
+        match self {
+            Protocol::Kitty => extension == "png", // Other formats would have to be decoded first
+            Protocol::Iterm2 => matches!(extension.as_str(), "png" | "jpg" | "jpeg" | "gif" | "webp" | "bmp"),
+            Protocol::None => false,
+        }
+    }
+}
+
+/// What the chat pane needs to know to leave room for images.
+#[derive(Clone, Copy, Debug)]
+pub struct Settings {
+    pub protocol: Protocol,
+    pub cols: u16,
+    pub rows: u16,
+}
+
+impl Settings {
+    pub fn new(config: &ImageConfig) -> Self {
+        Self { protocol: Protocol::detect(config), cols: config.max_width.max(1), rows: config.max_height.max(1) }
+    }
+
+    /// Rows left empty below the image line for `path`, none when it cannot be drawn.
+    pub fn rows_for(&self, path: &str) -> u16 {
+        if self.protocol.supports(Path::new(path)) { self.rows } else { 0 }
+    }
+}
+
+/// The alt text and path of a line that is just a markdown image, `![alt](path)`.
+pub fn find_ref(line: &str) -> Option<(&str, &str)> {
+    let captures = IMAGE_REF.captures(line)?;
+    Some((captures.get(1)?.as_str(), captures.get(2)?.as_str()))
+}
+
+/// An image to draw over the rows the chat pane left empty for it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Placement {
+    pub path: PathBuf,
+    pub x: u16,
+    pub y: u16,
+    pub cols: u16,
+    pub rows: u16,
+}
+
+/// Draw `placements` after a frame, replacing the images drawn before. Nothing is
+/// sent when they have not moved, iTerm2 images would otherwise flicker.
+pub fn draw(protocol: Protocol, placements: &[Placement], drawn: &mut Vec<Placement>) -> std::io::Result<()> {
+    if protocol == Protocol::None || placements == drawn.as_slice() {
+        return Ok(());
+    }
+    let mut out = std::io::stdout().lock();
+    if protocol == Protocol::Kitty {
+        // Remove the earlier placements and free their data
+        write!(out, "\x1b_Ga=d,d=A,q=2\x1b\\")?;
+    }
+    for placement in placements {
+        let Ok(bytes) = std::fs::read(&placement.path) else {
+            continue;
+        };
+        // Save the cursor, draw at the placement's cell, then restore it for ratatui
+        write!(out, "\x1b7\x1b[{};{}H", placement.y + 1, placement.x + 1)?;
+        let data = base64(&bytes);
+        match protocol {
+            Protocol::Kitty => {
+                let chunks: Vec<&[u8]> = data.as_bytes().chunks(KITTY_CHUNK).collect();
+                for (i, chunk) in chunks.iter().enumerate() {
+                    let more = u8::from(i + 1 < chunks.len());
+                    if i == 0 {
+                        write!(out, "\x1b_Ga=T,f=100,c={},r={},C=1,q=2,m={};", placement.cols, placement.rows, more)?;
+                    } else {
+                        write!(out, "\x1b_Gm={};", more)?;
+                    }
+                    out.write_all(chunk)?;
+                    write!(out, "\x1b\\")?;
+                }
+            }
+            Protocol::Iterm2 => {
+                write!(
+                    out,
+                    "\x1b]1337;File=inline=1;size={};width={};height={};preserveAspectRatio=1:{}\x07",
+                    bytes.len(),
+                    placement.cols,
+                    placement.rows,
+                    data
+                )?;
+            }
+            Protocol::None => {}
+        }
+        write!(out, "\x1b8")?;
+    }
+    out.flush()?;
+    *drawn = placements.to_vec();
+    Ok(())
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = (chunk[0] as u32) << 16 | (*chunk.get(1).unwrap_or(&0) as u32) << 8 | *chunk.get(2).unwrap_or(&0) as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
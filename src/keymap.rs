@@ -0,0 +1,346 @@
+use crate::models::KeyList;
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use std::collections::HashMap;
+use std::fmt;
+
+/// Modes whose keys can be remapped in the `keys` config section.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Mode {
+    Normal,
+    Insert,
+    Visual,
+}
+
+impl Mode {
+    pub const ALL: [Mode; 3] = [Mode::Normal, Mode::Insert, Mode::Visual];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Mode::Normal => "normal",
+            Mode::Insert => "insert",
+            Mode::Visual => "visual",
+        }
+    }
+}
+
+/// Something a key can be bound to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    ClearStatus,
+    ScrollDown,
+    ScrollUp,
+    HalfPageDown,
+    HalfPageUp,
+    PageDown,
+    PageUp,
+    NextMessage,
+    PreviousMessage,
+    Top,
+    Bottom,
+    SetMark,
+    JumpToMark,
+    JumpToMarkMessage,
+    JumpBack,
+    JumpForward,
+    Insert,
+    InsertNew,
+    CommandLine,
+    CommandPalette,
+    CommandHistory,
+    Help,
+    Visual,
+    Pin,
+    Paste,
+    PasteRegister,
+    DeleteMessage,
+    QuoteMessage,
+    RunCodeBlock,
+    ToggleThinking,
+    ExpandAllThinking,
+    FoldAllThinking,
+    AlternateSession,
+    FocusOtherPane,
+    CloseSplit,
+    VerticalSplit,
+    HorizontalSplit,
+    NewSession,
+    Sessions,
+    Models,
+    Agent,
+    Quit,
+    NormalMode,
+    Send,
+    Newline,
+    DeleteChar,
+    Yank,
+    YankMessage,
+}
+
+// Every action of a mode: name in the config, default keys, description
+const BINDINGS: &[(Mode, Action, &str, &[&str], &str)] = &[
+    (Mode::Normal, Action::ScrollDown, "scroll-down", &["j", "down"], "Scroll down in chat"),
+    (Mode::Normal, Action::ScrollUp, "scroll-up", &["k", "up"], "Scroll up in chat"),
+    (Mode::Normal, Action::HalfPageDown, "half-page-down", &["ctrl+d"], "Half-page down"),
+    (Mode::Normal, Action::HalfPageUp, "half-page-up", &["ctrl+u"], "Half-page up"),
+    (Mode::Normal, Action::PageDown, "page-down", &["pagedown"], "Page down"),
+    (Mode::Normal, Action::PageUp, "page-up", &["pageup"], "Page up"),
+    (Mode::Normal, Action::NextMessage, "next-message", &["}"], "Jump to the next message"),
+    (Mode::Normal, Action::PreviousMessage, "previous-message", &["{"], "Jump to the previous message"),
+    (Mode::Normal, Action::Top, "top", &["g"], "Go to top of chat"),
+    (Mode::Normal, Action::Bottom, "bottom", &["G"], "Go to bottom of chat"),
+    (Mode::Normal, Action::SetMark, "set-mark", &["m"], "Mark the selected line with the next letter"),
+    (Mode::Normal, Action::JumpToMark, "jump-to-mark", &["`"], "Jump to the line of the mark named by the next letter"),
+    (Mode::Normal, Action::JumpToMarkMessage, "jump-to-mark-message", &["'"], "Jump to the message of the mark named by the next letter"),
+    (Mode::Normal, Action::JumpBack, "jump-back", &["ctrl+o"], "Go back in the jump list"),
+    (Mode::Normal, Action::JumpForward, "jump-forward", &["ctrl+i", "tab"], "Go forward in the jump list"),
+    (Mode::Normal, Action::Insert, "insert", &["i"], "Enter insert mode"),
+    (Mode::Normal, Action::InsertNew, "insert-new", &["o", "O"], "Enter insert mode with the input cleared"),
+    (Mode::Normal, Action::CommandLine, "command-line", &[":"], "Enter command mode"),
+    (Mode::Normal, Action::CommandPalette, "command-palette", &["ctrl+p"], "Open the command palette"),
+    (Mode::Normal, Action::CommandHistory, "command-history", &["q :"], "Search the command history"),
+    (Mode::Normal, Action::Help, "help", &["?"], "Show help"),
+    (Mode::Normal, Action::Visual, "visual", &["v"], "Enter visual mode"),
+    (Mode::Normal, Action::Paste, "paste", &["p"], "Paste the clipboard, or the register picked with \"x, into the input"),
+    (Mode::Normal, Action::Pin, "pin", &["P"], "Pin/unpin the selected message"),
+    (Mode::Normal, Action::YankMessage, "yank-message", &["y y"], "Yank the selected message"),
+    (Mode::Normal, Action::DeleteMessage, "delete-message", &["d d"], "Delete the selected message"),
+    (Mode::Normal, Action::QuoteMessage, "quote-message", &["q r"], "Quote-reply to the selected message"),
+    (Mode::Normal, Action::RunCodeBlock, "run-code-block", &["x"], "Run the shell blocks of the selected message, with approval"),
+    (Mode::Normal, Action::ToggleThinking, "toggle-thinking", &["z a", "z o", "z c"], "Expand/fold the thinking of the selected message"),
+    (Mode::Normal, Action::ExpandAllThinking, "expand-all-thinking", &["z R"], "Expand all thinking"),
+    (Mode::Normal, Action::FoldAllThinking, "fold-all-thinking", &["z M"], "Fold all thinking"),
+    (Mode::Normal, Action::AlternateSession, "alternate-session", &["ctrl+^", "ctrl+6"], "Switch to the previous session"),
+    (Mode::Normal, Action::FocusOtherPane, "focus-other-pane", &["ctrl+w w", "ctrl+w p", "ctrl+w h", "ctrl+w j", "ctrl+w k", "ctrl+w l"], "Focus the other pane of the split"),
+    (Mode::Normal, Action::CloseSplit, "close-split", &["ctrl+w q", "ctrl+w c", "ctrl+w o"], "Close the split view"),
+    (Mode::Normal, Action::VerticalSplit, "vertical-split", &["ctrl+w v"], "Split with the previous session side by side"),
+    (Mode::Normal, Action::HorizontalSplit, "horizontal-split", &["ctrl+w s"], "Split with the previous session stacked"),
+    (Mode::Normal, Action::NewSession, "new-session", &[], "Start a new session"),
+    (Mode::Normal, Action::Sessions, "sessions", &[], "Open the session list"),
+    (Mode::Normal, Action::Models, "models", &[], "Open the model list"),
+    (Mode::Normal, Action::Agent, "toggle-agent", &[], "Enter agent mode"),
+    (Mode::Normal, Action::ClearStatus, "clear-status", &["esc"], "Clear the status messages"),
+    (Mode::Normal, Action::Quit, "quit", &["q q"], "Quit"),
+    (Mode::Insert, Action::Send, "send", &["enter"], "Send the message"),
+    (Mode::Insert, Action::Newline, "newline", &[], "Insert a line break"),
+    (Mode::Insert, Action::Paste, "paste", &["ctrl+v"], "Paste the clipboard"),
+    (Mode::Insert, Action::PasteRegister, "paste-register", &["ctrl+r"], "Paste the register named by the next key"),
+    (Mode::Insert, Action::DeleteChar, "delete-char", &["backspace"], "Delete the last character"),
+    (Mode::Insert, Action::CommandPalette, "command-palette", &["ctrl+p"], "Open the command palette"),
+    (Mode::Insert, Action::NormalMode, "normal-mode", &["esc"], "Return to normal mode"),
+    (Mode::Visual, Action::ScrollDown, "scroll-down", &["j", "down"], "Extend the selection down"),
+    (Mode::Visual, Action::ScrollUp, "scroll-up", &["k", "up"], "Extend the selection up"),
+    (Mode::Visual, Action::PageDown, "page-down", &["pagedown"], "Page down"),
+    (Mode::Visual, Action::PageUp, "page-up", &["pageup"], "Page up"),
+    (Mode::Visual, Action::Top, "top", &["g"], "Extend the selection to the top"),
+    (Mode::Visual, Action::Bottom, "bottom", &["G"], "Extend the selection to the bottom"),
+    (Mode::Visual, Action::Help, "help", &["?"], "Show help"),
+    (Mode::Visual, Action::Yank, "yank", &["y"], "Copy the selection to the clipboard"),
+    (Mode::Visual, Action::NormalMode, "normal-mode", &["esc", "q"], "Return to normal mode"),
+];
+
+/// One key press with its modifiers, e.g. `ctrl+w`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Chord {
+    code: KeyCode,
+    modifiers: KeyModifiers,
+}
+
+impl Chord {
+    /// The chord of a key event. Shift is part of the character for printable keys,
+    /// so `G` with or without the reported shift is the same chord.
+    pub fn from_event(key: &KeyEvent) -> Self {
+        let mut modifiers = key.modifiers & (KeyModifiers::CONTROL | KeyModifiers::ALT | KeyModifiers::SHIFT);
+        if matches!(key.code, KeyCode::Char(_)) {
+            modifiers.remove(KeyModifiers::SHIFT);
+        }
+        Self { code: key.code, modifiers }
+    }
+
+    /// The character of the key, typed in insert mode when nothing is bound to the chord.
+    pub fn char(&self) -> Option<char> {
+        match self.code {
+            KeyCode::Char(c) => Some(c),
+            _ => None,
+        }
+    }
+
+    fn parse(text: &str) -> Result<Self, String> {
+        let mut modifiers = KeyModifiers::NONE;
+        let mut rest = text;
+        // `+` alone, or as the last part of `ctrl++`, is the plus key
+        while let Some((modifier, key)) = rest.split_once('+').filter(|(_, key)| !key.is_empty()) {
+            modifiers |= match modifier.to_lowercase().as_str() {
+                "ctrl" => KeyModifiers::CONTROL,
+                "alt" | "meta" => KeyModifiers::ALT,
+                "shift" => KeyModifiers::SHIFT,
+                _ => return Err(format!("unknown modifier '{}' in '{}'", modifier, text)),
+            };
+            rest = key;
+        }
+        let mut chars = rest.chars();
+        let code = match (chars.next(), chars.next()) {
+            (Some(c), None) => KeyCode::Char(c),
+            _ => match rest.to_lowercase().as_str() {
+                "esc" | "escape" => KeyCode::Esc,
+                "enter" | "return" => KeyCode::Enter,
+                "tab" => KeyCode::Tab,
+                "backtab" => KeyCode::BackTab,
+                "backspace" => KeyCode::Backspace,
+                "delete" | "del" => KeyCode::Delete,
+                "insert" => KeyCode::Insert,
+                "space" => KeyCode::Char(' '),
+                "up" => KeyCode::Up,
+                "down" => KeyCode::Down,
+                "left" => KeyCode::Left,
+                "right" => KeyCode::Right,
+                "home" => KeyCode::Home,
+                "end" => KeyCode::End,
+                "pageup" => KeyCode::PageUp,
+                "pagedown" => KeyCode::PageDown,
+                name => match name.strip_prefix('f').and_then(|n| n.parse::<u8>().ok()) {
+                    Some(n @ 1..=12) => KeyCode::F(n),
+                    _ => return Err(format!("unknown key '{}'", text)),
+                },
+            },
+        };
+        if matches!(code, KeyCode::Char(_)) && modifiers.contains(KeyModifiers::SHIFT) {
+            return Err(format!("write '{}' as the character it types", text));
+        }
+        Ok(Self { code, modifiers })
+    }
+}
+
+impl fmt::Display for Chord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (modifier, name) in [(KeyModifiers::CONTROL, "ctrl+"), (KeyModifiers::ALT, "alt+"), (KeyModifiers::SHIFT, "shift+")] {
+            if self.modifiers.contains(modifier) {
+                f.write_str(name)?;
+            }
+        }
+        match self.code {
+            KeyCode::Char(' ') => f.write_str("space"),
+            KeyCode::Char(c) => write!(f, "{}", c),
+            KeyCode::F(n) => write!(f, "f{}", n),
+            KeyCode::Esc => f.write_str("esc"),
+            KeyCode::PageUp => f.write_str("pageup"),
+            KeyCode::PageDown => f.write_str("pagedown"),
+            KeyCode::BackTab => f.write_str("backtab"),
+            code => f.write_str(&format!("{:?}", code).to_lowercase()),
+        }
+    }
+}
+
+/// `d d` as a sequence of chords.
+fn parse_sequence(text: &str) -> Result<Vec<Chord>, String> {
+    let chords = text.split_whitespace().map(Chord::parse).collect::<Result<Vec<_>, _>>()?;
+    if chords.is_empty() {
+        return Err("empty key".to_string());
+    }
+    Ok(chords)
+}
+
+/// The chords of a sequence, space separated as in the config.
+pub fn describe(keys: &[Chord]) -> String {
+    keys.iter().map(Chord::to_string).collect::<Vec<_>>().join(" ")
+}
+
+/// What the keys typed so far lead to.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Lookup {
+    Action(Action),
+    Pending, // The start of a longer binding
+    Unbound,
+}
+
+#[derive(Clone, Debug)]
+struct Binding {
+    mode: Mode,
+    keys: Vec<Chord>,
+    action: Action,
+    description: &'static str,
+}
+
+/// Key bindings of the remappable modes: the defaults with the `keys` section of
+/// the config applied over them.
+#[derive(Clone, Debug)]
+pub struct Keymap {
+    bindings: Vec<Binding>,
+}
+
+impl Keymap {
+    /// `keys` maps a mode to actions and their keys; an action listed there loses its
+    /// default keys, and its keys win over the defaults of other actions.
+    pub fn new(keys: &HashMap<String, HashMap<String, KeyList>>) -> Result<Self, String> {
+        if let Some(name) = keys.keys().find(|name| !Mode::ALL.iter().any(|mode| mode.name() == name.as_str())) {
+            return Err(format!("keys: unknown mode '{}', use normal, insert or visual", name));
+        }
+        let mut bindings = Vec::new();
+        let mut default_bindings = Vec::new();
+        for mode in Mode::ALL {
+            let overrides = keys.get(mode.name());
+            if let Some(overrides) = overrides
+                && let Some(name) = overrides
+                    .keys()
+                    .find(|name| !BINDINGS.iter().any(|(m, _, n, ..)| *m == mode && n == name))
+            {
+                return Err(format!("keys.{}: unknown action '{}'", mode.name(), name));
+            }
+            for (_, action, name, defaults, description) in BINDINGS.iter().filter(|(m, ..)| *m == mode) {
+                let (sequences, target): (Vec<&str>, _) = match overrides.and_then(|overrides| overrides.get(*name)) {
+                    Some(KeyList::One(key)) => (vec![key.as_str()], &mut bindings),
+                    Some(KeyList::Many(keys)) => (keys.iter().map(String::as_str).collect(), &mut bindings),
+                    None => (defaults.to_vec(), &mut default_bindings),
+                };
+                for sequence in sequences {
+                    let keys = parse_sequence(sequence).map_err(|e| format!("keys.{}.{}: {}", mode.name(), name, e))?;
+                    target.push(Binding { mode, keys, action: *action, description });
+                }
+            }
+        }
+        // The first binding matching the keys is the one used
+        bindings.extend(default_bindings);
+        Ok(Self { bindings })
+    }
+
+    pub fn lookup(&self, mode: Mode, keys: &[Chord]) -> Lookup {
+        let mut pending = false;
+        for binding in self.bindings.iter().filter(|binding| binding.mode == mode) {
+            if binding.keys == keys {
+                return Lookup::Action(binding.action);
+            }
+            pending |= binding.keys.starts_with(keys);
+        }
+        if pending { Lookup::Pending } else { Lookup::Unbound }
+    }
+
+    /// Every bound action of `mode` in the order of the defaults, with its keys comma separated.
+    pub fn action_keys(&self, mode: Mode) -> Vec<(String, &'static str)> {
+        BINDINGS
+            .iter()
+            .filter(|(m, ..)| *m == mode)
+            .filter_map(|(_, action, _, _, description)| {
+                let keys: Vec<String> = self
+                    .bindings
+                    .iter()
+                    .filter(|binding| binding.mode == mode && binding.action == *action)
+                    .map(|binding| describe(&binding.keys))
+                    .collect();
+                (!keys.is_empty()).then(|| (keys.join(", "), *description))
+            })
+            .collect()
+    }
+
+    /// The keys that can follow `keys` and what they do, for the which-key popup.
+    /// A continuation shadowed by an earlier binding of the same keys is left out.
+    pub fn continuations(&self, mode: Mode, keys: &[Chord]) -> Vec<(String, &'static str)> {
+        let mut seen: Vec<&[Chord]> = Vec::new();
+        let mut out = Vec::new();
+        for binding in self.bindings.iter().filter(|binding| binding.mode == mode) {
+            if binding.keys.len() > keys.len() && binding.keys.starts_with(keys) && !seen.contains(&binding.keys.as_slice()) {
+                seen.push(&binding.keys);
+                out.push((describe(&binding.keys[keys.len()..]), binding.description));
+            }
+        }
+        out
+    }
+}
@@ -0,0 +1,82 @@
+use crate::image;
+use crate::markdown::{self, LineKind};
+use crate::models::Message;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::rc::Rc;
+
+// Past this many entries the cache starts over, e.g. after the terminal was resized a lot
+const MAX_ENTRIES: usize = 10_000;
+
+/// Lines of a message as shown in the chat pane, see `markdown::wrap_lines`.
+pub struct MessageLayout {
+    pub lines: Vec<(String, LineKind)>,
+    /// How many of the lines at the start show the `<think>` block
+    pub thinking_lines: usize,
+}
+
+impl MessageLayout {
+    fn new(message: &Message, width: usize, images: image::Settings) -> Self {
+        let (thinking, answer) = message.display_parts();
+        let (thinking_lines, content) = match thinking {
+            Some(thinking) => (markdown::wrap(&thinking, width, images).len(), Cow::Owned(format!("{}\n{}", thinking, answer))),
+            None => (0, answer),
+        };
+        let lines = markdown::wrap_lines(&content, width, images)
+            .into_iter()
+            .map(|(line, kind)| (line.into_owned(), kind))
+            .collect();
+        Self { lines, thinking_lines }
+    }
+}
+
+struct Entry {
+    fingerprint: u64, // Of the message as it was wrapped
+    layout: Rc<MessageLayout>,
+}
+
+/// Layouts of saved messages by message id and wrap width, so long sessions are not
+/// wrapped again on every frame and key press. An entry remembers what it was wrapped
+/// from, an edited message or a folded `<think>` block is wrapped anew; the reply still
+/// streaming has no id yet and is wrapped every time.
+#[derive(Default)]
+pub struct LayoutCache {
+    entries: RefCell<HashMap<(i64, usize), Entry>>,
+}
+
+impl LayoutCache {
+    pub fn get(&self, message: &Message, width: usize, images: image::Settings) -> Rc<MessageLayout> {
+        let Some(id) = message.id else {
+            return Rc::new(MessageLayout::new(message, width, images));
+        };
+        let fingerprint = fingerprint(message);
+        let mut entries = self.entries.borrow_mut();
+        if let Some(entry) = entries.get(&(id, width))
+            && entry.fingerprint == fingerprint
+        {
+            return entry.layout.clone();
+        }
+        if entries.len() >= MAX_ENTRIES {
+            entries.clear();
+        }
+        let layout = Rc::new(MessageLayout::new(message, width, images));
+        entries.insert((id, width), Entry { fingerprint, layout: layout.clone() });
+        layout
+    }
+
+    /// Forget every layout, for when images take up a different number of rows.
+    pub fn clear(&self) {
+        self.entries.borrow_mut().clear();
+    }
+}
+
+// Everything of a message its layout depends on
+fn fingerprint(message: &Message) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    message.role.hash(&mut hasher);
+    message.content.hash(&mut hasher);
+    message.thinking_expanded.hash(&mut hasher);
+    hasher.finish()
+}
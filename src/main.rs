@@ -1,53 +1,223 @@
 mod agent;
 mod app;
+mod attach;
+mod batch;
+mod bench;
+mod cache;
+mod cli;
+mod clipboard;
+mod commands;
+mod compare;
 mod config;
+mod context;
+mod crypto;
 mod db;
+mod diff;
 mod events;
+mod fuzzy;
+mod gitmsg;
+mod help;
+mod howto;
+mod image;
+mod keymap;
+mod layout;
+mod markdown;
+mod memory;
 mod models;
+mod notify;
 mod ollama;
+mod options;
+mod policy;
+mod project;
+mod prompts;
+mod rag;
+mod redact;
+mod server;
+mod sync;
+mod toast;
+mod tools;
 mod ui;
+mod update;
+mod web;
+mod workflow;
 
 use anyhow::Result;
 use app::AppState;
 use crossterm::{
+    event::{DisableFocusChange, EnableFocusChange},
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
 use ratatui::{prelude::*};
 use std::io::{self, Stdout};
-use std::time::Duration;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc;
 
+// How often the config file is checked for changes
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// How long the terminal has to keep its size before a resize is handled
+const RESIZE_DEBOUNCE: Duration = Duration::from_millis(100);
+// Shortest time between two frames, about 30 a second
+const FRAME_INTERVAL: Duration = Duration::from_millis(33);
+
+/// Poll timeouts of the terminal event task in milliseconds, `config.tick` as kept up to
+/// date by the main loop: ticks are only frequent while something is about to change.
+#[derive(Default)]
+struct TickRate {
+    current_ms: AtomicU64,
+    active_ms: AtomicU64,
+}
+
+impl TickRate {
+    fn update(&self, app_state: &AppState) {
+        let tick = &app_state.config.tick;
+        // Shown things changing with time rather than events: a reply streaming in, the
+        // which-key popup of an unfinished key sequence, toasts and status messages to expire
+        let active = !app_state.loading_sessions.is_empty()
+            || !app_state.pending_keys.is_empty()
+            || !app_state.toasts.visible.is_empty()
+            || !app_state.status_messages.is_empty();
+        let current = if active { tick.active_ms } else { tick.idle_ms };
+        self.current_ms.store(current.max(1), Ordering::Relaxed);
+        self.active_ms.store(tick.active_ms.max(1), Ordering::Relaxed);
+    }
+}
+
+/// What the last frame showed that changes without an event: the terminal size, the
+/// which-key popup due after a delay and session ages, which count in minutes.
+#[derive(PartialEq)]
+struct Drawn {
+    size: Size,
+    which_key: bool,
+    minute: i64,
+}
+
+impl Drawn {
+    fn of(app_state: &AppState, size: Size) -> Self {
+        Self { size, which_key: app_state.which_key_due(), minute: chrono::Utc::now().timestamp() / 60 }
+    }
+}
+
 fn setup_terminal() -> Result<Terminal<CrosstermBackend<Stdout>>> {
     let mut stdout = io::stdout();
     enable_raw_mode()?;
-    execute!(stdout, EnterAlternateScreen)?;
+    execute!(stdout, EnterAlternateScreen, EnableFocusChange)?;
     Ok(Terminal::new(CrosstermBackend::new(stdout))?)
 }
 
 fn restore_terminal(terminal: &mut Terminal<CrosstermBackend<Stdout>>) -> Result<()> {
     disable_raw_mode()?;
-    execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
+    execute!(terminal.backend_mut(), DisableFocusChange, LeaveAlternateScreen)?;
+    terminal.show_cursor()?;
     Ok(())
 }
 
+// A panic in the main loop restores the terminal before the message is printed, so
+// it is readable and the shell is usable afterwards. Background tasks run on other
+// threads and the UI keeps going when one of them panics, so the terminal is left alone.
+fn install_panic_hook() {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        if std::thread::current().name() == Some("main") {
+            disable_raw_mode().ok();
+            execute!(io::stdout(), DisableFocusChange, LeaveAlternateScreen, crossterm::cursor::Show).ok();
+        }
+        default_hook(info);
+    }));
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match cli::parse(&args).map_err(|e| anyhow::anyhow!(e))? {
+        cli::Command::Tui => {}
+        cli::Command::Ask(question) => return howto::run_cli(&question).await,
+        cli::Command::Run(script) => return batch::run(&script).await,
+        cli::Command::Sync(dir) => return sync::run_cli(dir.as_deref()),
+        cli::Command::Completions(shell) => {
+            print!("{}", cli::completions(&shell));
+            return Ok(());
+        }
+        cli::Command::Man => {
+            print!("{}", cli::man_page());
+            return Ok(());
+        }
+        cli::Command::Help => {
+            print!("{}", cli::usage());
+            return Ok(());
+        }
+        cli::Command::Version => {
+            println!("ollama-tui {}", env!("CARGO_PKG_VERSION"));
+            return Ok(());
+        }
+    }
+    // Loaded before the screen is taken over, as it may ask for the passphrase of the history
+    let migrated = config::migrate_json()?;
+    let app_state = AppState::load(config::load_or_create()?)?;
+    install_panic_hook();
     let mut terminal = setup_terminal()?;
-    let config = config::load_or_create()?;
-    let mut app_state = AppState::load(config)?;
+    // Errors are returned only after the terminal is back to normal
+    let result = run(&mut terminal, app_state, migrated).await;
+    restore_terminal(&mut terminal)?;
+    result
+}
+
+async fn run(terminal: &mut Terminal<CrosstermBackend<Stdout>>, mut app_state: AppState, migrated: Option<PathBuf>) -> Result<()> {
+    if let Some(path) = migrated {
+        app_state.push_toast(toast::Severity::Info, format!("Converted config.json to {}", path.display()));
+    }
 
     let (tx, mut rx) = mpsc::channel(32);
 
+    // HTTP API for editors and scripts, when enabled
+    if app_state.config.server.enabled {
+        match server::start(&app_state.config.server, tx.clone()).await {
+            Ok(addr) => app_state.push_toast(toast::Severity::Info, format!("API listening on http://{}", addr)),
+            Err(e) => app_state.push_toast(toast::Severity::Error, format!("Cannot start the API server: {}", e)),
+        }
+    }
+
     // Terminal event handler task
     let event_tx = tx.clone();
+    let tick_rate = Arc::new(TickRate::default());
+    tick_rate.update(&app_state);
+    let poll_rate = tick_rate.clone();
     tokio::spawn(async move {
+        // The main loop may not have switched to the active rate yet when a key comes in
+        let mut after_event = false;
         loop {
             // Poll for events with a timeout, allowing for UI updates even without input
-            if crossterm::event::poll(Duration::from_millis(50)).unwrap_or(false) {
-                if let crossterm::event::Event::Key(key) = crossterm::event::read().unwrap() {
-                    if event_tx.send(events::AppEvent::Terminal(key)).await.is_err() {
-                        break;
+            let rate = if after_event { &poll_rate.active_ms } else { &poll_rate.current_ms };
+            let timeout = Duration::from_millis(rate.load(Ordering::Relaxed));
+            after_event = crossterm::event::poll(timeout).unwrap_or(false);
+            if after_event {
+                let mut forward = vec![crossterm::event::read().unwrap()];
+                // Dragging the window sends a storm of sizes, only the one it ends with is handled
+                while let crossterm::event::Event::Resize(..) = forward[0]
+                    && crossterm::event::poll(RESIZE_DEBOUNCE).unwrap_or(false)
+                {
+                    match crossterm::event::read().unwrap() {
+                        resize @ crossterm::event::Event::Resize(..) => forward[0] = resize,
+                        other => {
+                            forward.push(other);
+                            break;
+                        }
+                    }
+                }
+                let forward = forward.into_iter().filter_map(|event| match event {
+                    crossterm::event::Event::Key(key) => Some(events::AppEvent::Terminal(key)),
+                    crossterm::event::Event::FocusGained => Some(events::AppEvent::Focus(true)),
+                    crossterm::event::Event::FocusLost => Some(events::AppEvent::Focus(false)),
+                    crossterm::event::Event::Resize(width, height) => Some(events::AppEvent::Resize(width, height)),
+                    _ => None,
+                });
+                for event in forward {
+                    if event_tx.send(event).await.is_err() {
+                        return;
                     }
                 }
             } else {
@@ -60,184 +230,82 @@ async fn main() -> Result<()> {
     });
 
     // Initial model fetch task
-    let models_tx = tx.clone();
-    let http_client_clone = app_state.http_client.clone();
-    let base_url_clone = app_state.ollama_base_url.clone();
-    let auth_config_clone = app_state.config.auth_method.clone();
-    let auth_enabled_clone = app_state.config.auth_enabled;
     app_state.is_fetching_models = true; // Set state before spawning the task
+    update::spawn_model_fetch(&app_state, &tx);
 
+    // Config file watcher, a change is read here and applied by the main loop
+    let config_tx = tx.clone();
     tokio::spawn(async move {
-        let result = ollama::fetch_models(
-            &http_client_clone,
-            &base_url_clone,
-            auth_enabled_clone,
-            auth_config_clone.as_ref(),
-        )
-        .await;
-        models_tx.send(events::AppEvent::Models(result)).await.ok();
+        let Ok(path) = config::get_config_path() else {
+            return;
+        };
+        let mut modified = config::modified(&path);
+        loop {
+            tokio::time::sleep(CONFIG_POLL_INTERVAL).await;
+            let now = config::modified(&path);
+            // Editors may remove the file for a moment while saving
+            if now.is_none() || now == modified {
+                continue;
+            }
+            modified = now;
+            let result = config::read(&path).map(Box::new).map_err(|e| e.to_string());
+            if config_tx.send(events::AppEvent::ConfigChanged(result)).await.is_err() {
+                break;
+            }
+        }
     });
 
-    // Main application loop
+    // Main application loop. The screen is only drawn again when an event changed the
+    // state or the frame is out of date, so an idle TUI does not redraw on every tick.
+    // Frames are at least FRAME_INTERVAL apart: events arriving in between, such as the
+    // chunks of a fast model, are all applied before the next one
+    let mut dirty = true;
+    let mut drawn: Option<Drawn> = None;
+    let mut last_draw: Option<Instant> = None;
     loop {
-        // Update terminal dimensions before drawing
         let terminal_area = terminal.size()?;
-        app_state.update_terminal_dimensions(terminal_area.width, terminal_area.height);
-        
-        terminal.draw(|f| ui::ui(f, &mut app_state))?;
-
-        match rx.recv().await {
-            Some(events::AppEvent::Terminal(key)) => {
-                if key.kind == crossterm::event::KeyEventKind::Press {
-                    if events::handle_key_event(key, &mut app_state, tx.clone()).await {
-                        break;
-                    }
-                    
-                    // Check if we need to fetch models after handling the key event
-                    if app_state.mode == app::AppMode::ModelSelection && app_state.is_fetching_models {
-                        let models_tx = tx.clone();
-                        let http_client_clone = app_state.http_client.clone();
-                        let base_url_clone = app_state.ollama_base_url.clone();
-                        let auth_config_clone = app_state.config.auth_method.clone();
-                        let auth_enabled_clone = app_state.config.auth_enabled;
-                        
-                        tokio::spawn(async move {
-                            let result = ollama::fetch_models(
-                                &http_client_clone,
-                                &base_url_clone,
-                                auth_enabled_clone,
-                                auth_config_clone.as_ref(),
-                            )
-                            .await;
-                            models_tx.send(events::AppEvent::Models(result)).await.ok();
-                        });
-                    }
-                }
-            }
-            Some(events::AppEvent::Tick) => {
-                // Handle auto-scroll for list view
-                let terminal_area = terminal.size()?;
-                app_state.update_terminal_dimensions(terminal_area.width, terminal_area.height);
-                
-                // Calculate chat area dimensions to match UI layout exactly:
-                // 75% width for left side (this matches the UI layout)
-                let chat_width = (terminal_area.width * 3) / 4;
-                // Total height minus input (3 lines) and status (1 line) minus borders (2 lines)
-                let chat_height = terminal_area.height.saturating_sub(6);
-                
-                // Only do auto-scroll during tick if we're not actively loading
-                if app_state.auto_scroll && !app_state.is_loading {
-                    app_state.auto_scroll_to_bottom(chat_height, chat_width);
-                }
-            }
-            Some(events::AppEvent::OllamaChunk(Ok(chunk))) => {
-                if let Some(last_message) = app_state.current_messages_mut().last_mut() {
-                    if last_message.role == models::Role::Assistant {
-                        last_message.content.push_str(&chunk);
-                        // Enable auto-scroll but don't trigger it on every chunk
-                        app_state.auto_scroll = true;
-                    }
-                }
-            }
-            Some(events::AppEvent::OllamaChunk(Err(e))) => {
-                if let Some(last_message) = app_state.current_messages_mut().last_mut() {
-                    if last_message.role == models::Role::Assistant {
-                        let err_msg = format!("\n[STREAM ERROR: {}]", e);
-                        last_message.content.push_str(&err_msg);
-                        // Enable auto-scroll when error content arrives and trigger immediately
-                        app_state.auto_scroll = true;
-                        app_state.trigger_auto_scroll();
-                    }
-                }
-                app_state.is_loading = false;
+        let frame = Drawn::of(&app_state, terminal_area);
+        let frame_due = last_draw.is_none_or(|last_draw| last_draw.elapsed() >= FRAME_INTERVAL);
+        if (dirty && frame_due) || drawn.as_ref() != Some(&frame) {
+            // Update terminal dimensions before drawing
+            app_state.update_terminal_dimensions(terminal_area.width, terminal_area.height);
+
+            terminal.draw(|f| ui::ui(f, &mut app_state))?;
+            // iTerm2 images stay in the cells ratatui thinks are unchanged, so the screen
+            // is redrawn from scratch before they move
+            if app_state.images.protocol == image::Protocol::Iterm2
+                && !app_state.drawn_images.is_empty()
+                && app_state.image_placements != app_state.drawn_images
+            {
+                terminal.clear()?;
+                terminal.draw(|f| ui::ui(f, &mut app_state))?;
             }
-            Some(events::AppEvent::OllamaDone) => {
-                app_state.is_loading = false;
-                
-                // Trigger auto-scroll when streaming is complete
-                if app_state.auto_scroll {
-                    app_state.trigger_auto_scroll();
-                }
+            image::draw(app_state.images.protocol, &app_state.image_placements, &mut app_state.drawn_images)?;
+            drawn = Some(frame);
+            dirty = false;
+            last_draw = Some(Instant::now());
+        }
 
-                // Parse commands if in agent mode
-                if app_state.agent_mode {
-                    if let Some(last_message) = app_state.current_messages().last() {
-                        if last_message.role == models::Role::Assistant {
-                            // TODO: Parse commands from response when agent module is implemented
-                            // let commands = agent::Agent::parse_commands_from_response(&last_message.content);
-                            // if !commands.is_empty() {
-                            //     tx.send(events::AppEvent::AgentCommands(commands)).await.ok();
-                            // }
-                        }
-                    }
-                }
+        tick_rate.update(&app_state);
 
-                let messages = app_state.current_messages();
-                if messages.len() >= 2 {
-                    let user_msg = &messages[messages.len() - 2];
-                    let assistant_msg = &messages[messages.len() - 1];
-                    db::save_message(&app_state.db_conn, app_state.current_session_id(), user_msg)
-                        .ok();
-                    db::save_message(
-                        &app_state.db_conn,
-                        app_state.current_session_id(),
-                        assistant_msg,
-                    )
-                    .ok();
-                }
-            }
-            Some(events::AppEvent::Models(Ok(models))) => {
-                app_state.is_fetching_models = false;
-                app_state.available_models = models;
-                if !app_state.available_models.is_empty()
-                    && app_state.current_model == "No model selected"
-                {
-                    app_state.current_model = app_state.available_models[0].clone();
-                    app_state.model_list_state.select(Some(0));
-                }
-            }
-            Some(events::AppEvent::Models(Err(e))) => {
-                app_state.is_fetching_models = false;
-                app_state.available_models.clear(); // Clear any stale models
-                app_state.current_messages_mut().push(models::Message {
-                    role: models::Role::Assistant,
-                    content: format!("Error fetching models: {}. Is Ollama running?", e),
-                });
-            }
-            Some(events::AppEvent::AgentCommands(commands)) => {
-                app_state.pending_commands = commands;
-                if !app_state.pending_commands.is_empty() {
-                    app_state.command_approval_index = Some(0);
-                }
-            }
-            Some(events::AppEvent::CommandExecuted(index, result)) => {
-                if let Some(cmd) = app_state.pending_commands.get_mut(index) {
-                    cmd.executed = true;
-                    let cmd_command = cmd.command.clone();
-                    match result {
-                        Ok(output) => {
-                            cmd.output = Some(output.clone());
-                            // Add command output to chat
-                            app_state.current_messages_mut().push(models::Message {
-                                role: models::Role::Assistant,
-                                content: format!("Command executed successfully:\n```\n{}\n```\n\nOutput:\n```\n{}\n```", cmd_command, output),
-                            });
-                        }
-                        Err(error) => {
-                            cmd.error = Some(error.clone());
-                            app_state.current_messages_mut().push(models::Message {
-                                role: models::Role::Assistant,
-                                content: format!("Command failed:\n```\n{}\n```\n\nError:\n```\n{}\n```", cmd_command, error),
-                            });
-                        }
-                    }
-                }
-            }
-            None => break,
+        let event = match last_draw.filter(|_| dirty) {
+            // A frame is held back, it is drawn once its time comes unless more events come first
+            Some(last_draw) => match tokio::time::timeout(FRAME_INTERVAL.saturating_sub(last_draw.elapsed()), rx.recv()).await {
+                Ok(event) => event,
+                Err(_) => continue,
+            },
+            None => rx.recv().await,
+        };
+        let Some(event) = event else {
+            break;
+        };
+        match update::handle(&mut app_state, event, terminal.size()?, &tx).await {
+            update::Outcome::Changed => dirty = true,
+            update::Outcome::Unchanged => {}
+            update::Outcome::Quit => break,
         }
     }
 
-    restore_terminal(&mut terminal)?;
     Ok(())
 }
 
@@ -0,0 +1,184 @@
+use crate::image;
+use std::borrow::Cow;
+use textwrap::wrap as wrap_text;
+use unicode_width::UnicodeWidthStr;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Align {
+    Left,
+    Center,
+    Right,
+}
+
+/// What a line of a message is, for the chat pane to color it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LineKind {
+    Text,
+    DiffHeader, // `diff --git`, `---` and `+++` lines of a ```diff block
+    DiffHunk,   // `@@ -1,2 +1,3 @@`
+    DiffAdded,
+    DiffRemoved,
+    Image,     // `![alt](path)` line of an image
+    ImageArea, // Rows left empty below it for the image to be drawn over
+}
+
+/// Kind of a line of a unified diff.
+pub fn diff_line_kind(line: &str) -> LineKind {
+    if line.starts_with("+++") || line.starts_with("---") || line.starts_with("diff ") || line.starts_with("index ") {
+        LineKind::DiffHeader
+    } else if line.starts_with("@@") {
+        LineKind::DiffHunk
+    } else if line.starts_with('+') {
+        LineKind::DiffAdded
+    } else if line.starts_with('-') {
+        LineKind::DiffRemoved
+    } else {
+        LineKind::Text
+    }
+}
+
+/// Lines of a message as shown in the chat pane, `width` columns wide: markdown
+/// tables outside code blocks are drawn as aligned, bordered tables when they fit,
+/// rows for the image to be drawn over follow each image line, everything else is word-wrapped.
+pub fn wrap(content: &str, width: usize, images: image::Settings) -> Vec<Cow<'_, str>> {
+    wrap_lines(content, width, images).into_iter().map(|(line, _)| line).collect()
+}
+
+/// `wrap`, with the kind of each line; lines wrapped from a diff line keep its kind.
+pub fn wrap_lines(content: &str, width: usize, images: image::Settings) -> Vec<(Cow<'_, str>, LineKind)> {
+    let lines: Vec<&str> = content.split('\n').collect();
+    let mut out = Vec::new();
+    let mut code: Option<&str> = None; // Language of the code block the line is in
+    let mut i = 0;
+    while i < lines.len() {
+        let line = lines[i];
+        let mut kind = LineKind::Text;
+        if let Some(fence) = line.trim_start().strip_prefix("```") {
+            code = match code {
+                Some(_) => None,
+                None => Some(fence.trim()),
+            };
+        } else if let Some(language) = code {
+            if matches!(language, "diff" | "patch") {
+                kind = diff_line_kind(line);
+            }
+        } else if let Some((_, path)) = image::find_ref(line) {
+            out.push((Cow::Borrowed(line), LineKind::Image));
+            out.extend((0..images.rows_for(path)).map(|_| (Cow::Borrowed(""), LineKind::ImageArea)));
+            i += 1;
+            continue;
+        } else if let Some((table, rows)) = parse_table(&lines[i..])
+            && let Some(drawn) = draw_table(&table, width)
+        {
+            out.extend(drawn.into_iter().map(|line| (Cow::Owned(line), LineKind::Text)));
+            i += rows;
+            continue;
+        }
+        out.extend(wrap_text(line, width).into_iter().map(|line| (line, kind)));
+        i += 1;
+    }
+    out
+}
+
+struct Table {
+    header: Vec<String>,
+    align: Vec<Align>,
+    rows: Vec<Vec<String>>,
+}
+
+fn cells(line: &str) -> Vec<String> {
+    let line = line.trim();
+    let line = line.strip_prefix('|').unwrap_or(line);
+    let line = line.strip_suffix('|').unwrap_or(line);
+    line.split('|').map(|cell| cell.trim().to_string()).collect()
+}
+
+// `|---|:--:|--:|` under the header row
+fn parse_separator(line: &str) -> Option<Vec<Align>> {
+    if !line.contains('-') || !line.contains('|') {
+        return None;
+    }
+    cells(line)
+        .iter()
+        .map(|cell| {
+            let dashes = cell.trim_start_matches(':').trim_end_matches(':');
+            if dashes.is_empty() || !dashes.chars().all(|c| c == '-') {
+                return None;
+            }
+            Some(match (cell.starts_with(':'), cell.ends_with(':')) {
+                (true, true) => Align::Center,
+                (false, true) => Align::Right,
+                _ => Align::Left,
+            })
+        })
+        .collect()
+}
+
+// The table starting at the first line and how many lines it takes up
+fn parse_table(lines: &[&str]) -> Option<(Table, usize)> {
+    let header = lines.first().filter(|line| line.trim_start().starts_with('|'))?;
+    let align = parse_separator(lines.get(1)?)?;
+    let header = cells(header);
+    if header.len() != align.len() {
+        return None;
+    }
+    let rows: Vec<Vec<String>> = lines[2..]
+        .iter()
+        .take_while(|line| line.trim_start().starts_with('|'))
+        .map(|line| {
+            let mut row = cells(line);
+            row.resize(header.len(), String::new());
+            row
+        })
+        .collect();
+    let count = rows.len() + 2;
+    Some((Table { header, align, rows }, count))
+}
+
+fn pad(text: &str, width: usize, align: Align) -> String {
+    let space = width.saturating_sub(text.width());
+    let (left, right) = match align {
+        Align::Left => (0, space),
+        Align::Right => (space, 0),
+        Align::Center => (space / 2, space - space / 2),
+    };
+    format!("{}{}{}", " ".repeat(left), text, " ".repeat(right))
+}
+
+// `None` when the table is wider than `width`, so it is shown as written instead
+fn draw_table(table: &Table, width: usize) -> Option<Vec<String>> {
+    let widths: Vec<usize> = (0..table.header.len())
+        .map(|column| {
+            table
+                .rows
+                .iter()
+                .map(|row| row[column].width())
+                .chain([table.header[column].width()])
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+    let total: usize = widths.iter().map(|w| w + 3).sum::<usize>() + 1;
+    if total > width {
+        return None;
+    }
+
+    let border = |left: &str, middle: &str, right: &str| {
+        let parts: Vec<String> = widths.iter().map(|w| "─".repeat(w + 2)).collect();
+        format!("{}{}{}", left, parts.join(middle), right)
+    };
+    let row = |cells: &[String], header: bool| {
+        let parts: Vec<String> = cells
+            .iter()
+            .zip(&widths)
+            .zip(&table.align)
+            .map(|((cell, width), align)| pad(cell, *width, if header { Align::Center } else { *align }))
+            .collect();
+        format!("│ {} │", parts.join(" │ "))
+    };
+
+    let mut lines = vec![border("┌", "┬", "┐"), row(&table.header, true), border("├", "┼", "┤")];
+    lines.extend(table.rows.iter().map(|cells| row(cells, false)));
+    lines.push(border("└", "┴", "┘"));
+    Some(lines)
+}
@@ -0,0 +1,84 @@
+use crate::models::{Message, Role};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashSet;
+
+/// Memories added to the system prompt of one session at most
+pub const MAX_INJECTED: usize = 8;
+
+// Words too common to make two texts related
+const STOP_WORDS: &[&str] = &[
+    "the", "and", "for", "are", "but", "not", "you", "your", "with", "this", "that", "from", "have", "has",
+    "was", "were", "will", "would", "can", "could", "should", "what", "when", "where", "which", "who", "how",
+    "why", "all", "any", "use", "uses", "using", "into", "about", "there", "their", "they", "them", "then",
+    "than", "also", "just", "like", "does", "did", "its", "our", "out", "user", "prefers", "want", "wants",
+];
+
+/// A durable fact remembered across sessions.
+#[derive(Clone, Debug)]
+pub struct Memory {
+    pub id: i64,
+    pub fact: String,
+}
+
+/// Facts the model found worth remembering in one exchange, requested as structured output.
+#[derive(Deserialize, Debug, Clone)]
+pub struct FactsResponse {
+    #[serde(default)]
+    pub facts: Vec<String>,
+}
+
+impl FactsResponse {
+    pub fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "facts": { "type": "array", "items": { "type": "string" } }
+            },
+            "required": ["facts"]
+        })
+    }
+}
+
+/// Ask for the lasting facts about the user or their projects stated in one exchange.
+pub fn extraction_messages(known: &[Memory], user: &str, assistant: &str) -> Vec<Message> {
+    let known: Vec<&str> = known.iter().map(|memory| memory.fact.as_str()).collect();
+    let system = "You maintain a long-term memory of durable facts about the user: preferences, \
+                  their environment and the projects they work on, such as \"User prefers tabs\" or \
+                  \"Project targets wasm32\". Reply with JSON: \"facts\" lists new facts stated or clearly \
+                  implied in the exchange, each one short self-contained sentence. Leave out anything \
+                  temporary, specific to this one question, or already known. Most exchanges contain none."
+        .to_string();
+    vec![
+        Message::new(Role::System, system),
+        Message::new(
+            Role::User,
+            format!(
+                "Already known:\n{}\n\nUser:\n{}\n\nAssistant:\n{}",
+                if known.is_empty() { "(nothing)".to_string() } else { known.join("\n") },
+                user,
+                assistant
+            ),
+        ),
+    ]
+}
+
+fn words(text: &str) -> HashSet<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .map(str::to_lowercase)
+        .filter(|word| word.len() >= 3 && !STOP_WORDS.contains(&word.as_str()))
+        .collect()
+}
+
+/// Memories sharing the most words with `text`, best first, ignoring unrelated ones.
+pub fn relevant<'a>(memories: &'a [Memory], text: &str, limit: usize) -> Vec<&'a Memory> {
+    let text_words = words(text);
+    let mut scored: Vec<(usize, &Memory)> = memories
+        .iter()
+        .map(|memory| (words(&memory.fact).intersection(&text_words).count(), memory))
+        .filter(|(score, _)| *score > 0)
+        .collect();
+    // Newer memories win ties
+    scored.sort_by(|a, b| b.0.cmp(&a.0).then(b.1.id.cmp(&a.1.id)));
+    scored.into_iter().take(limit).map(|(_, memory)| memory).collect()
+}
@@ -3,8 +3,10 @@ use anyhow::Result;
 use chrono::DateTime;
 use rusqlite::Connection;
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::HashMap;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum AuthMethod {
     #[serde(rename = "basic")]
     Basic {
@@ -26,6 +28,313 @@ pub struct Config {
     pub auth_method: Option<AuthMethod>,
     #[serde(default)]
     pub theme: Theme,
+    /// User-defined `:` commands, each expanding to a list of commands
+    #[serde(default)]
+    pub aliases: HashMap<String, Vec<String>>,
+    /// Session presets usable with `:new <template>`
+    #[serde(default)]
+    pub templates: HashMap<String, SessionTemplate>,
+    /// Prompt snippets usable with `:prompt use <name>`, `{{name}}` placeholders are asked for
+    #[serde(default)]
+    pub prompts: HashMap<String, String>,
+    #[serde(default)]
+    pub notifications: NotificationConfig,
+    /// Per-model `:set` option defaults, e.g. `{"llama3": {"stop": "###"}}`
+    #[serde(default)]
+    pub model_options: HashMap<String, HashMap<String, String>>,
+    #[serde(default)]
+    pub command_policy: CommandPolicy,
+    /// Environment variables added to every agent command
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    /// Provider behind the agent's `web_search` tool, which is offered only when set
+    #[serde(default)]
+    pub search: Option<SearchConfig>,
+    /// External executables offered to the agent as tools
+    #[serde(default)]
+    pub plugins: Vec<PluginConfig>,
+    #[serde(default)]
+    pub images: ImageConfig,
+    #[serde(default)]
+    pub server: ServerConfig,
+    #[serde(default)]
+    pub encryption: EncryptionConfig,
+    #[serde(default)]
+    pub redaction: RedactionConfig,
+    #[serde(default)]
+    pub tick: TickConfig,
+    /// Prompts run against each model by `:bench`, built-in ones when empty
+    #[serde(default)]
+    pub bench_prompts: Vec<String>,
+    /// Directory `:sync` keeps one JSON file per session in, unused when empty
+    #[serde(default)]
+    pub sync_dir: String,
+    /// Keys per mode and action, e.g. `{"insert": {"send": "ctrl+s"}}`, replacing the defaults
+    #[serde(default)]
+    pub keys: HashMap<String, HashMap<String, KeyList>>,
+}
+
+/// Keys bound to an action: one key sequence or several.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum KeyList {
+    One(String),
+    Many(Vec<String>),
+}
+
+/// A tool implemented by an external program: the call's arguments are written to
+/// its stdin as JSON, and it answers on stdout with `{"output": ...}` or `{"error": ...}`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PluginConfig {
+    pub name: String,
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    pub description: String,
+    /// Argument object as shown to the model, e.g. `{"city": "string"}`
+    #[serde(default = "default_plugin_arguments")]
+    pub arguments: serde_json::Value,
+}
+
+fn default_plugin_arguments() -> serde_json::Value {
+    serde_json::json!({})
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchProvider {
+    Searxng,
+    Brave,
+    Duckduckgo,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SearchConfig {
+    pub provider: SearchProvider,
+    /// Instance URL, required for SearXNG and optional for the others
+    #[serde(default)]
+    pub url: Option<String>,
+    /// API key, required for Brave
+    #[serde(default)]
+    pub api_key: Option<String>,
+    #[serde(default = "default_search_results")]
+    pub max_results: usize,
+}
+
+fn default_search_results() -> usize {
+    5
+}
+
+/// Rules checked before the agent runs a shell command. Rules are regular
+/// expressions, or whole-command wildcards when prefixed with `glob:`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CommandPolicy {
+    /// Commands that run without confirmation
+    #[serde(default = "default_allow_rules")]
+    pub allow: Vec<String>,
+    /// Commands that need an explicit override before they run
+    #[serde(default = "default_deny_rules")]
+    pub deny: Vec<String>,
+    /// Deny commands that write to paths outside the working directory
+    #[serde(default = "default_true")]
+    pub restrict_writes: bool,
+    /// Hosts the `fetch_url` tool may download from, subdomains included; `*` allows any
+    #[serde(default = "default_fetch_domains")]
+    pub fetch_domains: Vec<String>,
+}
+
+impl Default for CommandPolicy {
+    fn default() -> Self {
+        Self {
+            allow: default_allow_rules(),
+            deny: default_deny_rules(),
+            restrict_writes: true,
+            fetch_domains: default_fetch_domains(),
+        }
+    }
+}
+
+fn default_allow_rules() -> Vec<String> {
+    [
+        r"^(ls|pwd|cat|head|tail|wc|grep|rg|which|whoami|date|uname|echo|file|stat|du|df|tree)(\s|$)",
+        r"^git\s+(status|log|diff|show)(\s|$)",
+    ]
+    .iter()
+    .map(|rule| rule.to_string())
+    .collect()
+}
+
+fn default_deny_rules() -> Vec<String> {
+    [
+        r"\brm\s+(-\w+\s+)*-\w*[rR]\w*f|\brm\s+(-\w+\s+)*-\w*f\w*[rR]",
+        r"(^|[;&|]\s*)sudo\s",
+        r"\b(mkfs|shred|shutdown|reboot)\b",
+        r"\bgit\s+(-C\s+\S+\s+|-\S+\s+)*clean\s+(\S+\s+)*-(\w*f|-force\b)",
+        r"\bdd\s.*\bof=",
+        r"\bchmod\s+(-\w+\s+)*777\b",
+        r"(curl|wget)\s.*\|\s*(sh|bash)\b",
+    ]
+    .iter()
+    .map(|rule| rule.to_string())
+    .collect()
+}
+
+fn default_fetch_domains() -> Vec<String> {
+    ["docs.rs", "crates.io", "github.com", "raw.githubusercontent.com", "developer.mozilla.org", "wikipedia.org"]
+        .iter()
+        .map(|domain| domain.to_string())
+        .collect()
+}
+
+/// How to signal that a response finished while the user was looking elsewhere.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NotificationConfig {
+    /// Ring the terminal bell
+    #[serde(default = "default_true")]
+    pub bell: bool,
+    /// Show a desktop notification via `notify-send` (Linux) or `osascript` (macOS)
+    #[serde(default)]
+    pub desktop: bool,
+    /// Also notify while focused when a generation took at least this long; 0 disables
+    #[serde(default = "default_notify_after_secs")]
+    pub after_secs: u64,
+}
+
+impl Default for NotificationConfig {
+    fn default() -> Self {
+        Self {
+            bell: true,
+            desktop: false,
+            after_secs: default_notify_after_secs(),
+        }
+    }
+}
+
+fn default_true() -> bool { true }
+fn default_notify_after_secs() -> u64 { 30 }
+
+/// How images referenced as `![alt](path)` on a line of their own are drawn in the chat.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ImageConfig {
+    /// `auto`, `kitty`, `iterm2` or `none`
+    #[serde(default = "default_image_protocol")]
+    pub protocol: String,
+    /// Largest size of an image in terminal cells
+    #[serde(default = "default_image_width")]
+    pub max_width: u16,
+    #[serde(default = "default_image_height")]
+    pub max_height: u16,
+}
+
+impl Default for ImageConfig {
+    fn default() -> Self {
+        Self {
+            protocol: default_image_protocol(),
+            max_width: default_image_width(),
+            max_height: default_image_height(),
+        }
+    }
+}
+
+fn default_image_protocol() -> String { "auto".to_string() }
+fn default_image_width() -> u16 { 40 }
+fn default_image_height() -> u16 { 12 }
+
+/// The local HTTP API that lets editors and scripts drive the running TUI.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ServerConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Listened on at 127.0.0.1 only
+    #[serde(default = "default_server_port")]
+    pub port: u16,
+    /// Required as `Authorization: Bearer <token>`; the server does not start without one
+    #[serde(default)]
+    pub token: String,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self { enabled: false, port: default_server_port(), token: String::new() }
+    }
+}
+
+fn default_server_port() -> u16 { 11435 }
+
+/// Encryption of message content in the database, with a key derived from a passphrase
+/// asked for at startup.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EncryptionConfig {
+    /// Turning it off decrypts the history again, after the passphrase is given once more
+    #[serde(default)]
+    pub enabled: bool,
+    /// PBKDF2-HMAC-SHA256 rounds, used when the history is encrypted; the database keeps
+    /// the count it was encrypted with
+    #[serde(default = "default_encryption_iterations")]
+    pub iterations: u32,
+}
+
+impl Default for EncryptionConfig {
+    fn default() -> Self {
+        Self { enabled: false, iterations: default_encryption_iterations() }
+    }
+}
+
+fn default_encryption_iterations() -> u32 { 600_000 }
+
+/// Secrets replaced by `[REDACTED:<name>]` before messages are saved and before command
+/// output reaches the model or the audit log.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RedactionConfig {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// Regular expressions by name, added to the built-in ones; an empty one turns off the
+    /// built-in pattern of that name
+    #[serde(default)]
+    pub patterns: HashMap<String, String>,
+}
+
+impl Default for RedactionConfig {
+    fn default() -> Self {
+        Self { enabled: true, patterns: HashMap::new() }
+    }
+}
+
+/// How often the screen is checked for changes that come with time alone, such as
+/// toasts expiring, in milliseconds.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TickConfig {
+    /// While a reply streams, a key sequence is pending or messages wait to expire
+    #[serde(default = "default_active_tick")]
+    pub active_ms: u64,
+    /// Otherwise
+    #[serde(default = "default_idle_tick")]
+    pub idle_ms: u64,
+}
+
+fn default_active_tick() -> u64 {
+    50
+}
+
+fn default_idle_tick() -> u64 {
+    1000
+}
+
+impl Default for TickConfig {
+    fn default() -> Self {
+        Self { active_ms: default_active_tick(), idle_ms: default_idle_tick() }
+    }
+}
+
+/// A named session preset: model, system prompt and `:set` options.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SessionTemplate {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system_prompt: Option<String>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub options: HashMap<String, String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -96,12 +405,11 @@ impl Theme {
             "white" => Color::White,
             // Parse hex colors if needed (e.g., "#FF0000")
             hex if hex.starts_with('#') && hex.len() == 7 => {
-                if let Ok(r) = u8::from_str_radix(&hex[1..3], 16) {
-                    if let Ok(g) = u8::from_str_radix(&hex[3..5], 16) {
-                        if let Ok(b) = u8::from_str_radix(&hex[5..7], 16) {
-                            return Color::Rgb(r, g, b);
-                        }
-                    }
+                if let Ok(r) = u8::from_str_radix(&hex[1..3], 16)
+                    && let Ok(g) = u8::from_str_radix(&hex[3..5], 16)
+                    && let Ok(b) = u8::from_str_radix(&hex[5..7], 16)
+                {
+                    return Color::Rgb(r, g, b);
                 }
                 Color::White // fallback
             }
@@ -110,10 +418,119 @@ impl Theme {
     }
 }
 
+pub const THINK_OPEN: &str = "<think>";
+pub const THINK_CLOSE: &str = "</think>";
+
+/// Split a reply into the `<think>` block it starts with, if any, and the answer
+/// after it. While the model is still thinking the block has no end and the answer is empty.
+pub fn split_thinking(content: &str) -> (Option<&str>, &str) {
+    let Some(rest) = content.trim_start().strip_prefix(THINK_OPEN) else {
+        return (None, content);
+    };
+    match rest.find(THINK_CLOSE) {
+        Some(end) => (Some(rest[..end].trim_matches('\n')), rest[end + THINK_CLOSE.len()..].trim_start_matches('\n')),
+        None => (Some(rest.trim_start_matches('\n')), ""),
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Message {
     pub role: Role,
     pub content: String,
+    /// Ollama's separate reasoning output of thinking models, only read from replies;
+    /// it is folded into `content` as a `<think>` block while streaming
+    #[serde(default, skip_serializing)]
+    pub thinking: String,
+    /// Database row id, `None` until the message has been persisted
+    #[serde(skip)]
+    pub id: Option<i64>,
+    #[serde(skip)]
+    pub pinned: bool,
+    /// Whether the `<think>` block is shown in full rather than folded to one line
+    #[serde(skip)]
+    pub thinking_expanded: bool,
+    /// Reply taken from the response cache rather than generated
+    #[serde(skip)]
+    pub cached: bool,
+    /// Generation streaming into this reply, see `AppState::start_generation`
+    #[serde(skip)]
+    pub generation: Option<u64>,
+    /// The conversation up to this reply as `/api/generate` encoded it, sent with the
+    /// next prompt of `:set api=generate`
+    #[serde(skip)]
+    pub context: Option<Vec<i64>>,
+}
+
+impl Message {
+    pub fn new(role: Role, content: String) -> Self {
+        Self {
+            role,
+            content,
+            thinking: String::new(),
+            id: None,
+            pinned: false,
+            thinking_expanded: false,
+            cached: false,
+            generation: None,
+            context: None,
+        }
+    }
+
+    /// Content as shown in the chat pane, see `display_parts`.
+    pub fn display_content(&self) -> Cow<'_, str> {
+        match self.display_parts() {
+            (Some(thinking), answer) => Cow::Owned(format!("{}\n{}", thinking, answer)),
+            (None, answer) => answer,
+        }
+    }
+
+    /// The `<think>` block as shown in the chat pane (folded to one line unless expanded),
+    /// and the answer: JSON replies (from `:set format=json`) are pretty-printed once they
+    /// have finished streaming, everything else is shown verbatim. Finished messages are
+    /// wrapped once and kept in the `layout::LayoutCache`, so this runs again only for a
+    /// new wrap width or an edited message.
+    pub fn display_parts(&self) -> (Option<String>, Cow<'_, str>) {
+        let (thinking, answer) = if self.role == Role::Assistant {
+            split_thinking(&self.content)
+        } else {
+            (None, self.content.as_str())
+        };
+        let thinking = thinking.map(|thinking| {
+            if self.thinking_expanded {
+                format!("▾ Thinking (za to fold)\n{}\n", thinking)
+            } else if !self.content.contains(THINK_CLOSE) {
+                "▸ Thinking...".to_string()
+            } else {
+                format!("▸ Thought for {} lines (za to expand)", thinking.lines().count())
+            }
+        });
+
+        let trimmed = answer.trim();
+        let looks_like_json = (trimmed.starts_with('{') && trimmed.ends_with('}'))
+            || (trimmed.starts_with('[') && trimmed.ends_with(']'));
+        if self.role == Role::Assistant
+            && self.generation.is_none()
+            && looks_like_json
+            && let Ok(value) = serde_json::from_str::<serde_json::Value>(trimmed)
+            && let Ok(pretty) = serde_json::to_string_pretty(&value)
+        {
+            return (thinking, Cow::Owned(pretty));
+        }
+        (thinking, Cow::Borrowed(answer))
+    }
+
+    pub fn has_thinking(&self) -> bool {
+        self.role == Role::Assistant && split_thinking(&self.content).0.is_some()
+    }
+
+    /// Content without its `<think>` block, as sent back to the model or copied.
+    pub fn answer(&self) -> &str {
+        if self.role == Role::Assistant {
+            split_thinking(&self.content).1
+        } else {
+            &self.content
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -122,28 +539,47 @@ pub struct ChatSession {
     pub name: String,
     pub messages: Vec<Message>,
     pub created_at: DateTime<chrono::Utc>,
+    /// Sent to the model ahead of the conversation, set by `:system` or a template
+    pub system_prompt: Option<String>,
+    /// Time of the latest message, or creation time for empty sessions
+    pub last_activity: DateTime<chrono::Utc>,
+    /// Where agent commands run, set by `:cd`; `None` means the process directory
+    pub working_dir: Option<String>,
+    /// Knowledge session: documents were ingested with `:ingest`, and the most relevant
+    /// chunks are sent along with every prompt
+    pub knowledge: bool,
+    /// `:set` options of this session only, such as `stop` and `num_predict`, which take
+    /// precedence over the global ones
+    pub options: HashMap<String, String>,
 }
 
 impl ChatSession {
     pub fn new(conn: &Connection) -> Result<Self> {
+        let now = chrono::Utc::now();
         let session = Self {
             id: 0, // temp id
             name: format!("Chat {}", db::get_next_session_id(conn)?),
-            messages: vec![Message {
-                role: Role::Assistant,
-                content: "New chat started. Ask me anything!".to_string(),
-            }],
-            created_at: chrono::Utc::now(),
+            messages: vec![Message::new(
+                Role::Assistant,
+                "New chat started. Ask me anything!".to_string(),
+            )],
+            created_at: now,
+            system_prompt: None,
+            last_activity: now,
+            working_dir: None,
+            knowledge: false,
+            options: HashMap::new(),
         };
         Ok(session)
     }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
 #[serde(rename_all = "lowercase")]
 pub enum Role {
     User,
     Assistant,
+    System,
 }
 
 #[derive(Deserialize, Debug)]
@@ -154,6 +590,92 @@ pub struct ModelsResponse {
 #[derive(Deserialize, Debug, Clone)]
 pub struct ModelDetails {
     pub name: String,
+    #[serde(default)]
+    pub size: u64,
+    #[serde(default)]
+    pub digest: String,
+    #[serde(default)]
+    pub modified_at: Option<DateTime<chrono::Utc>>,
+    #[serde(default)]
+    pub details: ModelInfo,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct ModelInfo {
+    #[serde(default)]
+    pub family: String,
+    #[serde(default)]
+    pub parameter_size: String,
+    #[serde(default)]
+    pub quantization_level: String,
+}
+
+impl ModelDetails {
+    /// Download size in a human readable unit, e.g. "4.7 GB"
+    pub fn size_display(&self) -> String {
+        const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+        let mut size = self.size as f64;
+        let mut unit = 0;
+        while size >= 1000.0 && unit < UNITS.len() - 1 {
+            size /= 1000.0;
+            unit += 1;
+        }
+        if unit == 0 {
+            format!("{} {}", self.size, UNITS[unit])
+        } else {
+            format!("{:.1} {}", size, UNITS[unit])
+        }
+    }
+}
+
+/// Column the model picker is ordered by.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum ModelSort {
+    #[default]
+    Name,
+    Size,
+    Modified,
+}
+
+impl ModelSort {
+    pub fn next(self) -> Self {
+        match self {
+            ModelSort::Name => ModelSort::Size,
+            ModelSort::Size => ModelSort::Modified,
+            ModelSort::Modified => ModelSort::Name,
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            ModelSort::Name => "name",
+            ModelSort::Size => "size",
+            ModelSort::Modified => "modified",
+        }
+    }
+}
+
+/// Per-request model parameters, sent as Ollama's `options` object.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct ModelOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub num_predict: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+    /// Top-level `format` of the request rather than a model option
+    #[serde(skip)]
+    pub format: Option<serde_json::Value>,
+}
+
+impl ModelOptions {
+    /// Whether the same request gets the same reply: greedy sampling or a fixed seed.
+    pub fn is_deterministic(&self) -> bool {
+        self.temperature == Some(0.0) || self.seed.is_some()
+    }
 }
 
 #[derive(Serialize, Debug)]
@@ -161,19 +683,69 @@ pub struct ChatRequest<'a> {
     pub model: &'a str,
     pub messages: &'a [Message],
     pub stream: bool,
+    pub options: &'a ModelOptions,
+    /// `"json"` or a JSON schema the reply must follow
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<&'a serde_json::Value>,
+}
+
+/// Completion request of `:set api=generate`: a prompt rather than messages, the
+/// conversation so far is the `context` of the previous reply.
+#[derive(Serialize, Debug)]
+pub struct GenerateRequest<'a> {
+    pub model: &'a str,
+    pub prompt: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context: Option<&'a [i64]>,
+    pub stream: bool,
+    pub options: &'a ModelOptions,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<&'a serde_json::Value>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct StreamGenerateResponse {
+    #[serde(default)]
+    pub response: String,
+    #[serde(default)]
+    pub thinking: String,
+    pub done: bool,
+    /// Sent with the final chunk
+    #[serde(default)]
+    pub context: Option<Vec<i64>>,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct StreamChatResponse {
     pub message: Message,
     pub done: bool,
+    /// Statistics sent with the final chunk, durations in nanoseconds
+    #[serde(default)]
+    pub load_duration: u64,
+    #[serde(default)]
+    pub eval_count: u64,
+    #[serde(default)]
+    pub eval_duration: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct EmbedRequest<'a> {
+    pub model: &'a str,
+    pub input: &'a [String],
+}
+
+#[derive(Deserialize, Debug)]
+pub struct EmbedResponse {
+    pub embeddings: Vec<Vec<f32>>,
 }
 
 #[allow(dead_code)]
 #[derive(Clone, Debug)]
 pub struct AgentCommand {
     pub command: String,
-    #[allow(dead_code)]
+    pub risk: crate::agent::RiskLevel,
     pub approved: bool,
     pub executed: bool,
     pub output: Option<String>,
@@ -181,9 +753,9 @@ pub struct AgentCommand {
 }
 
 impl AgentCommand {
-    #[allow(dead_code)]
     pub fn new(command: String) -> Self {
         Self {
+            risk: crate::agent::assess_risk_level(&command),
             command,
             approved: false,
             executed: false,
@@ -0,0 +1,51 @@
+use crate::models::NotificationConfig;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+/// Whether a finished generation is worth a notification: always when the
+/// terminal is unfocused, otherwise only after a long wait.
+pub fn should_notify(config: &NotificationConfig, focused: bool, elapsed: Duration) -> bool {
+    if !config.bell && !config.desktop {
+        return false;
+    }
+    !focused || (config.after_secs > 0 && elapsed.as_secs() >= config.after_secs)
+}
+
+pub fn notify(config: &NotificationConfig, title: &str, body: &str) {
+    if config.bell {
+        let mut stdout = std::io::stdout();
+        stdout.write_all(b"\x07").ok();
+        stdout.flush().ok();
+    }
+    if config.desktop {
+        desktop_notification(title, body);
+    }
+}
+
+// Fire and forget, a missing notifier must never disturb the TUI. The notifier is
+// waited for on a thread of its own, so that it does not stay behind as a zombie
+fn desktop_notification(title: &str, body: &str) {
+    let mut command = if cfg!(target_os = "macos") {
+        let script = format!(
+            "display notification {} with title {}",
+            applescript_string(body),
+            applescript_string(title)
+        );
+        let mut command = Command::new("osascript");
+        command.arg("-e").arg(script);
+        command
+    } else {
+        let mut command = Command::new("notify-send");
+        command.arg("--app-name=ollama-tui").arg(title).arg(body);
+        command
+    };
+    let child = command.stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null()).spawn();
+    if let Ok(mut child) = child {
+        std::thread::spawn(move || child.wait().ok());
+    }
+}
+
+fn applescript_string(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
@@ -1,33 +1,42 @@
-use crate::{events::AppEvent, models};
+use crate::{bench, events::AppEvent, models};
 use anyhow::Result;
 use futures_util::StreamExt;
 use reqwest::Client;
+use serde::de::DeserializeOwned;
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc;
 
+// Wait before trying a refused completion request again
+const CONNECT_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+// Basic auth goes along with each request; a bearer token is a default header of the client
+fn authorize(
+    request_builder: reqwest::RequestBuilder,
+    auth_enabled: bool,
+    auth_method: Option<&models::AuthMethod>,
+) -> reqwest::RequestBuilder {
+    match auth_method {
+        Some(models::AuthMethod::Basic { username, password }) if auth_enabled => {
+            request_builder.basic_auth(username, Some(password))
+        }
+        _ => request_builder,
+    }
+}
+
 pub async fn fetch_models(
     client: &Client,
     base_url: &str,
     auth_enabled: bool,
     auth_method: Option<&models::AuthMethod>,
-) -> Result<Vec<String>, String> {
+) -> Result<Vec<models::ModelDetails>, String> {
     let url = format!("{}/api/tags", base_url);
-    let mut request_builder = client.get(&url);
-
-    if auth_enabled {
-        if let Some(models::AuthMethod::Basic { username, password }) = auth_method {
-            request_builder = request_builder.basic_auth(username, Some(password));
-        }
-    }
+    let request_builder = authorize(client.get(&url), auth_enabled, auth_method);
 
     let response = request_builder.send().await.map_err(|e| e.to_string())?;
 
     if response.status().is_success() {
         let models_response: models::ModelsResponse = response.json().await.map_err(|e| e.to_string())?;
-        Ok(models_response
-            .models
-            .into_iter()
-            .map(|m| m.name)
-            .collect())
+        Ok(models_response.models)
     } else {
         Err(format!(
             "Failed to fetch models: {}",
@@ -36,13 +45,124 @@ pub async fn fetch_models(
     }
 }
 
+/// Non-streaming chat request whose reply is parsed as `T`. Set `options.format`
+/// to a JSON schema so the model is constrained to produce a matching object.
+pub async fn complete_chat<T: DeserializeOwned>(
+    client: &Client,
+    base_url: &str,
+    model: &str,
+    messages: &[models::Message],
+    options: &models::ModelOptions,
+    auth_enabled: bool,
+    auth_method: Option<&models::AuthMethod>,
+) -> Result<T, String> {
+    let message = request_chat(client, base_url, model, messages, options, auth_enabled, auth_method).await?;
+    serde_json::from_str(&message.content)
+        .map_err(|e| format!("Model returned invalid JSON: {} in '{}'", e, message.content))
+}
+
+/// Non-streaming chat request returning the reply as text, its thinking in front
+/// as a `<think>` block like a streamed reply would have it.
+pub async fn complete_chat_text(
+    client: &Client,
+    base_url: &str,
+    model: &str,
+    messages: &[models::Message],
+    options: &models::ModelOptions,
+    auth_enabled: bool,
+    auth_method: Option<&models::AuthMethod>,
+) -> Result<String, String> {
+    let message = request_chat(client, base_url, model, messages, options, auth_enabled, auth_method).await?;
+    if message.thinking.is_empty() {
+        return Ok(message.content);
+    }
+    Ok(format!("{}{}{}\n{}", models::THINK_OPEN, message.thinking, models::THINK_CLOSE, message.content))
+}
+
+async fn request_chat(
+    client: &Client,
+    base_url: &str,
+    model: &str,
+    messages: &[models::Message],
+    options: &models::ModelOptions,
+    auth_enabled: bool,
+    auth_method: Option<&models::AuthMethod>,
+) -> Result<models::Message, String> {
+    let url = format!("{}/api/chat", base_url);
+    let request_payload = models::ChatRequest {
+        model,
+        messages,
+        stream: false,
+        options,
+        format: options.format.as_ref(),
+    };
+
+    let request_builder = authorize(client.post(&url).json(&request_payload), auth_enabled, auth_method);
+
+    // Nothing was sent when the connection is refused, e.g. while Ollama restarts, so
+    // every completion of the agent and the tools asks once more before failing
+    let retry = request_builder.try_clone();
+    let response = match (request_builder.send().await, retry) {
+        (Err(e), Some(retry)) if e.is_connect() => {
+            tokio::time::sleep(CONNECT_RETRY_DELAY).await;
+            retry.send().await
+        }
+        (response, _) => response,
+    }
+    .map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+        return Err(format!("{}: {}", status, body));
+    }
+    let chat_response: models::StreamChatResponse = response.json().await.map_err(|e| e.to_string())?;
+    Ok(chat_response.message)
+}
+
+/// Embedding vectors for `inputs`, one per input, from `/api/embed`.
+pub async fn embed(
+    client: &Client,
+    base_url: &str,
+    model: &str,
+    inputs: &[String],
+    auth_enabled: bool,
+    auth_method: Option<&models::AuthMethod>,
+) -> Result<Vec<Vec<f32>>, String> {
+    let url = format!("{}/api/embed", base_url);
+    let request = models::EmbedRequest { model, input: inputs };
+    let request_builder = authorize(client.post(&url).json(&request), auth_enabled, auth_method);
+
+    let response = request_builder.send().await.map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+        return Err(format!("Embedding with {} failed: {}: {}", model, status, body));
+    }
+    let embed_response: models::EmbedResponse = response.json().await.map_err(|e| e.to_string())?;
+    if embed_response.embeddings.len() != inputs.len() {
+        return Err(format!(
+            "Expected {} embeddings from {}, got {}",
+            inputs.len(),
+            model,
+            embed_response.embeddings.len()
+        ));
+    }
+    Ok(embed_response.embeddings)
+}
+
+/// Stream a chat reply, sending each piece of content as `chunk(...)` and `done()`
+/// once the reply is complete or has failed.
+#[allow(clippy::too_many_arguments)]
 pub async fn stream_chat_request(
     client: &Client,
     base_url: &str,
     model: &str,
     messages: &[models::Message],
+    options: &models::ModelOptions,
     auth_enabled: bool,
     auth_method: Option<&models::AuthMethod>,
+    chunk: impl Fn(Result<String, String>) -> AppEvent,
+    done: impl Fn() -> AppEvent,
     tx: mpsc::Sender<AppEvent>,
 ) {
     let url = format!("{}/api/chat", base_url);
@@ -50,23 +170,120 @@ pub async fn stream_chat_request(
         model,
         messages,
         stream: true,
+        options,
+        format: options.format.as_ref(),
     };
 
-    let mut request_builder = client.post(&url).json(&request_payload);
+    let request_builder = authorize(client.post(&url).json(&request_payload), auth_enabled, auth_method);
+    stream_request(request_builder, chunk, |_: models::StreamChatResponse| None, done, tx).await;
+}
 
-    if auth_enabled {
-        if let Some(models::AuthMethod::Basic { username, password }) = auth_method {
-            request_builder = request_builder.basic_auth(username, Some(password));
-        }
+/// Stream a completion from `/api/generate` for `:set api=generate`. With the `context`
+/// of the previous reply the prompt is the last user message of `messages`, the context
+/// stands for the conversation before it; without one (after a restart, a failed reply or
+/// a deleted message) the conversation is written out as the prompt and the system
+/// messages go along with it. The reply's own context is sent as `carry(...)` before `done()`.
+#[allow(clippy::too_many_arguments)]
+pub async fn stream_generate_request(
+    client: &Client,
+    base_url: &str,
+    model: &str,
+    messages: &[models::Message],
+    context: Option<&[i64]>,
+    options: &models::ModelOptions,
+    auth_enabled: bool,
+    auth_method: Option<&models::AuthMethod>,
+    chunk: impl Fn(Result<String, String>) -> AppEvent,
+    carry: impl Fn(Vec<i64>) -> AppEvent,
+    done: impl Fn() -> AppEvent,
+    tx: mpsc::Sender<AppEvent>,
+) {
+    let url = format!("{}/api/generate", base_url);
+    let prompt = generate_prompt(messages, context.is_some());
+    let system: Vec<&str> =
+        messages.iter().filter(|m| m.role == models::Role::System).map(|m| m.content.as_str()).collect();
+    let system = system.join("\n\n");
+    let request_payload = models::GenerateRequest {
+        model,
+        prompt: &prompt,
+        system: Some(system.as_str()).filter(|system| context.is_none() && !system.is_empty()),
+        context,
+        stream: true,
+        options,
+        format: options.format.as_ref(),
+    };
+
+    let request_builder = authorize(client.post(&url).json(&request_payload), auth_enabled, auth_method);
+    let last = |stream_res: models::StreamGenerateResponse| stream_res.context.map(carry);
+    stream_request(request_builder, chunk, last, done, tx).await;
+}
+
+// Prompt of a completion: the last user message when `context` carries the conversation
+// before it, otherwise the exchanges up to that message written out one after the other
+fn generate_prompt(messages: &[models::Message], has_context: bool) -> String {
+    let turns: Vec<&models::Message> =
+        messages.iter().filter(|m| m.role != models::Role::System && !m.content.is_empty()).collect();
+    let Some(last) = turns.iter().rposition(|m| m.role == models::Role::User) else {
+        return String::new();
+    };
+    if has_context || last == 0 {
+        return turns[last].content.clone();
+    }
+    let mut prompt = String::new();
+    for message in &turns[..=last] {
+        let speaker = if message.role == models::Role::User { "User" } else { "Assistant" };
+        prompt.push_str(speaker);
+        prompt.push_str(": ");
+        prompt.push_str(&message.content);
+        prompt.push_str("\n\n");
+    }
+    prompt.push_str("Assistant:");
+    prompt
+}
+
+// A line of a streamed `/api/chat` or `/api/generate` reply
+trait StreamPiece: DeserializeOwned {
+    // Thinking and content of the piece
+    fn parts(&self) -> (&str, &str);
+    fn is_done(&self) -> bool;
+}
+
+impl StreamPiece for models::StreamChatResponse {
+    fn parts(&self) -> (&str, &str) {
+        (&self.message.thinking, &self.message.content)
+    }
+
+    fn is_done(&self) -> bool {
+        self.done
+    }
+}
+
+impl StreamPiece for models::StreamGenerateResponse {
+    fn parts(&self) -> (&str, &str) {
+        (&self.thinking, &self.response)
     }
 
+    fn is_done(&self) -> bool {
+        self.done
+    }
+}
+
+// Send the NDJSON reply to `request_builder` piece by piece as `chunk(...)` and `done()` once
+// it is complete or has failed; `last` may turn the final piece into an event sent before `done()`
+async fn stream_request<R: StreamPiece>(
+    request_builder: reqwest::RequestBuilder,
+    chunk: impl Fn(Result<String, String>) -> AppEvent,
+    last: impl FnOnce(R) -> Option<AppEvent>,
+    done: impl Fn() -> AppEvent,
+    tx: mpsc::Sender<AppEvent>,
+) {
     let res = match request_builder.send().await {
         Ok(res) => res,
         Err(e) => {
-            tx.send(AppEvent::OllamaChunk(Err(e.to_string())))
+            tx.send(chunk(Err(e.to_string())))
                 .await
                 .ok();
-            tx.send(AppEvent::OllamaDone).await.ok();
+            tx.send(done()).await.ok();
             return;
         }
     };
@@ -75,45 +292,210 @@ pub async fn stream_chat_request(
             .text()
             .await
             .unwrap_or_else(|_| "Unknown API error".to_string());
-        tx.send(AppEvent::OllamaChunk(Err(err_body))).await.ok();
-        tx.send(AppEvent::OllamaDone).await.ok();
+        tx.send(chunk(Err(err_body))).await.ok();
+        tx.send(done()).await.ok();
         return;
     }
+    let mut thinking = false;
+    let mut buffer = LineBuffer::default();
     let mut stream = res.bytes_stream();
-    while let Some(chunk_result) = stream.next().await {
-        match chunk_result {
-            Ok(chunk) => {
-                let data = String::from_utf8_lossy(&chunk);
-                for line in data.lines() {
-                    if line.is_empty() {
-                        continue;
-                    }
-                    match serde_json::from_str::<models::StreamChatResponse>(line) {
-                        Ok(stream_res) => {
-                            tx.send(AppEvent::OllamaChunk(Ok(stream_res.message.content)))
-                                .await
-                                .ok();
-                            if stream_res.done {
-                                tx.send(AppEvent::OllamaDone).await.ok();
-                                return;
-                            }
-                        }
-                        Err(e) => {
-                            let err_msg =
-                                format!("Failed to parse stream JSON: {} on line '{}'", e, line);
-                            tx.send(AppEvent::OllamaChunk(Err(err_msg))).await.ok();
+    loop {
+        let (lines, ended) = match stream.next().await {
+            Some(Ok(bytes)) => (buffer.push(&bytes), false),
+            Some(Err(e)) => {
+                tx.send(chunk(Err(e.to_string())))
+                    .await
+                    .ok();
+                break;
+            }
+            None => (std::mem::take(&mut buffer).finish().into_iter().collect(), true),
+        };
+        for line in lines {
+            match serde_json::from_str::<R>(&line) {
+                Ok(stream_res) => {
+                    let (thinking_part, content_part) = stream_res.parts();
+                    let content = with_thinking(thinking_part, content_part, stream_res.is_done(), &mut thinking);
+                    tx.send(chunk(Ok(content)))
+                        .await
+                        .ok();
+                    if stream_res.is_done() {
+                        if let Some(event) = last(stream_res) {
+                            tx.send(event).await.ok();
                         }
+                        tx.send(done()).await.ok();
+                        return;
                     }
                 }
+                Err(e) => {
+                    let err_msg =
+                        format!("Failed to parse stream JSON: {} on line '{}'", e, line);
+                    tx.send(chunk(Err(err_msg))).await.ok();
+                }
             }
-            Err(e) => {
-                tx.send(AppEvent::OllamaChunk(Err(e.to_string())))
-                    .await
-                    .ok();
-                break;
+        }
+        if ended {
+            break;
+        }
+    }
+    tx.send(done()).await.ok();
+}
+
+// Bytes of an NDJSON stream until their line is complete: network chunks may end in the
+// middle of a JSON object, or of a UTF-8 character
+#[derive(Default)]
+struct LineBuffer {
+    pending: Vec<u8>,
+}
+
+impl LineBuffer {
+    // The non-empty lines completed by `bytes`, the incomplete end is kept for later
+    fn push(&mut self, bytes: &[u8]) -> Vec<String> {
+        self.pending.extend_from_slice(bytes);
+        let Some(end) = self.pending.iter().rposition(|&byte| byte == b'\n') else {
+            return Vec::new();
+        };
+        let complete: Vec<u8> = self.pending.drain(..=end).collect();
+        String::from_utf8_lossy(&complete)
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(str::to_string)
+            .collect()
+    }
+
+    // The last line, if the stream ended without a newline after it
+    fn finish(self) -> Option<String> {
+        let line = String::from_utf8_lossy(&self.pending).trim().to_string();
+        (!line.is_empty()).then_some(line)
+    }
+}
+
+// Content of a streamed chunk, with Ollama's separate `thinking` output wrapped in a
+// `<think>` block like the one DeepSeek-R1 style models write themselves
+fn with_thinking(thinking_part: &str, content_part: &str, done: bool, thinking: &mut bool) -> String {
+    let mut content = String::new();
+    if !thinking_part.is_empty() {
+        if !*thinking {
+            content.push_str(models::THINK_OPEN);
+            *thinking = true;
+        }
+        content.push_str(thinking_part);
+    }
+    if *thinking && (!content_part.is_empty() || done) {
+        content.push_str(models::THINK_CLOSE);
+        content.push('\n');
+        *thinking = false;
+    }
+    content.push_str(content_part);
+    content
+}
+
+/// Stream a chat reply without showing it, timing the first content and the whole
+/// reply for `:bench`.
+pub async fn measure_chat(
+    client: &Client,
+    base_url: &str,
+    model: &str,
+    messages: &[models::Message],
+    options: &models::ModelOptions,
+    auth_enabled: bool,
+    auth_method: Option<&models::AuthMethod>,
+) -> Result<bench::Timing, String> {
+    let url = format!("{}/api/chat", base_url);
+    let request_payload = models::ChatRequest {
+        model,
+        messages,
+        stream: true,
+        options,
+        format: options.format.as_ref(),
+    };
+
+    let request_builder = authorize(client.post(&url).json(&request_payload), auth_enabled, auth_method);
+
+    let started = Instant::now();
+    let res = request_builder.send().await.map_err(|e| e.to_string())?;
+    if !res.status().is_success() {
+        let status = res.status();
+        let body = res.text().await.unwrap_or_default();
+        return Err(format!("{}: {}", status, body));
+    }
+    let mut first_content = None;
+    let mut buffer = LineBuffer::default();
+    let mut stream = res.bytes_stream();
+    loop {
+        let (lines, ended) = match stream.next().await {
+            Some(bytes) => (buffer.push(&bytes.map_err(|e| e.to_string())?), false),
+            None => (std::mem::take(&mut buffer).finish().into_iter().collect::<Vec<_>>(), true),
+        };
+        for line in lines {
+            let stream_res: models::StreamChatResponse =
+                serde_json::from_str(&line).map_err(|e| format!("Failed to parse stream JSON: {}", e))?;
+            let has_output = !stream_res.message.content.is_empty() || !stream_res.message.thinking.is_empty();
+            if first_content.is_none() && has_output {
+                first_content = Some(started.elapsed());
             }
+            if stream_res.done {
+                let total = started.elapsed();
+                let load = Duration::from_nanos(stream_res.load_duration);
+                return Ok(bench::Timing {
+                    load,
+                    first_token: first_content.unwrap_or(total).saturating_sub(load),
+                    total,
+                    tokens: stream_res.eval_count,
+                    eval: Duration::from_nanos(stream_res.eval_duration),
+                });
+            }
+        }
+        if ended {
+            break;
         }
     }
-    tx.send(AppEvent::OllamaDone).await.ok();
+    Err("Stream ended before the reply was complete".to_string())
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use models::{Message, Role};
+
+    #[test]
+    fn completions_without_context_resend_the_conversation() {
+        let messages = vec![
+            Message::new(Role::System, "Be brief".to_string()),
+            Message::new(Role::User, "fn add(".to_string()),
+            Message::new(Role::Assistant, "a: i32, b: i32)".to_string()),
+            Message::new(Role::User, "fn sub(".to_string()),
+            Message::new(Role::Assistant, String::new()),
+        ];
+        assert_eq!(generate_prompt(&messages, true), "fn sub(");
+        assert_eq!(
+            generate_prompt(&messages, false),
+            "User: fn add(\n\nAssistant: a: i32, b: i32)\n\nUser: fn sub(\n\nAssistant:"
+        );
+        assert_eq!(generate_prompt(&messages[..2], false), "fn add(");
+    }
+
+    #[test]
+    fn a_line_split_across_chunks_is_joined() {
+        let mut buffer = LineBuffer::default();
+        assert!(buffer.push(br#"{"response": "caf"#).is_empty());
+        // The chunk boundary falls inside the two bytes of 'é'
+        assert!(buffer.push(&[0xc3]).is_empty());
+        assert_eq!(buffer.push(b"\xa9\"}\n"), [r#"{"response": "café"}"#]);
+        assert_eq!(buffer.finish(), None);
+    }
+
+    #[test]
+    fn several_lines_in_one_chunk() {
+        let mut buffer = LineBuffer::default();
+        assert_eq!(buffer.push(b"{\"a\": 1}\n\n{\"b\": 2}\r\n{\"c\""), [r#"{"a": 1}"#, r#"{"b": 2}"#]);
+        assert_eq!(buffer.push(b": 3}\n"), [r#"{"c": 3}"#]);
+    }
+
+    #[test]
+    fn a_last_line_without_newline_is_flushed_at_the_end() {
+        let mut buffer = LineBuffer::default();
+        assert_eq!(buffer.push(b"{\"a\": 1}\n{\"done\": true}"), [r#"{"a": 1}"#]);
+        assert_eq!(buffer.finish().as_deref(), Some(r#"{"done": true}"#));
+    }
+}
@@ -0,0 +1,365 @@
+use crate::{agent::ApprovalPolicy, commands, db, models};
+use anyhow::{anyhow, Result};
+use rusqlite::Connection;
+use std::collections::HashMap;
+use std::fs;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum OptionKind {
+    Bool,
+    Float,
+    Text,
+}
+
+pub struct OptionInfo {
+    pub name: &'static str,
+    pub kind: OptionKind,
+    pub description: &'static str,
+}
+
+// Every option understood by `:set`
+pub const OPTIONS: &[OptionInfo] = &[
+    OptionInfo { name: "wrap", kind: OptionKind::Bool, description: "Wrap long lines in the chat view" },
+    OptionInfo { name: "autoscroll", kind: OptionKind::Bool, description: "Follow streaming responses to the bottom" },
+    OptionInfo { name: "thinkcontext", kind: OptionKind::Bool, description: "Send the <think> blocks of earlier replies back to the model" },
+    OptionInfo { name: "thinkcopy", kind: OptionKind::Bool, description: "Include <think> blocks when copying or quoting messages" },
+    OptionInfo { name: "temperature", kind: OptionKind::Float, description: "Sampling temperature (empty for model default)" },
+    OptionInfo { name: "stop", kind: OptionKind::Text, description: "Comma-separated stop sequences of this session, \\, for a comma (empty for none)" },
+    OptionInfo { name: "num_predict", kind: OptionKind::Text, description: "Maximum tokens to generate in this session, -1 for unlimited (empty for model default)" },
+    OptionInfo { name: "seed", kind: OptionKind::Text, description: "Random seed, the same seed and prompt give the same reply (empty for random)" },
+    OptionInfo { name: "cache", kind: OptionKind::Bool, description: "Reuse the saved reply to a request made before with temperature 0 or a seed" },
+    OptionInfo { name: "model", kind: OptionKind::Text, description: "Model used for new messages" },
+    OptionInfo { name: "api", kind: OptionKind::Text, description: "chat, or generate for plain completion of base and code models" },
+    OptionInfo { name: "format", kind: OptionKind::Text, description: "Reply format: json, a JSON schema file, or empty for free text" },
+    OptionInfo { name: "memory", kind: OptionKind::Bool, description: "Remember facts from conversations and recall them in new sessions" },
+    OptionInfo { name: "embedmodel", kind: OptionKind::Text, description: "Model that embeds documents and prompts of knowledge sessions" },
+    OptionInfo { name: "sessionsort", kind: OptionKind::Text, description: "Sidebar order: activity, created or name" },
+    OptionInfo { name: "dryrun", kind: OptionKind::Bool, description: "Show agent commands without executing them" },
+    OptionInfo { name: "instructions", kind: OptionKind::Bool, description: "Give the agent the project's AGENTS.md or .ollama-tui.md" },
+    OptionInfo { name: "commandtimeout", kind: OptionKind::Text, description: "Seconds before an agent command is killed, 0 for no limit" },
+    OptionInfo { name: "maxsteps", kind: OptionKind::Text, description: "Command/result round trips an autonomous run may take" },
+    OptionInfo { name: "approval", kind: OptionKind::Text, description: "Autonomous command approval: manual, auto-safe or auto-moderate" },
+    OptionInfo { name: "agentapproval", kind: OptionKind::Text, description: "Agent mode command approval: manual, auto-safe or auto-moderate" },
+];
+
+// Set for the current session rather than globally, see `ChatSession::options`
+pub const SESSION_OPTIONS: &[&str] = &["stop", "num_predict"];
+// Options of the requests, which a template can give its session
+pub const REQUEST_OPTIONS: &[&str] = &["temperature", "stop", "num_predict", "seed", "format"];
+
+pub fn find_option(name: &str) -> Option<&'static OptionInfo> {
+    OPTIONS.iter().find(|o| o.name == name)
+}
+
+/// Runtime options settable with `:set`, persisted in the config table.
+#[derive(Clone, Debug)]
+pub struct Options {
+    pub wrap: bool,
+    pub autoscroll: bool,
+    pub think_context: bool,
+    pub think_copy: bool,
+    pub temperature: Option<f32>,
+    pub stop: Vec<String>,
+    pub num_predict: Option<i32>,
+    pub seed: Option<i64>,
+    pub cache: bool,
+    pub format: String,
+    pub api: String,
+    format_value: Option<serde_json::Value>, // Parsed `format`, validated when set
+    pub session_sort: String,
+    pub embed_model: String,
+    pub memory: bool,
+    pub dry_run: bool,
+    pub instructions: bool,
+    pub command_timeout: u64,
+    pub max_steps: usize,
+    pub approval: ApprovalPolicy,       // For autonomous runs
+    pub agent_approval: ApprovalPolicy, // For agent mode
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            wrap: true,
+            autoscroll: true,
+            think_context: false,
+            think_copy: false,
+            temperature: None,
+            stop: Vec::new(),
+            num_predict: None,
+            seed: None,
+            cache: true,
+            format: String::new(),
+            api: "chat".to_string(),
+            format_value: None,
+            session_sort: "activity".to_string(),
+            embed_model: "nomic-embed-text".to_string(),
+            memory: false,
+            dry_run: false,
+            instructions: true,
+            command_timeout: 120,
+            max_steps: crate::agent::MAX_AUTONOMOUS_STEPS,
+            approval: ApprovalPolicy::Manual,
+            agent_approval: ApprovalPolicy::Manual,
+        }
+    }
+}
+
+fn config_key(name: &str) -> String {
+    format!("option.{}", name)
+}
+
+pub fn parse_bool(value: &str) -> Result<bool> {
+    match value.to_lowercase().as_str() {
+        "true" | "on" | "yes" | "1" => Ok(true),
+        "false" | "off" | "no" | "0" => Ok(false),
+        _ => Err(anyhow!("Invalid boolean value '{}'", value)),
+    }
+}
+
+/// Comma-separated stop sequences, with `\,` for a comma inside one and `\\` for a backslash.
+fn parse_stop(value: &str) -> Vec<String> {
+    let mut stops = Vec::new();
+    let mut current = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(escaped @ (',' | '\\')) => current.push(escaped),
+                Some(other) => current.extend(['\\', other]),
+                None => current.push('\\'),
+            },
+            ',' => stops.push(std::mem::take(&mut current)),
+            _ => current.push(c),
+        }
+    }
+    stops.push(current);
+    stops.retain(|stop| !stop.is_empty());
+    stops
+}
+
+fn format_stop(stops: &[String]) -> String {
+    let escaped: Vec<String> = stops.iter().map(|stop| stop.replace('\\', "\\\\").replace(',', "\\,")).collect();
+    escaped.join(",")
+}
+
+/// `json` for any JSON reply, otherwise inline JSON or a path to a JSON schema file.
+fn parse_format(value: &str) -> Result<Option<serde_json::Value>> {
+    if value.is_empty() {
+        return Ok(None);
+    }
+    if value == "json" {
+        return Ok(Some(serde_json::Value::String("json".to_string())));
+    }
+    let schema = if value.starts_with('{') {
+        value.to_string()
+    } else {
+        fs::read_to_string(commands::expand_tilde(value))
+            .map_err(|e| anyhow!("Cannot read schema '{}': {}", value, e))?
+    };
+    let schema = serde_json::from_str(&schema).map_err(|e| anyhow!("Invalid JSON schema: {}", e))?;
+    Ok(Some(schema))
+}
+
+impl Options {
+    pub fn load(conn: &Connection) -> Result<Self> {
+        let mut options = Self::default();
+        // Session options stored globally by older versions are left alone
+        for info in OPTIONS.iter().filter(|info| !SESSION_OPTIONS.contains(&info.name)) {
+            if let Some(value) = db::load_config(conn, &config_key(info.name))? {
+                // Ignore stale or invalid persisted values rather than failing startup
+                options.set(info.name, &value).ok();
+            }
+        }
+        Ok(options)
+    }
+
+    /// Current value as displayed by `:set`, or `None` for options not stored here.
+    pub fn get(&self, name: &str) -> Option<String> {
+        match name {
+            "wrap" => Some(self.wrap.to_string()),
+            "autoscroll" => Some(self.autoscroll.to_string()),
+            "thinkcontext" => Some(self.think_context.to_string()),
+            "thinkcopy" => Some(self.think_copy.to_string()),
+            "temperature" => Some(self.temperature.map(|t| t.to_string()).unwrap_or_default()),
+            "stop" => Some(format_stop(&self.stop)),
+            "num_predict" => Some(self.num_predict.map(|n| n.to_string()).unwrap_or_default()),
+            "seed" => Some(self.seed.map(|seed| seed.to_string()).unwrap_or_default()),
+            "cache" => Some(self.cache.to_string()),
+            "format" => Some(self.format.clone()),
+            "api" => Some(self.api.clone()),
+            "sessionsort" => Some(self.session_sort.clone()),
+            "embedmodel" => Some(self.embed_model.clone()),
+            "memory" => Some(self.memory.to_string()),
+            "dryrun" => Some(self.dry_run.to_string()),
+            "instructions" => Some(self.instructions.to_string()),
+            "commandtimeout" => Some(self.command_timeout.to_string()),
+            "maxsteps" => Some(self.max_steps.to_string()),
+            "approval" => Some(self.approval.label().to_string()),
+            "agentapproval" => Some(self.agent_approval.label().to_string()),
+            _ => None,
+        }
+    }
+
+    pub fn set(&mut self, name: &str, value: &str) -> Result<()> {
+        match name {
+            "wrap" => self.wrap = parse_bool(value)?,
+            "autoscroll" => self.autoscroll = parse_bool(value)?,
+            "thinkcontext" => self.think_context = parse_bool(value)?,
+            "thinkcopy" => self.think_copy = parse_bool(value)?,
+            "dryrun" => self.dry_run = parse_bool(value)?,
+            "memory" => self.memory = parse_bool(value)?,
+            "instructions" => self.instructions = parse_bool(value)?,
+            "cache" => self.cache = parse_bool(value)?,
+            "commandtimeout" => {
+                self.command_timeout = value
+                    .parse()
+                    .map_err(|_| anyhow!("Invalid number of seconds '{}'", value))?;
+            }
+            "maxsteps" => {
+                self.max_steps = value
+                    .parse()
+                    .ok()
+                    .filter(|steps| *steps > 0)
+                    .ok_or_else(|| anyhow!("Invalid number of steps '{}'", value))?;
+            }
+            "temperature" => {
+                self.temperature = if value.is_empty() {
+                    None
+                } else {
+                    let temperature: f32 = value
+                        .parse()
+                        .map_err(|_| anyhow!("Invalid number '{}'", value))?;
+                    Some(temperature)
+                };
+            }
+            "stop" => self.stop = parse_stop(value),
+            "num_predict" => {
+                self.num_predict = if value.is_empty() {
+                    None
+                } else {
+                    let num_predict: i32 = value
+                        .parse()
+                        .map_err(|_| anyhow!("Invalid number '{}'", value))?;
+                    Some(num_predict)
+                };
+            }
+            "seed" => {
+                self.seed = if value.is_empty() {
+                    None
+                } else {
+                    Some(value.parse().map_err(|_| anyhow!("Invalid seed '{}'", value))?)
+                };
+            }
+            "format" => {
+                self.format_value = parse_format(value)?;
+                self.format = value.to_string();
+            }
+            "embedmodel" => {
+                if value.is_empty() {
+                    return Err(anyhow!("embedmodel cannot be empty"));
+                }
+                self.embed_model = value.to_string();
+            }
+            "api" => {
+                if !matches!(value, "chat" | "generate") {
+                    return Err(anyhow!("api must be chat or generate"));
+                }
+                self.api = value.to_string();
+            }
+            "sessionsort" => {
+                if !matches!(value, "activity" | "created" | "name") {
+                    return Err(anyhow!("sessionsort must be activity, created or name"));
+                }
+                self.session_sort = value.to_string();
+            }
+            "approval" | "agentapproval" => {
+                let policy = ApprovalPolicy::parse(value)
+                    .ok_or_else(|| anyhow!("{} must be manual, auto-safe or auto-moderate", name))?;
+                if name == "approval" {
+                    self.approval = policy;
+                } else {
+                    self.agent_approval = policy;
+                }
+            }
+            _ => return Err(anyhow!("Unknown option '{}'", name)),
+        }
+        Ok(())
+    }
+
+    /// Request options for `model` in a session with the options `session`, falling back
+    /// to the model's config defaults for anything not set with `:set`.
+    pub fn model_options(
+        &self,
+        model: &str,
+        defaults: &HashMap<String, HashMap<String, String>>,
+        session: &HashMap<String, String>,
+    ) -> models::ModelOptions {
+        let mut merged = self.clone();
+        for (name, value) in session {
+            merged.set(name, value).ok();
+        }
+        // "llama3" defaults also apply to "llama3:latest", "llama3:8b", ...
+        let model_defaults = defaults
+            .get(model)
+            .or_else(|| defaults.get(model.split(':').next().unwrap_or(model)));
+        if let Some(model_defaults) = model_defaults {
+            for (name, value) in model_defaults {
+                let unset = merged.get(name).is_some_and(|current| current.is_empty());
+                if unset {
+                    // Invalid config values are ignored rather than failing the request
+                    merged.set(name, value).ok();
+                }
+            }
+        }
+
+        models::ModelOptions {
+            temperature: merged.temperature,
+            stop: if merged.stop.is_empty() { None } else { Some(merged.stop) },
+            num_predict: merged.num_predict,
+            seed: merged.seed,
+            format: merged.format_value,
+        }
+    }
+
+    pub fn save(&self, conn: &Connection, name: &str) -> Result<()> {
+        if let Some(value) = self.get(name) {
+            db::save_config(conn, &config_key(name), &value)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stop_sequences_can_contain_commas() {
+        let mut options = Options::default();
+        options.set("stop", r"###,a\,b,,c\\").unwrap();
+        assert_eq!(options.stop, ["###", "a,b", "c\\"]);
+        let shown = options.get("stop").unwrap();
+        options.set("stop", &shown).unwrap();
+        assert_eq!(options.stop, ["###", "a,b", "c\\"]);
+    }
+
+    #[test]
+    fn session_options_come_before_global_and_model_ones() {
+        let mut options = Options::default();
+        options.set("temperature", "0.5").unwrap();
+        let defaults = HashMap::from([(
+            "llama3".to_string(),
+            HashMap::from([("num_predict".to_string(), "64".to_string()), ("stop".to_string(), "END".to_string())]),
+        )]);
+        let session = HashMap::from([("stop".to_string(), "User:".to_string())]);
+
+        let merged = options.model_options("llama3:8b", &defaults, &session);
+        assert_eq!(merged.stop, Some(vec!["User:".to_string()]));
+        assert_eq!(merged.num_predict, Some(64));
+        assert_eq!(merged.temperature, Some(0.5));
+
+        let merged = options.model_options("llama3", &defaults, &HashMap::new());
+        assert_eq!(merged.stop, Some(vec!["END".to_string()]));
+    }
+}
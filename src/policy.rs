@@ -0,0 +1,263 @@
+use crate::models::CommandPolicy;
+use crate::tools::ToolCall;
+use crate::web;
+use regex::Regex;
+use std::path::{Component, Path, PathBuf};
+
+/// Outcome of checking a command against the configured policy.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    /// Matches an allow rule, runs without confirmation even in single-step mode
+    Allowed,
+    /// Matches no rule, runs according to the usual pause/single-step settings
+    Unlisted,
+    /// Violates the policy and only runs after an explicit override
+    Denied(String),
+}
+
+// Commands whose path arguments are written to
+const WRITING_COMMANDS: &[&str] = &["rm", "mv", "cp", "tee", "touch", "mkdir", "rmdir", "chmod", "chown", "ln", "truncate"];
+
+// Anything that chains or substitutes commands disqualifies a command from the allowlist
+const SHELL_OPERATORS: &[&str] = &[";", "&", ">", "<", "`", "$(", "\n"];
+
+/// Turn a rule into a regex. Rules prefixed with `glob:` match the whole command
+/// with `*` and `?` wildcards, everything else is a regular expression.
+fn compile(rule: &str) -> Result<Regex, String> {
+    let pattern = match rule.strip_prefix("glob:") {
+        Some(glob) => {
+            let mut pattern = String::from("^");
+            for c in glob.chars() {
+                match c {
+                    '*' => pattern.push_str(".*"),
+                    '?' => pattern.push('.'),
+                    c => pattern.push_str(&regex::escape(&c.to_string())),
+                }
+            }
+            pattern.push('$');
+            pattern
+        }
+        None => rule.to_string(),
+    };
+    Regex::new(&pattern).map_err(|e| format!("invalid policy rule '{}': {}", rule, e))
+}
+
+/// Whether `command` matches one rule, written like the policy's allow and deny rules.
+pub fn matches_rule(rule: &str, command: &str) -> Result<bool, String> {
+    Ok(compile(rule)?.is_match(command.trim()))
+}
+
+/// A `CommandPolicy` with its rules compiled, built when the config is loaded so an
+/// invalid rule is reported then rather than by every command it is checked for.
+#[derive(Clone, Debug)]
+pub struct CompiledPolicy {
+    allow: Vec<(String, Regex)>,
+    deny: Vec<(String, Regex)>,
+    restrict_writes: bool,
+    fetch_domains: Vec<String>,
+}
+
+impl CompiledPolicy {
+    pub fn new(policy: &CommandPolicy) -> Result<Self, String> {
+        let compile_all =
+            |rules: &[String]| rules.iter().map(|rule| Ok((rule.clone(), compile(rule)?))).collect::<Result<Vec<_>, String>>();
+        Ok(Self {
+            allow: compile_all(&policy.allow)?,
+            deny: compile_all(&policy.deny)?,
+            restrict_writes: policy.restrict_writes,
+            fetch_domains: policy.fetch_domains.clone(),
+        })
+    }
+}
+
+fn matches_any<'a>(rules: &'a [(String, Regex)], command: &str) -> Option<&'a str> {
+    rules.iter().find(|(_, regex)| regex.is_match(command)).map(|(rule, _)| rule.as_str())
+}
+
+/// Check `command` before it runs in `cwd`. Deny rules win over allow rules, and a
+/// command is only allowed when every stage of its pipeline matches an allow rule.
+pub fn evaluate(policy: &CompiledPolicy, command: &str, cwd: &Path) -> Verdict {
+    let command = command.trim();
+    if let Some(rule) = matches_any(&policy.deny, command) {
+        return Verdict::Denied(format!("matches deny rule '{}'", rule));
+    }
+    if policy.restrict_writes
+        && let Some(path) = write_outside(command, cwd)
+    {
+        return Verdict::Denied(format!("writes outside {}: {}", cwd.display(), path));
+    }
+
+    if SHELL_OPERATORS.iter().any(|op| command.contains(op)) {
+        return Verdict::Unlisted;
+    }
+    if command.split('|').map(str::trim).all(|stage| matches_any(&policy.allow, stage).is_some()) {
+        Verdict::Allowed
+    } else {
+        Verdict::Unlisted
+    }
+}
+
+/// Check a tool call: deny rules see its summary such as `write_file src/main.rs`, and
+/// writing tools are held to `restrict_writes` and fetches to `fetch_domains`. Tools are
+/// never on the allowlist.
+pub fn evaluate_tool(policy: &CompiledPolicy, call: &ToolCall, cwd: &Path) -> Verdict {
+    if let Some(rule) = matches_any(&policy.deny, &call.summary()) {
+        return Verdict::Denied(format!("matches deny rule '{}'", rule));
+    }
+    if policy.restrict_writes
+        && call.writes()
+        && let Some(path) = call.path()
+        && is_outside(path, cwd)
+    {
+        return Verdict::Denied(format!("writes outside {}: {}", cwd.display(), path));
+    }
+    if let Some(url) = call.url()
+        && let Err(reason) = web::domain_allowed(url, &policy.fetch_domains)
+    {
+        return Verdict::Denied(reason);
+    }
+    Verdict::Unlisted
+}
+
+/// First path the command writes to outside `cwd`, from output redirections and
+/// the arguments of commands that modify files. Best effort, not a sandbox.
+fn write_outside(command: &str, cwd: &Path) -> Option<String> {
+    let tokens: Vec<&str> = command.split_whitespace().collect();
+    let mut writes_args = false;
+    let mut redirect = false;
+    for token in tokens {
+        if matches!(token, ";" | "&&" | "||" | "|") {
+            writes_args = false;
+            continue;
+        }
+        // `>file`, `>>file` and `2>file` carry the target in the same token
+        if let Some(index) = token.find('>') {
+            let target = token[index..].trim_start_matches('>');
+            if target.is_empty() {
+                redirect = true;
+            } else if !target.starts_with('&') && is_outside(target, cwd) {
+                return Some(target.to_string());
+            }
+            continue;
+        }
+        if redirect {
+            redirect = false;
+            if is_outside(token, cwd) {
+                return Some(token.to_string());
+            }
+            continue;
+        }
+        let program = token.rsplit('/').next().unwrap_or(token);
+        if WRITING_COMMANDS.contains(&program) {
+            writes_args = true;
+        } else if writes_args && !token.starts_with('-') && is_outside(token, cwd) {
+            return Some(token.to_string());
+        }
+    }
+    None
+}
+
+fn is_outside(path: &str, cwd: &Path) -> bool {
+    let path = path.trim_matches(|c| c == '"' || c == '\'');
+    if path == "/dev/null" {
+        return false;
+    }
+    let path = if let Some(rest) = path.strip_prefix('~') {
+        match std::env::var_os("HOME") {
+            Some(home) => PathBuf::from(home).join(rest.trim_start_matches('/')),
+            None => return true,
+        }
+    } else {
+        cwd.join(path)
+    };
+    !normalize(&path).starts_with(normalize(cwd))
+}
+
+// Resolve `.` and `..` without touching the filesystem, the target may not exist yet
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            Component::CurDir => {}
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(allow: &[&str], deny: &[&str]) -> CompiledPolicy {
+        let rules = |rules: &[&str]| rules.iter().map(|rule| rule.to_string()).collect();
+        CompiledPolicy::new(&CommandPolicy { allow: rules(allow), deny: rules(deny), ..Default::default() }).unwrap()
+    }
+
+    fn denied(verdict: Verdict) -> bool {
+        matches!(verdict, Verdict::Denied(_))
+    }
+
+    #[test]
+    fn operators_keep_commands_off_the_allowlist() {
+        let policy = policy(&[r"^ls(\s|$)", r"^pwd$"], &[]);
+        let cwd = Path::new("/work/project");
+        assert_eq!(evaluate(&policy, "ls -la", cwd), Verdict::Allowed);
+        for command in ["ls; pwd", "ls && pwd", "ls & pwd", "ls $(pwd)", "ls `pwd`", "ls < input", "ls\npwd"] {
+            assert_eq!(evaluate(&policy, command, cwd), Verdict::Unlisted, "{}", command);
+        }
+    }
+
+    #[test]
+    fn every_pipeline_stage_must_be_allowed() {
+        let policy = policy(&[r"^ls(\s|$)", r"^grep\s"], &[]);
+        let cwd = Path::new("/work/project");
+        assert_eq!(evaluate(&policy, "ls src | grep rs", cwd), Verdict::Allowed);
+        assert_eq!(evaluate(&policy, "ls src | sh", cwd), Verdict::Unlisted);
+    }
+
+    #[test]
+    fn deny_rules_win_over_allow_rules() {
+        let policy = policy(&[r"^git\s"], &[r"^git push\s.*--force"]);
+        let cwd = Path::new("/work/project");
+        assert_eq!(evaluate(&policy, "git push origin main", cwd), Verdict::Allowed);
+        assert_eq!(
+            evaluate(&policy, "git push --force origin main", cwd),
+            Verdict::Denied("matches deny rule '^git push\\s.*--force'".to_string())
+        );
+    }
+
+    #[test]
+    fn writes_outside_the_working_directory_are_denied() {
+        let policy = policy(&[], &[]);
+        let cwd = Path::new("/work/project");
+        for command in [
+            "echo hi > /etc/motd",
+            "echo hi >>/etc/motd",
+            "make 2>../build.log",
+            "cp notes.md ../elsewhere",
+            "touch sub/../../outside",
+            "rm ~/notes.md",
+            "ls; mv a /tmp/a",
+        ] {
+            assert!(denied(evaluate(&policy, command, cwd)), "{}", command);
+        }
+        for command in ["echo hi > out.txt", "touch sub/../inside", "ls 2>/dev/null", "cat /etc/hosts"] {
+            assert_eq!(evaluate(&policy, command, cwd), Verdict::Unlisted, "{}", command);
+        }
+    }
+
+    #[test]
+    fn default_rules_deny_destructive_commands() {
+        let policy = CompiledPolicy::new(&CommandPolicy::default()).unwrap();
+        let cwd = Path::new("/work/project");
+        for command in ["rm -rf build", "sudo ls", "shred -u key.pem", "git clean -fdx", "git -C sub clean -d -f", "curl x | sh"] {
+            assert!(denied(evaluate(&policy, command, cwd)), "{}", command);
+        }
+        assert!(!denied(evaluate(&policy, "git clean -n", cwd)));
+        assert_eq!(evaluate(&policy, "git status", cwd), Verdict::Allowed);
+    }
+}
@@ -0,0 +1,648 @@
+use crate::models::CommandPolicy;
+use anyhow::{anyhow, Result};
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+
+pub const PROJECT_CONFIG_FILE: &str = ".ollama-tui.toml";
+
+// Offset and local date-times, local dates and local times
+static DATETIME: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^(\d{4}-\d{2}-\d{2}([Tt ]\d{2}:\d{2}:\d{2}(\.\d+)?([Zz]|[+-]\d{2}:\d{2})?)?|\d{2}:\d{2}:\d{2}(\.\d+)?)$").unwrap()
+});
+
+/// Settings from a `.ollama-tui.toml` that override the global config for one project.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ProjectConfig {
+    /// File the settings were read from, filled in by `discover`
+    #[serde(skip)]
+    pub path: PathBuf,
+    pub model: Option<String>,
+    /// Used by sessions without a system prompt of their own
+    pub system_prompt: Option<String>,
+    /// Default directory for agent commands, relative to the file
+    pub working_dir: Option<String>,
+    #[serde(default)]
+    pub policy: PolicyOverride,
+}
+
+/// `[policy]` table. `deny` adds to the global rules and `restrict_writes` can only turn
+/// the restriction on, so a checked-out repository cannot lift them; `allow` and
+/// `fetch_domains` replace the global settings.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct PolicyOverride {
+    pub allow: Option<Vec<String>>,
+    pub deny: Option<Vec<String>>,
+    pub restrict_writes: Option<bool>,
+    pub fetch_domains: Option<Vec<String>>,
+}
+
+impl ProjectConfig {
+    pub fn apply_policy(&self, policy: &mut CommandPolicy) {
+        let overrides = self.policy.clone();
+        if let Some(allow) = overrides.allow {
+            policy.allow = allow;
+        }
+        for rule in overrides.deny.unwrap_or_default() {
+            if !policy.deny.contains(&rule) {
+                policy.deny.push(rule);
+            }
+        }
+        if let Some(restrict_writes) = overrides.restrict_writes {
+            policy.restrict_writes |= restrict_writes;
+        }
+        if let Some(fetch_domains) = overrides.fetch_domains {
+            policy.fetch_domains = fetch_domains;
+        }
+    }
+
+    /// The `[policy]` keys the file sets, to tell the user what it changed.
+    pub fn policy_keys(&self) -> Vec<&'static str> {
+        let policy = &self.policy;
+        [
+            ("allow", policy.allow.is_some()),
+            ("deny", policy.deny.is_some()),
+            ("restrict_writes", policy.restrict_writes.is_some()),
+            ("fetch_domains", policy.fetch_domains.is_some()),
+        ]
+        .into_iter()
+        .filter_map(|(key, set)| set.then_some(key))
+        .collect()
+    }
+
+    pub fn working_dir(&self) -> Option<PathBuf> {
+        let base = self.path.parent()?;
+        let dir = base.join(crate::commands::expand_tilde(self.working_dir.as_deref()?));
+        dir.canonicalize().ok().filter(|dir| dir.is_dir())
+    }
+}
+
+/// The nearest `.ollama-tui.toml` in `start` or one of its parents.
+pub fn discover(start: &Path) -> Result<Option<ProjectConfig>> {
+    let Some(path) = start
+        .ancestors()
+        .map(|dir| dir.join(PROJECT_CONFIG_FILE))
+        .find(|path| path.is_file())
+    else {
+        return Ok(None);
+    };
+    let text = fs::read_to_string(&path)?;
+    let value = parse_toml(&text).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+    let mut config: ProjectConfig =
+        serde_json::from_value(value).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+    config.path = path;
+    Ok(Some(config))
+}
+
+/// Parse the subset of TOML the config, project and workflow files need: `[table]`
+/// and `[[array]]` headers and keys, both possibly dotted, set to strings (multi-line
+/// and literal ones included), numbers, booleans, dates, arrays and inline tables, arrays
+/// possibly spanning lines. Dates and times are kept as the strings they are written as;
+/// a key or table defined twice is an error.
+pub fn parse_toml(text: &str) -> Result<Value, String> {
+    let mut root = Map::new();
+    // Path of the current table; arrays of tables along it stand for their last entry
+    let mut table: Vec<String> = Vec::new();
+    // Tables that had a `[table]` header, which may not come twice
+    let mut defined: HashSet<Vec<String>> = HashSet::new();
+    let mut lines = text.lines().enumerate();
+    while let Some((number, line)) = lines.next() {
+        let error = |message: &str| format!("line {}: {}", number + 1, message);
+        let line = strip_comment(line).trim().to_string();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix("[[") {
+            let name = name.strip_suffix("]]").ok_or_else(|| error("unclosed table header"))?;
+            let mut path = split_key(name).map_err(|e| error(&e))?;
+            let last = path.pop().unwrap_or_default();
+            let parent = table_at(&mut root, &path).map_err(|e| error(&e))?;
+            match parent.entry(last.clone()).or_insert_with(|| Value::Array(Vec::new())) {
+                Value::Array(items) => items.push(Value::Object(Map::new())),
+                _ => return Err(error("not an array of tables")),
+            }
+            path.push(last);
+            // The tables of the previous entry may be defined again in the new one
+            defined.retain(|defined| !defined.starts_with(&path));
+            table = path;
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[') {
+            let name = name.strip_suffix(']').ok_or_else(|| error("unclosed table header"))?;
+            table = split_key(name).map_err(|e| error(&e))?;
+            if !defined.insert(table.clone()) {
+                return Err(error(&format!("table '{}' defined twice", name.trim())));
+            }
+            table_at(&mut root, &table).map_err(|e| error(&e))?;
+            continue;
+        }
+        let (key, value) = split_assignment(&line).ok_or_else(|| error("expected key = value"))?;
+        let mut path = split_key(key).map_err(|e| error(&e))?;
+        let mut value = value.trim().to_string();
+        if let Some(quotes) = ["\"\"\"", "'''"].into_iter().find(|quotes| value.starts_with(quotes)) {
+            // Multi-line strings run until the closing quotes, taken as written
+            if closing_quotes(&value[quotes.len()..], quotes).is_none() {
+                loop {
+                    let (_, next) = lines.next().ok_or_else(|| error("unclosed string"))?;
+                    value.push('\n');
+                    match closing_quotes(next, quotes) {
+                        Some(end) => {
+                            if !strip_comment(&next[end + 3..]).trim().is_empty() {
+                                return Err(error("unexpected text after string"));
+                            }
+                            value.push_str(&next[..end + 3]);
+                            break;
+                        }
+                        None => value.push_str(next),
+                    }
+                }
+            }
+        } else if value.starts_with('[') || value.starts_with('{') {
+            // Arrays and inline tables may continue until the closing bracket
+            while !value_closed(&value) {
+                let (_, next) = lines.next().ok_or_else(|| error("unclosed array"))?;
+                value.push(' ');
+                value.push_str(strip_comment(next).trim());
+            }
+        }
+        let value = parse_value(&value).map_err(|e| error(&e))?;
+        let last = path.pop().unwrap_or_default();
+        path.splice(0..0, table.iter().cloned());
+        let target = table_at(&mut root, &path).map_err(|e| error(&e))?;
+        insert(target, last, value).map_err(|e| error(&e))?;
+    }
+    Ok(Value::Object(root))
+}
+
+fn insert(table: &mut Map<String, Value>, key: String, value: Value) -> Result<(), String> {
+    if table.contains_key(&key) {
+        return Err(format!("duplicate key '{}'", key));
+    }
+    table.insert(key, value);
+    Ok(())
+}
+
+// The table at `path`, created if missing; an array of tables leads to its last entry
+fn table_at<'a>(root: &'a mut Map<String, Value>, path: &[String]) -> Result<&'a mut Map<String, Value>, String> {
+    let mut table = root;
+    for name in path {
+        let mut next = table.entry(name.clone()).or_insert_with(|| Value::Object(Map::new()));
+        if let Value::Array(items) = next {
+            next = items.last_mut().ok_or_else(|| format!("'{}' is not a table", name))?;
+        }
+        table = next.as_object_mut().ok_or_else(|| format!("'{}' is not a table", name))?;
+    }
+    Ok(table)
+}
+
+// `a."b.c".d` into its parts
+fn split_key(text: &str) -> Result<Vec<String>, String> {
+    let mut parts = Vec::new();
+    let mut rest = text.trim();
+    loop {
+        let part = if rest.starts_with('"') || rest.starts_with('\'') {
+            match parse_item(&mut rest)? {
+                Value::String(part) => part,
+                _ => return Err("invalid key".to_string()),
+            }
+        } else {
+            let end = rest.find('.').unwrap_or(rest.len());
+            let part = rest[..end].trim();
+            rest = &rest[end..];
+            if part.is_empty() || !part.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+                return Err(format!("invalid key '{}'", text.trim()));
+            }
+            part.to_string()
+        };
+        parts.push(part);
+        rest = rest.trim_start();
+        match rest.strip_prefix('.') {
+            Some(after) => rest = after.trim_start(),
+            None if rest.is_empty() => return Ok(parts),
+            None => return Err(format!("invalid key '{}'", text.trim())),
+        }
+    }
+}
+
+// Split at the first `=` that is not inside a quoted key
+fn split_assignment(line: &str) -> Option<(&str, &str)> {
+    let mut quote = None;
+    for (i, c) in line.char_indices() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == '=' => return Some((&line[..i], &line[i + 1..])),
+            None => {}
+        }
+    }
+    None
+}
+
+/// `text` as a TOML basic string.
+pub fn toml_string(text: &str) -> String {
+    let mut out = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '"' => out.push_str("\\\""),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04X}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// `key` bare when TOML allows it, quoted otherwise.
+pub fn toml_key(key: &str) -> String {
+    if !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+        key.to_string()
+    } else {
+        toml_string(key)
+    }
+}
+
+// Everything before a `#` that is not inside a string
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match quote {
+            Some('"') if escaped => escaped = false,
+            Some('"') if c == '\\' => escaped = true,
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == '#' => return &line[..i],
+            None => {}
+        }
+    }
+    line
+}
+
+// Whether `value` is complete, or wrong before its end so that more lines cannot help
+fn value_closed(value: &str) -> bool {
+    let mut rest = value;
+    parse_item(&mut rest).is_ok() || !rest.trim().is_empty()
+}
+
+fn parse_value(text: &str) -> Result<Value, String> {
+    let mut rest = text;
+    let value = parse_item(&mut rest)?;
+    if !rest.trim().is_empty() {
+        return Err(format!("unexpected '{}'", rest.trim()));
+    }
+    Ok(value)
+}
+
+fn parse_item(rest: &mut &str) -> Result<Value, String> {
+    *rest = rest.trim_start();
+    if rest.starts_with('[') {
+        return parse_array(rest);
+    }
+    if rest.starts_with('{') {
+        return parse_inline_table(rest);
+    }
+    if let Some(after) = rest.strip_prefix("\"\"\"") {
+        let end = closing_quotes(after, "\"\"\"").ok_or("unclosed string")?;
+        // A newline right after the opening quotes is not part of the string
+        let text = after[..end].strip_prefix('\n').unwrap_or(&after[..end]);
+        *rest = &after[end + 3..];
+        return unescape(text).map(Value::String);
+    }
+    if let Some(after) = rest.strip_prefix("'''") {
+        let end = closing_quotes(after, "'''").ok_or("unclosed string")?;
+        let text = after[..end].strip_prefix('\n').unwrap_or(&after[..end]);
+        *rest = &after[end + 3..];
+        return Ok(Value::String(text.to_string()));
+    }
+    if let Some(after) = rest.strip_prefix('\'') {
+        let end = after.find('\'').ok_or("unclosed string")?;
+        *rest = &after[end + 1..];
+        return Ok(Value::String(after[..end].to_string()));
+    }
+    if let Some(after) = rest.strip_prefix('"') {
+        let mut escaped = false;
+        for (i, c) in after.char_indices() {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => {
+                    *rest = &after[i + 1..];
+                    return unescape(&after[..i]).map(Value::String);
+                }
+                _ => {}
+            }
+        }
+        return Err("unclosed string".to_string());
+    }
+    let end = rest.find([',', ']', '}']).unwrap_or(rest.len());
+    let word = rest[..end].trim();
+    *rest = &rest[end..];
+    let number = word.replace('_', "");
+    match word {
+        "true" => Ok(Value::Bool(true)),
+        "false" => Ok(Value::Bool(false)),
+        _ if DATETIME.is_match(word) => Ok(Value::String(word.to_string())),
+        _ if let Ok(integer) = number.parse::<i64>() => Ok(Value::from(integer)),
+        _ if number.contains(['.', 'e', 'E']) && let Ok(float) = number.parse::<f64>() => Ok(Value::from(float)),
+        _ => Err(format!("unsupported value '{}'", word)),
+    }
+}
+
+// Where the `quotes` closing a multi-line string are in `text`. Escaped quotes of a basic
+// string do not count, and up to two quotes right before the closing ones are content
+fn closing_quotes(text: &str, quotes: &str) -> Option<usize> {
+    let bytes = text.as_bytes();
+    let quote = quotes.as_bytes()[0];
+    let mut i = 0;
+    while i < bytes.len() {
+        if quote == b'"' && bytes[i] == b'\\' {
+            i += 2;
+            continue;
+        }
+        if bytes[i..].starts_with(quotes.as_bytes()) {
+            let run = bytes[i..].iter().take_while(|&&byte| byte == quote).count();
+            return Some(i + run.min(5) - 3);
+        }
+        i += 1;
+    }
+    None
+}
+
+fn unescape(text: &str) -> Result<String, String> {
+    let mut out = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('t') => out.push('\t'),
+            Some('r') => out.push('\r'),
+            Some(c @ ('"' | '\\')) => out.push(c),
+            Some(u @ ('u' | 'U')) => {
+                let hex: String = chars.by_ref().take(if u == 'u' { 4 } else { 8 }).collect();
+                let c = u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32).ok_or("invalid \\u escape")?;
+                out.push(c);
+            }
+            // A backslash at the end of a line joins it with the next non-blank text
+            Some(c) if c.is_whitespace() => chars = chars.as_str().trim_start().chars(),
+            _ => return Err("unsupported escape in string".to_string()),
+        }
+    }
+    Ok(out)
+}
+
+fn parse_inline_table(rest: &mut &str) -> Result<Value, String> {
+    *rest = rest.trim_start().strip_prefix('{').ok_or("expected an inline table")?;
+    let mut table = Map::new();
+    loop {
+        *rest = rest.trim_start();
+        if let Some(after) = rest.strip_prefix('}') {
+            *rest = after;
+            return Ok(Value::Object(table));
+        }
+        let (key, after) = split_assignment(rest).ok_or("expected key = value in inline table")?;
+        let mut path = split_key(key)?;
+        *rest = after;
+        let value = parse_item(rest)?;
+        let last = path.pop().unwrap_or_default();
+        insert(table_at(&mut table, &path)?, last, value)?;
+        *rest = rest.trim_start();
+        if let Some(after) = rest.strip_prefix(',') {
+            *rest = after;
+        } else if !rest.starts_with('}') {
+            return Err("expected , or } in inline table".to_string());
+        }
+    }
+}
+
+fn parse_array(rest: &mut &str) -> Result<Value, String> {
+    *rest = rest.trim_start().strip_prefix('[').ok_or("expected an array")?;
+    let mut items = Vec::new();
+    loop {
+        *rest = rest.trim_start();
+        if let Some(after) = rest.strip_prefix(']') {
+            *rest = after;
+            return Ok(Value::Array(items));
+        }
+        if rest.is_empty() {
+            return Err("unclosed array".to_string());
+        }
+        items.push(parse_item(rest)?);
+        *rest = rest.trim_start();
+        if let Some(after) = rest.strip_prefix(',') {
+            *rest = after;
+        } else if !rest.starts_with(']') {
+            return Err("expected , or ] in array".to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn tables_and_arrays_of_tables() {
+        let text = r#"
+            model = "llama3" # trailing comment
+            [keys.normal]
+            quit = ["q q", "ctrl+c"]
+            [model_defaults."llama3:8b"]
+            temperature = 0.2
+            [[workflow.step]]
+            name = "plan"
+            [workflow.step.retry]
+            times = 2
+            [[workflow.step]]
+            name = "run"
+            [workflow.step.retry]
+            times = 3
+        "#;
+        assert_eq!(
+            parse_toml(text).unwrap(),
+            json!({
+                "model": "llama3",
+                "keys": {"normal": {"quit": ["q q", "ctrl+c"]}},
+                "model_defaults": {"llama3:8b": {"temperature": 0.2}},
+                "workflow": {"step": [
+                    {"name": "plan", "retry": {"times": 2}},
+                    {"name": "run", "retry": {"times": 3}},
+                ]},
+            })
+        );
+    }
+
+    #[test]
+    fn values() {
+        let text = r#"
+            integer = 1_000
+            negative = -3
+            float = 6.5e-1
+            yes = true
+            dotted.key = "x"
+            "quoted key" = 'C:\path'
+            inline = { a = 1, b.c = [2, 3] }
+            list = [
+                "one", # first
+                "two",
+            ]
+        "#;
+        assert_eq!(
+            parse_toml(text).unwrap(),
+            json!({
+                "integer": 1000,
+                "negative": -3,
+                "float": 0.65,
+                "yes": true,
+                "dotted": {"key": "x"},
+                "quoted key": "C:\\path",
+                "inline": {"a": 1, "b": {"c": [2, 3]}},
+                "list": ["one", "two"],
+            })
+        );
+    }
+
+    #[test]
+    fn strings() {
+        let text = concat!(
+            "basic = \"tab\\t \\\"quoted\\\" \\u00e9 # not a comment\"\n",
+            "literal = 'no \\n escapes'\n",
+            "multi = \"\"\"\nfirst\nsecond \\\"x\\\"\"\"\"\n",
+            "raw = '''\nC:\\dir\n  kept # as written\n'''\n",
+            "one_line = '''it's'''\n",
+            "joined = \"\"\"\nsame \\\n    line, \\U0001F600\"\"\"\n",
+        );
+        assert_eq!(
+            parse_toml(text).unwrap(),
+            json!({
+                "basic": "tab\t \"quoted\" \u{e9} # not a comment",
+                "literal": "no \\n escapes",
+                "multi": "first\nsecond \"x\"",
+                "raw": "C:\\dir\n  kept # as written\n",
+                "one_line": "it's",
+                "joined": "same line, \u{1F600}",
+            })
+        );
+    }
+
+    #[test]
+    fn dates_are_kept_as_written() {
+        let text = "
+            offset = 1979-05-27T07:32:00-08:00
+            utc = 1979-05-27 07:32:00.999Z
+            local = 1979-05-27T07:32:00
+            day = 1979-05-27
+            time = 07:32:00
+            in_array = [1979-05-27, 2024-01-01]
+        ";
+        assert_eq!(
+            parse_toml(text).unwrap(),
+            json!({
+                "offset": "1979-05-27T07:32:00-08:00",
+                "utc": "1979-05-27 07:32:00.999Z",
+                "local": "1979-05-27T07:32:00",
+                "day": "1979-05-27",
+                "time": "07:32:00",
+                "in_array": ["1979-05-27", "2024-01-01"],
+            })
+        );
+    }
+
+    #[test]
+    fn invalid_documents() {
+        let cases = [
+            ("a = 1\na = 2", "line 2: duplicate key 'a'"),
+            ("a.b = 1\na.b = 2", "line 2: duplicate key 'b'"),
+            ("t = { a = 1, a = 2 }", "line 1: duplicate key 'a'"),
+            ("[t]\n[t]", "line 2: table 't' defined twice"),
+            ("a = 1\n[a]", "line 2: 'a' is not a table"),
+            ("[t]\n[[t]]", "line 2: not an array of tables"),
+            ("a = \"open", "line 1: unclosed string"),
+            ("a = '''\nnever closed", "line 1: unclosed string"),
+            ("a = [1, 2", "line 1: unclosed array"),
+            ("a = 1979-13", "line 1: unsupported value '1979-13'"),
+            ("a = 1 2", "line 1: unsupported value '1 2'"),
+            ("just a line", "line 1: expected key = value"),
+            ("bad key! = 1", "line 1: invalid key 'bad key!'"),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(parse_toml(text).unwrap_err(), expected, "{}", text);
+        }
+    }
+
+    #[test]
+    fn written_strings_read_back() {
+        let text = "quote \" backslash \\ newline \n tab \t bell \u{7}";
+        let parsed = parse_toml(&format!("{} = {}", toml_key("a key"), toml_string(text))).unwrap();
+        assert_eq!(parsed, json!({"a key": text}));
+    }
+
+    #[test]
+    fn the_written_config_reads_back() {
+        let config = crate::models::Config::default();
+        let text = crate::config::to_toml(&config).unwrap();
+        let mut expected = serde_json::to_value(&config).unwrap();
+        // Unset options are left out of the file
+        expected.as_object_mut().unwrap().retain(|_, value| !value.is_null());
+        assert_eq!(parse_toml(&text).unwrap(), expected);
+    }
+
+    #[test]
+    fn project_policy_cannot_lift_the_global_rules() {
+        let project = ProjectConfig {
+            policy: PolicyOverride {
+                allow: Some(vec!["^cargo test$".to_string()]),
+                deny: Some(vec!["^rm ".to_string(), "^curl ".to_string()]),
+                restrict_writes: Some(false),
+                fetch_domains: None,
+            },
+            ..Default::default()
+        };
+        let mut policy = CommandPolicy { deny: vec!["^rm ".to_string()], ..Default::default() };
+        let fetch_domains = policy.fetch_domains.clone();
+        project.apply_policy(&mut policy);
+        assert_eq!(policy.allow, vec!["^cargo test$"]);
+        assert_eq!(policy.deny, vec!["^rm ", "^curl "]);
+        assert!(policy.restrict_writes);
+        assert_eq!(policy.fetch_domains, fetch_domains);
+        assert_eq!(project.policy_keys(), vec!["allow", "deny", "restrict_writes"]);
+        assert!(ProjectConfig::default().policy_keys().is_empty());
+    }
+
+    #[test]
+    fn discover_finds_the_nearest_file() {
+        let root = std::env::temp_dir().join(format!("ollama-tui-project-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let nested = root.join("crate").join("src");
+        fs::create_dir_all(&nested).unwrap();
+        assert!(discover(&nested).unwrap().is_none_or(|project| !project.path.starts_with(&root)));
+
+        fs::write(root.join(PROJECT_CONFIG_FILE), "model = \"outer\"").unwrap();
+        fs::write(root.join("crate").join(PROJECT_CONFIG_FILE), "model = \"inner\"\n[policy]\ndeny = ['^make ']").unwrap();
+        let project = discover(&nested).unwrap().unwrap();
+        assert_eq!(project.path, root.join("crate").join(PROJECT_CONFIG_FILE));
+        assert_eq!(project.model.as_deref(), Some("inner"));
+        assert_eq!(project.policy.deny, Some(vec!["^make ".to_string()]));
+        assert_eq!(discover(&root).unwrap().unwrap().model.as_deref(), Some("outer"));
+
+        fs::write(root.join(PROJECT_CONFIG_FILE), "unknown = 1").unwrap();
+        assert!(discover(&root).unwrap_err().to_string().contains("unknown field"));
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,84 @@
+/// Names of the `{{placeholders}}` in a prompt snippet, each once, in order of appearance.
+pub fn placeholders(text: &str) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("{{") {
+        let after = &rest[start + 2..];
+        let Some(end) = after.find("}}") else {
+            break;
+        };
+        let name = after[..end].trim();
+        if !name.is_empty() && !name.contains('\n') && !names.iter().any(|existing| existing == name) {
+            names.push(name.to_string());
+        }
+        rest = &after[end + 2..];
+    }
+    names
+}
+
+/// A snippet being used whose placeholders are asked for one at a time.
+#[derive(Clone, Debug)]
+pub struct PromptFill {
+    pub name: String,
+    pub text: String,
+    pub placeholders: Vec<String>,
+    pub values: Vec<String>,
+    /// Value typed for the placeholder at `values.len()`
+    pub input: String,
+}
+
+impl PromptFill {
+    pub fn new(name: &str, text: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            text: text.to_string(),
+            placeholders: placeholders(text),
+            values: Vec::new(),
+            input: String::new(),
+        }
+    }
+
+    /// Ask for the values of `names` without a snippet to fill in, e.g. workflow variables.
+    pub fn for_names(name: &str, names: Vec<String>) -> Self {
+        Self {
+            name: name.to_string(),
+            text: String::new(),
+            placeholders: names,
+            values: Vec::new(),
+            input: String::new(),
+        }
+    }
+
+    /// Placeholder whose value is being typed, `None` once all have one.
+    pub fn current(&self) -> Option<&str> {
+        self.placeholders.get(self.values.len()).map(String::as_str)
+    }
+
+    /// Accept the typed value and move to the next placeholder.
+    pub fn next(&mut self) {
+        self.values.push(std::mem::take(&mut self.input));
+    }
+
+    /// The snippet with every placeholder replaced by its value.
+    pub fn fill(&self) -> String {
+        let mut out = String::new();
+        let mut rest = self.text.as_str();
+        while let Some(start) = rest.find("{{") {
+            let after = &rest[start + 2..];
+            let Some(end) = after.find("}}") else {
+                break;
+            };
+            let name = after[..end].trim();
+            match self.placeholders.iter().position(|p| p == name).and_then(|i| self.values.get(i)) {
+                Some(value) => {
+                    out.push_str(&rest[..start]);
+                    out.push_str(value);
+                }
+                None => out.push_str(&rest[..start + 2 + end + 2]),
+            }
+            rest = &after[end + 2..];
+        }
+        out.push_str(rest);
+        out
+    }
+}
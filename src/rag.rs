@@ -0,0 +1,94 @@
+use crate::models::{Message, Role};
+
+/// Documents are split into chunks of roughly this many characters
+const CHUNK_CHARS: usize = 1200;
+/// Chunks sent with each prompt of a knowledge session
+pub const TOP_K: usize = 4;
+/// Inputs per `/api/embed` request while ingesting
+pub const EMBED_BATCH: usize = 32;
+
+/// A piece of an ingested document together with its embedding.
+#[derive(Clone, Debug)]
+pub struct Chunk {
+    pub source: String,
+    pub content: String,
+    pub embedding: Vec<f32>,
+}
+
+/// Split `text` at paragraph breaks into chunks of about `CHUNK_CHARS`, cutting
+/// paragraphs that are longer than that at line or word boundaries.
+pub fn split(text: &str) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+    for paragraph in text.split("\n\n").map(str::trim).filter(|p| !p.is_empty()) {
+        if !current.is_empty() && current.len() + paragraph.len() + 2 > CHUNK_CHARS {
+            chunks.push(std::mem::take(&mut current));
+        }
+        let mut rest = paragraph;
+        while rest.len() > CHUNK_CHARS {
+            let mut end = CHUNK_CHARS;
+            while !rest.is_char_boundary(end) {
+                end -= 1;
+            }
+            let cut = rest[..end].rfind('\n').or_else(|| rest[..end].rfind(' ')).filter(|&i| i > 0).unwrap_or(end);
+            chunks.push(rest[..cut].trim().to_string());
+            rest = rest[cut..].trim_start();
+        }
+        if !current.is_empty() {
+            current.push_str("\n\n");
+        }
+        current.push_str(rest);
+    }
+    if !current.is_empty() {
+        chunks.push(current);
+    }
+    chunks
+}
+
+fn cosine(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let denominator = norm(a) * norm(b);
+    if denominator == 0.0 { 0.0 } else { dot / denominator }
+}
+
+/// The `k` chunks most similar to `query`, best first.
+pub fn top_k<'a>(chunks: &'a [Chunk], query: &[f32], k: usize) -> Vec<&'a Chunk> {
+    let mut scored: Vec<(f32, &Chunk)> = chunks
+        .iter()
+        .filter(|chunk| chunk.embedding.len() == query.len())
+        .map(|chunk| (cosine(&chunk.embedding, query), chunk))
+        .collect();
+    scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+    scored.into_iter().take(k).map(|(_, chunk)| chunk).collect()
+}
+
+/// Put the retrieved chunks in front of the last user message of a request.
+pub fn augment(messages: &mut [Message], chunks: &[&Chunk]) {
+    if chunks.is_empty() {
+        return;
+    }
+    let Some(message) = messages.iter_mut().rev().find(|m| m.role == Role::User) else {
+        return;
+    };
+    let excerpts: Vec<String> = chunks
+        .iter()
+        .map(|chunk| format!("[{}]\n{}", chunk.source, chunk.content))
+        .collect();
+    message.content = format!(
+        "Use these excerpts from the ingested documents if they are relevant:\n\n{}\n\nQuestion: {}",
+        excerpts.join("\n\n"),
+        message.content
+    );
+}
+
+pub fn encode_embedding(embedding: &[f32]) -> Vec<u8> {
+    embedding.iter().flat_map(|x| x.to_le_bytes()).collect()
+}
+
+pub fn decode_embedding(bytes: &[u8]) -> Vec<f32> {
+    bytes
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect()
+}
@@ -0,0 +1,57 @@
+use crate::models::{Message, RedactionConfig};
+use regex::{NoExpand, Regex};
+
+// Credentials with a recognisable shape; names are shown in the placeholder
+const BUILTIN: &[(&str, &str)] = &[
+    ("private_key", r"(?s)-----BEGIN [A-Z ]*PRIVATE KEY-----.*?(?:-----END [A-Z ]*PRIVATE KEY-----|\z)"),
+    ("aws_access_key", r"\b(?:AKIA|ASIA)[0-9A-Z]{16}\b"),
+    ("aws_secret_key", r#"(?i)aws_secret_access_key["']?\s*[=:]\s*["']?[A-Za-z0-9/+=]{40}"#),
+    ("github_token", r"\b(?:gh[pousr]_[A-Za-z0-9]{36,}|github_pat_[A-Za-z0-9_]{22,})"),
+    ("openai_key", r"\bsk-(?:proj-|ant-)?[A-Za-z0-9_-]{20,}"),
+    ("google_api_key", r"\bAIza[0-9A-Za-z_-]{35}"),
+    ("slack_token", r"\bxox[abprs]-[A-Za-z0-9-]{10,}"),
+    ("bearer_token", r"(?i)\bbearer\s+[A-Za-z0-9._~+/-]{20,}=*"),
+    ("api_key", r#"(?i)\b(?:api[_-]?key|secret[_-]?key|access[_-]?token)["']?\s*[=:]\s*["']?[A-Za-z0-9_\-./+]{16,}"#),
+];
+
+/// A secret pattern, matches are replaced by `[REDACTED:<name>]`.
+#[derive(Clone, Debug)]
+pub struct Rule {
+    name: String,
+    regex: Regex,
+}
+
+/// The built-in patterns with those of `config`, none when redaction is turned off.
+pub fn rules(config: &RedactionConfig) -> Result<Vec<Rule>, String> {
+    if !config.enabled {
+        return Ok(Vec::new());
+    }
+    let builtin = BUILTIN.iter().filter(|(name, _)| !config.patterns.contains_key(*name)).map(|(name, pattern)| (*name, *pattern));
+    let mut custom: Vec<(&str, &str)> = config.patterns.iter().map(|(name, pattern)| (name.as_str(), pattern.as_str())).collect();
+    custom.sort();
+    builtin
+        .chain(custom)
+        .filter(|(_, pattern)| !pattern.is_empty())
+        .map(|(name, pattern)| {
+            let regex = Regex::new(pattern).map_err(|e| format!("redaction pattern {}: {}", name, e))?;
+            Ok(Rule { name: name.to_string(), regex })
+        })
+        .collect()
+}
+
+/// `text` with every match of `rules` replaced.
+pub fn apply(text: &str, rules: &[Rule]) -> String {
+    let mut text = text.to_string();
+    for rule in rules {
+        if rule.regex.is_match(&text) {
+            let placeholder = format!("[REDACTED:{}]", rule.name);
+            text = rule.regex.replace_all(&text, NoExpand(&placeholder)).into_owned();
+        }
+    }
+    text
+}
+
+/// Redact the content of a message about to be saved.
+pub fn message(message: &mut Message, rules: &[Rule]) {
+    message.content = apply(&message.content, rules);
+}
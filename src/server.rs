@@ -0,0 +1,241 @@
+use crate::app::AppState;
+use crate::events::{self, AppEvent};
+use crate::models::ServerConfig;
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot};
+
+// Larger requests are refused, prompts are not that long
+const MAX_BODY_BYTES: usize = 1024 * 1024;
+const MAX_HEADER_LINES: usize = 100;
+// How long a request waits for the main loop before it is answered with an error
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A request to the HTTP API, answered by the main loop, which owns the app state.
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub body: Value,
+}
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub body: Value,
+}
+
+impl Response {
+    fn ok(body: Value) -> Self {
+        Self { status: 200, body }
+    }
+
+    fn accepted(body: Value) -> Self {
+        Self { status: 202, body }
+    }
+
+    fn error(status: u16, message: impl Into<String>) -> Self {
+        Self { status, body: json!({ "error": message.into() }) }
+    }
+}
+
+/// Listen on 127.0.0.1 and hand every request to the main loop as `AppEvent::ServerRequest`.
+/// Any local process could drive the TUI without a token, so it refuses to start without one.
+pub async fn start(config: &ServerConfig, tx: mpsc::Sender<AppEvent>) -> std::io::Result<SocketAddr> {
+    if config.token.trim().is_empty() {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "set a token in the [server] section"));
+    }
+    let listener = TcpListener::bind(("127.0.0.1", config.port)).await?;
+    let addr = listener.local_addr()?;
+    let token = config.token.clone();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let tx = tx.clone();
+            let token = token.clone();
+            tokio::spawn(async move {
+                serve(stream, &token, addr.port(), tx).await.ok();
+            });
+        }
+    });
+    Ok(addr)
+}
+
+async fn serve(stream: TcpStream, token: &str, port: u16, tx: mpsc::Sender<AppEvent>) -> std::io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let response = match read_request(&mut reader, token, port).await? {
+        Ok(request) => {
+            let (reply_tx, reply_rx) = oneshot::channel();
+            if tx.send(AppEvent::ServerRequest(request, reply_tx)).await.is_err() {
+                return Ok(());
+            }
+            match tokio::time::timeout(REPLY_TIMEOUT, reply_rx).await {
+                Ok(Ok(response)) => response,
+                _ => Response::error(503, "The TUI did not answer"),
+            }
+        }
+        Err(response) => response,
+    };
+    let body = response.body.to_string();
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        reason(response.status),
+        body.len()
+    );
+    writer.write_all(head.as_bytes()).await?;
+    writer.write_all(body.as_bytes()).await?;
+    writer.shutdown().await
+}
+
+// One HTTP/1.1 request; a malformed or unauthorized one is answered right away
+async fn read_request<R: AsyncBufReadExt + Unpin>(
+    reader: &mut R,
+    token: &str,
+    port: u16,
+) -> std::io::Result<Result<Request, Response>> {
+    let mut line = String::new();
+    reader.read_line(&mut line).await?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Ok(Err(Response::error(400, "Malformed request line")));
+    };
+    let method = method.to_uppercase();
+    let path = target.split('?').next().unwrap_or(target).trim_end_matches('/').to_string();
+
+    let mut length = 0;
+    let mut authorized = false;
+    let mut local_host = false;
+    let mut json = false;
+    for _ in 0..MAX_HEADER_LINES {
+        line.clear();
+        if reader.read_line(&mut line).await? == 0 || line.trim().is_empty() {
+            break;
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        match name.trim().to_lowercase().as_str() {
+            "content-length" => length = value.parse().unwrap_or(0),
+            "authorization" => authorized |= value.strip_prefix("Bearer ").is_some_and(|given| token_matches(given, token)),
+            // A page on another site can point its own name at 127.0.0.1, but the browser
+            // still sends that name as Host
+            "host" => local_host = value == format!("127.0.0.1:{}", port) || value == format!("localhost:{}", port),
+            "content-type" => json = value.starts_with("application/json"),
+            _ => {}
+        }
+    }
+    if !local_host {
+        return Ok(Err(Response::error(403, "Host must be 127.0.0.1 or localhost")));
+    }
+    if !authorized {
+        return Ok(Err(Response::error(401, "Missing or wrong bearer token")));
+    }
+    if length > MAX_BODY_BYTES {
+        return Ok(Err(Response::error(413, "Request body too large")));
+    }
+    // A web page cannot send JSON to another origin without asking first, so this keeps
+    // pages open in a browser from posting prompts
+    if method != "GET" && !json {
+        return Ok(Err(Response::error(415, "Send the body as application/json")));
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).await?;
+    let body = if body.is_empty() {
+        Value::Null
+    } else {
+        match serde_json::from_slice(&body) {
+            Ok(body) => body,
+            Err(e) => return Ok(Err(Response::error(400, format!("Invalid JSON: {}", e)))),
+        }
+    };
+    Ok(Ok(Request { method, path, body }))
+}
+
+// Looks at every byte whatever the first difference, so response times do not give the token away
+fn token_matches(given: &str, token: &str) -> bool {
+    let (given, token) = (given.as_bytes(), token.as_bytes());
+    given.len() == token.len() && given.iter().zip(token).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        202 => "Accepted",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        415 => "Unsupported Media Type",
+        _ => "Service Unavailable",
+    }
+}
+
+/// Answer a request of the HTTP API from the main loop.
+pub fn respond(app: &mut AppState, request: Request, tx: mpsc::Sender<AppEvent>) -> Response {
+    let segments: Vec<&str> = request.path.split('/').filter(|segment| !segment.is_empty()).collect();
+    match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["sessions"]) => Response::ok(json!({ "sessions": app.sessions.iter().map(|session| json!({
+            "id": session.id,
+            "name": session.name,
+            "messages": session.messages.len(),
+            "current": session.id == app.current_session_id(),
+            "loading": app.loading_sessions.contains(&session.id),
+        })).collect::<Vec<_>>() })),
+        ("GET", ["sessions", id]) => {
+            let Some(session) = id.parse().ok().and_then(|id| app.session_index_by_id(id)).map(|index| &app.sessions[index]) else {
+                return Response::error(404, format!("No session {}", id));
+            };
+            Response::ok(json!({
+                "id": session.id,
+                "name": session.name,
+                "system_prompt": session.system_prompt,
+                "messages": session.messages.iter().map(|message| json!({
+                    "role": message.role,
+                    "content": message.content,
+                })).collect::<Vec<_>>(),
+            }))
+        }
+        ("POST", ["prompt"]) => {
+            let Some(text) = request.body.get("text").and_then(Value::as_str).filter(|text| !text.trim().is_empty()) else {
+                return Response::error(400, "The body needs a \"text\" to send");
+            };
+            let session_id = match request.body.get("session").and_then(Value::as_i64) {
+                Some(id) if app.session_index_by_id(id).is_none() => return Response::error(404, format!("No session {}", id)),
+                Some(id) => id,
+                None => app.current_session_id(),
+            };
+            // Like prompts typed while a reply streams, it waits for the session to be free
+            let queued = app.loading_sessions.contains(&session_id);
+            if queued {
+                app.prompt_queue.push_back((session_id, text.to_string()));
+            } else {
+                events::send_prompt(app, session_id, text.to_string(), tx);
+            }
+            Response::accepted(json!({ "session": session_id, "queued": queued }))
+        }
+        ("GET", ["workflows"]) => Response::ok(json!({ "workflows": app.workflows.workflows().iter().map(|workflow| json!({
+            "name": workflow.name,
+            "description": workflow.description,
+            "steps": workflow.steps.len(),
+        })).collect::<Vec<_>>() })),
+        ("POST", ["workflows", name]) => {
+            if !app.workflows.workflows().iter().any(|workflow| workflow.name == *name) {
+                return Response::error(404, format!("No workflow '{}'", name));
+            }
+            // Started once the running one finishes, with its steps approved as usual
+            let session_id = app.current_session_id();
+            app.queue_workflows(vec![name.to_string()], session_id, Vec::new());
+            events::start_queued_workflow(app, tx);
+            Response::accepted(json!({ "workflow": name, "session": session_id }))
+        }
+        (_, ["sessions", ..] | ["prompt"] | ["workflows", ..]) => Response::error(405, "Method not allowed"),
+        _ => Response::error(404, format!("No endpoint {}", request.path)),
+    }
+}
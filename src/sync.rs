@@ -0,0 +1,435 @@
+use crate::app::AppState;
+use crate::models::{ChatSession, Config, Message, Role};
+use crate::{commands, config, crypto, db};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A session as written to `<dir>/<id>.json`. Working directories and knowledge chunks
+/// belong to the machine and are not synced.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct SyncedSession {
+    id: String,
+    name: String,
+    created_at: String,
+    #[serde(default)]
+    system_prompt: Option<String>,
+    #[serde(default)]
+    messages: Vec<SyncedMessage>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct SyncedMessage {
+    role: Role,
+    content: String,
+    #[serde(default)]
+    created_at: Option<String>,
+    #[serde(default)]
+    pinned: bool,
+}
+
+/// What a sync changed, on this machine and in the directory.
+#[derive(Default, Debug)]
+pub struct Report {
+    pub written: usize,          // Files written from sessions changed here
+    pub imported: usize,         // Sessions added from new files
+    pub updated: usize,          // Sessions replaced by files changed elsewhere
+    pub deleted: usize,          // Sessions deleted elsewhere, or files of sessions deleted here
+    pub conflicts: Vec<String>,  // Sessions changed on both sides, the other version kept as a copy
+    pub unreadable: Vec<String>, // Files that are not sessions
+}
+
+impl Report {
+    /// Whether sessions in the database changed, so the ones in memory are out of date.
+    pub fn changed_sessions(&self) -> bool {
+        self.imported + self.updated + self.deleted > 0 || !self.conflicts.is_empty()
+    }
+
+    pub fn summary(&self, dir: &Path) -> String {
+        let counts = [(self.written, "written"), (self.imported, "imported"), (self.updated, "updated"), (self.deleted, "deleted")];
+        let mut parts: Vec<String> =
+            counts.iter().filter(|(count, _)| *count > 0).map(|(count, what)| format!("{} {}", count, what)).collect();
+        if !self.conflicts.is_empty() {
+            parts.push(format!("{} conflict(s) kept as copies: {}", self.conflicts.len(), self.conflicts.join(", ")));
+        }
+        if !self.unreadable.is_empty() {
+            parts.push(format!("skipped {}", self.unreadable.join(", ")));
+        }
+        if parts.is_empty() {
+            return format!("Sessions already in sync with {}", dir.display());
+        }
+        format!("Synced with {}: {}", dir.display(), parts.join(", "))
+    }
+}
+
+// FNV-1a, stable across machines and Rust versions unlike the std hasher
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+fn hash(session: &SyncedSession) -> String {
+    format!("{:016x}", fnv1a(serde_json::to_string(session).unwrap_or_default().as_bytes()))
+}
+
+fn parse_time(time: &str) -> DateTime<Utc> {
+    DateTime::parse_from_rfc3339(time).map(|dt| dt.with_timezone(&Utc)).unwrap_or_else(|_| Utc::now())
+}
+
+// The creation time keeps the files in order, the rest tells sessions of several machines apart
+fn new_sync_id(session_id: i64, created_at: DateTime<Utc>) -> String {
+    let seed = format!("{} {} {:?}", session_id, std::process::id(), Utc::now().timestamp_nanos_opt());
+    format!("{}-{:08x}", created_at.format("%Y%m%d-%H%M%S"), fnv1a(seed.as_bytes()) as u32)
+}
+
+// Whether `longer` continues the conversation of `shorter`
+fn extends(longer: &SyncedSession, shorter: &SyncedSession) -> bool {
+    longer.messages.starts_with(&shorter.messages)
+}
+
+fn snapshot(conn: &Connection, session: &ChatSession, sync_id: &str) -> Result<SyncedSession> {
+    let times = db::load_message_times(conn, session.id)?;
+    Ok(SyncedSession {
+        id: sync_id.to_string(),
+        name: session.name.clone(),
+        created_at: session.created_at.to_rfc3339(),
+        system_prompt: session.system_prompt.clone(),
+        messages: session
+            .messages
+            .iter()
+            .zip(times.into_iter().chain(std::iter::repeat(None)))
+            .map(|(message, created_at)| SyncedMessage {
+                role: message.role.clone(),
+                content: message.content.clone(),
+                created_at,
+                pinned: message.pinned,
+            })
+            .collect(),
+    })
+}
+
+fn file_path(dir: &Path, sync_id: &str) -> PathBuf {
+    dir.join(format!("{}.json", sync_id))
+}
+
+fn read_dir(dir: &Path, report: &mut Report) -> Result<HashMap<String, SyncedSession>> {
+    let mut sessions = HashMap::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("json") {
+            continue;
+        }
+        let Some(sync_id) = path.file_stem().map(|stem| stem.to_string_lossy().to_string()) else {
+            continue;
+        };
+        let parsed = fs::read_to_string(&path).ok().and_then(|text| serde_json::from_str::<SyncedSession>(&text).ok());
+        match parsed {
+            // The file name is the id, so a renamed copy is a session of its own
+            Some(session) => sessions.insert(sync_id.clone(), SyncedSession { id: sync_id, ..session }),
+            None => {
+                report.unreadable.push(format!("{}.json", sync_id));
+                continue;
+            }
+        };
+    }
+    Ok(sessions)
+}
+
+// Written next to the file and renamed, so Syncthing or git never see half of it
+fn write_file(dir: &Path, session: &SyncedSession) -> Result<()> {
+    let path = file_path(dir, &session.id);
+    let temp = dir.join(format!(".{}.json.tmp", session.id));
+    fs::write(&temp, format!("{}\n", serde_json::to_string_pretty(session)?))?;
+    fs::rename(&temp, &path)?;
+    Ok(())
+}
+
+fn save_messages(conn: &Connection, session_id: i64, synced: &SyncedSession) -> Result<()> {
+    for message in &synced.messages {
+        let created_at = message.created_at.clone().unwrap_or_else(|| synced.created_at.clone());
+        let id = db::save_message_at(conn, session_id, &Message::new(message.role.clone(), message.content.clone()), &created_at)?;
+        if message.pinned {
+            db::set_message_pinned(conn, id, true)?;
+        }
+    }
+    Ok(())
+}
+
+fn import(conn: &Connection, synced: &SyncedSession) -> Result<i64> {
+    let created_at = parse_time(&synced.created_at);
+    let mut session = ChatSession {
+        id: 0,
+        name: synced.name.clone(),
+        messages: Vec::new(),
+        created_at,
+        system_prompt: synced.system_prompt.clone(),
+        last_activity: created_at,
+        working_dir: None,
+        knowledge: false,
+        options: HashMap::new(),
+    };
+    db::save_session(conn, &mut session)?;
+    save_messages(conn, session.id, synced)?;
+    Ok(session.id)
+}
+
+fn replace(conn: &Connection, session_id: i64, synced: &SyncedSession) -> Result<()> {
+    db::rename_session(conn, session_id, &synced.name)?;
+    db::set_session_system_prompt(conn, session_id, synced.system_prompt.as_deref())?;
+    db::clear_messages_for_session(conn, session_id)?;
+    save_messages(conn, session_id, synced)
+}
+
+/// The `sync_dir` setting, `None` when it is not set.
+pub fn default_dir(config: &Config) -> Option<PathBuf> {
+    Some(config.sync_dir.trim()).filter(|dir| !dir.is_empty()).map(commands::expand_tilde)
+}
+
+/// Merge the sessions in the database with the directory of session files `dir`.
+///
+/// Each side is compared with the version of the last sync with `dir`: a session changed
+/// on one side only takes that side's version, deletions included. When both changed and
+/// one conversation continues the other, the longer one wins; otherwise the version here
+/// is kept and the other one is added as a separate "(conflict)" session.
+///
+/// The database changes in one transaction, so a failed sync leaves it as it was.
+pub fn sync(conn: &Connection, dir: &Path) -> Result<Report> {
+    if crypto::enabled() {
+        return Err(anyhow!("Session files are plain JSON, turn encryption off to sync"));
+    }
+    let tx = conn.unchecked_transaction()?;
+    let report = merge(&tx, dir)?;
+    tx.commit()?;
+    Ok(report)
+}
+
+fn merge(conn: &Connection, dir: &Path) -> Result<Report> {
+    fs::create_dir_all(dir).map_err(|e| anyhow!("Cannot create {}: {}", dir.display(), e))?;
+    let dir = dir.canonicalize()?;
+    let key = dir.to_string_lossy().to_string();
+    let mut report = Report::default();
+    let mut files = read_dir(&dir, &mut report)?;
+    let mut sessions: HashMap<i64, ChatSession> = db::load_sessions(conn)?.into_iter().map(|s| (s.id, s)).collect();
+
+    for (sync_id, session_id, base) in db::load_sync_state(conn, &key)? {
+        let ours = sessions.remove(&session_id).map(|session| snapshot(conn, &session, &sync_id)).transpose()?;
+        let theirs = files.remove(&sync_id);
+        match (ours, theirs) {
+            (Some(ours), Some(theirs)) => {
+                let (our_hash, their_hash) = (hash(&ours), hash(&theirs));
+                let ours_changed = our_hash != base;
+                let theirs_changed = their_hash != base;
+                if our_hash == their_hash {
+                    db::save_sync_state(conn, &key, &sync_id, session_id, &our_hash)?;
+                } else if !theirs_changed || (ours_changed && extends(&ours, &theirs)) {
+                    write_file(&dir, &ours)?;
+                    db::save_sync_state(conn, &key, &sync_id, session_id, &our_hash)?;
+                    report.written += 1;
+                } else if !ours_changed || extends(&theirs, &ours) {
+                    replace(conn, session_id, &theirs)?;
+                    db::save_sync_state(conn, &key, &sync_id, session_id, &their_hash)?;
+                    report.updated += 1;
+                } else {
+                    write_file(&dir, &ours)?;
+                    db::save_sync_state(conn, &key, &sync_id, session_id, &our_hash)?;
+                    let mut copy = SyncedSession { name: format!("{} (conflict)", theirs.name), ..theirs };
+                    let copy_id = import(conn, &copy)?;
+                    copy.id = new_sync_id(copy_id, parse_time(&copy.created_at));
+                    write_file(&dir, &copy)?;
+                    db::save_sync_state(conn, &key, &copy.id, copy_id, &hash(&copy))?;
+                    report.conflicts.push(ours.name);
+                }
+            }
+            (Some(ours), None) => {
+                if hash(&ours) == base {
+                    db::delete_session(conn, session_id)?;
+                    db::delete_sync_state(conn, &key, &sync_id)?;
+                    report.deleted += 1;
+                } else {
+                    // Changed here since the file was deleted, so it comes back
+                    write_file(&dir, &ours)?;
+                    db::save_sync_state(conn, &key, &sync_id, session_id, &hash(&ours))?;
+                    report.written += 1;
+                }
+            }
+            (None, Some(theirs)) => {
+                let their_hash = hash(&theirs);
+                if their_hash == base {
+                    fs::remove_file(file_path(&dir, &sync_id))?;
+                    db::delete_sync_state(conn, &key, &sync_id)?;
+                    report.deleted += 1;
+                } else {
+                    let id = import(conn, &theirs)?;
+                    db::save_sync_state(conn, &key, &sync_id, id, &their_hash)?;
+                    report.imported += 1;
+                }
+            }
+            (None, None) => db::delete_sync_state(conn, &key, &sync_id)?,
+        }
+    }
+
+    // Sessions new here; empty ones wait until they have messages
+    let mut new_sessions: Vec<ChatSession> = sessions.into_values().filter(|s| !s.messages.is_empty()).collect();
+    new_sessions.sort_by_key(|session| session.created_at);
+    for session in new_sessions {
+        let synced = snapshot(conn, &session, &new_sync_id(session.id, session.created_at))?;
+        write_file(&dir, &synced)?;
+        db::save_sync_state(conn, &key, &synced.id, session.id, &hash(&synced))?;
+        report.written += 1;
+    }
+    let mut new_files: Vec<SyncedSession> = files.into_values().collect();
+    new_files.sort_by(|a, b| a.created_at.cmp(&b.created_at));
+    for synced in new_files {
+        let id = import(conn, &synced)?;
+        db::save_sync_state(conn, &key, &synced.id, id, &hash(&synced))?;
+        report.imported += 1;
+    }
+    Ok(report)
+}
+
+/// `ollama-tui sync [dir]`: the same as `:sync`, for cron jobs and git hooks.
+pub fn run_cli(dir: Option<&Path>) -> Result<()> {
+    let app = AppState::load(config::load_or_create()?)?;
+    let dir = dir
+        .map(Path::to_path_buf)
+        .or_else(|| default_dir(&app.config))
+        .ok_or_else(|| anyhow!("Pass the directory to sync with, or set sync_dir in config.toml"))?;
+    let report = sync(&app.db_conn, &dir)?;
+    println!("{}", report.summary(&dir));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A machine syncing with the directory of the test
+    fn machine() -> Connection {
+        db::get_connection(Path::new(":memory:")).unwrap()
+    }
+
+    fn sync_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ollama-tui-sync-{}-{}", std::process::id(), name));
+        fs::remove_dir_all(&dir).ok();
+        dir
+    }
+
+    fn add_session(conn: &Connection, name: &str, contents: &[&str]) -> i64 {
+        let now = Utc::now();
+        let mut session = ChatSession {
+            id: 0,
+            name: name.to_string(),
+            messages: Vec::new(),
+            created_at: now,
+            system_prompt: None,
+            last_activity: now,
+            working_dir: None,
+            knowledge: false,
+            options: HashMap::new(),
+        };
+        db::save_session(conn, &mut session).unwrap();
+        add_messages(conn, session.id, contents);
+        session.id
+    }
+
+    fn add_messages(conn: &Connection, session_id: i64, contents: &[&str]) {
+        for content in contents {
+            db::save_message(conn, session_id, &Message::new(Role::User, content.to_string())).unwrap();
+        }
+    }
+
+    // Name and message contents of every session, by name
+    fn sessions(conn: &Connection) -> Vec<(String, Vec<String>)> {
+        let mut sessions: Vec<(String, Vec<String>)> = db::load_sessions(conn)
+            .unwrap()
+            .into_iter()
+            .map(|session| (session.name, session.messages.into_iter().map(|message| message.content).collect()))
+            .collect();
+        sessions.sort();
+        sessions
+    }
+
+    fn session(name: &str, contents: &[&str]) -> (String, Vec<String>) {
+        (name.to_string(), contents.iter().map(|content| content.to_string()).collect())
+    }
+
+    #[test]
+    fn changes_of_one_side_are_taken_over() {
+        let dir = sync_dir("one-side");
+        let (here, there) = (machine(), machine());
+        let id = add_session(&here, "notes", &["a"]);
+        assert_eq!(sync(&here, &dir).unwrap().written, 1);
+        assert_eq!(sync(&there, &dir).unwrap().imported, 1);
+
+        add_messages(&here, id, &["b"]);
+        assert_eq!(sync(&here, &dir).unwrap().written, 1);
+        let report = sync(&there, &dir).unwrap();
+        assert_eq!(report.updated, 1);
+        assert_eq!(sessions(&there), [session("notes", &["a", "b"])]);
+        assert!(!sync(&here, &dir).unwrap().changed_sessions());
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn the_longer_conversation_wins_when_both_changed() {
+        let dir = sync_dir("both-sides");
+        let (here, there) = (machine(), machine());
+        let id = add_session(&here, "notes", &["a"]);
+        sync(&here, &dir).unwrap();
+        sync(&there, &dir).unwrap();
+
+        let there_id = db::load_sessions(&there).unwrap()[0].id;
+        add_messages(&there, there_id, &["b", "c"]);
+        sync(&there, &dir).unwrap();
+        db::rename_session(&here, id, "renamed").unwrap();
+        let report = sync(&here, &dir).unwrap();
+        assert_eq!((report.updated, report.conflicts.len()), (1, 0));
+        assert_eq!(sessions(&here), [session("notes", &["a", "b", "c"])]);
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn deletions_reach_the_other_side() {
+        let dir = sync_dir("deletions");
+        let (here, there) = (machine(), machine());
+        let id = add_session(&here, "notes", &["a"]);
+        sync(&here, &dir).unwrap();
+        sync(&there, &dir).unwrap();
+
+        db::delete_session(&here, id).unwrap();
+        assert_eq!(sync(&here, &dir).unwrap().deleted, 1);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        assert_eq!(sync(&there, &dir).unwrap().deleted, 1);
+        assert!(sessions(&there).is_empty());
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn diverged_sessions_keep_the_other_version_as_a_copy() {
+        let dir = sync_dir("conflict");
+        let (here, there) = (machine(), machine());
+        let id = add_session(&here, "notes", &["a"]);
+        sync(&here, &dir).unwrap();
+        sync(&there, &dir).unwrap();
+
+        let there_id = db::load_sessions(&there).unwrap()[0].id;
+        add_messages(&there, there_id, &["from there"]);
+        sync(&there, &dir).unwrap();
+        add_messages(&here, id, &["from here"]);
+        let report = sync(&here, &dir).unwrap();
+        assert_eq!(report.conflicts, ["notes"]);
+        assert_eq!(
+            sessions(&here),
+            [session("notes", &["a", "from here"]), session("notes (conflict)", &["a", "from there"])]
+        );
+
+        // The other machine gets this version back, and the copy as a session of its own
+        let report = sync(&there, &dir).unwrap();
+        assert_eq!((report.updated, report.imported), (1, 1));
+        assert_eq!(sessions(&there), sessions(&here));
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,87 @@
+use chrono::{DateTime, Local};
+use std::time::{Duration, Instant};
+
+// Toasts kept for `:messages`
+const HISTORY_LIMIT: usize = 200;
+// At most this many toasts are shown at once, the oldest make way
+const VISIBLE_LIMIT: usize = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+impl Severity {
+    pub fn label(self) -> &'static str {
+        match self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        }
+    }
+
+    // Errors stay up longer, they are the ones worth reading
+    fn ttl(self) -> Duration {
+        match self {
+            Severity::Info => Duration::from_secs(4),
+            Severity::Warning => Duration::from_secs(6),
+            Severity::Error => Duration::from_secs(10),
+        }
+    }
+}
+
+/// A short notice shown over the chat pane, such as a failed request.
+#[derive(Clone, Debug)]
+pub struct Toast {
+    pub severity: Severity,
+    pub text: String,
+    pub time: DateTime<Local>,
+    shown: Instant,
+}
+
+/// The toasts on screen and every toast of this run, newest last.
+#[derive(Debug, Default)]
+pub struct Toasts {
+    pub visible: Vec<Toast>,
+    pub history: Vec<Toast>,
+}
+
+impl Toasts {
+    pub fn push(&mut self, severity: Severity, text: String) {
+        let toast = Toast { severity, text, time: Local::now(), shown: Instant::now() };
+        self.history.push(toast.clone());
+        if self.history.len() > HISTORY_LIMIT {
+            self.history.remove(0);
+        }
+        self.visible.push(toast);
+        if self.visible.len() > VISIBLE_LIMIT {
+            self.visible.remove(0);
+        }
+    }
+
+    /// Drop the toasts that have been shown long enough; called on every tick.
+    /// Drop the toasts shown long enough, returns whether any was dropped.
+    pub fn expire(&mut self) -> bool {
+        let before = self.visible.len();
+        self.visible.retain(|toast| toast.shown.elapsed() < toast.severity.ttl());
+        self.visible.len() != before
+    }
+
+    pub fn dismiss(&mut self) {
+        self.visible.clear();
+    }
+
+    /// Lines of the `:messages` popup, newest first.
+    pub fn history_lines(&self) -> Vec<String> {
+        if self.history.is_empty() {
+            return vec!["No messages yet.".to_string()];
+        }
+        self.history
+            .iter()
+            .rev()
+            .map(|toast| format!("{} [{}] {}", toast.time.format("%H:%M:%S"), toast.severity.label(), toast.text))
+            .collect()
+    }
+}
@@ -0,0 +1,429 @@
+use crate::agent::{self, CommandOutput, RiskLevel};
+use crate::models::{Config, PluginConfig, SearchConfig};
+use crate::{diff, web};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+
+/// A structured tool the autonomous agent can call instead of a shell command.
+pub struct ToolSpec {
+    pub name: &'static str,
+    /// Argument object as shown to the model
+    pub arguments: &'static str,
+    pub description: &'static str,
+    /// Changes files, so the change is always shown for approval first
+    pub writes: bool,
+    pub risk: RiskLevel,
+}
+
+pub const TOOLS: &[ToolSpec] = &[
+    ToolSpec {
+        name: "read_file",
+        arguments: r#"{"path": string}"#,
+        description: "read a text file",
+        writes: false,
+        risk: RiskLevel::Safe,
+    },
+    ToolSpec {
+        name: "write_file",
+        arguments: r#"{"path": string, "content": string}"#,
+        description: "create or overwrite a file with the complete new content",
+        writes: true,
+        risk: RiskLevel::Low,
+    },
+    ToolSpec {
+        name: "apply_patch",
+        arguments: r#"{"path": string, "patch": string}"#,
+        description: "change an existing file with a unified diff",
+        writes: true,
+        risk: RiskLevel::Low,
+    },
+    ToolSpec {
+        name: "git_status",
+        arguments: "{}",
+        description: "show the branch and changed files of the git repository",
+        writes: false,
+        risk: RiskLevel::Safe,
+    },
+    ToolSpec {
+        name: "git_diff",
+        arguments: r#"{"path": string or null, "staged": bool}"#,
+        description: "show unstaged changes, or staged ones with staged set, optionally of one path",
+        writes: false,
+        risk: RiskLevel::Safe,
+    },
+    ToolSpec {
+        name: "git_commit",
+        arguments: r#"{"message": string, "files": [string]}"#,
+        description: "stage the files and commit them with the message, the user confirms both",
+        writes: true,
+        risk: RiskLevel::Low,
+    },
+    ToolSpec {
+        name: "fetch_url",
+        arguments: r#"{"url": string}"#,
+        description: "download a web page as readable text, only from the domains the user allows",
+        writes: false,
+        risk: RiskLevel::Moderate,
+    },
+    ToolSpec {
+        name: "web_search",
+        arguments: r#"{"query": string}"#,
+        description: "search the web, returning titles, links and snippets to follow up with fetch_url",
+        writes: false,
+        risk: RiskLevel::Moderate,
+    },
+];
+
+/// One tool call proposed by the model.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ToolCall {
+    pub name: String,
+    #[serde(default)]
+    pub arguments: Value,
+}
+
+impl ToolCall {
+    pub fn spec(&self) -> Option<&'static ToolSpec> {
+        TOOLS.iter().find(|spec| spec.name == self.name)
+    }
+
+    pub fn writes(&self) -> bool {
+        self.spec().is_none_or(|spec| spec.writes)
+    }
+
+    /// File the tool works on, if it takes one.
+    pub fn path(&self) -> Option<&str> {
+        self.arguments.get("path").and_then(Value::as_str)
+    }
+
+    pub fn url(&self) -> Option<&str> {
+        self.arguments.get("url").and_then(Value::as_str)
+    }
+
+    /// Short form for the progress panel and the audit log, e.g. `write_file src/main.rs`.
+    pub fn summary(&self) -> String {
+        let message = self.arguments.get("message").and_then(Value::as_str);
+        let query = self.arguments.get("query").and_then(Value::as_str);
+        match (self.path().or(self.url()).or(query), message) {
+            (Some(target), _) => format!("{} {}", self.name, target),
+            (None, Some(message)) => format!("{} \"{}\"", self.name, message.lines().next().unwrap_or_default()),
+            (None, None) => self.name.clone(),
+        }
+    }
+
+    pub fn risk(&self) -> RiskLevel {
+        self.spec().map(|spec| spec.risk).unwrap_or(RiskLevel::High)
+    }
+
+    fn arg(&self, key: &str) -> Result<&str, String> {
+        self.arguments
+            .get(key)
+            .and_then(Value::as_str)
+            .ok_or_else(|| format!("{} needs a \"{}\" argument", self.name, key))
+    }
+}
+
+/// Tools offered to the model; web search needs a configured provider.
+pub fn available(config: &Config) -> Vec<&'static ToolSpec> {
+    TOOLS
+        .iter()
+        .filter(|spec| spec.name != "web_search" || config.search.is_some())
+        .collect()
+}
+
+// Plugins named like a built-in tool are ignored
+fn plugins(config: &Config) -> impl Iterator<Item = &PluginConfig> {
+    config.plugins.iter().filter(|plugin| !TOOLS.iter().any(|spec| spec.name == plugin.name))
+}
+
+/// Tool list for the reasoning prompt.
+pub fn describe(config: &Config) -> String {
+    available(config)
+        .iter()
+        .map(|spec| format!("{} {}: {}", spec.name, spec.arguments, spec.description))
+        .chain(plugins(config).map(|plugin| format!("{} {}: {}", plugin.name, plugin.arguments, plugin.description)))
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+pub fn names(config: &Config) -> Vec<String> {
+    available(config)
+        .iter()
+        .map(|spec| spec.name.to_string())
+        .chain(plugins(config).map(|plugin| plugin.name.clone()))
+        .collect()
+}
+
+/// What a writing tool is about to change on disk.
+#[derive(Clone, Debug)]
+pub struct FileChange {
+    pub path: PathBuf,
+    pub diff: String,
+    content: String,
+}
+
+/// Work out the change a writing tool would make, without touching the disk.
+/// Returns `None` for tools that only read.
+pub fn prepare_change(call: &ToolCall, cwd: &Path) -> Result<Option<FileChange>, String> {
+    let content = match call.name.as_str() {
+        "write_file" => call.arg("content")?.to_string(),
+        "apply_patch" => {
+            let path = cwd.join(call.arg("path")?);
+            let original = fs::read_to_string(&path).map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+            diff::apply(&original, call.arg("patch")?).map_err(|e| format!("Cannot apply the patch: {}", e))?
+        }
+        _ => return Ok(None),
+    };
+    let display_path = call.arg("path")?;
+    let path = cwd.join(display_path);
+    let original = fs::read_to_string(&path).unwrap_or_default();
+    Ok(Some(FileChange {
+        diff: diff::unified(&original, &content, display_path),
+        path,
+        content,
+    }))
+}
+
+/// A commit waiting for approval, with the files the user can pick from.
+#[derive(Clone, Debug)]
+pub struct CommitPreview {
+    pub message: String,
+    pub files: Vec<CommitFile>,
+}
+
+#[derive(Clone, Debug)]
+pub struct CommitFile {
+    /// Two-letter status from `git status --porcelain`
+    pub status: String,
+    pub path: String,
+    pub selected: bool,
+}
+
+/// Changed files for a `git_commit` call, preselecting the ones the model asked
+/// for, otherwise what is already staged, otherwise everything.
+pub fn prepare_commit(call: &ToolCall, cwd: &Path) -> Result<Option<CommitPreview>, String> {
+    if call.name != "git_commit" {
+        return Ok(None);
+    }
+    let message = call.arg("message")?.trim().to_string();
+    if message.is_empty() {
+        return Err("git_commit needs a non-empty message".to_string());
+    }
+    let output = std::process::Command::new("git")
+        .args(["status", "--porcelain"])
+        .current_dir(cwd)
+        .output()
+        .map_err(|e| format!("Cannot run git: {}", e))?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+    let requested = string_list(&call.arguments, "files");
+    let mut files: Vec<CommitFile> = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter(|line| line.len() > 3)
+        .map(|line| {
+            // Renames are listed as `old -> new`
+            let path = line[3..].rsplit(" -> ").next().unwrap_or_default().trim_matches('"').to_string();
+            let status = line[..2].to_string();
+            let selected = if requested.is_empty() {
+                !status.starts_with(' ') && !status.starts_with('?')
+            } else {
+                requested.iter().any(|file| file.trim_start_matches("./") == path)
+            };
+            CommitFile { status, path, selected }
+        })
+        .collect();
+    if files.is_empty() {
+        return Err("Nothing to commit, the working tree is clean".to_string());
+    }
+    if !files.iter().any(|file| file.selected) {
+        files.iter_mut().for_each(|file| file.selected = true);
+    }
+    Ok(Some(CommitPreview { message, files }))
+}
+
+impl CommitPreview {
+    /// The tool call with the files the user picked, ready to run.
+    pub fn apply_to(&self, call: &mut ToolCall) {
+        let files: Vec<&str> = self.files.iter().filter(|file| file.selected).map(|file| file.path.as_str()).collect();
+        call.arguments["files"] = serde_json::json!(files);
+    }
+}
+
+fn string_list(arguments: &Value, key: &str) -> Vec<String> {
+    arguments
+        .get(key)
+        .and_then(Value::as_array)
+        .map(|items| items.iter().filter_map(Value::as_str).map(String::from).collect())
+        .unwrap_or_default()
+}
+
+/// What a tool call runs with, taken from the app when the call starts.
+pub struct ToolContext {
+    pub cwd: PathBuf,
+    /// Writing tools only report what they would change
+    pub dry_run: bool,
+    pub fetch_domains: Vec<String>,
+    pub search: Option<SearchConfig>,
+    pub plugins: Vec<PluginConfig>,
+    /// Environment added to plugin processes, as for commands
+    pub env: HashMap<String, String>,
+    pub timeout: Option<Duration>,
+}
+
+pub async fn execute(call: &ToolCall, context: &ToolContext) -> CommandOutput {
+    let (cwd, dry_run) = (context.cwd.as_path(), context.dry_run);
+    let result = match call.name.as_str() {
+        "read_file" => read_file(call, cwd),
+        "write_file" | "apply_patch" => write_change(call, cwd, dry_run),
+        "git_status" => git(cwd, &["status", "--short", "--branch"]).await,
+        "git_diff" => git_diff(call, cwd).await,
+        "git_commit" => git_commit(call, cwd, dry_run).await,
+        "fetch_url" => match call.arg("url") {
+            Ok(url) => web::fetch_readable(url, &context.fetch_domains).await.map(|text| agent::truncate(&text)),
+            Err(e) => Err(e),
+        },
+        "web_search" => match (&context.search, call.arg("query")) {
+            (Some(search), Ok(query)) => web::search(search, query).await,
+            (None, _) => Err("Web search is not configured".to_string()),
+            (_, Err(e)) => Err(e),
+        },
+        name => match context.plugins.iter().find(|plugin| plugin.name == name) {
+            Some(_) if dry_run => Ok(format!("[dry run] plugin {} was not run", name)),
+            Some(plugin) => run_plugin(plugin, call, context).await,
+            None => Err(format!("Unknown tool '{}'", name)),
+        },
+    };
+    CommandOutput { exit_code: None, result }
+}
+
+async fn run_plugin(plugin: &PluginConfig, call: &ToolCall, context: &ToolContext) -> Result<String, String> {
+    let mut child = tokio::process::Command::new(&plugin.command)
+        .args(&plugin.args)
+        .current_dir(&context.cwd)
+        .envs(&context.env)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| format!("Cannot start plugin {}: {}", plugin.name, e))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        let input = call.arguments.to_string();
+        stdin
+            .write_all(input.as_bytes())
+            .await
+            .map_err(|e| format!("Cannot send arguments to plugin {}: {}", plugin.name, e))?;
+    }
+    let output = match context.timeout {
+        Some(timeout) => tokio::time::timeout(timeout, child.wait_with_output())
+            .await
+            .map_err(|_| format!("Plugin {} timed out after {}s", plugin.name, timeout.as_secs()))?,
+        None => child.wait_with_output().await,
+    }
+    .map_err(|e| format!("Plugin {} failed: {}", plugin.name, e))?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("Plugin {} exited with {}\n{}{}", plugin.name, output.status, stdout, stderr));
+    }
+    let reply: Value = serde_json::from_str(stdout.trim())
+        .map_err(|e| format!("Plugin {} did not reply with JSON: {}", plugin.name, e))?;
+    let text = |value: &Value| match value {
+        Value::String(text) => text.clone(),
+        other => other.to_string(),
+    };
+    match (reply.get("error"), reply.get("output")) {
+        (Some(error), _) if !error.is_null() => Err(text(error)),
+        (_, Some(output)) => Ok(agent::truncate(&text(output))),
+        _ => Err(format!("Plugin {} replied without \"output\" or \"error\"", plugin.name)),
+    }
+}
+
+async fn git(cwd: &Path, args: &[&str]) -> Result<String, String> {
+    let output = tokio::process::Command::new("git")
+        .args(args)
+        .current_dir(cwd)
+        .output()
+        .await
+        .map_err(|e| format!("Cannot run git: {}", e))?;
+    let mut text = String::from_utf8_lossy(&output.stdout).to_string();
+    text.push_str(&String::from_utf8_lossy(&output.stderr));
+    let text = agent::truncate(text.trim_end());
+    if output.status.success() {
+        Ok(if text.is_empty() { "(no output)".to_string() } else { text })
+    } else {
+        Err(text)
+    }
+}
+
+async fn git_diff(call: &ToolCall, cwd: &Path) -> Result<String, String> {
+    let mut args = vec!["diff"];
+    if call.arguments.get("staged").and_then(Value::as_bool).unwrap_or(false) {
+        args.push("--staged");
+    }
+    if let Some(path) = call.path() {
+        args.extend(["--", path]);
+    }
+    git(cwd, &args).await
+}
+
+async fn git_commit(call: &ToolCall, cwd: &Path, dry_run: bool) -> Result<String, String> {
+    let message = call.arg("message")?;
+    let files = string_list(&call.arguments, "files");
+    if files.is_empty() {
+        return Err("git_commit needs at least one file".to_string());
+    }
+    if dry_run {
+        return Ok(format!("[dry run] {} files were not committed", files.len()));
+    }
+    let mut add = vec!["add", "--"];
+    add.extend(files.iter().map(String::as_str));
+    git(cwd, &add).await?;
+    // Passing the paths commits exactly these files, whatever else is staged
+    let mut commit = vec!["commit", "-m", message, "--"];
+    commit.extend(files.iter().map(String::as_str));
+    git(cwd, &commit).await
+}
+
+fn read_file(call: &ToolCall, cwd: &Path) -> Result<String, String> {
+    let path = cwd.join(call.arg("path")?);
+    fs::read_to_string(&path)
+        .map(|content| agent::truncate(&content))
+        .map_err(|e| format!("Cannot read {}: {}", path.display(), e))
+}
+
+fn write_change(call: &ToolCall, cwd: &Path, dry_run: bool) -> Result<String, String> {
+    let Some(change) = prepare_change(call, cwd)? else {
+        return Err(format!("{} does not change files", call.name));
+    };
+    let (added, removed) = change_stats(&change.diff);
+    let summary = format!("{} (+{} -{})", change.path.display(), added, removed);
+    if dry_run {
+        return Ok(format!("[dry run] {} was not written", summary));
+    }
+    if let Some(parent) = change.path.parent() {
+        fs::create_dir_all(parent).map_err(|e| format!("Cannot create {}: {}", parent.display(), e))?;
+    }
+    fs::write(&change.path, &change.content).map_err(|e| format!("Cannot write {}: {}", change.path.display(), e))?;
+    Ok(format!("Wrote {}", summary))
+}
+
+/// Added and removed line counts of a unified diff from `diff::unified`.
+pub fn change_stats(diff: &str) -> (usize, usize) {
+    // Skip the `---`/`+++` file headers
+    diff.lines()
+        .skip(2)
+        .fold((0, 0), |(added, removed), line| match line.chars().next() {
+            Some('+') => (added + 1, removed),
+            Some('-') => (added, removed + 1),
+            _ => (added, removed),
+        })
+}
@@ -1,12 +1,16 @@
 use crate::{
-    app::{AppMode, AppState},
-    models,
+    agent,
+    app::{AppMode, AppState, ChatFilter, SplitDirection},
+    attach,
+    commands, help, image, keymap, layout::{LayoutCache, MessageLayout}, markdown, models, policy, prompts, toast, tools, workflow,
 };
 use ratatui::{
     prelude::*,
-    widgets::{Block, Borders, Clear, List, ListItem, Paragraph, Wrap},
+    widgets::{Block, Borders, Clear, List, ListItem, ListState, Paragraph, Wrap},
     layout::Rect,
 };
+use std::ops::Range;
+use std::rc::Rc;
 use textwrap::wrap;
 use unicode_width::UnicodeWidthStr;
 
@@ -36,6 +40,37 @@ pub fn ui(f: &mut Frame, app: &mut AppState) {
         .constraints([Constraint::Min(0), Constraint::Length(3), Constraint::Length(1)].as_ref())
         .split(main_chunks[0]);
 
+    // The autonomous agent reports its progress in a panel beside the chat
+    let show_agent_panel = app
+        .autonomous
+        .as_ref()
+        .is_some_and(|run| app.mode == AppMode::Autonomous || !run.state.is_finished());
+    let (chat_area, agent_panel_area) = if show_agent_panel {
+        let chunks = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Percentage(55), Constraint::Percentage(45)])
+            .split(left_chunks[0]);
+        (chunks[0], Some(chunks[1]))
+    } else {
+        (left_chunks[0], None)
+    };
+
+    // With :split or :vsplit the chat area is shared with a second session
+    let (chat_area, split_area) = match app.split_view() {
+        Some(split) => {
+            let direction = match split.direction {
+                SplitDirection::Horizontal => Direction::Vertical,
+                SplitDirection::Vertical => Direction::Horizontal,
+            };
+            let chunks = Layout::default()
+                .direction(direction)
+                .constraints([Constraint::Percentage(50), Constraint::Percentage(50)])
+                .split(chat_area);
+            if split.current_first { (chunks[0], Some(chunks[1])) } else { (chunks[1], Some(chunks[0])) }
+        }
+        None => (chat_area, None),
+    };
+
     let chat_border_style = Style::default().fg(app.config.theme.parse_color(&app.config.theme.chat_border_color));
     let sessions_border_style = if app.mode == AppMode::SessionSelection {
         Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD)
@@ -53,147 +88,1653 @@ pub fn ui(f: &mut Frame, app: &mut AppState) {
             app.sessions.len())
     };
 
-    // Create the list items first, before borrowing app mutably
-    let messages = app.current_messages().clone();
-    let theme = app.config.theme.clone();
+    // The list items borrow the lines of the shown messages rather than app, which is
+    // borrowed mutably to draw them
     let visual_selection = if app.mode == AppMode::Visual {
         app.visual_start.zip(app.visual_end).map(|(start, end)| (start.min(end), start.max(end)))
     } else {
-        None
+        None
+    };
+    let wrap_width = app.wrap_width(chat_area.width);
+    let mut image_lines = Vec::new();
+    let shown = shown_messages(app.current_messages(), app.chat_filter.as_ref(), &app.layout, wrap_width, app.images);
+    
+    // The focused pane is marked by its border once there are two
+    let (mut chat_title, focused_border_style) = match split_area {
+        Some(_) => (
+            format!("{} (Ctrl+W w to switch, :only to close)", app.sessions[app.current_session_index].name),
+            chat_border_style.add_modifier(Modifier::BOLD).fg(Color::Yellow),
+        ),
+        None => ("Chat History (↑↓ to scroll, PgUp/PgDn to page)".to_string(), chat_border_style),
+    };
+    // Say so when messages are hidden, it is easy to forget
+    if let Some(filter) = &app.chat_filter {
+        let messages = app.current_messages();
+        let shown = messages.iter().filter(|message| filter.matches(message)).count();
+        chat_title = format!("FILTERED {}: {} of {} messages (:filter clears)", filter.describe(), shown, messages.len());
+    }
+    let block = Block::default()
+        .borders(Borders::ALL)
+        .title(chat_title)
+        .border_style(focused_border_style);
+    render_chat_list(f, &shown, &app.config.theme, visual_selection, block, chat_area, &mut app.chat_list_state, &mut image_lines);
+    app.image_placements = image_placements(app, app.current_session_id(), &image_lines, chat_area, &app.chat_list_state);
+
+    if let Some(area) = split_area {
+        render_split_pane(f, app, area, chat_border_style);
+    }
+
+    if let Some(area) = agent_panel_area {
+        render_agent_panel(f, app, area);
+    }
+
+    let input_title = match app.mode {
+        AppMode::Normal => "-- NORMAL --",
+        AppMode::Insert => "-- INSERT --",
+        AppMode::Command => "-- COMMAND --",
+        AppMode::Visual => "-- VISUAL --",
+        AppMode::ModelSelection => "-- MODEL SELECTION --",
+        AppMode::SessionSelection => "-- SESSION SELECTION --",
+        AppMode::Agent => "-- AGENT --",
+        AppMode::Autonomous => "-- AUTONOMOUS --",
+        AppMode::PlanReview => "-- PLAN REVIEW --",
+        AppMode::AgentRuns => "-- AGENT RUNS --",
+        AppMode::Audit => "-- AUDIT LOG --",
+        AppMode::ToolApproval => "-- APPROVE CHANGE --",
+        AppMode::Help => "-- HELP --",
+        AppMode::DeleteMessageConfirmation => "-- DELETE MESSAGE --",
+        AppMode::PinnedMessages => "-- PINNED MESSAGES --",
+        AppMode::CommandHistory => "-- COMMAND HISTORY --",
+        AppMode::Info => "-- INFO --",
+        AppMode::CommandPalette => "-- COMMAND PALETTE --",
+        AppMode::PromptVariables => "-- PROMPT --",
+        AppMode::Workflows => "-- WORKFLOWS --",
+        AppMode::WorkflowVariables => "-- WORKFLOW VARIABLES --",
+        AppMode::WorkflowDraft => "-- WORKFLOW DRAFT --",
+        AppMode::WorkflowEditor => "-- WORKFLOW EDITOR --",
+        AppMode::Compare => "-- COMPARE --",
+        AppMode::Bench => "-- BENCHMARK --",
+        AppMode::CommitMessage => "-- COMMIT MESSAGE --",
+        AppMode::HowTo => "-- HOW TO --",
+    };
+
+    let input_text = match app.mode {
+        AppMode::Command => format!(":{}", app.vim_command),
+        _ => {
+            // Keep showing the input while loading, prompts typed now are queued
+            if app.is_loading() && app.input.is_empty() {
+                "Thinking...".to_string()
+            } else {
+                app.input.clone()
+            }
+        }
+    };
+    
+    // Multi-line input (e.g. a quote-reply) only has room for one line, so keep
+    // the line being typed visible and note how many lines are above it
+    let input_line_count = input_text.split('\n').count();
+    let mut input_title = if input_line_count > 1 {
+        format!("{} ({} lines)", input_title, input_line_count)
+    } else {
+        input_title.to_string()
+    };
+    // Chip for the files that go out with the next message
+    if !app.attachments.is_empty() {
+        let names: Vec<&str> = app.attachments.iter().map(|a| a.name.as_str()).collect();
+        input_title.push_str(&format!(
+            " [+ {} (~{} tokens)] ",
+            names.join(", "),
+            attach::estimated_tokens(app.attachment_chars())
+        ));
+    }
+    let input_paragraph = Paragraph::new(input_text.as_str())
+        .block(Block::default().borders(Borders::ALL).title(input_title))
+        .scroll((input_line_count.saturating_sub(1) as u16, 0));
+    f.render_widget(input_paragraph, left_chunks[1]);
+
+    let status_bar_text = if let Some(message) = app.status_message() {
+        match app.status_messages.len() - 1 {
+            0 => message.text.clone(),
+            waiting => format!("{} (+{})", message.text, waiting),
+        }
+    } else if matches!(app.mode, AppMode::Normal | AppMode::Insert | AppMode::Visual)
+        && (app.count_prefix.is_some()
+            || !app.pending_keys.is_empty()
+            || app.pending_register.is_some()
+            || app.awaiting_register
+            || app.pending_argument.is_some())
+    {
+        // Echo the partially typed command like vim's showcmd
+        let register = match app.pending_register {
+            Some(name) => format!("\"{}", name),
+            None if app.awaiting_register => "\"".to_string(),
+            None => String::new(),
+        };
+        format!(
+            "{}{}{}{}",
+            register,
+            app.count_prefix.map(|count| count.to_string()).unwrap_or_default(),
+            keymap::describe(&app.pending_keys),
+            app.pending_argument.map(|(_, chord)| chord.to_string()).unwrap_or_default()
+        )
+    } else {
+        match app.mode {
+            AppMode::Normal => format!(
+                "Model: {} | {} | ? for help | i:insert | v:visual | :q quit | :n new | :m models | :s sessions",
+                app.session_model(app.current_session_id()),
+                display_dir(&app.working_dir(app.current_session_id()))
+            ),
+            AppMode::Insert => format!(
+                "Model: {} | ESC to normal mode | Enter to send",
+                app.session_model(app.current_session_id())
+            ),
+            AppMode::Command => "Type command and press Enter".to_string(),
+            AppMode::Visual => "VISUAL: j/k to extend selection | y to copy | ESC to exit".to_string(),
+            AppMode::SessionSelection => "SESSION SELECTION: j/k to navigate | Enter to select | d to delete | ESC to exit".to_string(),
+            AppMode::Autonomous => match &app.autonomous {
+                Some(run) => {
+                    let controls = match &run.pending_command {
+                        Some(_) if app.command_edit.is_some() => "editing command: Enter to save, Esc to cancel".to_string(),
+                        Some(_) if run.policy_violation.is_some() => {
+                            "blocked by policy: ! to run anyway, e to edit, x to reject".to_string()
+                        }
+                        _ if run.pending_tool.is_some() && run.policy_violation.is_some() => {
+                            "blocked by policy: ! to run anyway, x to reject".to_string()
+                        }
+                        _ if run.state == agent::AgentState::Paused && run.pending_tool.is_some() => {
+                            "n to run the tool, v to view the change, x to reject".to_string()
+                        }
+                        Some(command) if run.state == agent::AgentState::Paused => {
+                            format!("n to run `{}` ({} risk), e to edit", command, agent::assess_risk_level(command).label())
+                        }
+                        _ if run.state == agent::AgentState::Paused => "p to resume".to_string(),
+                        _ if run.pause_requested => "pausing after this step".to_string(),
+                        _ => "p to pause".to_string(),
+                    };
+                    format!(
+                        "AUTONOMOUS: {} | step {}/{} | {}{}{} | {} | s: single-step | ESC to stop",
+                        display_dir(&app.working_dir(run.session_id)),
+                        run.steps.len(),
+                        run.max_steps,
+                        run.state.label(),
+                        if run.single_step { " [single-step]" } else { "" },
+                        if app.options.dry_run { " [dry run]" } else { "" },
+                        controls
+                    )
+                }
+                None => "AUTONOMOUS | ESC to exit".to_string(),
+            },
+            _ => format!("Model: {} | ESC to normal mode", app.session_model(app.current_session_id())),
+        }
+    };
+    let queued = app.queued_prompt_count(app.current_session_id());
+    let status_bar_text = if queued > 0 {
+        format!("[{} queued] {}", queued, status_bar_text)
+    } else {
+        status_bar_text
+    };
+    let status_bar_text = match app.workflow_run.as_ref().filter(|run| !run.finished) {
+        Some(run) => {
+            let done = app.pending_commands.iter().filter(|command| command.executed).count();
+            format!("[wf {} {}/{}] {}", run.workflow.name, done, run.workflow.steps.len(), status_bar_text)
+        }
+        None => status_bar_text,
+    };
+    let status_bar_text = match &app.compare_models {
+        Some([first, second]) => format!("[A/B {} | {}] {}", first, second, status_bar_text),
+        None => status_bar_text,
+    };
+    // Show which project instructions the agent is given
+    let status_bar_text = match app.active_instructions().and_then(|(path, _)| path.file_name()) {
+        Some(name) => format!("[{}] {}", name.to_string_lossy(), status_bar_text),
+        None => status_bar_text,
+    };
+    let status_style = match app.status_message() {
+        Some(message) if message.error => Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
+        _ => Style::default().fg(app.config.theme.parse_color(&app.config.theme.status_bar_color)),
+    };
+    let status_bar = Paragraph::new(status_bar_text).style(status_style);
+    f.render_widget(status_bar, left_chunks[2]);
+
+    // Set cursor position based on mode
+    match app.mode {
+        AppMode::Insert => {
+            f.set_cursor_position((
+                left_chunks[1].x + app.input.rsplit('\n').next().unwrap_or("").width() as u16 + 1,
+                left_chunks[1].y + 1,
+            ));
+        }
+        AppMode::Command => {
+            f.set_cursor_position((
+                left_chunks[1].x + app.vim_command.width() as u16 + 2, // +2 for ":"
+                left_chunks[1].y + 1,
+            ));
+        }
+        _ => {}
+    }
+
+    let sidebar_width = main_chunks[1].width.saturating_sub(2) as usize;
+    let now = chrono::Utc::now();
+    let session_items: Vec<ListItem> = app
+        .sessions
+        .iter()
+        .map(|s| {
+            let style = if s.id == app.sessions[app.current_session_index].id {
+                Style::default()
+                    .fg(Color::Green)
+                    .add_modifier(Modifier::BOLD)
+            } else {
+                Style::default()
+            };
+            // Background activity badge: streaming, or finished but not yet viewed
+            let age = if app.loading_sessions.contains(&s.id) {
+                "⋯ typing".to_string()
+            } else if app.unread_sessions.contains(&s.id) {
+                format!("● {}", relative_time(now, s.last_activity))
+            } else {
+                relative_time(now, s.last_activity)
+            };
+            let name_width = sidebar_width.saturating_sub(age.width() + 1);
+            let name: String = s.name.chars().take(name_width).collect();
+            let padding = sidebar_width.saturating_sub(name.width() + age.width());
+            let header = Line::from(vec![
+                Span::styled(name, style),
+                Span::raw(" ".repeat(padding)),
+                Span::styled(age, Style::default().fg(Color::DarkGray)),
+            ]);
+
+            let last_line = s
+                .messages
+                .last()
+                .and_then(|m| m.content.lines().find(|l| !l.trim().is_empty()))
+                .unwrap_or("")
+                .trim();
+            let kind = if s.knowledge { ", knowledge" } else { "" };
+            let preview: String = format!("  {} ({} msgs{})", last_line, s.messages.len(), kind)
+                .chars()
+                .take(sidebar_width)
+                .collect();
+            ListItem::new(vec![
+                header,
+                Line::from(Span::styled(preview, Style::default().fg(Color::DarkGray))),
+            ])
+        })
+        .collect();
+
+    let sessions_highlight_style = if app.mode == AppMode::SessionSelection {
+        Style::default()
+            .bg(Color::Yellow)
+            .fg(Color::Black)
+            .add_modifier(Modifier::BOLD)
+    } else {
+        Style::default()
+            .bg(app.config.theme.parse_color(&app.config.theme.highlight_bg_color))
+            .fg(app.config.theme.parse_color(&app.config.theme.highlight_color))
+    };
+
+    let sessions_list = List::new(session_items)
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title(sessions_title)
+                .border_style(sessions_border_style),
+        )
+        .highlight_style(sessions_highlight_style)
+        .highlight_symbol(">> ");
+
+    f.render_stateful_widget(sessions_list, main_chunks[1], &mut app.session_list_state);
+
+    if app.mode == AppMode::Command && !app.completion_candidates.is_empty() {
+        render_completion_popup(f, app, left_chunks[1]);
+    }
+
+    if app.mode == AppMode::ModelSelection {
+        render_model_selection_popup(f, app);
+    }
+    
+    if app.mode == AppMode::Help {
+        render_help_popup(f, app);
+    }
+
+    if app.mode == AppMode::DeleteMessageConfirmation {
+        render_delete_message_popup(f, app);
+    }
+
+    if app.mode == AppMode::PinnedMessages {
+        render_pinned_messages_popup(f, app);
+    }
+
+    if app.mode == AppMode::CommandHistory {
+        render_command_history_popup(f, app);
+    }
+
+    if app.mode == AppMode::Info {
+        render_info_popup(f, app);
+    }
+
+    if app.mode == AppMode::CommandPalette {
+        render_command_palette(f, app);
+    }
+
+    if app.mode == AppMode::PromptVariables {
+        render_prompt_variables_popup(f, app);
+    }
+
+    if app.mode == AppMode::Workflows {
+        render_workflows_popup(f, app);
+    }
+
+    if app.mode == AppMode::WorkflowEditor {
+        render_workflow_editor_popup(f, app);
+    }
+
+    if app.mode == AppMode::WorkflowDraft {
+        render_workflow_draft_popup(f, app);
+    }
+
+    if app.mode == AppMode::CommitMessage {
+        render_commit_message_popup(f, app);
+    }
+
+    if app.mode == AppMode::HowTo {
+        render_howto_popup(f, app);
+    }
+
+    if app.mode == AppMode::Compare {
+        render_compare_popup(f, app);
+    }
+
+    if app.mode == AppMode::Bench {
+        render_bench_popup(f, app);
+    }
+
+    if app.mode == AppMode::WorkflowVariables {
+        render_workflows_popup(f, app);
+        render_workflow_variables_popup(f, app);
+    }
+
+    if app.mode == AppMode::PlanReview {
+        render_plan_review_popup(f, app);
+    }
+
+    if app.mode == AppMode::AgentRuns {
+        render_agent_runs_popup(f, app);
+    }
+
+    if app.mode == AppMode::Audit {
+        render_audit_popup(f, app);
+    }
+
+    if app.mode == AppMode::ToolApproval {
+        render_tool_approval_popup(f, app);
+    }
+
+    if app.which_key_due() {
+        render_which_key(f, app);
+    }
+
+    render_toasts(f, app);
+}
+
+// Newest at the top right of the chat pane, above everything else
+// Keys that complete the sequence being typed, in the bottom right of the chat pane
+fn render_which_key(f: &mut Frame, app: &AppState) {
+    let Some(mode) = app.key_mode() else {
+        return;
+    };
+    let continuations = app.keymap.continuations(mode, &app.pending_keys);
+    if continuations.is_empty() {
+        return;
+    }
+    let area = get_chat_area(f.area());
+    let key_width = continuations.iter().map(|(keys, _)| keys.width()).max().unwrap_or(0);
+    let lines: Vec<Line> = continuations
+        .iter()
+        .map(|(keys, description)| {
+            Line::from(vec![
+                Span::styled(format!(" {:<width$}  ", keys, width = key_width), Style::default().fg(Color::Yellow)),
+                Span::raw(*description),
+            ])
+        })
+        .collect();
+    let width = (lines.iter().map(Line::width).max().unwrap_or(0) as u16 + 3).min(area.width.saturating_sub(2));
+    let height = (lines.len() as u16 + 2).min(area.height.saturating_sub(2));
+    if width < 10 || height < 3 {
+        return;
+    }
+    let rect = Rect::new(area.x + area.width - width - 1, area.y + area.height - height - 1, width, height);
+    f.render_widget(Clear, rect);
+    f.render_widget(
+        Paragraph::new(lines).block(
+            Block::default()
+                .borders(Borders::ALL)
+                .border_style(Style::default().fg(app.config.theme.parse_color(&app.config.theme.popup_border_color)))
+                .title(format!(" {} ", keymap::describe(&app.pending_keys))),
+        ),
+        rect,
+    );
+}
+
+fn render_toasts(f: &mut Frame, app: &AppState) {
+    let area = get_chat_area(f.area());
+    let width = area.width.saturating_sub(4).min(60);
+    if width < 10 {
+        return;
+    }
+    let mut y = area.y + 1;
+    for toast in app.toasts.visible.iter().rev() {
+        // Long messages get up to four lines, :messages has them in full
+        let lines = wrap(&toast.text, width as usize - 2).len().clamp(1, 4) as u16;
+        if y + lines + 2 > area.y + area.height {
+            break;
+        }
+        let color = match toast.severity {
+            toast::Severity::Info => Color::Cyan,
+            toast::Severity::Warning => Color::Yellow,
+            toast::Severity::Error => Color::Red,
+        };
+        let rect = Rect::new(area.x + area.width - width - 2, y, width, lines + 2);
+        f.render_widget(Clear, rect);
+        f.render_widget(
+            Paragraph::new(toast.text.as_str())
+                .wrap(Wrap { trim: true })
+                .block(
+                    Block::default()
+                        .borders(Borders::ALL)
+                        .border_style(Style::default().fg(color))
+                        .title(format!(" {} ", toast.severity.label())),
+                ),
+            rect,
+        );
+        y += lines + 2;
+    }
+}
+
+fn render_audit_popup(f: &mut Frame, app: &mut AppState) {
+    let popup_area = centered_rect(80, 60, f.area());
+    let block = Block::default()
+        .title("Audit Log (Enter for details, r to re-run, e to edit and re-run, Esc/q to close)")
+        .borders(Borders::ALL)
+        .border_style(Style::default().fg(app.config.theme.parse_color(&app.config.theme.popup_border_color)));
+
+    let items: Vec<ListItem> = app
+        .audit_entries
+        .iter()
+        .map(|entry| {
+            let exit = match entry.exit_code {
+                _ if entry.dry_run => "dry".to_string(),
+                Some(code) => code.to_string(),
+                None => "-".to_string(),
+            };
+            let risk_color = match entry.risk.as_str() {
+                "safe" => Color::Green,
+                "low" => Color::Cyan,
+                "moderate" => Color::Yellow,
+                "high" => Color::LightRed,
+                _ => Color::Red,
+            };
+            ListItem::new(Line::from(vec![
+                Span::styled(
+                    format!("{} ", entry.executed_at.with_timezone(&chrono::Local).format("%m-%d %H:%M")),
+                    Style::default().add_modifier(Modifier::DIM),
+                ),
+                Span::styled(format!("{:<9}", entry.risk), Style::default().fg(risk_color)),
+                Span::raw(format!("{:>4} ", exit)),
+                Span::styled(format!("{:<16}", entry.approval), Style::default().add_modifier(Modifier::DIM)),
+                Span::raw(entry.command.clone()),
+            ]))
+        })
+        .collect();
+    let list = List::new(items)
+        .block(block)
+        .highlight_style(
+            Style::default()
+                .bg(app.config.theme.parse_color(&app.config.theme.highlight_bg_color))
+                .fg(app.config.theme.parse_color(&app.config.theme.highlight_color))
+                .add_modifier(Modifier::BOLD),
+        )
+        .highlight_symbol(">> ");
+
+    f.render_widget(Clear, popup_area);
+    f.render_stateful_widget(list, popup_area, &mut app.audit_list_state);
+}
+
+/// Path with the home directory abbreviated to `~`.
+fn display_dir(dir: &std::path::Path) -> String {
+    if let Some(home) = std::env::var_os("HOME")
+        && let Ok(rest) = dir.strip_prefix(&home)
+    {
+        return if rest.as_os_str().is_empty() {
+            "~".to_string()
+        } else {
+            format!("~/{}", rest.display())
+        };
+    }
+    dir.display().to_string()
+}
+
+fn risk_style(risk: agent::RiskLevel) -> Style {
+    let color = match risk {
+        agent::RiskLevel::Safe => Color::Green,
+        agent::RiskLevel::Low => Color::Cyan,
+        agent::RiskLevel::Moderate => Color::Yellow,
+        agent::RiskLevel::High => Color::LightRed,
+        agent::RiskLevel::Critical => Color::Red,
+    };
+    Style::default().fg(color)
+}
+
+// Output lines shown for an expanded step in the progress panel
+const PANEL_OUTPUT_LINES: usize = 8;
+
+fn render_agent_panel(f: &mut Frame, app: &mut AppState, area: Rect) {
+    let Some(run) = &app.autonomous else {
+        return;
+    };
+    let border_style = Style::default().fg(app.config.theme.parse_color(&app.config.theme.chat_border_color));
+    let block = Block::default()
+        .title("Autonomous Agent (j/k select, Tab expand)")
+        .borders(Borders::ALL)
+        .border_style(border_style);
+    let inner = block.inner(area);
+    f.render_widget(block, area);
+
+    let width = inner.width.saturating_sub(1) as usize;
+    let mut header: Vec<Line> = wrap(&format!("Goal: {}", run.goal), width.max(1))
+        .into_iter()
+        .map(|line| Line::from(line.into_owned()))
+        .collect();
+    let state_style = match run.state {
+        agent::AgentState::Completed => Style::default().fg(Color::Green),
+        agent::AgentState::Failed(_) => Style::default().fg(Color::Red),
+        agent::AgentState::Paused | agent::AgentState::Stopped => Style::default().fg(Color::Yellow),
+        _ => Style::default().fg(Color::Cyan),
+    };
+    header.push(Line::from(vec![
+        Span::raw(format!("Step {}/{} | ", run.steps.len(), run.max_steps)),
+        Span::styled(run.state.label(), state_style.add_modifier(Modifier::BOLD)),
+        Span::raw(if run.single_step { " | single-step" } else { "" }),
+        Span::styled(
+            if app.options.dry_run { " | DRY RUN" } else { "" },
+            Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD),
+        ),
+    ]));
+    if let Some(buffer) = &app.command_edit {
+        header.push(Line::from(vec![
+            Span::raw("Next: $ "),
+            Span::styled(format!("{}_", buffer), Style::default().fg(Color::Yellow)),
+        ]));
+    } else if let Some(call) = &run.pending_tool {
+        let risk = call.risk();
+        let hint = if app.pending_commit.is_some() {
+            " (v to pick the files)"
+        } else if app.pending_change.is_some() {
+            " (v to view the diff)"
+        } else {
+            ""
+        };
+        header.push(Line::from(vec![
+            Span::raw(format!("Next: {} ", call.summary())),
+            Span::styled(format!("[{} risk]", risk.label()), risk_style(risk).add_modifier(Modifier::BOLD)),
+            Span::styled(hint, Style::default().add_modifier(Modifier::DIM)),
+        ]));
+    } else if let Some(command) = &run.pending_command {
+        let risk = agent::assess_risk_level(command);
+        header.push(Line::from(vec![
+            Span::raw(format!("Next: $ {} ", command)),
+            Span::styled(format!("[{} risk]", risk.label()), risk_style(risk).add_modifier(Modifier::BOLD)),
+        ]));
+    }
+    if let Some(reason) = &run.policy_violation {
+        header.push(Line::styled(
+            format!("Blocked: {} (! to run anyway, e to edit, x to reject)", reason),
+            Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
+        ));
+    }
+    header.push(Line::from(""));
+
+    // Output of the running command gets its own pane below the steps
+    let executing = run.state == agent::AgentState::Executing;
+    let output_height = if executing { (inner.height / 2).max(3) } else { 0 };
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Length(header.len() as u16),
+            Constraint::Min(0),
+            Constraint::Length(output_height),
+        ])
+        .split(inner);
+    f.render_widget(Paragraph::new(header), chunks[0]);
+    if executing {
+        let output = agent::redact_secrets(&String::from_utf8_lossy(&app.live_command_output), &app.command_env, &app.redaction);
+        let visible = chunks[2].height.saturating_sub(1) as usize;
+        let lines: Vec<&str> = output.lines().collect();
+        let tail = lines[lines.len().saturating_sub(visible)..].join("\n");
+        let pane = Paragraph::new(tail).block(
+            Block::default()
+                .borders(Borders::TOP)
+                .title("Live output (Ctrl+C to kill)")
+                .border_style(border_style),
+        );
+        f.render_widget(pane, chunks[2]);
+    }
+
+    let clip = |text: &str, indent: usize| -> String {
+        let line: String = text.chars().take(width.saturating_sub(indent)).collect();
+        format!("{}{}", " ".repeat(indent), line)
+    };
+    let items: Vec<ListItem> = run
+        .steps
+        .iter()
+        .enumerate()
+        .map(|(i, step)| {
+            let (marker, marker_style) = match &step.output {
+                Some(Ok(_)) => ("✓", Style::default().fg(Color::Green)),
+                Some(Err(_)) => ("✗", Style::default().fg(Color::Red)),
+                None => ("…", Style::default().fg(Color::Cyan)),
+            };
+            let expanded = app.expanded_agent_steps.contains(&i);
+            let exit = step.exit_code.map(|code| format!(" [exit {}]", code)).unwrap_or_default();
+            let risk = step
+                .command
+                .as_deref()
+                .map(agent::assess_risk_level)
+                .or(step.tool.as_ref().map(tools::ToolCall::risk));
+            let risk_tag = risk.map(|risk| format!(" {}", risk.label())).unwrap_or_default();
+            let title = format!("{} {}. {}", if expanded { "▾" } else { "▸" }, i + 1, step.thought);
+            let mut lines = vec![Line::from(vec![
+                Span::styled(format!("{} ", marker), marker_style),
+                Span::raw(title.chars().take(width.saturating_sub(exit.width() + risk_tag.width() + 2)).collect::<String>()),
+                Span::styled(risk_tag, risk.map(risk_style).unwrap_or_default()),
+                Span::styled(exit, Style::default().add_modifier(Modifier::DIM)),
+            ])];
+            if expanded {
+                if let Some(command) = &step.command {
+                    lines.push(Line::from(clip(&format!("$ {}", command), 4)));
+                }
+                if let Some(tool) = &step.tool {
+                    lines.push(Line::from(clip(&format!("tool: {}", tool.summary()), 4)));
+                }
+                let output = match &step.output {
+                    Some(Ok(output)) | Some(Err(output)) => output.as_str(),
+                    None => "",
+                };
+                let output_lines: Vec<&str> = output.lines().collect();
+                let skipped = output_lines.len().saturating_sub(PANEL_OUTPUT_LINES);
+                if skipped > 0 {
+                    lines.push(Line::styled(
+                        clip(&format!("[{} earlier lines]", skipped), 4),
+                        Style::default().add_modifier(Modifier::DIM),
+                    ));
+                }
+                lines.extend(output_lines[skipped..].iter().map(|l| Line::from(clip(l, 4))));
+                if let Some(analysis) = &step.analysis {
+                    for line in wrap(&format!("Analysis: {}", analysis), width.saturating_sub(4).max(1)) {
+                        lines.push(Line::styled(clip(&line, 4), Style::default().add_modifier(Modifier::ITALIC)));
+                    }
+                }
+            }
+            ListItem::new(lines)
+        })
+        .collect();
+    let list = List::new(items).highlight_style(
+        Style::default()
+            .bg(app.config.theme.parse_color(&app.config.theme.highlight_bg_color))
+            .fg(app.config.theme.parse_color(&app.config.theme.highlight_color)),
+    );
+    if app.agent_step_list_state.selected().is_none() {
+        // Follow the latest step until the user selects one
+        *app.agent_step_list_state.offset_mut() = run.steps.len().saturating_sub(chunks[1].height as usize);
+    }
+    f.render_stateful_widget(list, chunks[1], &mut app.agent_step_list_state);
+}
+
+fn render_agent_runs_popup(f: &mut Frame, app: &mut AppState) {
+    let popup_area = centered_rect(70, 60, f.area());
+    let block = Block::default()
+        .title("Autonomous Runs (Enter for details, r to resume, Esc/q to close)")
+        .borders(Borders::ALL)
+        .border_style(Style::default().fg(app.config.theme.parse_color(&app.config.theme.popup_border_color)));
+
+    let items: Vec<ListItem> = app
+        .agent_runs
+        .iter()
+        .map(|run| {
+            let session = app
+                .sessions
+                .iter()
+                .find(|s| s.id == run.session_id)
+                .map(|s| s.name.as_str())
+                .unwrap_or("deleted session");
+            ListItem::new(Line::from(vec![
+                Span::styled(format!("#{:<4} ", run.id), Style::default().add_modifier(Modifier::BOLD)),
+                Span::raw(format!("{:<12} ", run.state.label().split(':').next().unwrap_or_default())),
+                Span::raw(format!("{:>2} steps  ", run.steps.len())),
+                Span::raw(run.goal.clone()),
+                Span::styled(format!("  ({})", session), Style::default().add_modifier(Modifier::DIM)),
+            ]))
+        })
+        .collect();
+    let list = List::new(items)
+        .block(block)
+        .highlight_style(
+            Style::default()
+                .bg(app.config.theme.parse_color(&app.config.theme.highlight_bg_color))
+                .fg(app.config.theme.parse_color(&app.config.theme.highlight_color))
+                .add_modifier(Modifier::BOLD),
+        )
+        .highlight_symbol(">> ");
+
+    f.render_widget(Clear, popup_area);
+    f.render_stateful_widget(list, popup_area, &mut app.runs_list_state);
+}
+
+fn render_tool_approval_popup(f: &mut Frame, app: &mut AppState) {
+    if app.pending_commit.is_some() {
+        return render_commit_approval_popup(f, app);
+    }
+    let popup_area = centered_rect(80, 70, f.area());
+    let Some(change) = &app.pending_change else {
+        return;
+    };
+    let blocked = app.autonomous.as_ref().and_then(|run| run.policy_violation.as_ref());
+    let keys = if blocked.is_some() {
+        "! apply anyway, n reject"
+    } else {
+        "y apply, n reject"
+    };
+    let (added, removed) = tools::change_stats(&change.diff);
+    let title = format!(
+        "Write {} (+{} -{}) ({}, j/k scroll, Esc back)",
+        change.path.display(),
+        added,
+        removed,
+        keys
+    );
+    let block = Block::default()
+        .title(title)
+        .borders(Borders::ALL)
+        .border_style(Style::default().fg(app.config.theme.parse_color(&app.config.theme.popup_border_color)));
+
+    let mut lines: Vec<Line> = Vec::new();
+    if let Some(reason) = blocked {
+        lines.push(Line::styled(
+            format!("Blocked: {}", reason),
+            Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
+        ));
+    }
+    if change.diff.is_empty() {
+        lines.push(Line::styled("(no changes)", Style::default().add_modifier(Modifier::DIM)));
+    }
+    lines.extend(
+        change
+            .diff
+            .lines()
+            .map(|line| Line::styled(line.to_string(), diff_style(Style::default(), markdown::diff_line_kind(line)))),
+    );
+
+    let paragraph = Paragraph::new(lines).block(block).scroll((app.diff_scroll, 0));
+    f.render_widget(Clear, popup_area);
+    f.render_widget(paragraph, popup_area);
+}
+
+fn render_commit_approval_popup(f: &mut Frame, app: &mut AppState) {
+    let popup_area = centered_rect(70, 60, f.area());
+    let Some(commit) = &app.pending_commit else {
+        return;
+    };
+    let selected = commit.files.iter().filter(|file| file.selected).count();
+    let title = format!(
+        "Commit {} of {} files (Space toggle, a all, y commit, n reject, Esc back)",
+        selected,
+        commit.files.len()
+    );
+    let border_style = Style::default().fg(app.config.theme.parse_color(&app.config.theme.popup_border_color));
+    let block = Block::default().title(title).borders(Borders::ALL).border_style(border_style);
+    let inner = block.inner(popup_area);
+    f.render_widget(Clear, popup_area);
+    f.render_widget(block, popup_area);
+
+    let mut message: Vec<Line> = commit
+        .message
+        .lines()
+        .map(|line| Line::styled(line.to_string(), Style::default().add_modifier(Modifier::BOLD)))
+        .collect();
+    if let Some(reason) = app.autonomous.as_ref().and_then(|run| run.policy_violation.as_ref()) {
+        message.push(Line::styled(
+            format!("Blocked: {} (! to commit anyway)", reason),
+            Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
+        ));
+    }
+    let message_height = (message.len() as u16 + 1).min(inner.height / 2);
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Length(message_height), Constraint::Min(0)])
+        .split(inner);
+    f.render_widget(Paragraph::new(message).wrap(Wrap { trim: false }), chunks[0]);
+
+    let items: Vec<ListItem> = commit
+        .files
+        .iter()
+        .map(|file| {
+            let status_color = match file.status.trim() {
+                "??" => Color::Cyan,
+                status if status.contains('D') => Color::Red,
+                status if status.contains('A') => Color::Green,
+                _ => Color::Yellow,
+            };
+            ListItem::new(Line::from(vec![
+                Span::raw(if file.selected { "[x] " } else { "[ ] " }),
+                Span::styled(format!("{} ", file.status), Style::default().fg(status_color)),
+                Span::raw(file.path.clone()),
+            ]))
+        })
+        .collect();
+    let list = List::new(items)
+        .highlight_style(
+            Style::default()
+                .bg(app.config.theme.parse_color(&app.config.theme.highlight_bg_color))
+                .fg(app.config.theme.parse_color(&app.config.theme.highlight_color))
+                .add_modifier(Modifier::BOLD),
+        )
+        .highlight_symbol(">> ");
+    f.render_stateful_widget(list, chunks[1], &mut app.commit_file_state);
+}
+
+fn render_plan_review_popup(f: &mut Frame, app: &mut AppState) {
+    let popup_area = centered_rect(70, 60, f.area());
+    let title = if app.plan_edit.is_some() {
+        "Edit step (Enter to save, Esc to cancel)"
+    } else {
+        "Review plan (Enter/y approve, e edit, a add, d delete, Esc/n reject)"
+    };
+    let block = Block::default()
+        .title(title)
+        .borders(Borders::ALL)
+        .border_style(Style::default().fg(app.config.theme.parse_color(&app.config.theme.popup_border_color)));
+
+    let (goal, plan) = match &app.autonomous {
+        Some(run) => (run.goal.clone(), run.plan.clone()),
+        None => (String::new(), Vec::new()),
+    };
+    let selected = app.plan_list_state.selected();
+    let mut items: Vec<ListItem> = plan
+        .iter()
+        .enumerate()
+        .map(|(i, step)| {
+            let text = match &app.plan_edit {
+                Some(buffer) if Some(i) == selected => buffer.as_str(),
+                _ => step.as_str(),
+            };
+            ListItem::new(format!("{}. {}", i + 1, text))
+        })
+        .collect();
+    if items.is_empty() {
+        items.push(ListItem::new("(empty plan, press a to add a step)"));
+    }
+    let list = List::new(items)
+        .block(block.title_bottom(format!("Goal: {}", goal)))
+        .highlight_style(
+            Style::default()
+                .bg(app.config.theme.parse_color(&app.config.theme.highlight_bg_color))
+                .fg(app.config.theme.parse_color(&app.config.theme.highlight_color))
+                .add_modifier(Modifier::BOLD),
+        )
+        .highlight_symbol(">> ");
+
+    f.render_widget(Clear, popup_area);
+    f.render_stateful_widget(list, popup_area, &mut app.plan_list_state);
+}
+
+fn render_command_palette(f: &mut Frame, app: &mut AppState) {
+    let popup_area = centered_rect(60, 60, f.area());
+    let border_style = Style::default().fg(app.config.theme.parse_color(&app.config.theme.popup_border_color));
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Length(3), Constraint::Min(0)])
+        .split(popup_area);
+
+    let query = Paragraph::new(format!("> {}", app.palette_query)).block(
+        Block::default()
+            .borders(Borders::ALL)
+            .title("Command Palette (type to search, Enter to run, Esc to close)")
+            .border_style(border_style),
+    );
+
+    let items: Vec<ListItem> = app
+        .filtered_palette_entries()
+        .into_iter()
+        .map(|(command, description, _)| {
+            ListItem::new(Line::from(vec![
+                Span::styled(format!(":{:<12} ", command), Style::default().add_modifier(Modifier::BOLD)),
+                Span::raw(description),
+            ]))
+        })
+        .collect();
+    let list = List::new(items)
+        .block(Block::default().borders(Borders::ALL).border_style(border_style))
+        .highlight_style(
+            Style::default()
+                .bg(app.config.theme.parse_color(&app.config.theme.highlight_bg_color))
+                .fg(app.config.theme.parse_color(&app.config.theme.highlight_color)),
+        )
+        .highlight_symbol(">> ");
+
+    f.render_widget(Clear, popup_area);
+    f.render_widget(query, chunks[0]);
+    f.render_stateful_widget(list, chunks[1], &mut app.palette_list_state);
+    f.set_cursor_position((chunks[0].x + app.palette_query.width() as u16 + 3, chunks[0].y + 1));
+}
+
+fn render_info_popup(f: &mut Frame, app: &AppState) {
+    let popup_area = centered_rect(80, 60, f.area());
+    let block = Block::default()
+        .title(format!("{} (j/k to scroll, Esc/q to close)", app.info_title))
+        .borders(Borders::ALL)
+        .border_style(Style::default().fg(app.config.theme.parse_color(&app.config.theme.popup_border_color)));
+
+    let paragraph = Paragraph::new(app.info_lines.join("\n"))
+        .block(block)
+        .scroll((app.info_scroll, 0));
+
+    f.render_widget(Clear, popup_area);
+    f.render_widget(paragraph, popup_area);
+}
+
+fn render_prompt_variables_popup(f: &mut Frame, app: &AppState) {
+    let Some(fill) = &app.prompt_fill else {
+        return;
+    };
+    let input_title = format!("{{{{{}}}}}", fill.current().unwrap_or(""));
+    render_fill_popup(f, app, fill, &format!("Prompt '{}'", fill.name), &input_title);
+}
+
+fn render_workflow_editor_popup(f: &mut Frame, app: &mut AppState) {
+    let Some(editor) = &app.workflow_editor else {
+        return;
+    };
+    let popup_area = centered_rect(80, 70, f.area());
+    let fields = editor.fields();
+    let label_width = fields.iter().map(|field| editor.label(*field).width()).max().unwrap_or(0);
+    let items: Vec<ListItem> = fields
+        .iter()
+        .enumerate()
+        .map(|(i, field)| {
+            let value = match &editor.editing {
+                Some(text) if i == editor.selected => format!("{}▏", text),
+                _ => editor.value(*field),
+            };
+            let label_style = match field {
+                workflow::Field::StepName(_) | workflow::Field::Name => Style::default().add_modifier(Modifier::BOLD),
+                _ => Style::default().add_modifier(Modifier::DIM),
+            };
+            ListItem::new(Line::from(vec![
+                Span::styled(format!("{:<width$}  ", editor.label(*field), width = label_width), label_style),
+                Span::raw(value),
+            ]))
+        })
+        .collect();
+    let title = if editor.editing.is_some() {
+        "Edit workflow (Enter keep, Esc cancel)"
+    } else {
+        "Edit workflow (Enter edit, a add step, v add variable, t add trigger, d delete, J/K move step, s save, Esc close)"
+    };
+    let list = List::new(items)
+        .block(
+            Block::default()
+                .title(title)
+                .borders(Borders::ALL)
+                .border_style(Style::default().fg(app.config.theme.parse_color(&app.config.theme.popup_border_color))),
+        )
+        .highlight_style(
+            Style::default()
+                .bg(app.config.theme.parse_color(&app.config.theme.highlight_bg_color))
+                .fg(app.config.theme.parse_color(&app.config.theme.highlight_color))
+                .add_modifier(Modifier::BOLD),
+        )
+        .highlight_symbol(">> ");
+    app.workflow_editor_state.select(Some(editor.selected));
+
+    f.render_widget(Clear, popup_area);
+    f.render_stateful_widget(list, popup_area, &mut app.workflow_editor_state);
+}
+
+fn render_workflow_draft_popup(f: &mut Frame, app: &AppState) {
+    let Some(draft) = &app.workflow_draft else {
+        return;
+    };
+    let popup_area = centered_rect(80, 70, f.area());
+    let mut lines = Vec::new();
+    if app.workflows.workflows().iter().any(|workflow| workflow.name == draft.name) {
+        lines.push(Line::styled(
+            format!("Replaces the existing workflow '{}'", draft.name),
+            Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD),
+        ));
+    }
+    // Risk of each command, as it would be rated when the workflow runs
+    for step in &draft.steps {
+        let risk = agent::assess_risk_level(&step.command);
+        lines.push(Line::from(vec![
+            Span::styled(format!("[{}] ", risk.label()), risk_style(risk)),
+            Span::raw(step.name.clone()),
+        ]));
+    }
+    lines.push(Line::from(""));
+    lines.extend(draft.to_toml().lines().map(|line| Line::from(line.to_string())));
+    let paragraph = Paragraph::new(lines)
+        .wrap(Wrap { trim: false })
+        .scroll((app.draft_scroll, 0))
+        .block(
+            Block::default()
+                .title("Workflow draft (y save, e edit, n discard, j/k scroll)")
+                .borders(Borders::ALL)
+                .border_style(Style::default().fg(app.config.theme.parse_color(&app.config.theme.popup_border_color))),
+        );
+
+    f.render_widget(Clear, popup_area);
+    f.render_widget(paragraph, popup_area);
+}
+
+fn render_commit_message_popup(f: &mut Frame, app: &AppState) {
+    let popup_area = centered_rect(70, 50, f.area());
+    let message = app.commit_message.as_deref().unwrap_or_default();
+    let mut lines: Vec<Line> = message.lines().map(|line| Line::from(line.to_string())).collect();
+    if app.commit_message_editing {
+        // The cursor stays at the end, on a new line after Enter
+        if message.is_empty() || message.ends_with('\n') {
+            lines.push(Line::from(""));
+        }
+        if let Some(last) = lines.last_mut() {
+            last.spans.push(Span::raw("█"));
+        }
+    } else if message.lines().next().is_some_and(|subject| subject.chars().count() > 72) {
+        lines.push(Line::from(""));
+        lines.push(Line::styled("The subject is longer than 72 characters", Style::default().fg(Color::Yellow)));
+    }
+    let title = if app.commit_message_editing {
+        "Edit commit message (Enter new line, Esc done)"
+    } else {
+        "Commit message (c commit, y copy, e edit, n discard)"
+    };
+    let paragraph = Paragraph::new(lines).wrap(Wrap { trim: false }).block(
+        Block::default()
+            .title(title)
+            .borders(Borders::ALL)
+            .border_style(Style::default().fg(app.config.theme.parse_color(&app.config.theme.popup_border_color))),
+    );
+
+    f.render_widget(Clear, popup_area);
+    f.render_widget(paragraph, popup_area);
+}
+
+fn render_howto_popup(f: &mut Frame, app: &AppState) {
+    let Some(suggestion) = &app.howto else {
+        return;
+    };
+    let popup_area = centered_rect(70, 40, f.area());
+    let risk = agent::assess_risk_level(&suggestion.command);
+    let mut lines = vec![
+        Line::from(vec![
+            Span::styled(format!("[{}] ", risk.label()), risk_style(risk)),
+            Span::styled(format!("$ {}", suggestion.command.trim()), Style::default().add_modifier(Modifier::BOLD)),
+        ]),
+        Line::from(""),
+    ];
+    lines.extend(suggestion.explanation.lines().map(|line| Line::from(line.to_string())));
+    if let policy::Verdict::Denied(reason) = app.check_command_policy(&suggestion.command) {
+        lines.push(Line::from(""));
+        lines.push(Line::styled(format!("Blocked by the command policy: {}", reason), Style::default().fg(Color::Red)));
+    }
+    let paragraph = Paragraph::new(lines).wrap(Wrap { trim: false }).block(
+        Block::default()
+            .title("How to (r run, y copy, i insert, n close)")
+            .borders(Borders::ALL)
+            .border_style(Style::default().fg(app.config.theme.parse_color(&app.config.theme.popup_border_color))),
+    );
+
+    f.render_widget(Clear, popup_area);
+    f.render_widget(paragraph, popup_area);
+}
+
+// The unfocused session of a split view, kept at its latest message while a reply streams into it
+fn render_split_pane(f: &mut Frame, app: &mut AppState, area: Rect, border_style: Style) {
+    let Some(index) = app.split.as_ref().and_then(|split| app.session_index_by_id(split.session_id)) else {
+        return;
+    };
+    let session = &app.sessions[index];
+    let mut image_lines = Vec::new();
+    let shown = shown_messages(&session.messages, None, &app.layout, app.wrap_width(area.width), app.images);
+    let title = if app.loading_sessions.contains(&session.id) {
+        format!("{} ⋯", session.name)
+    } else {
+        session.name.clone()
+    };
+    let last = line_count(&shown).checked_sub(1);
+    let streaming = app.loading_sessions.contains(&session.id);
+    let block = Block::default().borders(Borders::ALL).title(title).border_style(border_style);
+    let Some(split) = app.split.as_mut() else {
+        return;
+    };
+    if streaming || split.list_state.selected().is_none() {
+        split.list_state.select(last);
+    }
+    render_chat_list(f, &shown, &app.config.theme, None, block, area, &mut split.list_state, &mut image_lines);
+    let Some(split) = app.split.as_ref() else {
+        return;
+    };
+    let placements = image_placements(app, split.session_id, &image_lines, area, &split.list_state);
+    app.image_placements.extend(placements);
+}
+
+// Where the images of a chat pane go: below their image line, when the rows left for
+// them are all visible and the image file exists
+fn image_placements(app: &AppState, session_id: i64, image_lines: &[(usize, String)], area: Rect, state: &ListState) -> Vec<image::Placement> {
+    // Popups would be drawn over
+    if !matches!(app.mode, AppMode::Normal | AppMode::Insert | AppMode::Visual | AppMode::Command | AppMode::Agent) {
+        return Vec::new();
+    }
+    let cwd = app.working_dir(session_id);
+    let inner_height = area.height.saturating_sub(2) as usize;
+    // Indent of continuation lines, after the highlight symbol of a list with a selection
+    let indent = if state.selected().is_some() { 6 } else { 4 };
+    image_lines
+        .iter()
+        .filter_map(|(line, path)| {
+            let rows = app.images.rows_for(path);
+            let first_row = (line + 1).checked_sub(state.offset())?;
+            if rows == 0 || first_row + rows as usize > inner_height {
+                return None;
+            }
+            let path = cwd.join(commands::expand_tilde(path));
+            path.is_file().then(|| image::Placement {
+                path,
+                x: area.x + 1 + indent,
+                y: area.y + 1 + first_row as u16,
+                cols: app.images.cols.min(area.width.saturating_sub(2 + indent)),
+                rows,
+            })
+        })
+        .collect()
+}
+
+// Averages per model, then the timing of every prompt
+fn render_bench_popup(f: &mut Frame, app: &AppState) {
+    let Some(bench) = &app.bench else {
+        return;
+    };
+    let popup_area = centered_rect(85, 70, f.area());
+    let secs = |duration: std::time::Duration| format!("{:.2}s", duration.as_secs_f64());
+    let header_style = Style::default().add_modifier(Modifier::BOLD);
+    let width = bench.models.iter().map(|model| model.len()).max().unwrap_or(0).max(5);
+
+    let mut lines = vec![Line::styled(
+        format!("{:<width$}  {:>8}  {:>8}  {:>8}  {:>8}  {:>6}", "Model", "Load", "TTFT", "Total", "Tok/s", "Failed"),
+        header_style,
+    )];
+    for model in &bench.models {
+        let row = match bench.summary(model) {
+            Some(summary) => format!(
+                "{:<width$}  {:>8}  {:>8}  {:>8}  {:>8.1}  {:>6}",
+                model,
+                secs(summary.load),
+                secs(summary.first_token),
+                secs(summary.total),
+                summary.tokens_per_sec,
+                summary.failed
+            ),
+            None => format!("{:<width$}  {:>8}", model, "..."),
+        };
+        lines.push(Line::from(row));
+    }
+    lines.push(Line::from(""));
+    for (i, prompt) in bench.prompts.iter().enumerate() {
+        lines.push(Line::styled(format!("Prompt {}: {}", i + 1, prompt), header_style));
+        for sample in bench.samples.iter().filter(|sample| sample.prompt == i) {
+            let row = match &sample.result {
+                Ok(timing) => format!(
+                    "  {:<width$}  TTFT {}  total {}  {} tokens  {:.1} tok/s",
+                    sample.model,
+                    secs(timing.first_token),
+                    secs(timing.total),
+                    timing.tokens,
+                    timing.tokens_per_sec()
+                ),
+                Err(e) => format!("  {:<width$}  failed: {}", sample.model, e),
+            };
+            let style = if sample.result.is_ok() { Style::default() } else { Style::default().fg(Color::Red) };
+            lines.push(Line::styled(row, style));
+        }
+    }
+
+    let title = if bench.is_running() {
+        format!("Benchmark {}/{} (x stop, j/k scroll, Esc close)", bench.samples.len(), bench.total_runs())
+    } else {
+        format!("Benchmark {}/{} done (j/k scroll, Esc close)", bench.samples.len(), bench.total_runs())
     };
-    let chat_list_items = render_messages_as_list(&messages, left_chunks[0].width, &theme, visual_selection);
-    
-    let chat_list = List::new(chat_list_items)
+    let paragraph = Paragraph::new(lines)
+        .scroll((app.bench_scroll, 0))
         .block(
             Block::default()
+                .title(title)
                 .borders(Borders::ALL)
-                .title("Chat History (↑↓ to scroll, PgUp/PgDn to page)")
-                .border_style(chat_border_style),
-        )
-        .highlight_style(Style::default().bg(Color::DarkGray).add_modifier(Modifier::DIM))
-        .highlight_symbol("  ");  // Less intrusive highlight
-    f.render_stateful_widget(chat_list, left_chunks[0], &mut app.chat_list_state);
+                .border_style(Style::default().fg(app.config.theme.parse_color(&app.config.theme.popup_border_color))),
+        );
 
-    let input_title = match app.mode {
-        AppMode::Normal => "-- NORMAL --",
-        AppMode::Insert => "-- INSERT --",
-        AppMode::Command => "-- COMMAND --",
-        AppMode::Visual => "-- VISUAL --",
-        AppMode::ModelSelection => "-- MODEL SELECTION --",
-        AppMode::SessionSelection => "-- SESSION SELECTION --",
-        AppMode::Agent => "-- AGENT --",
-        AppMode::Help => "-- HELP --",
-    };
+    f.render_widget(Clear, popup_area);
+    f.render_widget(paragraph, popup_area);
+}
 
-    let input_text = match app.mode {
-        AppMode::Command => format!(":{}", app.vim_command),
-        _ => {
-            if app.is_loading {
-                "Thinking...".to_string()
-            } else {
-                app.input.clone()
-            }
-        }
+// The two replies of the latest comparison in columns, scrolled together
+fn render_compare_popup(f: &mut Frame, app: &AppState) {
+    let Some(comparison) = &app.comparison else {
+        return;
     };
-    
-    let input_paragraph = Paragraph::new(input_text.as_str())
-        .block(Block::default().borders(Borders::ALL).title(input_title));
-    f.render_widget(input_paragraph, left_chunks[1]);
+    let popup_area = centered_rect(90, 80, f.area());
+    let border_style = Style::default().fg(app.config.theme.parse_color(&app.config.theme.popup_border_color));
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Length(3), Constraint::Min(0)])
+        .split(popup_area);
+    let columns = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Percentage(50), Constraint::Percentage(50)])
+        .split(chunks[1]);
 
-    let status_bar_text = if let Some(ref msg) = app.status_message {
-        msg.clone()
-    } else {
-        match app.mode {
-            AppMode::Normal => format!(
-                "Model: {} | ? for help | i:insert | v:visual | :q quit | :n new | :m models | :s sessions",
-                app.current_model
-            ),
-            AppMode::Insert => format!(
-                "Model: {} | ESC to normal mode | Enter to send",
-                app.current_model
-            ),
-            AppMode::Command => "Type command and press Enter".to_string(),
-            AppMode::Visual => "VISUAL: j/k to extend selection | y to copy | ESC to exit".to_string(),
-            AppMode::SessionSelection => "SESSION SELECTION: j/k to navigate | Enter to select | d to delete | ESC to exit".to_string(),
-            _ => format!("Model: {} | ESC to normal mode", app.current_model),
-        }
+    f.render_widget(Clear, popup_area);
+    let prompt = Paragraph::new(comparison.prompt.lines().next().unwrap_or("").to_string()).block(
+        Block::default()
+            .title("Compare (j/k scroll, i write next prompt, Esc close)")
+            .borders(Borders::ALL)
+            .border_style(border_style),
+    );
+    f.render_widget(prompt, chunks[0]);
+    for (reply, area) in comparison.replies.iter().zip(columns.iter()) {
+        let title = match reply.elapsed {
+            Some(elapsed) => format!("{} ({:.1}s)", reply.model, elapsed.as_secs_f64()),
+            None => format!("{} ...", reply.model),
+        };
+        let paragraph = Paragraph::new(reply.content.clone())
+            .wrap(Wrap { trim: false })
+            .scroll((app.compare_scroll, 0))
+            .block(Block::default().title(title).borders(Borders::ALL).border_style(border_style));
+        f.render_widget(paragraph, *area);
+    }
+}
+
+fn render_workflow_variables_popup(f: &mut Frame, app: &AppState) {
+    let Some((_, fill)) = &app.workflow_fill else {
+        return;
     };
-    let status_bar = Paragraph::new(status_bar_text).style(Style::default().fg(app.config.theme.parse_color(&app.config.theme.status_bar_color)));
-    f.render_widget(status_bar, left_chunks[2]);
+    let input_title = format!("${{{}}}", fill.current().unwrap_or(""));
+    render_fill_popup(f, app, fill, &format!("Workflow '{}'", fill.name), &input_title);
+}
 
-    // Set cursor position based on mode
-    match app.mode {
-        AppMode::Insert => {
-            if !app.is_loading {
-                f.set_cursor_position((
-                    left_chunks[1].x + app.input.width() as u16 + 1,
-                    left_chunks[1].y + 1,
-                ));
-            }
-        }
-        AppMode::Command => {
-            f.set_cursor_position((
-                left_chunks[1].x + app.vim_command.width() as u16 + 2, // +2 for ":"
-                left_chunks[1].y + 1,
-            ));
-        }
-        _ => {}
-    }
+// Values typed so far and the input for the next one
+fn render_fill_popup(f: &mut Frame, app: &AppState, fill: &prompts::PromptFill, title: &str, input_title: &str) {
+    let popup_area = centered_rect(60, 30, f.area());
+    let border_style = Style::default().fg(app.config.theme.parse_color(&app.config.theme.popup_border_color));
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Min(0), Constraint::Length(3)])
+        .split(popup_area);
 
-    let session_items: Vec<ListItem> = app
-        .sessions
+    // Placeholders filled in so far, then the one being asked for
+    let mut lines: Vec<Line> = fill
+        .placeholders
         .iter()
-        .map(|s| {
-            let style = if s.id == app.sessions[app.current_session_index].id {
-                Style::default()
-                    .fg(Color::Green)
-                    .add_modifier(Modifier::BOLD)
-            } else {
-                Style::default()
-            };
-            ListItem::new(s.name.as_str()).style(style)
-        })
+        .zip(&fill.values)
+        .map(|(name, value)| Line::from(format!("{}: {}", name, value)))
         .collect();
+    if let Some(name) = fill.current() {
+        lines.push(Line::from(Span::styled(
+            format!("{}: ...", name),
+            Style::default().add_modifier(Modifier::BOLD),
+        )));
+    }
+    let summary = Paragraph::new(lines).block(
+        Block::default()
+            .borders(Borders::ALL)
+            .title(format!(
+                "{} ({}/{}, Enter for the next value, Esc to cancel)",
+                title,
+                fill.values.len() + 1,
+                fill.placeholders.len()
+            ))
+            .border_style(border_style),
+    );
+    let input = Paragraph::new(fill.input.as_str()).block(
+        Block::default()
+            .borders(Borders::ALL)
+            .title(input_title)
+            .border_style(border_style),
+    );
 
-    let sessions_highlight_style = if app.mode == AppMode::SessionSelection {
-        Style::default()
-            .bg(Color::Yellow)
-            .fg(Color::Black)
-            .add_modifier(Modifier::BOLD)
-    } else {
-        Style::default()
-            .bg(app.config.theme.parse_color(&app.config.theme.highlight_bg_color))
-            .fg(app.config.theme.parse_color(&app.config.theme.highlight_color))
-    };
+    f.render_widget(Clear, popup_area);
+    f.render_widget(summary, chunks[0]);
+    f.render_widget(input, chunks[1]);
+    f.set_cursor_position((chunks[1].x + fill.input.width() as u16 + 1, chunks[1].y + 1));
+}
 
-    let sessions_list = List::new(session_items)
+fn render_workflows_popup(f: &mut Frame, app: &mut AppState) {
+    let popup_area = centered_rect(80, 60, f.area());
+    let border_style = Style::default().fg(app.config.theme.parse_color(&app.config.theme.popup_border_color));
+    let chunks = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Percentage(35), Constraint::Percentage(65)])
+        .split(popup_area);
+    // Workflow files that failed to load are listed below the workflows
+    let errors = app.workflows.errors();
+    let left = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Min(0),
+            Constraint::Length(if errors.is_empty() { 0 } else { (errors.len() as u16 * 2 + 2).min(popup_area.height / 2) }),
+        ])
+        .split(chunks[0]);
+    let error_list = Paragraph::new(
+        errors
+            .iter()
+            .map(|error| Line::styled(error.clone(), Style::default().fg(Color::Red)))
+            .collect::<Vec<_>>(),
+    )
+    .wrap(Wrap { trim: false })
+    .block(Block::default().title("Not loaded").borders(Borders::ALL).border_style(border_style));
+
+    let items: Vec<ListItem> = app
+        .workflows
+        .workflows()
+        .iter()
+        .map(|workflow| {
+            ListItem::new(Line::from(vec![
+                Span::styled(workflow.name.clone(), Style::default().add_modifier(Modifier::BOLD)),
+                Span::styled(format!("  {} steps", workflow.steps.len()), Style::default().add_modifier(Modifier::DIM)),
+            ]))
+        })
+        .collect();
+    let list = List::new(items)
         .block(
             Block::default()
+                .title("Workflows (Enter run, e edit, c new, Esc/q close)")
                 .borders(Borders::ALL)
-                .title(sessions_title)
-                .border_style(sessions_border_style),
+                .border_style(border_style),
+        )
+        .highlight_style(
+            Style::default()
+                .bg(app.config.theme.parse_color(&app.config.theme.highlight_bg_color))
+                .fg(app.config.theme.parse_color(&app.config.theme.highlight_color))
+                .add_modifier(Modifier::BOLD),
         )
-        .highlight_style(sessions_highlight_style)
         .highlight_symbol(">> ");
 
-    f.render_stateful_widget(sessions_list, main_chunks[1], &mut app.session_list_state);
+    // Steps of the selected workflow, with their progress if it is the one that ran last.
+    // A one-off run, such as the shell block of a reply, is shown until it finishes.
+    let one_off = app
+        .workflow_run
+        .as_ref()
+        .filter(|run| !run.finished && !app.workflows.workflows().iter().any(|w| w.name == run.workflow.name))
+        .map(|run| &run.workflow);
+    let mut lines: Vec<Line> = Vec::new();
+    if let Some(workflow) = one_off.or(app.selected_workflow()) {
+        let is_run = app.workflow_run.as_ref().is_some_and(|run| run.workflow.name == workflow.name);
+        if !workflow.description.is_empty() {
+            lines.push(Line::from(workflow.description.clone()));
+            lines.push(Line::from(""));
+        }
+        if !workflow.triggers.is_empty() {
+            let triggers: Vec<String> = workflow.triggers.iter().map(|trigger| trigger.describe()).collect();
+            lines.push(Line::from(Span::styled(
+                format!("Runs {}", triggers.join(", ")),
+                Style::default().fg(Color::Cyan),
+            )));
+            lines.push(Line::from(""));
+        }
+        for (i, step) in workflow.steps.iter().enumerate() {
+            let status = if is_run { app.workflow_step_status(i) } else { workflow::StepStatus::Pending };
+            let color = match status {
+                workflow::StepStatus::Succeeded => Color::Green,
+                workflow::StepStatus::Failed => Color::Red,
+                workflow::StepStatus::Running | workflow::StepStatus::AwaitingApproval => Color::Yellow,
+                workflow::StepStatus::Pending | workflow::StepStatus::Skipped => Color::Gray,
+            };
+            let mut spans = vec![
+                Span::styled(format!("{} ", status.symbol()), Style::default().fg(color)),
+                Span::styled(format!("{}. {}", i + 1, step.name), Style::default().add_modifier(Modifier::BOLD)),
+            ];
+            if let Some(run) = app.workflow_run.as_ref().filter(|run| is_run && run.running == Some(i) && step.retry_count > 0) {
+                spans.push(Span::raw(format!("  attempt {}/{}", run.attempt, step.retry_count + 1)));
+            }
+            lines.push(Line::from(spans));
+            let command = match app.pending_commands.get(i).filter(|_| is_run) {
+                Some(command) => format!("   $ {} [{} risk]", command.command, command.risk.label()),
+                None => format!("   $ {}", workflow::expand_variables(&step.command, &workflow.variables)),
+            };
+            lines.push(Line::styled(command, Style::default().add_modifier(Modifier::DIM)));
+            let settings = step.settings();
+            if !settings.is_empty() {
+                lines.push(Line::styled(format!("   {}", settings), Style::default().add_modifier(Modifier::DIM)));
+            }
+        }
+    }
+    // Tail of the running step's output
+    if let Some(run) = app.workflow_run.as_ref().filter(|run| run.running.is_some() && !run.output.is_empty()) {
+        let output = String::from_utf8_lossy(&run.output);
+        let tail: Vec<&str> = output.lines().rev().take(8).collect();
+        lines.push(Line::from(""));
+        lines.extend(tail.into_iter().rev().map(|line| Line::styled(format!("   {}", line), Style::default().fg(Color::Gray))));
+    }
+    let keys = match &app.workflow_run {
+        Some(run) if !run.finished && app.command_approval_index.is_some() => "y approve, n stop",
+        Some(run) if !run.finished => "x stop",
+        _ => "",
+    };
+    let steps = Paragraph::new(lines).wrap(Wrap { trim: false }).block(
+        Block::default()
+            .title(if keys.is_empty() { "Steps".to_string() } else { format!("Steps ({})", keys) })
+            .borders(Borders::ALL)
+            .border_style(border_style),
+    );
 
-    if app.mode == AppMode::ModelSelection {
-        render_model_selection_popup(f, app);
+    f.render_widget(Clear, popup_area);
+    f.render_stateful_widget(list, left[0], &mut app.workflow_list_state);
+    if !app.workflows.errors().is_empty() {
+        f.render_widget(error_list, left[1]);
     }
-    
-    if app.mode == AppMode::Help {
-        render_help_popup(f, app);
+    f.render_widget(steps, chunks[1]);
+}
+
+fn render_command_history_popup(f: &mut Frame, app: &mut AppState) {
+    let popup_area = centered_rect(60, 60, f.area());
+    let border_style = Style::default().fg(app.config.theme.parse_color(&app.config.theme.popup_border_color));
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Length(3), Constraint::Min(0)])
+        .split(popup_area);
+
+    let filter = Paragraph::new(format!("/{}", app.history_filter)).block(
+        Block::default()
+            .borders(Borders::ALL)
+            .title("Command History (type to search, Enter to run, Tab to edit, Esc to close)")
+            .border_style(border_style),
+    );
+
+    let items: Vec<ListItem> = app
+        .filtered_command_history()
+        .into_iter()
+        .map(|command| ListItem::new(format!(":{}", command)))
+        .collect();
+    let list = List::new(items)
+        .block(Block::default().borders(Borders::ALL).border_style(border_style))
+        .highlight_style(
+            Style::default()
+                .bg(app.config.theme.parse_color(&app.config.theme.highlight_bg_color))
+                .fg(app.config.theme.parse_color(&app.config.theme.highlight_color))
+                .add_modifier(Modifier::BOLD),
+        )
+        .highlight_symbol(">> ");
+
+    f.render_widget(Clear, popup_area);
+    f.render_widget(filter, chunks[0]);
+    f.render_stateful_widget(list, chunks[1], &mut app.history_list_state);
+    f.set_cursor_position((chunks[0].x + app.history_filter.width() as u16 + 2, chunks[0].y + 1));
+}
+
+fn render_pinned_messages_popup(f: &mut Frame, app: &mut AppState) {
+    let popup_area = centered_rect(70, 60, f.area());
+    let block = Block::default()
+        .title("Pinned Messages (Enter to jump, p/d to unpin, Esc/q to close)")
+        .borders(Borders::ALL)
+        .border_style(Style::default().fg(app.config.theme.parse_color(&app.config.theme.popup_border_color)));
+
+    if app.pinned_messages.is_empty() {
+        let text = Paragraph::new("No pinned messages. Press p on a message or use :pin to pin it.")
+            .alignment(Alignment::Center)
+            .wrap(Wrap { trim: true })
+            .block(block);
+        f.render_widget(Clear, popup_area);
+        f.render_widget(text, popup_area);
+        return;
     }
+
+    let preview_width = (popup_area.width as usize).saturating_sub(8);
+    let items: Vec<ListItem> = app
+        .pinned_messages
+        .iter()
+        .map(|&(session_index, message_index)| {
+            let session = &app.sessions[session_index];
+            let message = &session.messages[message_index];
+            let prefix = match message.role {
+                models::Role::User => "You: ",
+                models::Role::Assistant => "AI: ",
+                models::Role::System => "System: ",
+            };
+            let first_line = message.content.lines().next().unwrap_or("");
+            let preview: String = format!("[{}] {}{}", session.name, prefix, first_line)
+                .chars()
+                .take(preview_width)
+                .collect();
+            ListItem::new(preview)
+        })
+        .collect();
+    let list = List::new(items)
+        .block(block)
+        .highlight_style(
+            Style::default()
+                .bg(app.config.theme.parse_color(&app.config.theme.highlight_bg_color))
+                .fg(app.config.theme.parse_color(&app.config.theme.highlight_color))
+                .add_modifier(Modifier::BOLD),
+        )
+        .highlight_symbol(">> ");
+
+    f.render_widget(Clear, popup_area);
+    f.render_stateful_widget(list, popup_area, &mut app.pinned_list_state);
 }
 
-fn render_model_selection_popup(f: &mut Frame, app: &mut AppState) {
-    let popup_area = centered_rect(60, 50, f.area());
+fn render_delete_message_popup(f: &mut Frame, app: &AppState) {
+    let popup_area = centered_rect(50, 25, f.area());
     let block = Block::default()
-        .title("Select a Model (Enter to confirm, Esc/q to cancel)")
+        .title("Delete message? (y/Enter to confirm, n/Esc to cancel)")
         .borders(Borders::ALL)
         .border_style(Style::default().fg(app.config.theme.parse_color(&app.config.theme.popup_border_color)));
 
+    let preview = app
+        .pending_delete_message
+        .and_then(|index| app.current_messages().get(index))
+        .map(|message| {
+            let prefix = match message.role {
+                models::Role::User => "You: ",
+                models::Role::Assistant => "AI: ",
+                models::Role::System => "System: ",
+            };
+            format!("{}{}", prefix, message.content)
+        })
+        .unwrap_or_default();
+
+    let text = Paragraph::new(preview)
+        .block(block)
+        .wrap(Wrap { trim: true });
+
+    f.render_widget(Clear, popup_area);
+    f.render_widget(text, popup_area);
+}
+
+/// Small list of Tab completion candidates drawn just above the input box.
+fn render_completion_popup(f: &mut Frame, app: &AppState, input_area: Rect) {
+    const MAX_VISIBLE: usize = 8;
+    let candidates = &app.completion_candidates;
+    let selected = app.completion_index.unwrap_or(0);
+    // Scroll the window so the applied candidate is always visible
+    let first = selected.saturating_sub(MAX_VISIBLE - 1);
+    // Command names are completed at offset 0; show what each one does
+    let visible: Vec<String> = candidates
+        .iter()
+        .skip(first)
+        .take(MAX_VISIBLE)
+        .map(|candidate| match commands::find_command(candidate) {
+            Some(info) if app.completion_start == 0 => format!("{:<8} {}", candidate, info.description),
+            _ => candidate.clone(),
+        })
+        .collect();
+
+    let width = visible
+        .iter()
+        .map(|c| c.width())
+        .max()
+        .unwrap_or(0)
+        .max(20) as u16
+        + 4;
+    let height = visible.len() as u16 + 2;
+    let area = Rect {
+        x: input_area.x + 1,
+        y: input_area.y.saturating_sub(height),
+        width: width.min(input_area.width.saturating_sub(1)),
+        height: height.min(input_area.y),
+    };
+
+    let items: Vec<ListItem> = visible
+        .iter()
+        .enumerate()
+        .map(|(i, candidate)| {
+            let style = if first + i == selected && app.completion_index.is_some() {
+                Style::default()
+                    .bg(app.config.theme.parse_color(&app.config.theme.highlight_bg_color))
+                    .fg(app.config.theme.parse_color(&app.config.theme.highlight_color))
+            } else {
+                Style::default()
+            };
+            ListItem::new(candidate.as_str()).style(style)
+        })
+        .collect();
+    let list = List::new(items).block(
+        Block::default()
+            .borders(Borders::ALL)
+            .title(format!("{}/{}", selected + 1, candidates.len()))
+            .border_style(Style::default().fg(app.config.theme.parse_color(&app.config.theme.popup_border_color))),
+    );
+
+    f.render_widget(Clear, area);
+    f.render_widget(list, area);
+}
+
+/// Compact age such as "just now", "5m ago" or "2h ago", falling back to a date.
+fn relative_time(now: chrono::DateTime<chrono::Utc>, then: chrono::DateTime<chrono::Utc>) -> String {
+    let seconds = (now - then).num_seconds().max(0);
+    match seconds {
+        0..60 => "just now".to_string(),
+        60..3600 => format!("{}m ago", seconds / 60),
+        3600..86400 => format!("{}h ago", seconds / 3600),
+        86400..604800 => format!("{}d ago", seconds / 86400),
+        _ => then.with_timezone(&chrono::Local).format("%Y-%m-%d").to_string(),
+    }
+}
+
+fn render_model_selection_popup(f: &mut Frame, app: &mut AppState) {
+    let popup_area = centered_rect(80, 50, f.area());
+    let border_style = Style::default().fg(app.config.theme.parse_color(&app.config.theme.popup_border_color));
+    let block = Block::default()
+        .title("Select a Model (type to filter, ↑/↓ to move, Ctrl+S to sort, Enter to confirm, Esc to cancel)")
+        .borders(Borders::ALL)
+        .border_style(border_style);
+
     if app.is_fetching_models {
         let text = Paragraph::new("Fetching models...")
             .alignment(Alignment::Center)
@@ -205,7 +1746,7 @@ fn render_model_selection_popup(f: &mut Frame, app: &mut AppState) {
 
     if app.available_models.is_empty() {
         let text = Paragraph::new(
-            "No models found. Ensure Ollama is running and models are pulled. Press 'q' to close.",
+            "No models found. Ensure Ollama is running and models are pulled. Press Esc to close.",
         )
         .alignment(Alignment::Center)
         .wrap(Wrap { trim: true })
@@ -215,13 +1756,58 @@ fn render_model_selection_popup(f: &mut Frame, app: &mut AppState) {
         return;
     }
 
-    let items: Vec<ListItem> = app
-        .available_models
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Length(3), Constraint::Min(0)])
+        .split(popup_area);
+    let filtered = app.filtered_models();
+    let filter = Paragraph::new(format!("/{}", app.model_filter)).block(
+        block.title_bottom(format!(
+            "{}/{} models, sorted by {}",
+            filtered.len(),
+            app.available_models.len(),
+            app.model_sort.label()
+        )),
+    );
+
+    let name_width = filtered
         .iter()
-        .map(|s| ListItem::new(s.as_str()))
+        .map(|&i| app.available_models[i].name.width())
+        .max()
+        .unwrap_or(0);
+    let items: Vec<ListItem> = filtered
+        .iter()
+        .map(|&i| {
+            let model = &app.available_models[i];
+            let modified = model
+                .modified_at
+                .map(|t| t.with_timezone(&chrono::Local).format("%Y-%m-%d").to_string())
+                .unwrap_or_default();
+            // Short digest, as shown by `ollama list`
+            let id: String = model.digest.chars().take(12).collect();
+            ListItem::new(format!(
+                "{:<name_width$}  {:<12}  {:>6}  {:<7}  {:>9}  {:<10}  {}",
+                model.name,
+                id,
+                model.details.parameter_size,
+                model.details.quantization_level,
+                model.size_display(),
+                model.details.family,
+                modified,
+            ))
+        })
         .collect();
+    let header = format!(
+        "   {:<name_width$}  {:<12}  {:>6}  {:<7}  {:>9}  {:<10}  {}",
+        "NAME", "ID", "PARAMS", "QUANT", "SIZE", "FAMILY", "MODIFIED"
+    );
     let list = List::new(items)
-        .block(block)
+        .block(
+            Block::default()
+                .title(header)
+                .borders(Borders::ALL)
+                .border_style(border_style),
+        )
         .highlight_style(
             Style::default()
                 .bg(app.config.theme.parse_color(&app.config.theme.highlight_bg_color))
@@ -231,7 +1817,9 @@ fn render_model_selection_popup(f: &mut Frame, app: &mut AppState) {
         .highlight_symbol(">> ");
 
     f.render_widget(Clear, popup_area);
-    f.render_stateful_widget(list, popup_area, &mut app.model_list_state);
+    f.render_widget(filter, chunks[0]);
+    f.render_stateful_widget(list, chunks[1], &mut app.model_list_state);
+    f.set_cursor_position((chunks[0].x + app.model_filter.width() as u16 + 2, chunks[0].y + 1));
 }
 
 fn centered_rect(percent_x: u16, percent_y: u16, r: Rect) -> Rect {
@@ -260,12 +1848,15 @@ fn render_messages<'a>(messages: &'a [models::Message], width: u16, theme: &crat
         let style = match message.role {
             models::Role::User => Style::default().fg(theme.parse_color(&theme.user_message_color)),
             models::Role::Assistant => Style::default().fg(theme.parse_color(&theme.assistant_message_color)),
+            models::Role::System => Style::default().fg(theme.parse_color(&theme.status_bar_color)),
         };
         let prefix = match message.role {
             models::Role::User => "You: ",
             models::Role::Assistant => "AI: ",
+            models::Role::System => "System: ",
         };
-        let wrapped_content = wrap(&message.content, (width as usize).saturating_sub(6));
+        let content = message.display_content();
+        let wrapped_content = wrap(&content, (width as usize).saturating_sub(6));
         for (i, line_content) in wrapped_content.iter().enumerate() {
             if i == 0 {
                 lines.push(Line::from(vec![
@@ -286,46 +1877,152 @@ fn render_messages<'a>(messages: &'a [models::Message], width: u16, theme: &crat
     Text::from(lines)
 }
 
-fn render_messages_as_list<'a>(messages: &'a [models::Message], width: u16, theme: &crate::models::Theme, visual_selection: Option<(usize, usize)>) -> Vec<ListItem<'a>> {
+// `style` colored for a line of a diff, unchanged for other lines
+fn diff_style(style: Style, kind: markdown::LineKind) -> Style {
+    match kind {
+        markdown::LineKind::Text => style,
+        markdown::LineKind::DiffHeader => style.add_modifier(Modifier::BOLD),
+        markdown::LineKind::DiffHunk => style.fg(Color::Cyan),
+        markdown::LineKind::DiffAdded => style.fg(Color::Green),
+        markdown::LineKind::DiffRemoved => style.fg(Color::Red),
+        markdown::LineKind::Image => style.fg(Color::Magenta),
+        markdown::LineKind::ImageArea => style,
+    }
+}
+
+// A message passing the filter, with what its lines in the chat list need
+struct ShownMessage {
+    role: models::Role,
+    pinned: bool,
+    cached: bool,
+    empty: bool,
+    layout: Rc<MessageLayout>,
+}
+
+fn shown_messages(
+    messages: &[models::Message],
+    filter: Option<&ChatFilter>,
+    layouts: &LayoutCache,
+    wrap_width: usize,
+    images: image::Settings,
+) -> Vec<ShownMessage> {
+    messages
+        .iter()
+        .filter(|message| filter.is_none_or(|filter| filter.matches(message)))
+        .map(|message| ShownMessage {
+            role: message.role.clone(),
+            pinned: message.pinned,
+            cached: message.cached,
+            empty: message.content.is_empty(),
+            layout: layouts.get(message, wrap_width, images),
+        })
+        .collect()
+}
+
+// Lines the messages take up in the chat list
+fn line_count(messages: &[ShownMessage]) -> usize {
+    messages.iter().map(|message| message.layout.lines.len() + usize::from(!message.empty)).sum()
+}
+
+// Draw the chat list with `state`, making list items of only the lines that can come
+// into view: those within a screen of the scroll offset and the selection. Long
+// sessions would otherwise turn every line of every message into an item each frame
+#[allow(clippy::too_many_arguments)]
+fn render_chat_list(
+    f: &mut Frame,
+    messages: &[ShownMessage],
+    theme: &crate::models::Theme,
+    visual_selection: Option<(usize, usize)>,
+    block: Block,
+    area: Rect,
+    state: &mut ListState,
+    image_lines: &mut Vec<(usize, String)>,
+) {
+    let total = line_count(messages);
+    let height = area.height.saturating_sub(2) as usize;
+    // Like the list itself would, keep the selection on a line that exists
+    let selected = state.selected().map(|selected| selected.min(total.saturating_sub(1)));
+    let offset = state.offset().min(total.saturating_sub(1));
+    let start = selected.map_or(offset, |selected| selected.min(offset)).saturating_sub(height);
+    let end = selected.map_or(offset, |selected| selected.max(offset)) + 2 * height;
+    let items = render_messages_as_list(messages, theme, visual_selection, start..end, image_lines);
+    let list = List::new(items)
+        .block(block)
+        .highlight_style(Style::default().bg(Color::DarkGray).add_modifier(Modifier::DIM))
+        .highlight_symbol("  ");  // Less intrusive highlight
+    let mut window_state = ListState::default().with_offset(offset - start).with_selected(selected.map(|selected| selected - start));
+    f.render_stateful_widget(list, area, &mut window_state);
+    *state.offset_mut() = start + window_state.offset();
+    state.select(window_state.selected().map(|selected| selected + start));
+}
+
+// Items of the lines within `window`; `image_lines` gets the line index and path of every
+// image line among them
+fn render_messages_as_list<'a>(
+    messages: &'a [ShownMessage],
+    theme: &crate::models::Theme,
+    visual_selection: Option<(usize, usize)>,
+    window: Range<usize>,
+    image_lines: &mut Vec<(usize, String)>,
+) -> Vec<ListItem<'a>> {
     let mut list_items = Vec::new();
     let mut line_index = 0;
     
     for message in messages {
+        let lines = message.layout.lines.len() + usize::from(!message.empty);
+        // Messages wholly outside the window only count their lines
+        if line_index + lines <= window.start || line_index >= window.end {
+            line_index += lines;
+            continue;
+        }
         let style = match message.role {
             models::Role::User => Style::default().fg(theme.parse_color(&theme.user_message_color)),
             models::Role::Assistant => Style::default().fg(theme.parse_color(&theme.assistant_message_color)),
+            models::Role::System => Style::default().fg(theme.parse_color(&theme.status_bar_color)),
         };
         let prefix = match message.role {
             models::Role::User => "You: ",
             models::Role::Assistant => "AI: ",
+            models::Role::System => "System: ",
         };
         
-        let wrapped_content = wrap(&message.content, (width as usize).saturating_sub(6));
-        
-        for (i, line_content) in wrapped_content.iter().enumerate() {
+        for (i, (line_content, kind)) in message.layout.lines.iter().enumerate() {
+            if !window.contains(&line_index) {
+                line_index += 1;
+                continue;
+            }
+            if *kind == markdown::LineKind::Image
+                && let Some((_, path)) = image::find_ref(line_content)
+            {
+                image_lines.push((line_index, path.to_string()));
+            }
             // Check if this line is within the visual selection
             let line_style = if let Some((start, end)) = visual_selection {
                 if line_index >= start && line_index <= end {
                     style.bg(Color::Blue).add_modifier(Modifier::REVERSED)
                 } else {
-                    style
+                    diff_style(style, *kind)
                 }
             } else {
-                style
+                diff_style(style, *kind)
             };
             
             if i == 0 {
                 // First line with prefix
+                let pin_marker = if message.pinned { "★ " } else { "" };
+                let cached_badge = if message.cached { "cached " } else { "" };
                 let line = Line::from(vec![
+                    Span::styled(pin_marker, line_style.fg(Color::Yellow)),
+                    Span::styled(cached_badge, line_style.fg(Color::DarkGray)),
                     Span::styled(prefix, line_style.add_modifier(Modifier::BOLD)),
-                    Span::styled(line_content.to_string(), line_style),
+                    Span::styled(line_content.as_str(), line_style),
                 ]);
                 list_items.push(ListItem::new(line));
             } else {
                 // Continuation lines with indentation
                 let line = Line::from(vec![
                     Span::raw("    "),
-                    Span::styled(line_content.to_string(), line_style),
+                    Span::styled(line_content.as_str(), line_style),
                 ]);
                 list_items.push(ListItem::new(line));
             }
@@ -333,7 +2030,7 @@ fn render_messages_as_list<'a>(messages: &'a [models::Message], width: u16, them
         }
         
         // Add empty line after each message if content is not empty
-        if !message.content.is_empty() {
+        if !message.empty {
             // Check if the empty line is within the visual selection
             let empty_line_style = if let Some((start, end)) = visual_selection {
                 if line_index >= start && line_index <= end {
@@ -345,7 +2042,9 @@ fn render_messages_as_list<'a>(messages: &'a [models::Message], width: u16, them
                 Style::default()
             };
             
-            list_items.push(ListItem::new(Line::from("")).style(empty_line_style));
+            if window.contains(&line_index) {
+                list_items.push(ListItem::new(Line::from("")).style(empty_line_style));
+            }
             line_index += 1;
         }
     }
@@ -355,71 +2054,56 @@ fn render_messages_as_list<'a>(messages: &'a [models::Message], width: u16, them
 
 fn render_help_popup(f: &mut Frame, app: &mut AppState) {
     let popup_area = centered_rect(80, 70, f.area());
+    let sections = help::filter(help::sections(app, &app.help_context), &app.help_filter);
+    let key_width = sections
+        .iter()
+        .flat_map(|section| section.entries.iter().map(|(keys, _)| keys.width()))
+        .max()
+        .unwrap_or(0)
+        .min(24);
+    let mut lines = Vec::new();
+    for section in &sections {
+        if !lines.is_empty() {
+            lines.push(Line::from(""));
+        }
+        lines.push(Line::from(Span::styled(section.title.clone(), Style::default().add_modifier(Modifier::BOLD))));
+        for (keys, description) in &section.entries {
+            lines.push(Line::from(vec![
+                Span::styled(format!("  {:<width$}  ", keys, width = key_width), Style::default().fg(Color::Yellow)),
+                Span::raw(description.clone()),
+            ]));
+        }
+    }
+    if lines.is_empty() {
+        lines.push(Line::from("Nothing matches the search."));
+    }
+
+    // Keep the last page in view when scrolled past the end
+    let height = popup_area.height.saturating_sub(3);
+    app.help_scroll = app.help_scroll.min((lines.len() as u16).saturating_sub(height));
     let block = Block::default()
-        .title("Help - Vim-style Commands (Press ? or ESC to close)")
+        .title("Help (/ to search, j/k to scroll, ? or ESC to close)")
         .borders(Borders::ALL)
         .border_style(Style::default().fg(app.config.theme.parse_color(&app.config.theme.popup_border_color)));
-
-    let help_text = vec![
-        "VIM-STYLE NAVIGATION AND COMMANDS",
-        "",
-        "MODES:",
-        "  Normal Mode    - Navigate and issue commands",
-        "  Insert Mode    - Type messages",
-        "  Command Mode   - Enter vim-style commands",
-        "",
-        "NORMAL MODE KEYS:",
-        "  i              - Enter insert mode",
-        "  o/O            - Enter insert mode (clear input)",
-        "  v              - Enter visual mode (select text)",
-        "  :              - Enter command mode",
-        "  ?              - Show this help",
-        "  q              - Quick quit",
-        "  j/↓            - Scroll down",
-        "  k/↑            - Scroll up",
-        "  g              - Go to top",
-        "  G              - Go to bottom",
-        "  PgUp/PgDn      - Page up/down",
-        "",
-        "INSERT MODE KEYS:",
-        "  ESC            - Return to normal mode",
-        "  Enter          - Send message",
-        "  Backspace      - Delete character",
-        "",
-        "VISUAL MODE KEYS:",
-        "  j/k or ↑/↓     - Extend selection",
-        "  g              - Go to top",
-        "  G              - Go to bottom",
-        "  PgUp/PgDn      - Page up/down",
-        "  y              - Copy selection to clipboard",
-        "  ESC            - Return to normal mode",
-        "",
-        "COMMAND MODE COMMANDS:",
-        "  :q             - Quit application",
-        "  :w             - Save current session",
-        "  :wq            - Save and quit",
-        "  :n             - Create new session",
-        "  :c             - Clear current session",
-        "  :m             - Select model",
-        "  :s             - Select session",
-        "  :a             - Enter agent mode",
-        "  :h or :?       - Show this help",
-        "  :d             - Delete current session",
-        "  :d<N>          - Delete session N",
-        "  :b<N>          - Switch to session N",
-        "",
-        "SPECIAL MODES:",
-        "  Model Selection - Use j/k or ↑/↓ to navigate, Enter to select",
-        "  Session Selection - Use j/k or ↑/↓ to navigate, Enter to select, d to delete",
-        "  Agent Mode     - Interactive AI agent (experimental)",
-    ];
-
-    let help_paragraph = Paragraph::new(help_text.join("\n"))
-        .block(block)
-        .wrap(Wrap { trim: true })
-        .scroll((0, 0));
+    let inner = block.inner(popup_area);
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Min(0), Constraint::Length(1)])
+        .split(inner);
+    let search = if app.help_searching {
+        format!("/{}", app.help_filter)
+    } else if !app.help_filter.is_empty() {
+        format!("/{}  (ESC to clear)", app.help_filter)
+    } else {
+        String::new()
+    };
 
     f.render_widget(Clear, popup_area);
-    f.render_widget(help_paragraph, popup_area);
+    f.render_widget(block, popup_area);
+    f.render_widget(Paragraph::new(lines).scroll((app.help_scroll, 0)), chunks[0]);
+    f.render_widget(Paragraph::new(search).style(Style::default().fg(Color::Cyan)), chunks[1]);
+    if app.help_searching {
+        f.set_cursor_position((chunks[1].x + 1 + app.help_filter.width() as u16, chunks[1].y));
+    }
 }
 
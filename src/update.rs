@@ -0,0 +1,846 @@
+// Applies the events of the main loop to the app state. `handle` only dispatches, each
+// event has a handler of its own below, so a new event is a new arm and a new function.
+
+use crate::app::{self, AppState};
+use crate::events::{self, AppEvent};
+use crate::{agent, cache, db, memory, models, notify, ollama, redact, server, toast, workflow};
+use ratatui::layout::Size;
+use tokio::sync::mpsc;
+
+// Bytes of a running command's output kept for the live output panes
+const LIVE_OUTPUT_LIMIT: usize = 64 * 1024;
+
+/// What the main loop does after an event.
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    /// Nothing shown changed, no frame is needed
+    Unchanged,
+    /// The screen is drawn again once the next frame is due
+    Changed,
+    Quit,
+}
+
+/// Apply `event` to `app`. `size` is the terminal size, for ticks keeping the chat
+/// scrolled to the bottom.
+pub async fn handle(app: &mut AppState, event: AppEvent, size: Size, tx: &mpsc::Sender<AppEvent>) -> Outcome {
+    match event {
+        AppEvent::Terminal(key) => {
+            if key.kind == crossterm::event::KeyEventKind::Press && events::handle_key_event(key, app, tx.clone()).await {
+                return Outcome::Quit;
+            }
+            start_requested_work(app, tx);
+        }
+        // A tick says itself whether it changed anything
+        AppEvent::Tick => return tick(app, size),
+        AppEvent::OllamaChunk(session_id, generation, Ok(chunk)) => ollama_chunk(app, session_id, generation, chunk),
+        AppEvent::OllamaChunk(session_id, generation, Err(e)) => ollama_error(app, session_id, generation, e),
+        AppEvent::OllamaDone(session_id, generation) => ollama_done(app, session_id, generation, tx),
+        AppEvent::GenerateContext(session_id, generation, context) => {
+            if let Some(reply) = app.generation_reply(session_id, generation) {
+                reply.context = Some(context);
+            }
+        }
+        AppEvent::CompareChunk(side, content) => compare_chunk(app, side, content),
+        AppEvent::CompareDone(side) => compare_done(app, side, tx),
+        AppEvent::BenchSample(sample) => bench_sample(app, sample),
+        AppEvent::ConfigChanged(Ok(config)) => config_changed(app, *config),
+        AppEvent::ConfigChanged(Err(e)) => {
+            app.push_toast(toast::Severity::Error, format!("Config not reloaded, the previous one stays in use: {}", e));
+        }
+        AppEvent::Focus(focused) => app.terminal_focused = focused,
+        AppEvent::Resize(width, height) => resize(app, width, height),
+        AppEvent::Models(result) => models_fetched(app, result),
+        AppEvent::AgentPlan(result) => agent_plan(app, result),
+        AppEvent::AgentReasoning(result) => agent_reasoning(app, result, tx),
+        AppEvent::CommandOutputChunk(chunk) => {
+            append_live_output(&mut app.live_command_output, &chunk);
+        }
+        AppEvent::AgentCommandOutput(output) => agent_command_output(app, output, tx),
+        AppEvent::AgentAnalysis(result) => agent_analysis(app, result, tx),
+        AppEvent::Ingested(session_id, Ok(chunks)) => {
+            if let Err(e) = app.finish_ingest(session_id, chunks) {
+                app.push_toast(toast::Severity::Error, format!("Cannot save ingested documents: {}", e));
+            }
+        }
+        AppEvent::Ingested(_, Err(e)) => {
+            app.push_toast(toast::Severity::Error, format!("Ingest failed: {}", e));
+        }
+        AppEvent::PipeOutput(request, result) => pipe_output(app, request, result),
+        AppEvent::CommitMessage(result) => {
+            app.clear_status_message();
+            match result {
+                Ok(message) => app.review_commit_message(message.text()),
+                Err(e) => app.push_toast(toast::Severity::Error, format!("Cannot write commit message: {}", e)),
+            }
+        }
+        AppEvent::ServerRequest(request, reply) => {
+            reply.send(server::respond(app, request, tx.clone())).ok();
+        }
+        AppEvent::HowTo(result) => {
+            app.clear_status_message();
+            match result {
+                Ok(suggestion) => app.review_howto(suggestion),
+                Err(e) => app.push_toast(toast::Severity::Error, format!("Cannot suggest a command: {}", e)),
+            }
+        }
+        AppEvent::MemoryFacts(session_id, Ok(response)) => memory_facts(app, session_id, response),
+        // Extraction runs in the background, a failure is not worth interrupting for
+        AppEvent::MemoryFacts(_, Err(_)) => {}
+        AppEvent::AgentCommands(commands) => app.set_pending_commands(commands),
+        AppEvent::WorkflowDraft(result) => match result.and_then(workflow::from_draft) {
+            Ok(workflow) => app.review_workflow_draft(workflow),
+            Err(e) => app.push_toast(toast::Severity::Error, format!("Cannot draft workflow: {}", e)),
+        },
+        AppEvent::WorkflowProgress(index, event) => workflow_progress(app, index, event),
+        AppEvent::CommandExecuted(index, output) => command_executed(app, index, output, tx),
+    }
+    Outcome::Changed
+}
+
+// Start what the key just handled asked for in the background
+fn start_requested_work(app: &mut AppState, tx: &mpsc::Sender<AppEvent>) {
+    // Kick off a goal started with :auto
+    if app.autonomous.as_ref().is_some_and(|run| run.state == agent::AgentState::Starting) {
+        events::request_agent_plan(app, tx.clone());
+    }
+
+    // Draft the workflow described with :wf generate
+    if app.pending_workflow_request.is_some() {
+        events::request_workflow_draft(app, tx.clone());
+    }
+
+    // Describe the changes staged for :gitmsg
+    if app.pending_commit_diff.is_some() {
+        events::request_commit_message(app, tx.clone());
+    }
+
+    // Answer the question of :howto
+    if app.pending_howto.is_some() {
+        events::request_howto(app, tx.clone());
+    }
+
+    // Run the prompts of :bench
+    if app.pending_bench.is_some() {
+        events::start_bench(app, tx.clone());
+    }
+
+    // Embed documents read by :ingest
+    if app.pending_ingest.is_some() {
+        events::start_ingest(app, tx.clone());
+    }
+
+    // Pipe the last reply through the command of :!
+    if app.pending_pipe.is_some() {
+        events::start_pipe(app, tx.clone());
+    }
+
+    // Fetch the models for the model selection just opened
+    if app.mode == app::AppMode::ModelSelection && app.is_fetching_models {
+        spawn_model_fetch(app, tx);
+    }
+}
+
+/// Fetch the models Ollama has, answered by `AppEvent::Models`.
+pub fn spawn_model_fetch(app: &AppState, tx: &mpsc::Sender<AppEvent>) {
+    let models_tx = tx.clone();
+    let http_client = app.http_client.clone();
+    let base_url = app.ollama_base_url.clone();
+    let auth_config = app.config.auth_method.clone();
+    let auth_enabled = app.config.auth_enabled;
+
+    tokio::spawn(async move {
+        let result = ollama::fetch_models(&http_client, &base_url, auth_enabled, auth_config.as_ref()).await;
+        models_tx.send(AppEvent::Models(result)).await.ok();
+    });
+}
+
+fn tick(app: &mut AppState, size: Size) -> Outcome {
+    let mut changed = app.toasts.expire();
+    changed |= app.expire_status_message();
+    let selected = app.chat_list_state.selected();
+    // Handle auto-scroll for list view
+    app.update_terminal_dimensions(size.width, size.height);
+
+    // Calculate chat area dimensions to match UI layout exactly:
+    // 75% width for left side (this matches the UI layout)
+    let chat_width = (size.width * 3) / 4;
+    // Total height minus input (3 lines) and status (1 line) minus borders (2 lines)
+    let chat_height = size.height.saturating_sub(6);
+
+    // Only do auto-scroll during tick if we're not actively loading
+    if app.auto_scroll && !app.is_loading() {
+        app.auto_scroll_to_bottom(chat_height, chat_width);
+    }
+    changed |= app.chat_list_state.selected() != selected;
+    if changed { Outcome::Changed } else { Outcome::Unchanged }
+}
+
+fn ollama_chunk(app: &mut AppState, session_id: i64, generation: u64, chunk: String) {
+    let is_current = session_id == app.current_session_id();
+    if let Some(reply) = app.generation_reply(session_id, generation) {
+        reply.content.push_str(&chunk);
+        // Enable auto-scroll but don't trigger it on every chunk
+        if is_current {
+            app.auto_scroll = app.options.autoscroll;
+        }
+    }
+}
+
+fn ollama_error(app: &mut AppState, session_id: i64, generation: u64, e: String) {
+    // Failures of a generation replaced since are of no interest
+    if app.generations.get(&session_id) != Some(&generation) {
+        return;
+    }
+    // The reply keeps what arrived before the failure, the error is only a toast
+    let message = match app.sessions.iter().find(|s| s.id == session_id) {
+        Some(session) if session_id != app.current_session_id() => format!("Request in {} failed: {}", session.name, e),
+        _ => format!("Request failed: {}", e),
+    };
+    app.push_toast(toast::Severity::Error, message);
+    app.loading_sessions.remove(&session_id);
+    // A reply cut short is not worth keeping
+    app.pending_cache_keys.remove(&session_id);
+}
+
+fn ollama_done(app: &mut AppState, session_id: i64, generation: u64, tx: &mpsc::Sender<AppEvent>) {
+    if app.generations.get(&session_id) != Some(&generation) {
+        return;
+    }
+    app.generations.remove(&session_id);
+    app.loading_sessions.remove(&session_id);
+    let cache_key = app.pending_cache_keys.remove(&session_id);
+    if let Some(started) = app.generation_started.remove(&session_id)
+        && notify::should_notify(&app.config.notifications, app.terminal_focused, started.elapsed())
+        && let Some(session) = app.sessions.iter().find(|s| s.id == session_id)
+    {
+        notify::notify(&app.config.notifications, "ollama-tui", &format!("Response ready in {}", session.name));
+    }
+    // The session may have been deleted while the response was streaming
+    let Some(index) = app.session_index_by_id(session_id) else {
+        return;
+    };
+    let is_current = index == app.current_session_index;
+
+    // Trigger auto-scroll when streaming is complete
+    if is_current && app.auto_scroll {
+        app.trigger_auto_scroll();
+    }
+    if !is_current {
+        app.unread_sessions.insert(session_id);
+    }
+
+    // Parse commands if in agent mode
+    if is_current
+        && app.agent_mode
+        && let Some(last_message) = app.current_messages().last()
+        && last_message.role == models::Role::Assistant
+    {
+        // TODO: Parse commands from response when agent module is implemented
+        // let commands = agent::Agent::parse_commands_from_response(&last_message.content);
+        // if !commands.is_empty() {
+        //     tx.send(events::AppEvent::AgentCommands(commands)).await.ok();
+        // }
+    }
+
+    save_reply(app, index, generation, cache_key, tx);
+
+    // Send the next prompt typed while this response was generating
+    if let Some(prompt) = app.take_queued_prompt(session_id) {
+        events::send_prompt(app, session_id, prompt, tx.clone());
+    }
+}
+
+// Save the finished reply of `generation` and the prompt it answers
+fn save_reply(app: &mut AppState, index: usize, generation: u64, cache_key: Option<String>, tx: &mpsc::Sender<AppEvent>) {
+    let session_id = app.sessions[index].id;
+    // The reply may have been deleted while it streamed, its prompt is right before it
+    let messages = &mut app.sessions[index].messages;
+    let Some(reply) = messages.iter().rposition(|message| message.generation == Some(generation)).filter(|&reply| reply >= 1)
+    else {
+        return;
+    };
+    messages[reply].generation = None;
+    // Secrets in the exchange are removed before it is saved or mined for memories
+    for message in &mut messages[reply - 1..=reply] {
+        redact::message(message, &app.redaction);
+    }
+    if let Some(key) = &cache_key
+        && !messages[reply].content.is_empty()
+        && let Err(e) = cache::store(&app.db_conn, key, &messages[reply].content)
+    {
+        app.push_toast(toast::Severity::Warning, format!("Cannot cache the reply: {}", e));
+    }
+    let messages = &app.sessions[index].messages;
+    let user_msg = &messages[reply - 1];
+    let assistant_msg = &messages[reply];
+    if app.options.memory && !assistant_msg.content.is_empty() {
+        events::request_memory_extraction(app, session_id, &user_msg.content, &assistant_msg.content, tx.clone());
+    }
+    let user_id = db::save_message(&app.db_conn, session_id, user_msg).ok();
+    let assistant_id = db::save_message(&app.db_conn, session_id, assistant_msg).ok();
+
+    // Remember row ids so the messages can be edited or deleted later
+    let session = &mut app.sessions[index];
+    session.messages[reply - 1].id = user_id;
+    session.messages[reply].id = assistant_id;
+    session.last_activity = chrono::Utc::now();
+    app.sort_sessions();
+}
+
+fn compare_chunk(app: &mut AppState, side: usize, content: Result<String, String>) {
+    let Some(reply) = app.comparison.as_mut().and_then(|comparison| comparison.replies.get_mut(side)) else {
+        return;
+    };
+    match content {
+        Ok(chunk) => reply.content.push_str(&chunk),
+        Err(e) => {
+            let message = format!("{} failed: {}", reply.model, e);
+            app.push_toast(toast::Severity::Error, message);
+        }
+    }
+}
+
+fn compare_done(app: &mut AppState, side: usize, tx: &mpsc::Sender<AppEvent>) {
+    if let Some(comparison) = app.comparison.as_mut() {
+        comparison.finish(side);
+    }
+    if let Some(session_id) = app.finish_comparison()
+        && let Some(prompt) = app.take_queued_prompt(session_id)
+    {
+        events::send_prompt(app, session_id, prompt, tx.clone());
+    }
+}
+
+fn bench_sample(app: &mut AppState, sample: crate::bench::Sample) {
+    if let Some(bench) = app.bench.as_mut().filter(|bench| bench.is_running()) {
+        bench.samples.push(sample);
+        if !bench.is_running() {
+            app.push_toast(toast::Severity::Info, "Benchmark finished".to_string());
+        }
+    }
+}
+
+fn config_changed(app: &mut AppState, config: models::Config) {
+    match app.reload_config(config) {
+        Ok(true) => app.push_toast(
+            toast::Severity::Warning,
+            "Config reloaded; server, authentication and database changes apply after a restart".to_string(),
+        ),
+        Ok(false) => app.push_toast(toast::Severity::Info, "Config reloaded".to_string()),
+        Err(e) => app.push_toast(toast::Severity::Error, format!("Config not reloaded, the previous one stays in use: {}", e)),
+    }
+}
+
+fn resize(app: &mut AppState, width: u16, height: u16) {
+    // Lines wrapped at the old width are of no use any more, the messages are
+    // wrapped again once at the final one
+    app.update_terminal_dimensions(width, height);
+    app.layout.clear();
+    app.trigger_auto_scroll();
+}
+
+fn models_fetched(app: &mut AppState, result: Result<Vec<models::ModelDetails>, String>) {
+    app.is_fetching_models = false;
+    match result {
+        Ok(models) => app.set_available_models(models),
+        Err(e) => {
+            app.available_models.clear(); // Clear any stale models
+            app.push_toast(toast::Severity::Error, format!("Cannot fetch models: {}. Is Ollama running?", e));
+        }
+    }
+}
+
+fn finish_autonomous_run(app: &mut AppState, state: agent::AgentState, summary: Option<String>) {
+    let Some(run) = app.autonomous.as_mut() else {
+        return;
+    };
+    let session_id = run.session_id;
+    let steps = run.steps.len();
+    let message = match (&state, summary) {
+        (agent::AgentState::Completed, Some(summary)) => format!("Goal completed in {} steps: {}", steps, summary),
+        (agent::AgentState::Completed, None) => format!("Goal completed in {} steps.", steps),
+        _ => format!("Autonomous run {}.", state.label()),
+    };
+    run.state = state;
+    app.push_session_message(session_id, message).ok();
+}
+
+// Whether the autonomous run still waits in `state`, it may have been stopped while
+// the request was in flight
+fn run_is(app: &AppState, state: agent::AgentState) -> bool {
+    app.autonomous.as_ref().is_some_and(|run| run.state == state)
+}
+
+fn agent_plan(app: &mut AppState, result: Result<agent::PlanResponse, String>) {
+    if !run_is(app, agent::AgentState::Planning) {
+        return;
+    }
+    match result {
+        Ok(plan) => app.review_plan(plan.steps),
+        Err(e) => finish_autonomous_run(app, agent::AgentState::Failed(e), None),
+    }
+    app.persist_autonomous_run();
+}
+
+fn agent_reasoning(app: &mut AppState, result: Result<agent::ReasoningResponse, String>, tx: &mpsc::Sender<AppEvent>) {
+    if !run_is(app, agent::AgentState::Reasoning) {
+        return;
+    }
+    match result {
+        Ok(reasoning) => agent_step(app, reasoning, tx),
+        Err(e) => finish_autonomous_run(app, agent::AgentState::Failed(e), None),
+    }
+    app.persist_autonomous_run();
+}
+
+// Record the step the model chose and move on to its command or tool call
+fn agent_step(app: &mut AppState, reasoning: agent::ReasoningResponse, tx: &mpsc::Sender<AppEvent>) {
+    let Some(run) = app.autonomous.as_mut() else {
+        return;
+    };
+    // A tool call takes the place of the command
+    let tool = reasoning.tool.filter(|_| !reasoning.done);
+    let command = reasoning.command.filter(|c| !c.trim().is_empty() && !reasoning.done && tool.is_none());
+    run.steps.push(agent::AgentStep {
+        thought: reasoning.thought,
+        command: command.clone(),
+        tool: tool.clone(),
+        output: None,
+        exit_code: None,
+        analysis: None,
+    });
+    let step = run.steps.len();
+    let max_steps = run.max_steps;
+    if command.is_none() && tool.is_none() {
+        finish_autonomous_run(app, agent::AgentState::Completed, reasoning.summary);
+    } else if step > max_steps {
+        finish_autonomous_run(app, agent::AgentState::Failed(format!("step limit of {} reached", max_steps)), None);
+    } else if let Some(call) = tool {
+        events::advance_to_tool(app, call, tx.clone());
+    } else if let Some(command) = command {
+        events::advance_to_command(app, command, tx.clone());
+    }
+}
+
+// Only the tail of a command's output is shown, memory stays bounded for chatty commands
+fn append_live_output(output: &mut Vec<u8>, chunk: &[u8]) {
+    output.extend_from_slice(chunk);
+    let excess = output.len().saturating_sub(LIVE_OUTPUT_LIMIT);
+    output.drain(..excess);
+}
+
+fn agent_command_output(app: &mut AppState, output: agent::CommandOutput, tx: &mpsc::Sender<AppEvent>) {
+    app.command_cancel = None;
+    if let Some(mut entry) = app.pending_audit.take() {
+        entry.exit_code = output.exit_code;
+        entry.output = match &output.result {
+            Ok(text) | Err(text) => agent::truncate(text),
+        };
+        if let Err(e) = db::append_audit_entry(&app.db_conn, &entry) {
+            app.set_error_message(format!("Failed to write audit log: {}", e));
+        }
+    }
+    let Some(run) = app.autonomous.as_mut().filter(|run| run.state == agent::AgentState::Executing) else {
+        return;
+    };
+    let session_id = run.session_id;
+    let command = run.steps.last().and_then(|step| step.command.clone());
+    let succeeded = output.result.is_ok();
+    // Let a successful `cd` carry over to the following steps
+    let new_dir = match &command {
+        Some(command) if succeeded => agent::directory_after(command, &app.working_dir(session_id)),
+        _ => None,
+    };
+    if let Some(step) = app.autonomous.as_mut().and_then(|run| run.steps.last_mut()) {
+        step.output = Some(output.result);
+        step.exit_code = output.exit_code;
+    }
+    if let Some(dir) = new_dir {
+        app.change_directory(session_id, &dir.to_string_lossy()).ok();
+    }
+    // Start the workflows chained to this command
+    if let Some(command) = command.as_deref().filter(|_| succeeded) {
+        let triggered = app.workflows.triggered_by_command(command);
+        app.queue_workflows(triggered, session_id, Vec::new());
+        events::start_queued_workflow(app, tx.clone());
+    }
+    events::request_agent_analysis(app, tx.clone());
+    app.persist_autonomous_run();
+}
+
+fn agent_analysis(app: &mut AppState, result: Result<agent::AnalysisResponse, String>, tx: &mpsc::Sender<AppEvent>) {
+    if !run_is(app, agent::AgentState::Analyzing) {
+        return;
+    }
+    match result {
+        Ok(analysis) => {
+            if let Some(step) = app.autonomous.as_mut().and_then(|run| run.steps.last_mut()) {
+                step.analysis = Some(analysis.analysis);
+            }
+            if analysis.goal_achieved {
+                finish_autonomous_run(app, agent::AgentState::Completed, None);
+            } else {
+                events::advance_to_reasoning(app, tx.clone());
+            }
+        }
+        Err(e) => finish_autonomous_run(app, agent::AgentState::Failed(e), None),
+    }
+    app.persist_autonomous_run();
+}
+
+fn pipe_output(app: &mut AppState, request: app::PipeRequest, result: Result<String, String>) {
+    match result {
+        Ok(output) => {
+            app.clear_status_message();
+            if request.into_input {
+                app.input.push_str(output.trim_end());
+                app.mode = app::AppMode::Insert;
+            } else {
+                let lines = output.lines().map(str::to_string).collect();
+                app.show_info(&format!("!{}", request.command), lines);
+            }
+        }
+        Err(e) => app.push_toast(toast::Severity::Error, format!("!{} failed: {}", request.command, e)),
+    }
+}
+
+fn memory_facts(app: &mut AppState, session_id: i64, response: memory::FactsResponse) {
+    match app.remember(response.facts, Some(session_id)) {
+        Ok(0) => {}
+        Ok(added) => app.push_toast(toast::Severity::Info, format!("Remembered {} new fact(s), :memory lists them", added)),
+        Err(e) => app.push_toast(toast::Severity::Error, format!("Cannot save memories: {}", e)),
+    }
+}
+
+fn workflow_progress(app: &mut AppState, index: usize, event: workflow::StepEvent) {
+    let Some(run) = app.workflow_run.as_mut().filter(|run| run.running == Some(index)) else {
+        return;
+    };
+    match event {
+        workflow::StepEvent::Attempt(attempt) => {
+            run.attempt = attempt;
+            run.output.clear();
+            if attempt > 1 {
+                let step = &run.workflow.steps[index];
+                let message = format!("Retrying '{}' (attempt {}/{})", step.name, attempt, step.retry_count + 1);
+                app.set_status_message(message);
+            }
+        }
+        workflow::StepEvent::Output(chunk) => append_live_output(&mut run.output, &chunk),
+    }
+}
+
+fn command_executed(app: &mut AppState, index: usize, output: Option<agent::CommandOutput>, tx: &mpsc::Sender<AppEvent>) {
+    let Some(run) = app.workflow_run.as_mut().filter(|run| run.running == Some(index)) else {
+        return;
+    };
+    run.running = None;
+    run.cancel = None;
+    let session_id = run.session_id;
+    let stopped = run.finished;
+    let step = run.workflow.steps[index].clone();
+    let attempts = run.attempt;
+    let audit = run.audit.take();
+    let Some(output) = output else {
+        // The condition did not hold, the command never ran
+        run.skipped.push(index);
+        if let Some(cmd) = app.pending_commands.get_mut(index) {
+            cmd.executed = true;
+        }
+        let message = format!("Skipped '{}', its condition did not hold.", step.name);
+        app.push_session_message(session_id, message).ok();
+        if !stopped {
+            events::advance_workflow(app, agent::Approval::Automatic, tx.clone());
+        }
+        return;
+    };
+    let failure = output.result.is_err().then(|| match step.continue_on_error {
+        true => format!("Workflow '{}': step '{}' failed, continuing.", run.workflow.name, step.name),
+        false => format!("Workflow '{}' failed at step '{}'.", run.workflow.name, step.name),
+    });
+    if let Some(mut entry) = audit {
+        entry.exit_code = output.exit_code;
+        entry.output = match &output.result {
+            Ok(text) | Err(text) => agent::truncate(text),
+        };
+        if let Err(e) = db::append_audit_entry(&app.db_conn, &entry) {
+            app.set_error_message(format!("Failed to write audit log: {}", e));
+        }
+    }
+    let Some(cmd) = app.pending_commands.get_mut(index) else {
+        return;
+    };
+    cmd.executed = true;
+    let cmd_command = cmd.command.clone();
+    let tries = if attempts > 1 { format!(" after {} attempts", attempts) } else { String::new() };
+    // Add command output to chat
+    let message = match output.result {
+        Ok(output) => {
+            cmd.output = Some(output.clone());
+            format!("Command executed successfully{}:\n```\n{}\n```\n\nOutput:\n```\n{}\n```", tries, cmd_command, output)
+        }
+        Err(error) => {
+            cmd.error = Some(error.clone());
+            format!("Command failed{}:\n```\n{}\n```\n\nError:\n```\n{}\n```", tries, cmd_command, error)
+        }
+    };
+    app.push_session_message(session_id, message).ok();
+    if stopped {
+        return;
+    }
+    if let Some(failure) = failure {
+        app.push_session_message(session_id, failure.clone()).ok();
+        app.set_error_message(failure);
+        if !step.continue_on_error {
+            if let Some(run) = app.workflow_run.as_mut() {
+                run.finished = true;
+            }
+            // A failed workflow triggers nothing, but others may be queued
+            events::start_queued_workflow(app, tx.clone());
+            return;
+        }
+    }
+    events::advance_workflow(app, agent::Approval::Automatic, tx.clone());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn app() -> AppState {
+        let conn = db::get_connection(Path::new(":memory:")).unwrap();
+        AppState::with_connection(models::Config::default(), conn, None).unwrap()
+    }
+
+    // The prompt and the empty reply a new generation streams into, as send_prompt leaves them
+    fn start_reply(app: &mut AppState, prompt: &str) -> (i64, u64) {
+        let session_id = app.current_session_id();
+        let generation = app.start_generation(session_id);
+        app.loading_sessions.insert(session_id);
+        let index = app.current_session_index;
+        let messages = &mut app.sessions[index].messages;
+        messages.push(models::Message::new(models::Role::User, prompt.to_string()));
+        let mut reply = models::Message::new(models::Role::Assistant, String::new());
+        reply.generation = Some(generation);
+        messages.push(reply);
+        (session_id, generation)
+    }
+
+    fn reply(app: &AppState) -> &models::Message {
+        app.current_messages().last().unwrap()
+    }
+
+    fn last_toast(app: &AppState) -> Option<(toast::Severity, &str)> {
+        app.toasts.history.last().map(|toast| (toast.severity, toast.text.as_str()))
+    }
+
+    fn start_run(app: &mut AppState, state: agent::AgentState) {
+        let mut run = agent::AutonomousRun::new(app.current_session_id(), "build it".to_string());
+        run.state = state;
+        app.autonomous = Some(run);
+    }
+
+    fn run(app: &AppState) -> &agent::AutonomousRun {
+        app.autonomous.as_ref().unwrap()
+    }
+
+    fn step(command: Option<&str>) -> agent::AgentStep {
+        agent::AgentStep {
+            thought: "next".to_string(),
+            command: command.map(str::to_string),
+            tool: None,
+            output: None,
+            exit_code: None,
+            analysis: None,
+        }
+    }
+
+    fn reasoning(command: Option<&str>, done: bool) -> agent::ReasoningResponse {
+        agent::ReasoningResponse {
+            thought: "next".to_string(),
+            command: command.map(str::to_string),
+            tool: None,
+            done,
+            summary: None,
+        }
+    }
+
+    #[test]
+    fn chunks_of_a_replaced_generation_are_dropped() {
+        let mut app = app();
+        let (session_id, generation) = start_reply(&mut app, "hi");
+        ollama_chunk(&mut app, session_id, generation, "Hel".to_string());
+        ollama_chunk(&mut app, session_id, generation, "lo".to_string());
+        assert_eq!(reply(&app).content, "Hello");
+
+        app.start_generation(session_id);
+        ollama_chunk(&mut app, session_id, generation, " stale".to_string());
+        ollama_error(&mut app, session_id, generation, "stale failure".to_string());
+        assert_eq!(reply(&app).content, "Hello");
+        assert!(app.toasts.history.is_empty());
+        assert!(app.loading_sessions.contains(&session_id));
+    }
+
+    #[test]
+    fn done_redacts_and_saves_the_exchange() {
+        let mut app = app();
+        let (tx, _rx) = mpsc::channel(8);
+        let token = format!("ghp_{}", "a".repeat(36));
+        let (session_id, generation) = start_reply(&mut app, &format!("my token is {}", token));
+        ollama_chunk(&mut app, session_id, generation, format!("Do not share {}", token));
+        ollama_done(&mut app, session_id, generation, &tx);
+
+        assert!(!app.loading_sessions.contains(&session_id));
+        assert!(!app.generations.contains_key(&session_id));
+        assert_eq!(reply(&app).generation, None);
+        assert!(reply(&app).id.is_some());
+        let saved = db::load_sessions(&app.db_conn).unwrap();
+        let saved = &saved.iter().find(|session| session.id == session_id).unwrap().messages;
+        assert_eq!(saved.len(), 2);
+        for message in saved {
+            assert!(!message.content.contains(&token));
+            assert!(message.content.contains("[REDACTED:github_token]"));
+        }
+    }
+
+    #[test]
+    fn done_of_a_replaced_generation_saves_nothing() {
+        let mut app = app();
+        let (tx, _rx) = mpsc::channel(8);
+        let (session_id, generation) = start_reply(&mut app, "hi");
+        app.start_generation(session_id);
+        ollama_done(&mut app, session_id, generation, &tx);
+
+        assert!(app.loading_sessions.contains(&session_id));
+        assert_eq!(reply(&app).generation, Some(generation));
+        let saved = db::load_sessions(&app.db_conn).unwrap();
+        assert!(saved.iter().all(|session| session.messages.is_empty()));
+    }
+
+    #[test]
+    fn error_keeps_the_partial_reply() {
+        let mut app = app();
+        let (session_id, generation) = start_reply(&mut app, "hi");
+        app.pending_cache_keys.insert(session_id, "key".to_string());
+        ollama_chunk(&mut app, session_id, generation, "Partial".to_string());
+        ollama_error(&mut app, session_id, generation, "connection reset".to_string());
+
+        assert_eq!(reply(&app).content, "Partial");
+        assert!(!app.loading_sessions.contains(&session_id));
+        assert!(!app.pending_cache_keys.contains_key(&session_id));
+        assert_eq!(last_toast(&app), Some((toast::Severity::Error, "Request failed: connection reset")));
+    }
+
+    #[test]
+    fn reasoning_waits_for_approval_of_the_command() {
+        let mut app = app();
+        let (tx, _rx) = mpsc::channel(8);
+        start_run(&mut app, agent::AgentState::Reasoning);
+        agent_reasoning(&mut app, Ok(reasoning(Some("make"), false)), &tx);
+
+        assert_eq!(run(&app).state, agent::AgentState::Paused);
+        assert_eq!(run(&app).pending_command.as_deref(), Some("make"));
+        assert_eq!(run(&app).steps.len(), 1);
+        assert_ne!(run(&app).id, 0, "the run is saved");
+    }
+
+    #[test]
+    fn reasoning_finishes_or_fails_the_run() {
+        let mut app = app();
+        let (tx, _rx) = mpsc::channel(8);
+        start_run(&mut app, agent::AgentState::Reasoning);
+        agent_reasoning(&mut app, Ok(reasoning(Some("make"), true)), &tx);
+        assert_eq!(run(&app).state, agent::AgentState::Completed);
+        assert_eq!(run(&app).pending_command, None);
+
+        start_run(&mut app, agent::AgentState::Reasoning);
+        agent_reasoning(&mut app, Err("invalid JSON".to_string()), &tx);
+        assert_eq!(run(&app).state, agent::AgentState::Failed("invalid JSON".to_string()));
+
+        start_run(&mut app, agent::AgentState::Reasoning);
+        let max_steps = run(&app).max_steps;
+        app.autonomous.as_mut().unwrap().steps = vec![step(Some("make")); max_steps];
+        agent_reasoning(&mut app, Ok(reasoning(Some("make"), false)), &tx);
+        assert!(matches!(run(&app).state, agent::AgentState::Failed(_)));
+    }
+
+    #[test]
+    fn reasoning_for_a_stopped_run_is_ignored() {
+        let mut app = app();
+        let (tx, _rx) = mpsc::channel(8);
+        start_run(&mut app, agent::AgentState::Stopped);
+        agent_reasoning(&mut app, Ok(reasoning(Some("make"), false)), &tx);
+
+        assert_eq!(run(&app).state, agent::AgentState::Stopped);
+        assert!(run(&app).steps.is_empty());
+    }
+
+    #[tokio::test]
+    async fn command_output_moves_the_run_to_analysis() {
+        let mut app = app();
+        let (tx, _rx) = mpsc::channel(8);
+        start_run(&mut app, agent::AgentState::Executing);
+        app.autonomous.as_mut().unwrap().steps.push(step(Some("make")));
+        agent_command_output(&mut app, agent::CommandOutput { exit_code: Some(0), result: Ok("built\n".to_string()) }, &tx);
+
+        assert_eq!(run(&app).state, agent::AgentState::Analyzing);
+        let step = run(&app).steps.last().unwrap();
+        assert_eq!(step.output, Some(Ok("built\n".to_string())));
+        assert_eq!(step.exit_code, Some(0));
+    }
+
+    #[test]
+    fn command_output_of_a_stopped_run_is_only_audited() {
+        let mut app = app();
+        let (tx, _rx) = mpsc::channel(8);
+        start_run(&mut app, agent::AgentState::Stopped);
+        app.autonomous.as_mut().unwrap().steps.push(step(Some("make")));
+        app.pending_audit = Some(agent::AuditEntry {
+            id: 0,
+            executed_at: chrono::Utc::now(),
+            session_id: app.current_session_id(),
+            command: "make".to_string(),
+            cwd: ".".to_string(),
+            risk: "low".to_string(),
+            approval: "manual".to_string(),
+            dry_run: false,
+            exit_code: None,
+            output: String::new(),
+        });
+        agent_command_output(&mut app, agent::CommandOutput { exit_code: Some(2), result: Err("failed".to_string()) }, &tx);
+
+        assert_eq!(run(&app).state, agent::AgentState::Stopped);
+        assert_eq!(run(&app).steps.last().unwrap().output, None);
+        let audit = db::load_audit_log(&app.db_conn, 10).unwrap();
+        assert_eq!(audit.len(), 1);
+        assert_eq!(audit[0].exit_code, Some(2));
+        assert_eq!(audit[0].output, "failed");
+    }
+
+    #[test]
+    fn config_changed_reports_what_applies() {
+        let mut app = app();
+        config_changed(&mut app, models::Config::default());
+        assert_eq!(last_toast(&app), Some((toast::Severity::Info, "Config reloaded")));
+
+        let mut config = models::Config::default();
+        config.server.port += 1;
+        config_changed(&mut app, config);
+        assert_eq!(last_toast(&app).map(|(severity, _)| severity), Some(toast::Severity::Warning));
+        assert_eq!(app.config.server.port, models::ServerConfig::default().port, "the server keeps its port until a restart");
+
+        let mut config = models::Config::default();
+        config.redaction.patterns.insert("broken".to_string(), "(".to_string());
+        config_changed(&mut app, config);
+        assert_eq!(last_toast(&app).map(|(severity, _)| severity), Some(toast::Severity::Error));
+        assert!(app.config.redaction.patterns.is_empty());
+
+        let mut config = models::Config::default();
+        config.command_policy.deny.push("glob:make *".to_string());
+        config.command_policy.deny.push("[unclosed".to_string());
+        config_changed(&mut app, config);
+        assert_eq!(last_toast(&app).map(|(severity, _)| severity), Some(toast::Severity::Error));
+        assert_eq!(app.check_command_policy("make clean"), crate::policy::Verdict::Unlisted, "the previous policy stays in use");
+    }
+}
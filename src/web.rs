@@ -0,0 +1,256 @@
+use crate::models::{SearchConfig, SearchProvider};
+use regex::Regex;
+use reqwest::Url;
+use serde_json::Value;
+use std::time::Duration;
+
+// Pages are cut off after this many bytes, readable text is much shorter anyway
+const MAX_DOWNLOAD_BYTES: usize = 2 * 1024 * 1024;
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Whether `url` points at one of `domains` or a subdomain of one. `*` allows any host.
+pub fn domain_allowed(url: &str, domains: &[String]) -> Result<(), String> {
+    let parsed = Url::parse(url).map_err(|e| format!("invalid URL '{}': {}", url, e))?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        return Err(format!("only http and https URLs can be fetched, not {}", parsed.scheme()));
+    }
+    let host = parsed.host_str().unwrap_or_default().to_lowercase();
+    let allowed = domains.iter().any(|domain| {
+        let domain = domain.trim().to_lowercase();
+        domain == "*" || host == domain || host.ends_with(&format!(".{}", domain))
+    });
+    if allowed {
+        Ok(())
+    } else {
+        Err(format!("{} is not in fetch_domains", host))
+    }
+}
+
+/// Download `url` and reduce it to readable text, following redirects only within
+/// `domains`. The agent's HTTP client carries the Ollama credentials, so pages are
+/// fetched with a client of their own.
+pub async fn fetch_readable(url: &str, domains: &[String]) -> Result<String, String> {
+    let domains = domains.to_vec();
+    let redirects = reqwest::redirect::Policy::custom(move |attempt| {
+        if attempt.previous().len() >= 10 {
+            attempt.error("too many redirects")
+        } else if let Err(reason) = domain_allowed(attempt.url().as_str(), &domains) {
+            attempt.error(format!("redirected to a blocked host: {}", reason))
+        } else {
+            attempt.follow()
+        }
+    });
+    let client = reqwest::Client::builder()
+        .timeout(FETCH_TIMEOUT)
+        .redirect(redirects)
+        .user_agent(concat!("ollama-tui/", env!("CARGO_PKG_VERSION")))
+        .build()
+        .map_err(|e| e.to_string())?;
+    let mut response = client.get(url).send().await.map_err(|e| format!("Cannot fetch {}: {}", url, e))?;
+    let status = response.status();
+    let is_html = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.contains("html"));
+
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await.map_err(|e| format!("Cannot fetch {}: {}", url, e))? {
+        body.extend_from_slice(&chunk);
+        if body.len() >= MAX_DOWNLOAD_BYTES {
+            body.truncate(MAX_DOWNLOAD_BYTES);
+            break;
+        }
+    }
+    let text = String::from_utf8_lossy(&body);
+    let text = if is_html { html_to_text(&text) } else { text.trim().to_string() };
+    if !status.is_success() {
+        return Err(format!("{} returned {}\n{}", url, status, text));
+    }
+    Ok(text)
+}
+
+/// Strip a page to its text, keeping headings, list items and links in markdown form.
+pub fn html_to_text(html: &str) -> String {
+    let replace = |text: String, pattern: &str, with: &str| -> String {
+        Regex::new(pattern).map(|re| re.replace_all(&text, with).into_owned()).unwrap_or(text)
+    };
+
+    let mut text = replace(html.to_string(), r"(?s)<!--.*?-->", "");
+    for tag in ["head", "script", "style", "noscript", "svg", "nav", "footer"] {
+        text = replace(text, &format!(r"(?is)<{}\b.*?</{}\s*>", tag, tag), "");
+    }
+    if let Ok(heading) = Regex::new(r"(?i)<h([1-6])\b[^>]*>") {
+        text = heading
+            .replace_all(&text, |caps: &regex::Captures| {
+                let level = caps[1].parse().unwrap_or(1);
+                format!("\n\n{} ", "#".repeat(level))
+            })
+            .into_owned();
+    }
+    text = replace(text, r"(?i)</h[1-6]\s*>", "\n\n");
+    text = replace(text, r"(?i)<li\b[^>]*>", "\n- ");
+    text = replace(text, r#"(?is)<a\s[^>]*href="(https?://[^"]*)"[^>]*>(.*?)</a\s*>"#, "[$2]($1)");
+    text = replace(
+        text,
+        r"(?i)</?(p|div|br|hr|tr|ul|ol|table|section|article|main|pre|blockquote)\b[^>]*>",
+        "\n",
+    );
+    text = replace(text, r"<[^>]*>", "");
+    let text = decode_entities(&text);
+
+    // Collapse the whitespace left behind by the markup
+    let mut out = String::new();
+    let mut blank = 0;
+    for line in text.lines() {
+        let line = line.split_whitespace().collect::<Vec<_>>().join(" ");
+        if line.is_empty() {
+            blank += 1;
+            continue;
+        }
+        if !out.is_empty() {
+            out.push_str(if blank > 0 { "\n\n" } else { "\n" });
+        }
+        out.push_str(&line);
+        blank = 0;
+    }
+    out
+}
+
+fn decode_entities(text: &str) -> String {
+    let Ok(entity) = Regex::new(r"&(#[0-9]+|#[xX][0-9a-fA-F]+|[a-zA-Z]+);") else {
+        return text.to_string();
+    };
+    entity
+        .replace_all(text, |caps: &regex::Captures| {
+            let name = &caps[1];
+            let decoded = match name {
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                "nbsp" => Some(' '),
+                _ => match name.strip_prefix("#x").or_else(|| name.strip_prefix("#X")) {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok().and_then(char::from_u32),
+                    None => name.strip_prefix('#').and_then(|n| n.parse().ok()).and_then(char::from_u32),
+                },
+            };
+            decoded.map(String::from).unwrap_or_else(|| caps[0].to_string())
+        })
+        .into_owned()
+}
+
+struct SearchResult {
+    title: String,
+    url: String,
+    snippet: String,
+}
+
+/// Query the configured search provider, returning one numbered entry per result.
+pub async fn search(config: &SearchConfig, query: &str) -> Result<String, String> {
+    let client = reqwest::Client::builder()
+        .timeout(FETCH_TIMEOUT)
+        .user_agent(concat!("ollama-tui/", env!("CARGO_PKG_VERSION")))
+        .build()
+        .map_err(|e| e.to_string())?;
+    let results = match config.provider {
+        SearchProvider::Searxng => search_searxng(&client, config, query).await?,
+        SearchProvider::Brave => search_brave(&client, config, query).await?,
+        SearchProvider::Duckduckgo => search_duckduckgo(&client, config, query).await?,
+    };
+    if results.is_empty() {
+        return Ok(format!("No results for '{}'", query));
+    }
+    Ok(results
+        .iter()
+        .take(config.max_results)
+        .enumerate()
+        .map(|(i, result)| format!("{}. {}\n   {}\n   {}", i + 1, result.title, result.url, result.snippet))
+        .collect::<Vec<_>>()
+        .join("\n"))
+}
+
+async fn get_json(request: reqwest::RequestBuilder) -> Result<Value, String> {
+    let response = request.send().await.map_err(|e| format!("Search failed: {}", e))?;
+    if !response.status().is_success() {
+        return Err(format!("Search failed: {}", response.status()));
+    }
+    response.json().await.map_err(|e| format!("Unexpected search response: {}", e))
+}
+
+fn results_from(items: Option<&Value>, snippet_key: &str) -> Vec<SearchResult> {
+    let field = |item: &Value, key: &str| item.get(key).and_then(Value::as_str).unwrap_or_default().to_string();
+    items
+        .and_then(Value::as_array)
+        .map(|items| {
+            items
+                .iter()
+                .map(|item| SearchResult {
+                    title: field(item, "title"),
+                    url: field(item, "url"),
+                    snippet: html_to_text(&field(item, snippet_key)),
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+async fn search_searxng(client: &reqwest::Client, config: &SearchConfig, query: &str) -> Result<Vec<SearchResult>, String> {
+    let base = config.url.as_deref().ok_or("SearXNG needs the instance url in search.url")?;
+    let request = client
+        .get(format!("{}/search", base.trim_end_matches('/')))
+        .query(&[("q", query), ("format", "json")]);
+    let body = get_json(request).await?;
+    Ok(results_from(body.get("results"), "content"))
+}
+
+async fn search_brave(client: &reqwest::Client, config: &SearchConfig, query: &str) -> Result<Vec<SearchResult>, String> {
+    let key = config.api_key.as_deref().ok_or("Brave search needs search.api_key")?;
+    let base = config.url.as_deref().unwrap_or("https://api.search.brave.com/res/v1/web/search");
+    let count = config.max_results.to_string();
+    let request = client
+        .get(base)
+        .header("X-Subscription-Token", key)
+        .header(reqwest::header::ACCEPT, "application/json")
+        .query(&[("q", query), ("count", count.as_str())]);
+    let body = get_json(request).await?;
+    Ok(results_from(body.get("web").and_then(|web| web.get("results")), "description"))
+}
+
+// DuckDuckGo has no free results API, so this reads its HTML-only frontend
+async fn search_duckduckgo(client: &reqwest::Client, config: &SearchConfig, query: &str) -> Result<Vec<SearchResult>, String> {
+    let base = config.url.as_deref().unwrap_or("https://html.duckduckgo.com/html/");
+    let response = client
+        .post(base)
+        .form(&[("q", query)])
+        .send()
+        .await
+        .map_err(|e| format!("Search failed: {}", e))?;
+    if !response.status().is_success() {
+        return Err(format!("Search failed: {}", response.status()));
+    }
+    let html = response.text().await.map_err(|e| format!("Search failed: {}", e))?;
+
+    let link = Regex::new(r#"(?s)<a[^>]*class="result__a"[^>]*href="([^"]*)"[^>]*>(.*?)</a>"#).map_err(|e| e.to_string())?;
+    let snippet = Regex::new(r#"(?s)class="result__snippet"[^>]*>(.*?)</a>"#).map_err(|e| e.to_string())?;
+    let snippets: Vec<String> = snippet.captures_iter(&html).map(|caps| html_to_text(&caps[1])).collect();
+    Ok(link
+        .captures_iter(&html)
+        .enumerate()
+        .map(|(i, caps)| SearchResult {
+            title: html_to_text(&caps[2]),
+            url: duckduckgo_target(&decode_entities(&caps[1])),
+            snippet: snippets.get(i).cloned().unwrap_or_default(),
+        })
+        .collect())
+}
+
+// Result links go through a redirect carrying the real URL in `uddg`
+fn duckduckgo_target(href: &str) -> String {
+    let absolute = if href.starts_with("//") { format!("https:{}", href) } else { href.to_string() };
+    Url::parse(&absolute)
+        .ok()
+        .and_then(|url| url.query_pairs().find(|(key, _)| key == "uddg").map(|(_, target)| target.into_owned()))
+        .unwrap_or(absolute)
+}
@@ -0,0 +1,905 @@
+use crate::agent::{self, AuditEntry, CommandOutput};
+use crate::models::{AgentCommand, Message, Role};
+use crate::{config, policy, project};
+use crate::project::{toml_key, toml_string};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+
+/// A named sequence of shell commands run one after another, e.g. build → test.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Workflow {
+    /// Defaults to the file name for workflows loaded from files
+    #[serde(default)]
+    pub name: String,
+    /// File the workflow was loaded from, `None` for built-in and generated ones
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
+    #[serde(default)]
+    pub description: String,
+    /// Values for `${name}` references in the step commands
+    #[serde(default)]
+    pub variables: HashMap<String, String>,
+    pub steps: Vec<WorkflowStep>,
+    /// Start this workflow automatically after something else finished
+    #[serde(default)]
+    pub triggers: Vec<WorkflowTrigger>,
+}
+
+/// When a workflow is started automatically, e.g. to chain build → test → deploy.
+/// Its steps still wait for approval like those of a workflow started by hand.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum WorkflowTrigger {
+    /// The named workflow completed without a failing step
+    AfterWorkflow(String),
+    /// An autonomous agent command matching the rule succeeded; rules are written
+    /// like `command_policy` rules, as a regex or with a `glob:` prefix
+    AfterCommand(String),
+}
+
+impl WorkflowTrigger {
+    pub fn describe(&self) -> String {
+        match self {
+            WorkflowTrigger::AfterWorkflow(name) => format!("after_workflow={}", name),
+            WorkflowTrigger::AfterCommand(rule) => format!("after_command={}", rule),
+        }
+    }
+
+    /// Read the `after_workflow=<name>` or `after_command=<rule>` form of `describe`.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        match text.split_once('=').map(|(kind, value)| (kind.trim(), value.trim())) {
+            Some(("after_workflow", name)) if !name.is_empty() => Ok(WorkflowTrigger::AfterWorkflow(name.to_string())),
+            Some(("after_command", rule)) if !rule.is_empty() => Ok(WorkflowTrigger::AfterCommand(rule.to_string())),
+            _ => Err("Use after_workflow=<name> or after_command=<rule>".to_string()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct WorkflowStep {
+    pub name: String,
+    pub command: String,
+    /// Shell command that must succeed for the step to run
+    #[serde(default)]
+    pub condition: Option<String>,
+    #[serde(default)]
+    pub retry_count: u32,
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+    /// Carry on with the next step when this one fails
+    #[serde(default)]
+    pub continue_on_error: bool,
+}
+
+impl WorkflowStep {
+    fn new(name: &str, command: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            command: command.to_string(),
+            condition: None,
+            retry_count: 0,
+            timeout_secs: None,
+            continue_on_error: false,
+        }
+    }
+
+    /// Condition, retries and timeout in a few words, for the workflow popup.
+    pub fn settings(&self) -> String {
+        let mut settings = Vec::new();
+        if let Some(condition) = &self.condition {
+            settings.push(format!("if `{}`", condition));
+        }
+        if self.retry_count > 0 {
+            settings.push(format!("{} retries", self.retry_count));
+        }
+        if let Some(timeout) = self.timeout_secs {
+            settings.push(format!("{}s timeout", timeout));
+        }
+        if self.continue_on_error {
+            settings.push("continues on error".to_string());
+        }
+        settings.join(", ")
+    }
+}
+
+impl Workflow {
+    pub fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "name": { "type": "string" },
+                "description": { "type": "string" },
+                "variables": { "type": "object", "additionalProperties": { "type": "string" } },
+                "steps": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "name": { "type": "string" },
+                            "command": { "type": "string" },
+                            "condition": { "type": "string" },
+                            "retry_count": { "type": "integer" },
+                            "timeout_secs": { "type": "integer" },
+                            "continue_on_error": { "type": "boolean" }
+                        },
+                        "required": ["name", "command"]
+                    }
+                }
+            },
+            "required": ["name", "description", "steps"]
+        })
+    }
+
+    /// The workflow as a `.toml` file that `load_file` reads back.
+    pub fn to_toml(&self) -> String {
+        let mut out = format!("name = {}\n", toml_string(&self.name));
+        if !self.description.is_empty() {
+            out.push_str(&format!("description = {}\n", toml_string(&self.description)));
+        }
+        if !self.variables.is_empty() {
+            out.push_str("\n[variables]\n");
+            let mut variables: Vec<_> = self.variables.iter().collect();
+            variables.sort();
+            for (name, value) in variables {
+                out.push_str(&format!("{} = {}\n", toml_key(name), toml_string(value)));
+            }
+        }
+        for step in &self.steps {
+            out.push_str("\n[[steps]]\n");
+            out.push_str(&format!("name = {}\n", toml_string(&step.name)));
+            out.push_str(&format!("command = {}\n", toml_string(&step.command)));
+            if let Some(condition) = &step.condition {
+                out.push_str(&format!("condition = {}\n", toml_string(condition)));
+            }
+            if step.retry_count > 0 {
+                out.push_str(&format!("retry_count = {}\n", step.retry_count));
+            }
+            if let Some(timeout) = step.timeout_secs {
+                out.push_str(&format!("timeout_secs = {}\n", timeout));
+            }
+            if step.continue_on_error {
+                out.push_str("continue_on_error = true\n");
+            }
+        }
+        for trigger in &self.triggers {
+            out.push_str("\n[[triggers]]\n");
+            match trigger {
+                WorkflowTrigger::AfterWorkflow(name) => out.push_str(&format!("after_workflow = {}\n", toml_string(name))),
+                WorkflowTrigger::AfterCommand(rule) => out.push_str(&format!("after_command = {}\n", toml_string(rule))),
+            }
+        }
+        out
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.steps.is_empty() {
+            return Err("has no steps".to_string());
+        }
+        for (i, step) in self.steps.iter().enumerate() {
+            if step.name.trim().is_empty() {
+                return Err(format!("step {} has no name", i + 1));
+            }
+            if step.command.trim().is_empty() {
+                return Err(format!("step '{}' has no command", step.name));
+            }
+            if step.timeout_secs == Some(0) {
+                return Err(format!("step '{}' has a timeout of 0 seconds", step.name));
+            }
+        }
+        for trigger in &self.triggers {
+            match trigger {
+                WorkflowTrigger::AfterWorkflow(name) if *name == self.name => {
+                    return Err("cannot be triggered by itself".to_string());
+                }
+                WorkflowTrigger::AfterCommand(rule) => {
+                    policy::matches_rule(rule, "")?;
+                }
+                WorkflowTrigger::AfterWorkflow(_) => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Every workflow the user can run with `:wf`.
+pub struct WorkflowManager {
+    workflows: Vec<Workflow>,
+    /// Workflow files that could not be loaded, with the reason
+    errors: Vec<String>,
+}
+
+impl WorkflowManager {
+    /// The built-in workflows plus those in the workflow directory, where a file may
+    /// replace a built-in workflow by using its name.
+    pub fn load() -> Self {
+        let mut manager = Self { workflows: create_common_workflows(), errors: Vec::new() };
+        match workflow_dir() {
+            Ok(dir) => manager.load_dir(&dir),
+            Err(e) => manager.errors.push(e.to_string()),
+        }
+        manager
+    }
+
+    fn load_dir(&mut self, dir: &Path) {
+        let Ok(entries) = fs::read_dir(dir) else {
+            return;
+        };
+        let mut paths: Vec<PathBuf> = entries.filter_map(|entry| entry.ok().map(|entry| entry.path())).collect();
+        paths.sort();
+        for path in paths {
+            if !matches!(path.extension().and_then(|ext| ext.to_str()), Some("toml" | "json")) {
+                continue;
+            }
+            match load_file(&path) {
+                Ok(workflow) => self.add(workflow),
+                Err(e) => self.errors.push(format!("{}: {}", path.display(), e)),
+            }
+        }
+    }
+
+    /// Add a workflow, replacing the one with the same name.
+    pub fn add(&mut self, workflow: Workflow) {
+        match self.workflows.iter_mut().find(|existing| existing.name == workflow.name) {
+            Some(existing) => *existing = workflow,
+            None => self.workflows.push(workflow),
+        }
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.workflows.retain(|workflow| workflow.name != name);
+    }
+
+    /// Names of the workflows to start after `workflow` completed.
+    pub fn triggered_by_workflow(&self, workflow: &str) -> Vec<String> {
+        self.triggered(|trigger| matches!(trigger, WorkflowTrigger::AfterWorkflow(name) if name == workflow))
+    }
+
+    /// Names of the workflows to start after the agent ran `command` successfully.
+    pub fn triggered_by_command(&self, command: &str) -> Vec<String> {
+        self.triggered(|trigger| match trigger {
+            WorkflowTrigger::AfterCommand(rule) => policy::matches_rule(rule, command).unwrap_or(false),
+            WorkflowTrigger::AfterWorkflow(_) => false,
+        })
+    }
+
+    fn triggered(&self, mut fires: impl FnMut(&WorkflowTrigger) -> bool) -> Vec<String> {
+        self.workflows
+            .iter()
+            .filter(|workflow| workflow.triggers.iter().any(&mut fires))
+            .map(|workflow| workflow.name.clone())
+            .collect()
+    }
+
+    pub fn workflows(&self) -> &[Workflow] {
+        &self.workflows
+    }
+
+    pub fn errors(&self) -> &[String] {
+        &self.errors
+    }
+}
+
+/// `workflows/` next to the config file, e.g. `~/.config/ollama-tui/workflows`.
+pub fn workflow_dir() -> Result<PathBuf> {
+    Ok(config::get_config_path()?
+        .parent()
+        .ok_or_else(|| anyhow!("Config path has no parent directory"))?
+        .join("workflows"))
+}
+
+/// Read one workflow from a `.toml` or `.json` file.
+pub fn load_file(path: &Path) -> Result<Workflow, String> {
+    let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let value = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("json") => serde_json::from_str(&text).map_err(|e| e.to_string())?,
+        _ => project::parse_toml(&text)?,
+    };
+    let mut workflow: Workflow = serde_json::from_value(value).map_err(|e| e.to_string())?;
+    if workflow.name.trim().is_empty() {
+        workflow.name = path.file_stem().unwrap_or_default().to_string_lossy().to_string();
+    }
+    workflow.validate()?;
+    workflow.path = Some(path.to_path_buf());
+    Ok(workflow)
+}
+
+/// Write the workflow back to the file it came from, or to `<name>.toml` in the
+/// workflow directory if it has none, and remember the path.
+pub fn save(workflow: &mut Workflow) -> Result<PathBuf> {
+    let path = match &workflow.path {
+        Some(path) => path.clone(),
+        None => {
+            let file_name: String = workflow
+                .name
+                .chars()
+                .map(|c| if c.is_alphanumeric() || c == '-' || c == '_' { c } else { '-' })
+                .collect();
+            workflow_dir()?.join(format!("{}.toml", file_name))
+        }
+    };
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let text = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("json") => serde_json::to_string_pretty(workflow)?,
+        _ => workflow.to_toml(),
+    };
+    fs::write(&path, text)?;
+    workflow.path = Some(path.clone());
+    Ok(path)
+}
+
+/// Ask the model to draft a workflow for `description`, as JSON following `Workflow::schema`.
+pub fn generation_messages(description: &str, cwd: &Path) -> Vec<Message> {
+    let system = format!(
+        "You write workflows for a terminal assistant on a {} machine, run in the directory {}. A workflow \
+         is a named sequence of shell commands run one after another. Reply with JSON: \"name\" is short \
+         and kebab-case, \"description\" one sentence, and \"steps\" lists the steps, each with a \"name\" \
+         and exactly one shell \"command\". A step may have a \"condition\" (a shell command that must \
+         succeed for the step to run), \"retry_count\" and \"timeout_secs\" for flaky or slow commands, and \
+         \"continue_on_error\" when later steps do not depend on it. Put values the user is likely to \
+         change, such as hosts or branch names, in \"variables\" and refer to them as ${{name}}. Check \
+         before changing things and never use sudo unless asked.",
+        std::env::consts::OS,
+        cwd.display()
+    );
+    vec![Message::new(Role::System, system), Message::new(Role::User, description.to_string())]
+}
+
+/// Turn the model's draft into a valid workflow, dropping empty optional fields.
+pub fn from_draft(mut value: Value) -> Result<Workflow, String> {
+    if let Some(steps) = value.get_mut("steps").and_then(Value::as_array_mut) {
+        for step in steps.iter_mut().filter_map(Value::as_object_mut) {
+            step.retain(|key, field| {
+                !(field.is_null()
+                    || (key == "condition" && field.as_str().is_some_and(|c| c.trim().is_empty()))
+                    || (key == "timeout_secs" && field.as_u64() == Some(0)))
+            });
+        }
+    }
+    if let Some(object) = value.as_object_mut() {
+        object.retain(|_, field| !field.is_null());
+    }
+    let mut workflow: Workflow = serde_json::from_value(value).map_err(|e| e.to_string())?;
+    if workflow.name.trim().is_empty() {
+        workflow.name = "generated".to_string();
+    }
+    workflow.validate()?;
+    Ok(workflow)
+}
+
+/// Built-in workflows for everyday project chores.
+pub fn create_common_workflows() -> Vec<Workflow> {
+    vec![
+        Workflow {
+            path: None,
+            name: "rust-check".to_string(),
+            description: "Format check, lint and test a Cargo project".to_string(),
+            variables: HashMap::new(),
+            triggers: Vec::new(),
+            steps: vec![
+                WorkflowStep::new("Check formatting", "cargo fmt --check"),
+                WorkflowStep::new("Lint", "cargo clippy --all-targets -- -D warnings"),
+                WorkflowStep::new("Test", "cargo test"),
+            ],
+        },
+        Workflow {
+            path: None,
+            name: "git-overview".to_string(),
+            description: "Summarize the state of the git repository".to_string(),
+            variables: HashMap::new(),
+            triggers: Vec::new(),
+            steps: vec![
+                WorkflowStep::new("Status", "git status --short --branch"),
+                WorkflowStep::new("Recent commits", "git log --oneline -10"),
+                WorkflowStep::new("Changes", "git diff --stat"),
+            ],
+        },
+        Workflow {
+            path: None,
+            name: "git-sync".to_string(),
+            description: "Fetch and fast-forward a branch".to_string(),
+            variables: HashMap::from([("branch".to_string(), "main".to_string())]),
+            triggers: Vec::new(),
+            steps: vec![
+                WorkflowStep::new("Fetch", "git fetch origin"),
+                WorkflowStep::new("Fast-forward", "git merge --ff-only origin/${branch}"),
+            ],
+        },
+        Workflow {
+            path: None,
+            name: "node-test".to_string(),
+            description: "Install dependencies and run the test script of a Node project".to_string(),
+            variables: HashMap::new(),
+            triggers: Vec::new(),
+            steps: vec![
+                WorkflowStep::new("Install", "npm ci"),
+                WorkflowStep::new("Test", "npm test"),
+            ],
+        },
+        Workflow {
+            path: None,
+            name: "system-info".to_string(),
+            description: "Show the machine, disk and memory usage".to_string(),
+            variables: HashMap::new(),
+            triggers: Vec::new(),
+            steps: vec![
+                WorkflowStep::new("Machine", "uname -a"),
+                WorkflowStep::new("Disk", "df -h"),
+                WorkflowStep::new("Memory", "free -h || vm_stat"),
+            ],
+        },
+    ]
+}
+
+/// Names referenced as `${name}` in the step commands and conditions, each once.
+pub fn referenced_variables(workflow: &Workflow) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    let texts = workflow.steps.iter().flat_map(|step| std::iter::once(&step.command).chain(&step.condition));
+    for text in texts {
+        let mut rest = text.as_str();
+        while let Some(start) = rest.find("${") {
+            let after = &rest[start + 2..];
+            let Some(end) = after.find('}') else {
+                break;
+            };
+            let name = &after[..end];
+            if !name.is_empty() && !names.iter().any(|existing| existing == name) {
+                names.push(name.to_string());
+            }
+            rest = &after[end + 1..];
+        }
+    }
+    names
+}
+
+/// Value of `${response}` taken from a reply: the first code block if there is one,
+/// otherwise the whole text.
+pub fn response_value(reply: &str) -> String {
+    let block = reply.split_once("```").and_then(|(_, rest)| {
+        let (_, body) = rest.split_once('\n')?;
+        Some(body.split_once("```").map_or(body, |(body, _)| body))
+    });
+    block.unwrap_or(reply).trim().to_string()
+}
+
+/// Replace `${name}` with the workflow's variables, leaving unknown names as they are.
+/// The command is read once from the start, a value is never expanded itself.
+pub fn expand_variables(command: &str, variables: &HashMap<String, String>) -> String {
+    let mut expanded = String::with_capacity(command.len());
+    let mut rest = command;
+    while let Some(start) = rest.find("${") {
+        let after = &rest[start + 2..];
+        let Some(end) = after.find('}') else {
+            break;
+        };
+        expanded.push_str(&rest[..start]);
+        match variables.get(&after[..end]) {
+            Some(value) => expanded.push_str(value),
+            None => expanded.push_str(&rest[start..start + end + 3]),
+        }
+        rest = &after[end + 1..];
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+/// `value` as a single word of the shell commands run, so nothing in it runs as shell syntax.
+pub fn shell_quote(value: &str) -> String {
+    if cfg!(windows) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        format!("'{}'", value.replace('\'', "'\\''"))
+    }
+}
+
+/// A one-off workflow running `commands` in order, such as the shell block of a reply.
+pub fn from_commands(name: &str, description: &str, commands: Vec<String>) -> Workflow {
+    Workflow {
+        path: None,
+        name: name.to_string(),
+        description: description.to_string(),
+        variables: HashMap::new(),
+        triggers: Vec::new(),
+        steps: commands
+            .iter()
+            .enumerate()
+            .map(|(i, command)| WorkflowStep::new(&format!("Command {}", i + 1), command))
+            .collect(),
+    }
+}
+
+/// The workflow's steps as agent commands, to be approved and run in order. A step's
+/// condition runs under the same approval, so it counts towards the risk.
+pub fn step_commands(workflow: &Workflow) -> Vec<AgentCommand> {
+    workflow
+        .steps
+        .iter()
+        .map(|step| {
+            let mut command = AgentCommand::new(expand_variables(&step.command, &workflow.variables));
+            if let Some(condition) = &step.condition {
+                let condition = expand_variables(condition, &workflow.variables);
+                command.risk = command.risk.max(agent::assess_risk_level(&condition));
+            }
+            command
+        })
+        .collect()
+}
+
+/// Progress of the running step, reported while it executes.
+pub enum StepEvent {
+    Attempt(u32), // Attempt number, counting from 1
+    Output(Vec<u8>),
+}
+
+/// Run one step: check its condition, then run `command` until it succeeds or the
+/// retries are used up. Returns `None` when the condition does not hold. The step's
+/// timeout replaces `default_timeout`, and `cancel` kills whatever is running.
+#[allow(clippy::too_many_arguments)]
+pub async fn execute_step(
+    step: &WorkflowStep,
+    command: &str,
+    condition: Option<&str>,
+    cwd: &Path,
+    env: &HashMap<String, String>,
+    dry_run: bool,
+    default_timeout: Option<Duration>,
+    mut cancel: oneshot::Receiver<()>,
+    progress: mpsc::UnboundedSender<StepEvent>,
+) -> Option<CommandOutput> {
+    let timeout = step.timeout_secs.map(Duration::from_secs).or(default_timeout);
+    if let Some(condition) = condition {
+        let (output, cancelled) = run_cancellable(condition, cwd, env, dry_run, timeout, &mut cancel, None).await;
+        if cancelled {
+            return Some(output);
+        }
+        output.result.as_ref().ok()?;
+    }
+    let mut attempt = 1;
+    loop {
+        progress.send(StepEvent::Attempt(attempt)).ok();
+        let (chunk_tx, mut chunk_rx) = mpsc::unbounded_channel();
+        let execution = run_cancellable(command, cwd, env, dry_run, timeout, &mut cancel, Some(chunk_tx));
+        tokio::pin!(execution);
+        let (output, cancelled) = loop {
+            tokio::select! {
+                Some(chunk) = chunk_rx.recv() => {
+                    progress.send(StepEvent::Output(chunk)).ok();
+                }
+                result = &mut execution => break result,
+            }
+        };
+        while let Ok(chunk) = chunk_rx.try_recv() {
+            progress.send(StepEvent::Output(chunk)).ok();
+        }
+        if output.result.is_ok() || cancelled || attempt > step.retry_count {
+            return Some(output);
+        }
+        attempt += 1;
+    }
+}
+
+// `execute_command` with a cancel signal that outlives it, so it can stop several commands
+async fn run_cancellable(
+    command: &str,
+    cwd: &Path,
+    env: &HashMap<String, String>,
+    dry_run: bool,
+    timeout: Option<Duration>,
+    cancel: &mut oneshot::Receiver<()>,
+    live_output: Option<mpsc::UnboundedSender<Vec<u8>>>,
+) -> (CommandOutput, bool) {
+    let (kill_tx, kill_rx) = oneshot::channel();
+    let execution = agent::execute_command(command, cwd, env, dry_run, timeout, kill_rx, live_output);
+    tokio::pin!(execution);
+    tokio::select! {
+        output = &mut execution => (output, false),
+        Ok(()) = &mut *cancel => {
+            kill_tx.send(()).ok();
+            (execution.await, true)
+        }
+    }
+}
+
+/// A workflow being run. Its steps are `AppState::pending_commands`, in the same order,
+/// going through the agent's approval rules and reported back with `CommandExecuted`.
+pub struct WorkflowRun {
+    pub workflow: Workflow,
+    pub session_id: i64,
+    /// Step whose command is executing
+    pub running: Option<usize>,
+    /// Attempt of the running step, counting from 1
+    pub attempt: u32,
+    /// Output of the running step so far
+    pub output: Vec<u8>,
+    /// Steps not run because their condition did not hold
+    pub skipped: Vec<usize>,
+    /// Workflows that triggered this one, oldest first, so a chain cannot loop
+    pub chain: Vec<String>,
+    /// Set once every step ran, a step failed or the user stopped the run
+    pub finished: bool,
+    /// Audit record of the running step, completed when it finishes
+    pub audit: Option<AuditEntry>,
+    pub cancel: Option<oneshot::Sender<()>>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum StepStatus {
+    Pending,
+    AwaitingApproval,
+    Running,
+    Succeeded,
+    Failed,
+    Skipped,
+}
+
+impl StepStatus {
+    pub fn symbol(self) -> &'static str {
+        match self {
+            StepStatus::Pending => "·",
+            StepStatus::AwaitingApproval => "?",
+            StepStatus::Running => "▶",
+            StepStatus::Succeeded => "✓",
+            StepStatus::Failed => "✗",
+            StepStatus::Skipped => "-",
+        }
+    }
+}
+
+/// One editable row of the workflow editor.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Field {
+    Name,
+    Description,
+    Variable(usize), // Index into `WorkflowEditor::variables`
+    Trigger(usize),
+    StepName(usize),
+    Command(usize),
+    Condition(usize),
+    Retries(usize),
+    Timeout(usize),
+    ContinueOnError(usize),
+}
+
+impl Field {
+    pub fn step(self) -> Option<usize> {
+        match self {
+            Field::StepName(i)
+            | Field::Command(i)
+            | Field::Condition(i)
+            | Field::Retries(i)
+            | Field::Timeout(i)
+            | Field::ContinueOnError(i) => Some(i),
+            _ => None,
+        }
+    }
+}
+
+/// Form for creating or changing a workflow, one field per row.
+pub struct WorkflowEditor {
+    pub workflow: Workflow,
+    /// Variable names in the order they are shown
+    pub variables: Vec<String>,
+    pub selected: usize,
+    /// Text typed for the selected field, `None` when not editing
+    pub editing: Option<String>,
+    /// Name the workflow had when the editor opened, `None` for a new one
+    pub original_name: Option<String>,
+}
+
+impl WorkflowEditor {
+    pub fn new(workflow: Workflow, original_name: Option<String>) -> Self {
+        let mut variables: Vec<String> = workflow.variables.keys().cloned().collect();
+        variables.sort();
+        Self { workflow, variables, selected: 0, editing: None, original_name }
+    }
+
+    pub fn fields(&self) -> Vec<Field> {
+        let mut fields = vec![Field::Name, Field::Description];
+        fields.extend((0..self.variables.len()).map(Field::Variable));
+        fields.extend((0..self.workflow.triggers.len()).map(Field::Trigger));
+        for i in 0..self.workflow.steps.len() {
+            fields.extend([
+                Field::StepName(i),
+                Field::Command(i),
+                Field::Condition(i),
+                Field::Retries(i),
+                Field::Timeout(i),
+                Field::ContinueOnError(i),
+            ]);
+        }
+        fields
+    }
+
+    pub fn current(&self) -> Option<Field> {
+        self.fields().get(self.selected).copied()
+    }
+
+    pub fn label(&self, field: Field) -> String {
+        match field {
+            Field::Name => "Name".to_string(),
+            Field::Description => "Description".to_string(),
+            Field::Variable(_) => "Variable".to_string(),
+            Field::Trigger(_) => "Trigger".to_string(),
+            Field::StepName(i) => format!("Step {}", i + 1),
+            Field::Command(_) => "  Command".to_string(),
+            Field::Condition(_) => "  Condition".to_string(),
+            Field::Retries(_) => "  Retries".to_string(),
+            Field::Timeout(_) => "  Timeout (s)".to_string(),
+            Field::ContinueOnError(_) => "  Continue on error".to_string(),
+        }
+    }
+
+    pub fn value(&self, field: Field) -> String {
+        let steps = &self.workflow.steps;
+        match field {
+            Field::Name => self.workflow.name.clone(),
+            Field::Description => self.workflow.description.clone(),
+            Field::Variable(i) => {
+                let name = &self.variables[i];
+                format!("{}={}", name, self.workflow.variables.get(name).map(String::as_str).unwrap_or(""))
+            }
+            Field::Trigger(i) => self.workflow.triggers[i].describe(),
+            Field::StepName(i) => steps[i].name.clone(),
+            Field::Command(i) => steps[i].command.clone(),
+            Field::Condition(i) => steps[i].condition.clone().unwrap_or_default(),
+            Field::Retries(i) => steps[i].retry_count.to_string(),
+            Field::Timeout(i) => steps[i].timeout_secs.map(|t| t.to_string()).unwrap_or_default(),
+            Field::ContinueOnError(i) => if steps[i].continue_on_error { "yes" } else { "no" }.to_string(),
+        }
+    }
+
+    /// Start editing the selected field with its current value; toggles flip instead.
+    pub fn edit(&mut self) {
+        match self.current() {
+            Some(Field::ContinueOnError(i)) => {
+                let step = &mut self.workflow.steps[i];
+                step.continue_on_error = !step.continue_on_error;
+            }
+            Some(field) => self.editing = Some(self.value(field)),
+            None => {}
+        }
+    }
+
+    /// Store the typed text in the selected field, keeping it for correction if it is invalid.
+    pub fn commit(&mut self) -> Result<(), String> {
+        let (Some(field), Some(text)) = (self.current(), self.editing.clone()) else {
+            return Ok(());
+        };
+        let text = text.trim();
+        let optional = |text: &str| Some(text.to_string()).filter(|text| !text.is_empty());
+        match field {
+            Field::Name => self.workflow.name = text.to_string(),
+            Field::Description => self.workflow.description = text.to_string(),
+            Field::Variable(i) => {
+                let (name, value) = text.split_once('=').unwrap_or((text, ""));
+                let name = name.trim().to_string();
+                if name.is_empty() {
+                    return Err("Use name=value".to_string());
+                }
+                if name != self.variables[i] && self.variables.contains(&name) {
+                    return Err(format!("Variable '{}' already exists", name));
+                }
+                let old = std::mem::replace(&mut self.variables[i], name.clone());
+                self.workflow.variables.remove(&old);
+                self.workflow.variables.insert(name, value.trim().to_string());
+            }
+            Field::Trigger(i) => self.workflow.triggers[i] = WorkflowTrigger::parse(text)?,
+            Field::StepName(i) => self.workflow.steps[i].name = text.to_string(),
+            Field::Command(i) => self.workflow.steps[i].command = text.to_string(),
+            Field::Condition(i) => self.workflow.steps[i].condition = optional(text),
+            Field::Retries(i) => {
+                self.workflow.steps[i].retry_count = text.parse().map_err(|_| "Retries must be a number".to_string())?;
+            }
+            Field::Timeout(i) => {
+                self.workflow.steps[i].timeout_secs = match optional(text) {
+                    Some(text) => Some(text.parse().map_err(|_| "Timeout must be a number of seconds".to_string())?),
+                    None => None,
+                };
+            }
+            Field::ContinueOnError(_) => {}
+        }
+        self.editing = None;
+        Ok(())
+    }
+
+    fn select(&mut self, field: Field) {
+        self.selected = self.fields().iter().position(|f| *f == field).unwrap_or(0);
+    }
+
+    /// Insert an empty step after the selected one and start naming it.
+    pub fn add_step(&mut self) {
+        let index = self.current().and_then(Field::step).map_or(self.workflow.steps.len(), |i| i + 1);
+        self.workflow.steps.insert(index, WorkflowStep::new("", ""));
+        self.select(Field::StepName(index));
+        self.editing = Some(String::new());
+    }
+
+    pub fn add_variable(&mut self) {
+        let mut n = self.variables.len() + 1;
+        while self.variables.contains(&format!("var{}", n)) {
+            n += 1;
+        }
+        let name = format!("var{}", n);
+        self.workflow.variables.insert(name.clone(), String::new());
+        self.variables.push(name);
+        self.select(Field::Variable(self.variables.len() - 1));
+        self.edit();
+    }
+
+    /// Add a trigger and start typing it, e.g. `after_workflow=build`.
+    pub fn add_trigger(&mut self) {
+        self.workflow.triggers.push(WorkflowTrigger::AfterWorkflow(String::new()));
+        self.select(Field::Trigger(self.workflow.triggers.len() - 1));
+        self.editing = Some("after_workflow=".to_string());
+    }
+
+    /// Remove the selected step, variable or trigger.
+    pub fn delete(&mut self) {
+        match self.current() {
+            Some(Field::Variable(i)) => {
+                let name = self.variables.remove(i);
+                self.workflow.variables.remove(&name);
+            }
+            Some(Field::Trigger(i)) => {
+                self.workflow.triggers.remove(i);
+            }
+            Some(field) => {
+                if let Some(i) = field.step() {
+                    self.workflow.steps.remove(i);
+                }
+            }
+            None => {}
+        }
+        self.selected = self.selected.min(self.fields().len().saturating_sub(1));
+    }
+
+    /// Swap the selected step with the next or previous one.
+    pub fn move_step(&mut self, down: bool) {
+        let Some(field) = self.current() else {
+            return;
+        };
+        let Some(i) = field.step() else {
+            return;
+        };
+        let j = if down { i + 1 } else { i.wrapping_sub(1) };
+        if j >= self.workflow.steps.len() {
+            return;
+        }
+        self.workflow.steps.swap(i, j);
+        self.select(Field::StepName(j));
+    }
+
+    pub fn move_selection(&mut self, down: bool) {
+        let len = self.fields().len();
+        self.selected = if down { (self.selected + 1) % len } else { (self.selected + len - 1) % len };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn variables_are_expanded_in_one_pass() {
+        let variables = HashMap::from([
+            ("a".to_string(), "${b}".to_string()),
+            ("b".to_string(), "two".to_string()),
+        ]);
+        assert_eq!(expand_variables("echo ${a} ${b} ${c} $HOME ${", &variables), "echo ${b} two ${c} $HOME ${");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn quoted_values_stay_one_word() {
+        assert_eq!(shell_quote("fix: it's $(rm -rf ~); done"), r"'fix: it'\''s $(rm -rf ~); done'");
+    }
+}